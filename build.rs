@@ -0,0 +1,164 @@
+//! ROM database(NES 2.0 XML database形式)をsrc/rom_db.rsから読める表に変換します
+//! 環境変数NES_ROM_DBがあればsrc/rom_db.xmlの代わりにそちらを使う
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+struct Game {
+    title: String,
+    crc32: u32,
+    sha1: [u8; 20],
+    mapper: u16,
+    submapper: u8,
+    mirroring: String,
+    is_battery: bool,
+    prg_ram: usize,
+    prg_nvram: usize,
+    chr_ram: usize,
+    chr_nvram: usize,
+    console_type: u8,
+    region: u8,
+    vs_ppu: u8,
+    vs_hardware: u8,
+}
+
+/// `<name ... />`の中身を返します
+fn find_tag<'a>(block: &'a str, name: &str) -> Option<&'a str> {
+    let start = block.find(&format!("<{} ", name))?;
+    let end = block[start..].find("/>")?;
+    Some(&block[start..start + end])
+}
+
+/// タグ内の`name="value"`のvalueを返します
+fn find_attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!(" {}=\"", name);
+    let start = tag.find(&key)? + key.len();
+    let end = tag[start..].find('"')?;
+    Some(&tag[start..start + end])
+}
+
+fn attr_num<T: std::str::FromStr + Default>(block: &str, tag: &str, name: &str) -> T {
+    find_tag(block, tag)
+        .and_then(|t| find_attr(t, name))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+fn parse_sha1(s: &str) -> Option<[u8; 20]> {
+    if s.len() != 40 {
+        return None;
+    }
+    let mut dst = [0u8; 20];
+    for (i, d) in dst.iter_mut().enumerate() {
+        *d = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(dst)
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn parse_game(block: &str) -> Option<Game> {
+    let rom = find_tag(block, "rom")?;
+    let crc32 = u32::from_str_radix(find_attr(rom, "crc32")?, 16).ok()?;
+    let sha1 = find_attr(rom, "sha1")
+        .and_then(parse_sha1)
+        .unwrap_or([0; 20]);
+    // タイトルはコメントに書かれている
+    let title = match (block.find("<!--"), block.find("-->")) {
+        (Some(start), Some(end)) if start < end => unescape(block[start + 4..end].trim()),
+        _ => String::new(),
+    };
+    Some(Game {
+        title,
+        crc32,
+        sha1,
+        mapper: attr_num(block, "pcb", "mapper"),
+        submapper: attr_num(block, "pcb", "submapper"),
+        mirroring: find_tag(block, "pcb")
+            .and_then(|t| find_attr(t, "mirroring"))
+            .unwrap_or("")
+            .to_string(),
+        is_battery: attr_num::<u8>(block, "pcb", "battery") != 0,
+        prg_ram: attr_num(block, "prgram", "size"),
+        prg_nvram: attr_num(block, "prgnvram", "size"),
+        chr_ram: attr_num(block, "chrram", "size"),
+        chr_nvram: attr_num(block, "chrnvram", "size"),
+        console_type: attr_num(block, "console", "type"),
+        region: attr_num(block, "console", "region"),
+        vs_ppu: attr_num(block, "vs", "ppu"),
+        vs_hardware: attr_num(block, "vs", "hardware"),
+    })
+}
+
+fn main() {
+    let db_path = env::var("NES_ROM_DB").unwrap_or_else(|_| "src/rom_db.xml".to_string());
+    println!("cargo:rerun-if-env-changed=NES_ROM_DB");
+    println!("cargo:rerun-if-changed={}", db_path);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let xml = fs::read_to_string(&db_path).unwrap_or_else(|e| panic!("{}: {}", db_path, e));
+    let mut games: Vec<Game> = xml
+        .split("<game>")
+        .skip(1)
+        .filter_map(|s| s.split("</game>").next())
+        .filter_map(parse_game)
+        .collect();
+    // 二分探索できるようにCRC32順に並べる
+    games.sort_by_key(|g| g.crc32);
+
+    let mut dst = String::from("[\n");
+    for g in games.iter() {
+        let (mirror, is_four_screen) = match g.mirroring.as_str() {
+            "H" => ("Some(NameTableMirror::Horizontal)", false),
+            "V" => ("Some(NameTableMirror::Vertical)", false),
+            "4" => ("Some(NameTableMirror::FourScreen)", true),
+            _ => ("None", false),
+        };
+        let timing = match g.region {
+            0 => "TimingRegion::Ntsc",
+            1 => "TimingRegion::Pal",
+            2 => "TimingRegion::MultiRegion",
+            _ => "TimingRegion::Dendy",
+        };
+        let console_type = match g.console_type {
+            0 => "ConsoleType::Famicom".to_string(),
+            1 => format!(
+                "ConsoleType::VsSystem {{ ppu: {}, hardware: {} }}",
+                g.vs_ppu, g.vs_hardware
+            ),
+            2 => "ConsoleType::PlayChoice10".to_string(),
+            n => format!("ConsoleType::Extended({})", n),
+        };
+        writeln!(
+            dst,
+            "    RomDbEntry {{ crc32: 0x{:08x}, sha1: {:?}, title: {:?}, mapper_number: {}, submapper_number: {}, nametable_mirror: {}, is_four_screen: {}, is_battery: {}, prg_ram_bytes: {}, prg_nvram_bytes: {}, chr_ram_bytes: {}, chr_nvram_bytes: {}, timing: {}, console_type: {} }},",
+            g.crc32,
+            g.sha1,
+            g.title,
+            g.mapper,
+            g.submapper,
+            mirror,
+            is_four_screen,
+            g.is_battery,
+            g.prg_ram,
+            g.prg_nvram,
+            g.chr_ram,
+            g.chr_nvram,
+            timing,
+            console_type
+        )
+        .unwrap();
+    }
+    dst.push(']');
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("rom_db_table.rs");
+    fs::write(out_path, dst).unwrap();
+}
@@ -4,6 +4,7 @@ use rust_nes_emulator::prelude::*;
 // for read ines file
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 // for save screenshot
 extern crate bmp;
@@ -17,15 +18,143 @@ extern crate piston_window;
 use nfd::Response;
 use piston_window::*;
 
+/// FDSのBIOSの場所、環境変数FDS_BIOSがなければdisk imageと同じ場所のdisksys.rom
+#[allow(dead_code)]
+fn fds_bios_path(disk_path: &str) -> PathBuf {
+    match std::env::var("FDS_BIOS") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => Path::new(disk_path).with_file_name("disksys.rom"),
+    }
+}
+
+/// .fds/.qdファイルならtrue
+#[allow(dead_code)]
+fn is_fds_image(path: &str) -> bool {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("fds") || ext.eq_ignore_ascii_case("qd"),
+        None => false,
+    }
+}
+
+/// .unf/.unifファイルならtrue
+#[allow(dead_code)]
+fn is_unif_image(path: &str) -> bool {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("unf") || ext.eq_ignore_ascii_case("unif"),
+        None => false,
+    }
+}
+
+/// ROMファイルを読み込めなかった理由
+#[derive(Debug)]
+enum LoadError {
+    /// ファイルが開けない、読めない
+    Io(std::io::Error),
+    /// ROMイメージとして解釈できない
+    Rom(RomLoadError),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "file read error: {}", e),
+            LoadError::Rom(e) => write!(f, "ines binary read error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<RomLoadError> for LoadError {
+    fn from(e: RomLoadError) -> Self {
+        LoadError::Rom(e)
+    }
+}
+
+/// 起動時に読み込めなかった場合はエラーを表示して終了します
+#[allow(dead_code)]
+fn exit_with_error(message: &dyn std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
 /// NESファイルを読み込んでカセットにロードさせます
+/// .fds/.qdの場合はBIOSと一緒に読み込む
+/// 失敗した場合はカセットの中身は変わらない
 #[allow(dead_code)]
-fn load_cassette(cassette: &mut Cassette, path: String) {
-    let mut file = File::open(path).unwrap();
+fn load_cassette(cassette: &mut Cassette, path: String) -> Result<(), LoadError> {
+    let mut file = File::open(&path)?;
     let mut buf: Vec<u8> = Vec::new();
-    let _ = file.read_to_end(&mut buf).unwrap();
+    file.read_to_end(&mut buf)?;
     // casseteに展開
-    if !cassette.from_ines_binary(|addr: usize| buf[addr]) {
-        panic!("ines binary read error");
+    let header = if is_fds_image(&path) {
+        let bios_path = fds_bios_path(&path);
+        // BIOSが読めない場合はどのファイルを探したのか分かるようにpathを付けて返す
+        let bios = std::fs::read(&bios_path).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("FDS BIOS {}: {}", bios_path.display(), e))
+        })?;
+        cassette.from_fds_binary(&bios, &buf)?
+    } else if is_unif_image(&path) {
+        cassette.from_unif_binary(buf.len(), |addr: usize| buf[addr])?
+    } else {
+        cassette.from_ines_binary(buf.len(), |addr: usize| buf[addr])?
+    };
+    println!("{:?}", header);
+    if let Some(title) = cassette.rom_title() {
+        println!("{} ({:?})", title, header.timing);
+    }
+    load_save_file(cassette, &path);
+    Ok(())
+}
+
+/// バッテリーバックアップ(FDSの場合はdiskへの書き込み)の保存先、ROMと同じ場所に拡張子.savで置く
+#[allow(dead_code)]
+fn save_file_path(rom_path: &str) -> PathBuf {
+    Path::new(rom_path).with_extension("sav")
+}
+
+/// .savファイルがあればカセット内RAMに読み込みます
+/// FDSの場合は元のimageに対するdiskへの書き込みの差分が入っている
+#[allow(dead_code)]
+fn load_save_file(cassette: &mut Cassette, rom_path: &str) {
+    if let Ok(buf) = std::fs::read(save_file_path(rom_path)) {
+        match cassette.fds_disk_mut() {
+            Some(disk) => {
+                if !disk.restore_diff(&buf) {
+                    println!("save file has broken entries");
+                }
+            }
+            None => {
+                cassette.restore_battery_backup(&buf);
+            }
+        }
+    }
+}
+
+/// カセット内RAMが書き換わっていれば.savファイルに書き出します
+#[allow(dead_code)]
+fn flush_save_file(cassette: &mut Cassette, rom_path: &str) {
+    if let Some(disk) = cassette.fds_disk_mut() {
+        if disk.is_dirty {
+            match std::fs::write(save_file_path(rom_path), disk.serialize_diff()) {
+                Ok(()) => disk.is_dirty = false,
+                Err(e) => println!("save file write error: {}", e),
+            }
+        }
+        return;
+    }
+    if !cassette.is_battery_backup_dirty() {
+        return;
+    }
+    if let Some(data) = cassette.battery_backup() {
+        match std::fs::write(save_file_path(rom_path), data) {
+            Ok(()) => cassette.clear_battery_backup_dirty(),
+            Err(e) => println!("save file write error: {}", e),
+        }
     }
 }
 
@@ -50,12 +179,14 @@ fn save_framebuffer(
 
 #[allow(dead_code)]
 fn main() {
-    let rom_path = "../roms/my_dump/mario.nes".to_string();
-    let rom_exists = std::path::Path::new(&rom_path).is_file();
+    let mut rom_path = "../roms/my_dump/mario.nes".to_string();
+    let rom_exists = Path::new(&rom_path).is_file();
     // snapshot
     let mut ss_cpu: Cpu = Default::default();
     let mut ss_cpu_sys: System = Default::default();
     let mut ss_ppu: Ppu = Default::default();
+    // snapshotを取ったROM, 別のROMのsnapshotを戻してセーブデータを上書きしないようにする
+    let mut ss_rom_path: Option<String> = None;
 
     // emu
     let mut cpu: Cpu = Default::default();
@@ -63,17 +194,21 @@ fn main() {
     let mut ppu: Ppu = Default::default();
 
     if rom_exists {
-        load_cassette(&mut cpu_sys.cassette, rom_path);
+        if let Err(e) = load_cassette(&mut cpu_sys.cassette, rom_path.clone()) {
+            exit_with_error(&e);
+        }
     } else {
         // 選んで
-        let result = nfd::open_file_dialog(None, None).unwrap_or_else(|e| {
-            panic!(e);
-        });
+        let result = nfd::open_file_dialog(None, None)
+            .unwrap_or_else(|e| exit_with_error(&format!("{:?}", e)));
         match result {
             Response::Okay(file_path) => {
-                load_cassette(&mut cpu_sys.cassette, file_path.clone());
+                if let Err(e) = load_cassette(&mut cpu_sys.cassette, file_path.clone()) {
+                    exit_with_error(&e);
+                }
+                rom_path = file_path;
             }
-            _ => panic!("no input file"),
+            _ => exit_with_error(&"no input file"),
         }
     }
     cpu.reset();
@@ -104,6 +239,9 @@ fn main() {
         Texture::from_image(&mut texture_context, &canvas, &TextureSettings::new()).unwrap();
     // グリッド表示有無
     let mut is_show_grid = false;
+    // .savファイルを書き出す間隔
+    let save_interval_frames = 300;
+    let mut frame_count: usize = 0;
 
     while let Some(e) = window.next() {
         // 描画
@@ -111,17 +249,26 @@ fn main() {
             // 1frameの実行時間を控える
             // let start = Instant::now();
             // エミュを進める
-            let mut total_cycle: usize = 0;
-            while total_cycle < CYCLE_PER_DRAW_FRAME {
-                let cpu_cycle = usize::from(cpu.step(&mut cpu_sys));
-                if let Some(interrupt) = ppu.step(cpu_cycle, &mut cpu_sys, &mut fb) {
-                    cpu.interrupt(&mut cpu_sys, interrupt);
+            // PPUが1frame描き終えるまで進める
+            let current_frame = ppu.frame_count;
+            while ppu.frame_count == current_frame {
+                // cycle-accurate featureが有効なら、busアクセスごとにppuを1cycleずつ進める
+                #[cfg(feature = "cycle-accurate")]
+                cpu.step_cycle_accurate(&mut cpu_sys, |sys| ppu.step(1, sys, &mut fb));
+                #[cfg(not(feature = "cycle-accurate"))]
+                {
+                    let cpu_cycle = usize::from(cpu.step(&mut cpu_sys));
+                    ppu.step(cpu_cycle, &mut cpu_sys, &mut fb);
                 }
-
-                total_cycle = total_cycle + cpu_cycle;
             }
             // let emulate_duration = start.elapsed();
 
+            // セーブデータは定期的に書き出しておく
+            frame_count += 1;
+            if frame_count % save_interval_frames == 0 {
+                flush_save_file(&mut cpu_sys.cassette, &rom_path);
+            }
+
             // 画面更新(毎回やらんほうが良さげ?)
             for j in 0..VISIBLE_SCREEN_HEIGHT {
                 for i in 0..VISIBLE_SCREEN_WIDTH {
@@ -197,6 +344,18 @@ fn main() {
                 Key::G => {
                     is_show_grid = !is_show_grid;
                 }
+                Key::F => {
+                    // FDSのdiskを次の面に入れ替える
+                    let side_count = cpu_sys.cassette.fds_side_count();
+                    if side_count > 0 {
+                        let side = cpu_sys
+                            .cassette
+                            .fds_inserted_side()
+                            .map_or(0, |side| (side + 1) % side_count);
+                        cpu_sys.cassette.fds_insert_side(side);
+                        println!("disk side {}/{}", side + 1, side_count);
+                    }
+                }
                 Key::R => {
                     // Reset
                     cpu.reset();
@@ -206,16 +365,32 @@ fn main() {
                 }
                 Key::O => {
                     // 別のファイルを開いてリセットする
-                    let result = nfd::open_file_dialog(None, None).unwrap_or_else(|e| {
-                        panic!(e);
-                    });
+                    let result = match nfd::open_file_dialog(None, None) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            println!("{:?}", e);
+                            Response::Cancel
+                        }
+                    };
                     match result {
                         Response::Okay(file_path) => {
-                            load_cassette(&mut cpu_sys.cassette, file_path.clone());
-                            cpu.reset();
-                            cpu_sys.reset();
-                            ppu.reset();
-                            cpu.interrupt(&mut cpu_sys, Interrupt::RESET);
+                            flush_save_file(&mut cpu_sys.cassette, &rom_path);
+                            match load_cassette(&mut cpu_sys.cassette, file_path.clone()) {
+                                Ok(()) => {
+                                    rom_path = file_path;
+                                    // 前のROMのsnapshotは捨てる
+                                    ss_cpu = Default::default();
+                                    ss_cpu_sys = Default::default();
+                                    ss_ppu = Default::default();
+                                    ss_rom_path = None;
+                                    cpu.reset();
+                                    cpu_sys.reset();
+                                    ppu.reset();
+                                    cpu.interrupt(&mut cpu_sys, Interrupt::RESET);
+                                }
+                                // 読めなかった場合は今のカセットのまま続ける
+                                Err(e) => println!("{}", e),
+                            }
                         }
                         _ => {}
                     }
@@ -225,15 +400,27 @@ fn main() {
                     ss_cpu = cpu.clone();
                     ss_cpu_sys = cpu_sys.clone();
                     ss_ppu = ppu.clone();
+                    ss_rom_path = Some(rom_path.clone());
                 }
                 Key::Z => {
                     // Snapshot Restore
-                    cpu = ss_cpu.clone();
-                    cpu_sys = ss_cpu_sys.clone();
-                    ppu = ss_ppu.clone();
+                    if ss_rom_path.as_ref() == Some(&rom_path) {
+                        cpu = ss_cpu.clone();
+                        cpu_sys = ss_cpu_sys.clone();
+                        ppu = ss_ppu.clone();
+                        // セーブデータも巻き戻るので次の定期保存で書き出す
+                        cpu_sys.cassette.mem.is_battery_packed_ram_dirty = true;
+                        if let Some(disk) = cpu_sys.cassette.fds_disk_mut() {
+                            disk.is_dirty = true;
+                        }
+                    } else {
+                        println!("no snapshot for {}", rom_path);
+                    }
                 }
                 _ => {}
             }
         };
     }
+    // 終了時にセーブデータを書き出す
+    flush_save_file(&mut cpu_sys.cassette, &rom_path);
 }
@@ -33,9 +33,16 @@ extern "C" {
 void EmbeddedEmulator_init();
 
 /// .nesファイルを読み込みます
-/// `data` - nesファイルのバイナリ
+/// ファームウェアに埋め込んだROMをそのまま参照します
 bool EmbeddedEmulator_load();
 
+/// メモリ上に配置された.nesファイルを読み込みます
+/// `data` - nesファイルの先頭アドレス、QSPIのmemory mapped領域などを想定
+/// `len` - nesファイルのサイズ
+/// ROMはコピーせずに参照し続けるので、dataの領域はエミュレータを使い終わるまで書き換えないこと
+bool EmbeddedEmulator_load_rom(const uint8_t *data,
+                               uintptr_t len);
+
 /// エミュレータをリセットします
 /// カセットの中身はリセットしないので実機のリセット相当の処理です
 void EmbeddedEmulator_reset();
@@ -77,18 +77,44 @@ pub unsafe extern "C" fn EmbeddedEmulator_reset() {
     }
 }
 
+impl EmbeddedEmulator {
+    /// flash/QSPIにマップされたnesファイルをコピーせずにカセットとして読み込みます
+    /// PRG-ROM/CHR-ROMはbinaryを直接参照し、SRAMにはPRG-RAM/CHR-RAMだけが置かれる
+    pub fn load_rom(&mut self, binary: &'static [u8]) -> bool {
+        self.cpu_sys.cassette.from_ines_static(binary).is_ok()
+    }
+}
+
 /// .nesファイルを読み込みます
-/// `data` - nesファイルのバイナリ
+/// ファームウェアに埋め込んだROMをそのまま参照します
 #[no_mangle]
 pub unsafe extern "C" fn EmbeddedEmulator_load() -> bool {
     let binary = include_bytes!("../../roms/other/hello.nes");
     // let binary = include_bytes!("../../roms/my_dump/mario.nes");
 
     if let Some(ref mut emu) = EMULATOR {
-        let success = emu
-            .cpu_sys
-            .cassette
-            .from_ines_binary(|addr: usize| binary[addr]);
+        let success = emu.load_rom(binary);
+        if success {
+            EmbeddedEmulator_reset();
+        }
+        success
+    } else {
+        false
+    }
+}
+
+/// メモリ上に配置された.nesファイルを読み込みます
+/// `data` - nesファイルの先頭アドレス、QSPIのmemory mapped領域などを想定
+/// `len` - nesファイルのサイズ
+/// ROMはコピーせずに参照し続けるので、dataの領域はエミュレータを使い終わるまで書き換えないこと
+#[no_mangle]
+pub unsafe extern "C" fn EmbeddedEmulator_load_rom(data: *const u8, len: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let binary: &'static [u8] = core::slice::from_raw_parts(data, len);
+    if let Some(ref mut emu) = EMULATOR {
+        let success = emu.load_rom(binary);
         if success {
             EmbeddedEmulator_reset();
         }
@@ -105,13 +131,11 @@ pub unsafe extern "C" fn EmbeddedEmulator_update_screen(
              EMBEDDED_EMULATOR_VISIBLE_SCREEN_HEIGHT],
 ) {
     if let Some(ref mut emu) = EMULATOR {
-        let mut total_cycle: usize = 0;
-        while total_cycle < CYCLE_PER_DRAW_FRAME {
+        // PPUが1frame描き終えるまで進める
+        let current_frame = emu.ppu.frame_count;
+        while emu.ppu.frame_count == current_frame {
             let cpu_cycle = usize::from(emu.cpu.step(&mut emu.cpu_sys));
-            if let Some(interrupt) = emu.ppu.step(cpu_cycle, &mut emu.cpu_sys, fb) {
-                emu.cpu.interrupt(&mut emu.cpu_sys, interrupt);
-            }
-            total_cycle = total_cycle + cpu_cycle;
+            emu.ppu.step(cpu_cycle, &mut emu.cpu_sys, fb);
         }
     }
 }
@@ -1,3 +1,4 @@
+use super::cassette::*;
 use super::cpu::*;
 
 #[derive(Copy, Clone)]
@@ -151,6 +152,148 @@ impl Default for DmcSound {
     }
 }
 
+/// DMCの出力周期(NTSC, CPU cycle)
+pub const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+/// DMCがサンプルを1byte読むたびにCPUが止まるcycle数
+pub const DMC_FETCH_STALL_CYCLES: u16 = 4;
+
+/// DMCのメモリリーダ
+/// 音はまだ出さないが、サンプルを読むたびにCPUを止めるのでタイミングだけ再現する
+/// https://wiki.nesdev.com/w/index.php/APU_DMC
+#[derive(Clone)]
+pub struct DmcReader {
+    /// 次に読むアドレス, $ffffの次は$8000に戻る
+    pub current_addr: u16,
+    /// 読み残しているbyte数
+    pub bytes_remaining: u16,
+    /// 次に出力unitをclockするまでのcycle数
+    pub timer: u16,
+    /// 出力中のbyteの残りbit数
+    pub bits_remaining: u8,
+    /// 読んだサンプル
+    pub sample_buffer: u8,
+    /// sample bufferにデータが入っているか
+    pub is_buffer_full: bool,
+    /// 最後まで読んだときのIRQ
+    pub is_irq: bool,
+}
+
+impl Default for DmcReader {
+    fn default() -> Self {
+        Self {
+            current_addr: 0xc000,
+            bytes_remaining: 0,
+            timer: 0,
+            bits_remaining: 0,
+            sample_buffer: 0,
+            is_buffer_full: false,
+            is_irq: false,
+        }
+    }
+}
+
+impl DmcReader {
+    /// 読み残しがなければ、$4012, $4013の設定から読み直します
+    pub fn start(&mut self, sample_addr: u8, sample_length: u8) {
+        if self.bytes_remaining == 0 {
+            self.current_addr = 0xc000 | (u16::from(sample_addr) << 6);
+            self.bytes_remaining = (u16::from(sample_length) << 4) | 0x01;
+        }
+    }
+    /// 読み残しを捨てて止めます
+    pub fn stop(&mut self) {
+        self.bytes_remaining = 0;
+    }
+    /// 1cycle進めます
+    /// `rate_index` - $4010の下位4bit
+    /// ret: sample bufferが空いて次のbyteを読む必要があれば、そのアドレス
+    pub fn step(&mut self, rate_index: u8) -> Option<u16> {
+        if self.timer > 0 {
+            self.timer -= 1;
+        } else {
+            self.timer = DMC_RATE_TABLE[usize::from(rate_index & 0x0f)] - 1;
+            if self.bits_remaining > 0 {
+                self.bits_remaining -= 1;
+            }
+            // 8bit出力し終えたら、sample bufferの中身をshift registerに移す
+            if self.bits_remaining == 0 {
+                self.bits_remaining = 8;
+                self.is_buffer_full = false;
+            }
+        }
+        if !self.is_buffer_full && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+    /// 読んだサンプルをbufferに入れて、アドレスを進めます
+    /// 最後まで読んだら、loopなら最初から読み直し、そうでなければIRQを出す
+    pub fn fill(&mut self, data: u8, config: &DmcSound) {
+        self.sample_buffer = data;
+        self.is_buffer_full = true;
+        self.current_addr = self.current_addr.wrapping_add(1) | 0x8000;
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if config.is_loop_enable {
+                self.start(config.sample_addr, config.sample_length);
+            } else if config.is_irq_enable {
+                self.is_irq = true;
+            }
+        }
+    }
+}
+
+/// Frame Counterの4-step modeで、frame IRQを立て始めるcycle(NTSC, CPU cycle)
+pub const FRAME_COUNTER_IRQ_CYCLE: u16 = 29829;
+/// Frame Counterの1周のcycle数(NTSC, CPU cycle)
+/// 4-step modeは29830cycle, 5-step modeは37282cycle
+pub const FRAME_COUNTER_PERIOD: [u16; 2] = [29830, 37282];
+
+/// APUのFrame Counter
+/// 音はまだ出さないが、4-step modeで1周するたびにframe IRQを出す
+/// $4017を書いてからsequencerがresetされるまでの3,4cycleの遅延は省略している
+/// 電源投入時は$4017に0を書いたのと同じ状態(4-step mode, IRQ有効)
+/// https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+#[derive(Clone, Default)]
+pub struct FrameCounter {
+    /// $4017 bit7, trueなら5-step mode(frame IRQを出さない)
+    pub is_five_step_mode: bool,
+    /// $4017 bit6, trueならframe IRQを出さない
+    pub is_irq_inhibit: bool,
+    /// sequencerの現在のcycle
+    pub cycle: u16,
+    /// frame interrupt flag, $4015を読むと下りる
+    pub is_irq: bool,
+}
+
+impl FrameCounter {
+    /// $4017を書いたとき、modeを切り替えてsequencerをresetします
+    /// IRQ inhibitを立てたらframe IRQも下ろす
+    pub fn write(&mut self, data: u8) {
+        self.is_five_step_mode = (data & 0x80) == 0x80;
+        self.is_irq_inhibit = (data & 0x40) == 0x40;
+        if self.is_irq_inhibit {
+            self.is_irq = false;
+        }
+        self.cycle = 0;
+    }
+    /// 1cycle進めます
+    /// 4-step modeの最後の2cycleでframe IRQを立てる
+    pub fn step(&mut self) {
+        self.cycle += 1;
+        if !self.is_five_step_mode && !self.is_irq_inhibit && self.cycle >= FRAME_COUNTER_IRQ_CYCLE
+        {
+            self.is_irq = true;
+        }
+        if self.cycle >= FRAME_COUNTER_PERIOD[usize::from(self.is_five_step_mode)] {
+            self.cycle = 0;
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Apu {
     /// Frame Sequencer、CPUサイクルに連動して加算 11bit
@@ -176,4 +319,11 @@ impl Apu {
     pub fn step(&mut self, _cpu: &mut Cpu, _cpu_cyc: u8) {
         // TODO:がんばる
     }
+    /// 現在の出力を1sample分mixします
+    /// カセットの拡張音源(VRC6など)も同じスケールで加算する
+    pub fn mix(&self, cassette: &Cassette) -> f32 {
+        // TODO: pulse, triangle, noise, dmcの出力
+        let internal = 0.0;
+        internal + cassette.expansion_audio_output()
+    }
 }
@@ -1,218 +1,806 @@
+use super::cassette_header::*;
+#[cfg(feature = "alloc")]
+use super::cassette_unif::*;
+use super::cpu::IrqSource;
+#[cfg(feature = "alloc")]
+use super::fds::*;
 use super::interface::*;
+use super::mapper::*;
+#[cfg(feature = "alloc")]
+use super::mapper_fds::*;
+use super::rom_db::*;
 
-pub const PRG_ROM_MAX_SIZE: usize = 0x8000;
-pub const CHR_ROM_MAX_SIZE: usize = 0x2000;
-pub const BATTERY_PACKED_RAM_MAX_SIZE: usize = 0x2000;
+#[cfg(feature = "alloc")]
+use alloc::rc::Rc;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+pub const PRG_ROM_MAX_SIZE: usize = 0x40_0000; // 4MB
+pub const CHR_ROM_MAX_SIZE: usize = 0x20_0000; // 2MB
+/// iNES headerでCHR-ROMが0の場合のCHR-RAMサイズ
+pub const CHR_RAM_SIZE: usize = 0x2000;
+/// CHR-RAMの最大サイズ、heapが使えない環境では配列で持つので8KBまで
+#[cfg(feature = "alloc")]
+pub const CHR_RAM_MAX_SIZE: usize = 0x8000; // 32KB
+#[cfg(not(feature = "alloc"))]
+pub const CHR_RAM_MAX_SIZE: usize = CHR_RAM_SIZE;
+/// 0x6000 - 0x7fffに見えるカセット内RAMのサイズ
+pub const BATTERY_PACKED_RAM_SIZE: usize = 0x2000;
+/// bank切り替えで使えるカセット内RAMの最大サイズ(MMC5)、heapが使えない環境では8KBまで
+#[cfg(feature = "alloc")]
+pub const BATTERY_PACKED_RAM_MAX_SIZE: usize = 0x1_0000; // 64KB
+#[cfg(not(feature = "alloc"))]
+pub const BATTERY_PACKED_RAM_MAX_SIZE: usize = BATTERY_PACKED_RAM_SIZE;
+/// カセット上のNameTable用VRAMの最大サイズ(four-screenの2KB)、heapが使えない環境では持たない
+#[cfg(feature = "alloc")]
+pub const CART_NAME_TABLE_RAM_MAX_SIZE: usize = 0x0800;
+#[cfg(not(feature = "alloc"))]
+pub const CART_NAME_TABLE_RAM_MAX_SIZE: usize = 0;
+pub const CART_NAME_TABLE_SLOT_SIZE: usize = 0x0400;
 
 pub const PRG_ROM_SYSTEM_BASE_ADDR: u16 = 0x8000;
 pub const BATTERY_PACKED_RAM_BASE_ADDR: u16 = 0x6000;
+/// trainerはカセット内RAMの0x7000 - 0x71ffに置かれる
+pub const INES_TRAINER_BASE_ADDR: u16 = 0x7000;
 
 pub const INES_TRAINER_DATA_SIZE: usize = 0x0200;
 
-#[derive(Copy, Clone)]
-pub enum Mapper {
-    Unknown,
-    /// Mapper0: no mapper
-    Nrom,
-}
-
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 pub enum NameTableMirror {
+    #[default]
     Unknown,
     Horizontal,
     Vertical,
-    SingleScreen,
+    /// 1面のみ(0x2000側)
+    SingleScreenLower,
+    /// 1面のみ(0x2400側)
+    SingleScreenUpper,
     FourScreen,
 }
-/// Cassete and mapper implement
-/// https://wiki.nesdev.com/w/index.php/List_of_mappers
+
+/// PRG-ROM/CHR-ROMの実体
+/// 数MBになるので配列では持たず、snapshotでcloneしてもROMはコピーされないようにする
+#[derive(Clone, Default)]
+pub enum RomImage {
+    #[default]
+    Empty,
+    /// flashなどに置かれたイメージをそのまま参照する(no_std向け)
+    Static(&'static [u8]),
+    /// heapに展開したイメージ
+    #[cfg(feature = "alloc")]
+    Shared(Rc<[u8]>),
+}
+
+impl RomImage {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            RomImage::Empty => &[],
+            RomImage::Static(data) => data,
+            #[cfg(feature = "alloc")]
+            RomImage::Shared(data) => data,
+        }
+    }
+}
+
+/// カセット上のRAMの実体
+/// heapが使える場合はheaderから求めたサイズだけ確保し、使えない環境では`N`byteの配列の先頭だけ使う
 #[derive(Clone)]
-pub struct Cassette {
-    // Mapperの種類
-    pub mapper: Mapper,
-    /// Video領域での0x2000 ~ 0x2effのミラーリング設定
-    pub nametable_mirror: NameTableMirror,
-    /// 0x6000 ~ 0x7fffのカセット内RAMを有効化する
-    pub is_exists_battery_backed_ram: bool,
+pub struct RamImage<const N: usize> {
+    #[cfg(feature = "alloc")]
+    data: Vec<u8>,
+    #[cfg(not(feature = "alloc"))]
+    data: [u8; N],
+    #[cfg(not(feature = "alloc"))]
+    len: usize,
+}
+
+impl<const N: usize> Default for RamImage<N> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
 
+impl<const N: usize> RamImage<N> {
+    /// 0で埋めた`len`byteのRAMを用意します。heapが使えない環境では`N`byteまで
+    pub fn new(len: usize) -> Self {
+        #[cfg(feature = "alloc")]
+        {
+            Self {
+                data: alloc::vec![0; len],
+            }
+        }
+        #[cfg(not(feature = "alloc"))]
+        {
+            debug_assert!(len <= N);
+            Self {
+                data: [0; N],
+                len: core::cmp::min(len, N),
+            }
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        #[cfg(feature = "alloc")]
+        {
+            &self.data
+        }
+        #[cfg(not(feature = "alloc"))]
+        {
+            &self.data[..self.len]
+        }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        #[cfg(feature = "alloc")]
+        {
+            &mut self.data
+        }
+        #[cfg(not(feature = "alloc"))]
+        {
+            &mut self.data[..self.len]
+        }
+    }
+}
+
+/// カセット上のROM/RAMの実体
+/// bank切り替えはMapperが行い、ここでは範囲外アクセスのwrapだけ面倒を見る
+#[derive(Clone)]
+pub struct CassetteMemory {
     // data size
     pub prg_rom_bytes: usize,
     pub chr_rom_bytes: usize,
     // datas
-    pub prg_rom: [u8; PRG_ROM_MAX_SIZE], // 32KB
-    pub chr_rom: [u8; CHR_ROM_MAX_SIZE], // 8K
-    pub battery_packed_ram: [u8; BATTERY_PACKED_RAM_MAX_SIZE],
+    pub prg_rom: RomImage,
+    pub chr_rom: RomImage,
+    /// CHR-ROMを持たないカセットのCHR-RAM, CHR-ROMを持つカセットでは空
+    pub chr_ram: RamImage<CHR_RAM_MAX_SIZE>,
+    /// 0x6000 - 0x7fffのカセット内RAM, bank切り替えしないMapperでは8KB
+    pub battery_packed_ram: RamImage<BATTERY_PACKED_RAM_MAX_SIZE>,
+    /// battery_packed_ramが書き換えられたらtrue, 保存先に書き出したらfalseに戻す
+    pub is_battery_packed_ram_dirty: bool,
+    /// four-screenなどでカセットが持っているNameTable用VRAM, 持たないカセットでは空
+    pub nametable_ram: RamImage<CART_NAME_TABLE_RAM_MAX_SIZE>,
+    /// FDSのdisk, FDS以外ではNone
+    #[cfg(feature = "alloc")]
+    pub disk: Option<FdsDisk>,
 }
 
-impl Default for Cassette {
+impl Default for CassetteMemory {
     fn default() -> Self {
         Self {
-            mapper: Mapper::Unknown,
-            nametable_mirror: NameTableMirror::Unknown,
-            is_exists_battery_backed_ram: false,
-
             prg_rom_bytes: 0,
             chr_rom_bytes: 0,
 
-            prg_rom: [0; PRG_ROM_MAX_SIZE],
-            chr_rom: [0; CHR_ROM_MAX_SIZE],
-            battery_packed_ram: [0; BATTERY_PACKED_RAM_MAX_SIZE],
+            prg_rom: RomImage::Empty,
+            chr_rom: RomImage::Empty,
+            chr_ram: RamImage::default(),
+            battery_packed_ram: RamImage::new(BATTERY_PACKED_RAM_SIZE),
+            is_battery_packed_ram_dirty: false,
+            nametable_ram: RamImage::default(),
+            #[cfg(feature = "alloc")]
+            disk: None,
         }
     }
 }
 
+impl EmulateControl for CassetteMemory {
+    fn reset(&mut self) {
+        self.prg_rom_bytes = 0;
+        self.chr_rom_bytes = 0;
+        self.prg_rom = RomImage::Empty;
+        self.chr_rom = RomImage::Empty;
+        self.chr_ram = RamImage::default();
+        self.battery_packed_ram = RamImage::new(BATTERY_PACKED_RAM_SIZE);
+        self.is_battery_packed_ram_dirty = false;
+        self.nametable_ram = RamImage::default();
+        #[cfg(feature = "alloc")]
+        {
+            self.disk = None;
+        }
+    }
+}
+
+impl CassetteMemory {
+    /// PRG-ROMをbank単位で読み出します
+    /// `bank_size` - bankの大きさ(byte)
+    /// `bank` - bank番号、ROMサイズを超えた分はwrapする(16KB ROMのミラーもこれで処理される)
+    /// `offset` - bank内のoffset
+    pub fn read_prg_rom(&self, bank_size: usize, bank: usize, offset: usize) -> u8 {
+        if self.prg_rom_bytes == 0 {
+            return 0;
+        }
+        let index = (bank * bank_size + offset) % self.prg_rom_bytes;
+        arr_read!(self.prg_rom.as_slice(), index)
+    }
+    /// CHRがRAMであればtrue
+    pub fn is_chr_ram(&self) -> bool {
+        !self.chr_ram.is_empty()
+    }
+    /// CHR-ROM/CHR-RAMをbank単位で読み出します
+    pub fn read_chr(&self, bank_size: usize, bank: usize, offset: usize) -> u8 {
+        if self.is_chr_ram() {
+            let index = (bank * bank_size + offset) % self.chr_ram.len();
+            arr_read!(self.chr_ram.as_slice(), index)
+        } else if self.chr_rom_bytes > 0 {
+            let index = (bank * bank_size + offset) % self.chr_rom_bytes;
+            arr_read!(self.chr_rom.as_slice(), index)
+        } else {
+            0
+        }
+    }
+    /// CHR-RAMに書き込みます。CHR-ROMへの書き込みは無視
+    pub fn write_chr(&mut self, bank_size: usize, bank: usize, offset: usize, data: u8) {
+        if self.is_chr_ram() {
+            let index = (bank * bank_size + offset) % self.chr_ram.len();
+            arr_write!(self.chr_ram.as_mut_slice(), index, data);
+        }
+    }
+    /// カセット上のNameTable用VRAMを1KB単位で読み出します
+    pub fn read_nametable_ram(&self, index: usize, offset: usize) -> u8 {
+        if self.nametable_ram.is_empty() {
+            return 0;
+        }
+        let addr = (index * CART_NAME_TABLE_SLOT_SIZE + offset) % self.nametable_ram.len();
+        arr_read!(self.nametable_ram.as_slice(), addr)
+    }
+    /// カセット上のNameTable用VRAMに1KB単位で書き込みます
+    pub fn write_nametable_ram(&mut self, index: usize, offset: usize, data: u8) {
+        if self.nametable_ram.is_empty() {
+            return;
+        }
+        let addr = (index * CART_NAME_TABLE_SLOT_SIZE + offset) % self.nametable_ram.len();
+        arr_write!(self.nametable_ram.as_mut_slice(), addr, data);
+    }
+    /// 0x6000 - 0x7fffのカセット内RAMを読み出します
+    pub fn read_battery_packed_ram(&self, addr: u16) -> u8 {
+        debug_assert!(addr >= BATTERY_PACKED_RAM_BASE_ADDR);
+        let index = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR) % BATTERY_PACKED_RAM_SIZE;
+        arr_read!(self.battery_packed_ram.as_slice(), index)
+    }
+    /// 0x6000 - 0x7fffのカセット内RAMに書き込みます
+    pub fn write_battery_packed_ram(&mut self, addr: u16, data: u8) {
+        debug_assert!(addr >= BATTERY_PACKED_RAM_BASE_ADDR);
+        let index = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR) % BATTERY_PACKED_RAM_SIZE;
+        self.write_battery_packed_ram_index(index, data);
+    }
+    /// カセット内RAMを8KB bank単位で読み出します。サイズを超えた分はwrapする
+    pub fn read_battery_packed_ram_bank(&self, bank: usize, offset: usize) -> u8 {
+        let index = (bank * BATTERY_PACKED_RAM_SIZE + offset) % self.battery_packed_ram.len();
+        arr_read!(self.battery_packed_ram.as_slice(), index)
+    }
+    /// カセット内RAMに8KB bank単位で書き込みます。サイズを超えた分はwrapする
+    pub fn write_battery_packed_ram_bank(&mut self, bank: usize, offset: usize, data: u8) {
+        let index = (bank * BATTERY_PACKED_RAM_SIZE + offset) % self.battery_packed_ram.len();
+        self.write_battery_packed_ram_index(index, data);
+    }
+    fn write_battery_packed_ram_index(&mut self, index: usize, data: u8) {
+        if arr_read!(self.battery_packed_ram.as_slice(), index) != data {
+            arr_write!(self.battery_packed_ram.as_mut_slice(), index, data);
+            self.is_battery_packed_ram_dirty = true;
+        }
+    }
+}
+
+/// Cassete and mapper implement
+/// https://wiki.nesdev.com/w/index.php/List_of_mappers
+#[derive(Clone, Default)]
+pub struct Cassette {
+    /// iNES/NES 2.0 headerの内容
+    pub header: RomHeader,
+    /// Mapper(基板)の実装, iNESのMapper番号から選ばれる
+    pub mapper: MapperBoard,
+    /// Video領域での0x2000 ~ 0x2effのミラーリング設定(iNES headerの値)
+    /// Mapperがミラーリングを制御する場合はそちらが優先される
+    pub nametable_mirror: NameTableMirror,
+    /// 0x6000 ~ 0x7fffのカセット内RAMを有効化する
+    pub is_exists_battery_backed_ram: bool,
+    /// ROM/RAMの実体
+    pub mem: CassetteMemory,
+    /// trueならROM databaseでheaderを補正しない
+    pub is_ignore_rom_db: bool,
+    /// headerの補正に使うROM database, Noneなら組み込みの`ROM_DATABASE`を引く
+    pub rom_database: Option<&'static [RomDbEntry]>,
+    /// PRG-ROM + CHR-ROMのCRC32
+    pub rom_crc32: u32,
+    /// ROM databaseで見つかったentry, headerはこの内容で補正されている
+    pub rom_db_entry: Option<&'static RomDbEntry>,
+}
+
 impl Cassette {
-    /// inesファイルから読み出してメモリ上に展開します
+    /// iNES headerを解析して、長さ`len`のイメージから読み込めるかを確認します
+    /// 失敗した場合はカセットの状態を変えないように、selfには触らない
+    /// `database` - 載っていればheaderを補正する, Noneなら引かない
+    fn check_ines_image(
+        len: usize,
+        read_func: &impl Fn(usize) -> u8,
+        database: Option<&'static [RomDbEntry]>,
+    ) -> Result<(RomHeader, MapperBoard, u32, Option<&'static RomDbEntry>), RomLoadError> {
+        if len < INES_HEADER_SIZE {
+            return Err(RomLoadError::TruncatedHeader);
+        }
+        let mut data = [0u8; INES_HEADER_SIZE];
+        for (index, d) in data.iter_mut().enumerate() {
+            *d = read_func(index);
+        }
+        let mut header = RomHeader::parse(&data)?;
+        header.validate(len)?;
+        let (crc32, entry) = if let Some(database) = database {
+            let rom_len = header.prg_rom_bytes + header.chr_rom_bytes;
+            lookup_rom_db(database, header.prg_rom_offset(), rom_len, read_func)
+        } else {
+            (0, None)
+        };
+        if let Some(entry) = entry {
+            entry.apply(&mut header);
+            header.validate(len)?;
+        }
+        // Mapper番号から基板を選ぶ。未対応のものは間違ったbankで動かさないようにエラーにする
+        let mapper = MapperBoard::from_mapper_number(header.mapper_number, header.submapper_number)
+            .ok_or(RomLoadError::UnsupportedMapper(header.mapper_number))?;
+        Ok((header, mapper, crc32, entry))
+    }
+    /// イメージの読み込み時に引くROM database
+    fn rom_database(&self) -> Option<&'static [RomDbEntry]> {
+        if self.is_ignore_rom_db {
+            None
+        } else {
+            Some(self.rom_database.unwrap_or(ROM_DATABASE))
+        }
+    }
+    /// 検証済みのheaderをカセットに反映します
+    fn apply_ines_header(&mut self, header: &RomHeader, mapper: MapperBoard) {
+        self.mapper = mapper;
+        self.rom_crc32 = 0;
+        self.rom_db_entry = None;
+        self.nametable_mirror = if header.is_four_screen {
+            NameTableMirror::FourScreen
+        } else {
+            header.nametable_mirror
+        };
+        self.is_exists_battery_backed_ram = header.is_battery; // 0x6000 - 0x7fffのRAMを使わせる
+        self.header = header.clone();
+        // rom sizeをセットしとく
+        self.mem.prg_rom_bytes = header.prg_rom_bytes;
+        self.mem.chr_rom_bytes = header.chr_rom_bytes;
+        self.mem.chr_ram = RamImage::new(header.chr_ram_total_bytes());
+        self.mem.nametable_ram = RamImage::new(header.nametable_ram_bytes());
+        // 前のカセットのセーブデータが残らないようにする
+        self.mem.battery_packed_ram = RamImage::new(header.battery_packed_ram_bytes());
+        self.mem.is_battery_packed_ram_dirty = false;
+        // 前に入れていたFDSのdiskを外す
+        #[cfg(feature = "alloc")]
+        {
+            self.mem.disk = None;
+        }
+    }
+    /// trainerがあればカセット内RAMの0x7000 - 0x71ffに展開します
+    fn load_trainer(&mut self, header: &RomHeader, read_func: &impl Fn(usize) -> u8) {
+        if !header.is_trainer {
+            return;
+        }
+        // ROMの一部なので保存対象の変更としては扱わない
+        let offset = usize::from(INES_TRAINER_BASE_ADDR - BATTERY_PACKED_RAM_BASE_ADDR);
+        for index in 0..INES_TRAINER_DATA_SIZE {
+            self.mem.battery_packed_ram.as_mut_slice()[offset + index] =
+                read_func(INES_HEADER_SIZE + index);
+        }
+    }
+    /// inesファイルから読み出してheap上に展開します
     /// 組み込み環境でRAM展開されていなくても利用できるように、多少パフォーマンスを犠牲にしてもclosure経由で読み出します
-    pub fn from_ines_binary(&mut self, read_func: impl Fn(usize) -> u8) -> bool {
-        // header : 16byte
-        // trainer: 0 or 512byte
-        // prg rom: prg_rom_size * 16KB(0x4000)
-        // chr rom: prg_rom_size * 8KB(0x2000)
-        // playchoise inst-rom: 0 or 8192byte(8KB)
-        // playchoise prom: 16byte
-
-        // header check
-        if read_func(0) != 0x4e {
-            // N
-            return false;
+    /// `len` - inesファイルのサイズ、これを超えてread_funcを呼ぶことはない
+    #[cfg(feature = "alloc")]
+    pub fn from_ines_binary(
+        &mut self,
+        len: usize,
+        read_func: impl Fn(usize) -> u8,
+    ) -> Result<RomHeader, RomLoadError> {
+        let (header, mapper, crc32, entry) =
+            Self::check_ines_image(len, &read_func, self.rom_database())?;
+        // PRG-ROM
+        let prg_rom_baseaddr = header.prg_rom_offset();
+        let prg_rom: Vec<u8> = (0..header.prg_rom_bytes)
+            .map(|index| read_func(prg_rom_baseaddr + index))
+            .collect();
+        // CHR-ROM
+        let chr_rom_baseaddr = header.chr_rom_offset();
+        let chr_rom: Vec<u8> = (0..header.chr_rom_bytes)
+            .map(|index| read_func(chr_rom_baseaddr + index))
+            .collect();
+
+        self.mem.prg_rom = RomImage::Shared(Rc::from(prg_rom));
+        self.mem.chr_rom = RomImage::Shared(Rc::from(chr_rom));
+        self.apply_ines_header(&header, mapper);
+        self.rom_crc32 = crc32;
+        self.rom_db_entry = entry;
+        self.load_trainer(&header, &read_func);
+
+        // やったね
+        Ok(header)
+    }
+    /// flashなどに置かれたinesファイルをコピーせずにそのまま参照します
+    /// heapが使えない環境向け
+    pub fn from_ines_static(&mut self, binary: &'static [u8]) -> Result<RomHeader, RomLoadError> {
+        let (header, mapper, crc32, entry) = Self::check_ines_image(
+            binary.len(),
+            &|addr: usize| binary[addr],
+            self.rom_database(),
+        )?;
+        let prg_rom_baseaddr = header.prg_rom_offset();
+        let chr_rom_baseaddr = header.chr_rom_offset();
+        let chr_rom_end = chr_rom_baseaddr + header.chr_rom_bytes;
+
+        self.mem.prg_rom = RomImage::Static(&binary[prg_rom_baseaddr..chr_rom_baseaddr]);
+        self.mem.chr_rom = RomImage::Static(&binary[chr_rom_baseaddr..chr_rom_end]);
+        self.apply_ines_header(&header, mapper);
+        self.rom_crc32 = crc32;
+        self.rom_db_entry = entry;
+        self.load_trainer(&header, &|addr: usize| binary[addr]);
+
+        Ok(header)
+    }
+    /// UNIFファイルから読み出してheap上に展開します
+    /// board名からiNESのMapper番号を引いて、inesファイルと同じカセットの状態にする
+    /// `len` - UNIFファイルのサイズ、これを超えてread_funcを呼ぶことはない
+    #[cfg(feature = "alloc")]
+    pub fn from_unif_binary(
+        &mut self,
+        len: usize,
+        read_func: impl Fn(usize) -> u8,
+    ) -> Result<RomHeader, RomLoadError> {
+        let image = UnifImage::parse(len, &read_func)?;
+        let mut header = image.header;
+        // PRGn/CHRnをchunk番号順に連結する
+        let read_chunks = |chunks: &[Option<UnifChunk>]| -> Vec<u8> {
+            chunks
+                .iter()
+                .flatten()
+                .flat_map(|chunk| (chunk.offset..chunk.offset + chunk.len).map(&read_func))
+                .collect()
+        };
+        let prg_rom = read_chunks(&image.prg_chunks);
+        let chr_rom = read_chunks(&image.chr_chunks);
+        // inesファイルと同じくPRG-ROM + CHR-ROMでdatabaseを引く
+        let (crc32, entry) = if let Some(database) = self.rom_database() {
+            let read_rom = |addr: usize| {
+                if addr < prg_rom.len() {
+                    prg_rom[addr]
+                } else {
+                    chr_rom[addr - prg_rom.len()]
+                }
+            };
+            lookup_rom_db(database, 0, prg_rom.len() + chr_rom.len(), &read_rom)
+        } else {
+            (0, None)
+        };
+        if let Some(entry) = entry {
+            entry.apply(&mut header);
+            header.validate_size()?;
         }
-        if read_func(1) != 0x45 {
-            // E
-            return false;
+        let mapper = MapperBoard::from_mapper_number(header.mapper_number, header.submapper_number)
+            .ok_or(RomLoadError::UnsupportedMapper(header.mapper_number))?;
+
+        self.mem.prg_rom = RomImage::Shared(Rc::from(prg_rom));
+        self.mem.chr_rom = RomImage::Shared(Rc::from(chr_rom));
+        self.apply_ines_header(&header, mapper);
+        self.rom_crc32 = crc32;
+        self.rom_db_entry = entry;
+
+        Ok(header)
+    }
+    /// FDSのBIOS(disksys.rom)とdisk image(.fds/.qd)を読み込みます
+    /// diskは1面目が入った状態で始まる
+    #[cfg(feature = "alloc")]
+    pub fn from_fds_binary(
+        &mut self,
+        bios: &[u8],
+        image: &[u8],
+    ) -> Result<RomHeader, RomLoadError> {
+        if bios.len() != FDS_BIOS_SIZE {
+            return Err(RomLoadError::BadFdsBiosSize(bios.len()));
         }
-        if read_func(2) != 0x53 {
-            // S
-            return false;
+        let disk = FdsDisk::parse(image)?;
+        let header = RomHeader {
+            mapper_number: FDS_MAPPER_NUMBER,
+            prg_rom_bytes: FDS_BIOS_SIZE,
+            prg_ram_bytes: FDS_PRG_RAM_SIZE,
+            chr_ram_bytes: CHR_RAM_SIZE,
+            ..Default::default()
+        };
+        let mut mapper = Fds::default();
+        mapper.insert_side(0, true);
+
+        self.mem.prg_rom = RomImage::Shared(Rc::from(bios));
+        self.mem.chr_rom = RomImage::Empty;
+        self.apply_ines_header(&header, MapperBoard::Fds(mapper));
+        self.mem.disk = Some(disk);
+
+        Ok(header)
+    }
+}
+
+/// Famicom Disk Systemのdisk操作
+#[cfg(feature = "alloc")]
+impl Cassette {
+    /// diskの面の数、FDSでなければ0
+    pub fn fds_side_count(&self) -> usize {
+        self.mem.disk.as_ref().map_or(0, |disk| disk.side_count())
+    }
+    /// 今入っている面、取り出されているかFDSでなければNone
+    pub fn fds_inserted_side(&self) -> Option<usize> {
+        match &self.mapper {
+            MapperBoard::Fds(m) => m.inserted_side,
+            _ => None,
         }
-        if read_func(3) != 0x1a {
-            // character break
+    }
+    /// `side`面に入れ替えます。BIOSが気付けるように一度取り出してから入る
+    /// 存在しない面を指定した場合はfalse
+    pub fn fds_insert_side(&mut self, side: usize) -> bool {
+        if side >= self.fds_side_count() {
             return false;
         }
-        let prg_rom_size = usize::from(read_func(4)); // * 16KBしてあげる
-        let chr_rom_size = usize::from(read_func(5)); // * 8KBしてあげる
-        let flags6 = read_func(6);
-        let _flags7 = read_func(7);
-        let _flags8 = read_func(8);
-        let _flags9 = read_func(9);
-        let _flags10 = read_func(10);
-        // 11~15 unused_padding
-        debug_assert!(prg_rom_size > 0);
-
-        // flags parsing
-        let is_mirroring_vertical = (flags6 & 0x01) == 0x01;
-        if is_mirroring_vertical {
-            self.nametable_mirror = NameTableMirror::Vertical;
-        } else {
-            self.nametable_mirror = NameTableMirror::Horizontal;
-        }
-        self.is_exists_battery_backed_ram = (flags6 & 0x02) == 0x02; // 0x6000 - 0x7fffのRAMを使わせる
-        let is_exists_trainer = (flags6 & 0x04) == 0x04; // 512byte trainer at 0x7000-0x71ff in ines file
-
-        // 領域計算
-        let header_bytes = 16;
-        let trainer_bytes = if is_exists_trainer { 512 } else { 0 };
-        let prg_rom_bytes = prg_rom_size * 0x4000; // 単位変換する
-        let chr_rom_bytes = chr_rom_size * 0x2000; // 単位変換する
-        let trainer_baseaddr = header_bytes;
-        let prg_rom_baseaddr = header_bytes + trainer_bytes;
-        let chr_rom_baseaddr = header_bytes + trainer_bytes + prg_rom_bytes;
-
-        // 現在はMapper0しか対応しない
-        self.mapper = Mapper::Nrom;
-        debug_assert!(prg_rom_bytes <= PRG_ROM_MAX_SIZE);
-        debug_assert!(chr_rom_bytes <= CHR_ROM_MAX_SIZE);
-
-        // Battery Packed RAMの初期値
-        if is_exists_trainer {
-            // 0x7000 - 0x71ffに展開する
-            for index in 0..INES_TRAINER_DATA_SIZE {
-                let ines_binary_addr = trainer_baseaddr + index;
-                self.prg_rom[index] = read_func(ines_binary_addr);
+        match &mut self.mapper {
+            MapperBoard::Fds(m) => {
+                m.insert_side(side, false);
+                true
             }
+            _ => false,
+        }
+    }
+    /// diskを取り出します
+    pub fn fds_eject(&mut self) {
+        if let MapperBoard::Fds(m) = &mut self.mapper {
+            m.eject();
         }
+    }
+    /// diskの実体、書き込み内容の保存に使う
+    pub fn fds_disk(&self) -> Option<&FdsDisk> {
+        self.mem.disk.as_ref()
+    }
+    pub fn fds_disk_mut(&mut self) -> Option<&mut FdsDisk> {
+        self.mem.disk.as_mut()
+    }
+}
 
-        // PRG-ROM
-        for index in 0..prg_rom_bytes {
-            let ines_binary_addr = prg_rom_baseaddr + index;
-            self.prg_rom[index] = read_func(ines_binary_addr);
+impl Cassette {
+    /// 現在のNameTable Mirror設定を返します
+    /// Mapperが制御している場合はそちらを優先し、そうでなければiNES headerの値を使う
+    /// four-screenのカセットはMapperのミラーリング設定が配線されていないので常にFourScreen
+    pub fn read_nametable_mirror(&self) -> NameTableMirror {
+        if self.nametable_mirror == NameTableMirror::FourScreen {
+            return NameTableMirror::FourScreen;
         }
-        // CHR-ROM
-        for index in 0..chr_rom_bytes {
-            let ines_binary_addr = chr_rom_baseaddr + index;
-            self.chr_rom[index] = read_func(ines_binary_addr);
+        self.mapper
+            .as_mapper()
+            .nametable_mirror()
+            .unwrap_or(self.nametable_mirror)
+    }
+    /// NameTableの`slot`番目(0x2000 + 0x400 * slot)の参照先を返します
+    ///
+    /// [A, B]: A-0x2000, B-0x2400
+    /// [C, D]: C-0x2800, D-0x2c00
+    pub fn nametable_source(&self, slot: usize) -> NameTableSource {
+        if let Some(source) = self.mapper.as_mapper().nametable_source(slot) {
+            return source;
         }
+        match self.read_nametable_mirror() {
+            // [A, A]
+            // [B, B]
+            NameTableMirror::Horizontal => NameTableSource::Ciram((slot >> 1) & 0x01),
+            // [A, B]
+            // [A, B]
+            NameTableMirror::Vertical | NameTableMirror::Unknown => {
+                NameTableSource::Ciram(slot & 0x01)
+            }
+            // [A, A]
+            // [A, A]
+            NameTableMirror::SingleScreenLower => NameTableSource::Ciram(0),
+            // [B, B]
+            // [B, B]
+            NameTableMirror::SingleScreenUpper => NameTableSource::Ciram(1),
+            // [A, B]
+            // [C, D] C, Dはカセット上のVRAM
+            NameTableMirror::FourScreen => {
+                if slot < 2 {
+                    NameTableSource::Ciram(slot)
+                } else {
+                    NameTableSource::CartRam(slot - 2)
+                }
+            }
+        }
+    }
+    /// カセット側が持っているNameTableを読み出します
+    /// CIRAMが選ばれている場合は0を返すので、呼び出し元で本体のVRAMを読むこと
+    pub fn read_nametable_u8(&mut self, slot: usize, offset: usize) -> u8 {
+        match self.nametable_source(slot) {
+            NameTableSource::CartRam(index) => self.mem.read_nametable_ram(index, offset),
+            NameTableSource::Mapper => {
+                self.mapper
+                    .as_mapper_mut()
+                    .read_nametable_u8(&mut self.mem, slot, offset)
+            }
+            NameTableSource::Ciram(_) => 0,
+        }
+    }
+    /// カセット側が持っているNameTableに書き込みます
+    pub fn write_nametable_u8(&mut self, slot: usize, offset: usize, data: u8) {
+        match self.nametable_source(slot) {
+            NameTableSource::CartRam(index) => self.mem.write_nametable_ram(index, offset, data),
+            NameTableSource::Mapper => {
+                self.mapper
+                    .as_mapper_mut()
+                    .write_nametable_u8(&mut self.mem, slot, offset, data)
+            }
+            NameTableSource::Ciram(_) => {}
+        }
+    }
+    /// PPUが読み出したNameTableのデータをMapperに渡し、必要なら差し替えてもらいます
+    pub fn filter_nametable_read(&mut self, addr: u16, data: u8) -> u8 {
+        self.mapper
+            .as_mapper_mut()
+            .filter_nametable_read(&mut self.mem, addr, data)
+    }
+    /// CPUがPPUレジスタに書き込んだことをMapperに通知します
+    pub fn notify_ppu_register_write(&mut self, addr: u16, data: u8) {
+        self.mapper
+            .as_mapper_mut()
+            .notify_ppu_register_write(addr, data);
+    }
+    /// PPUのfetch対象が切り替わったことをMapperに通知します
+    pub fn notify_ppu_fetch_phase(&mut self, phase: PpuFetchPhase) {
+        self.mapper.as_mapper_mut().notify_ppu_fetch_phase(phase);
+    }
+    /// MapperがIRQを要求していればtrue
+    pub fn is_irq_asserted(&self) -> bool {
+        self.mapper.as_mapper().is_irq_asserted()
+    }
+    /// カセットのIRQの要因、FDSはMapperと区別する
+    pub fn irq_source(&self) -> IrqSource {
+        match self.mapper {
+            #[cfg(feature = "alloc")]
+            MapperBoard::Fds(_) => IrqSource::Fds,
+            _ => IrqSource::Mapper,
+        }
+    }
+    /// PPUが1line描画したことをMapperに通知します
+    pub fn notify_scanline(&mut self) {
+        self.mapper.as_mapper_mut().notify_scanline();
+    }
+    /// CPUがcpu_cyc分進んだことをMapperに通知します
+    pub fn notify_cpu_cycles(&mut self, cpu_cyc: usize) {
+        self.mapper
+            .as_mapper_mut()
+            .notify_cpu_cycles(&mut self.mem, cpu_cyc);
+    }
+    /// カセットの拡張音源の出力
+    pub fn expansion_audio_output(&self) -> f32 {
+        self.mapper.as_mapper().expansion_audio_output()
+    }
+    /// Mapperのレジスタとカセット内RAMを書き出します
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.mapper.as_mapper().save_state(writer);
+        writer.write_bytes(self.mem.battery_packed_ram.as_slice());
+        writer.write_bytes(self.mem.chr_ram.as_slice());
+        writer.write_bytes(self.mem.nametable_ram.as_slice());
+        #[cfg(feature = "alloc")]
+        {
+            if let Some(disk) = &self.mem.disk {
+                disk.save_state(writer);
+            }
+        }
+    }
+    /// `save_state`で書き出した状態を復元します
+    /// ROMは含まれないので、同じカセットをロードした状態で呼ぶこと
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.mapper.as_mapper_mut().load_state(reader);
+        reader.read_bytes(self.mem.battery_packed_ram.as_mut_slice());
+        self.mem.is_battery_packed_ram_dirty = true;
+        reader.read_bytes(self.mem.chr_ram.as_mut_slice());
+        reader.read_bytes(self.mem.nametable_ram.as_mut_slice());
+        #[cfg(feature = "alloc")]
+        {
+            if let Some(disk) = &mut self.mem.disk {
+                disk.load_state(reader);
+            }
+        }
+    }
+}
 
-        // rom sizeをセットしとく
-        self.prg_rom_bytes = prg_rom_bytes;
-        self.chr_rom_bytes = chr_rom_bytes;
+impl Cassette {
+    /// ROM databaseで特定できたタイトル
+    pub fn rom_title(&self) -> Option<&'static str> {
+        self.rom_db_entry.map(|entry| entry.title)
+    }
+    /// ROM databaseで特定できたリージョン
+    pub fn rom_region(&self) -> Option<TimingRegion> {
+        self.rom_db_entry.map(|entry| entry.timing)
+    }
+}
 
-        // やったね
+impl Cassette {
+    /// バッテリーバックアップされたカセット内RAMの中身を返します
+    /// バッテリーを持たないカセットの場合はNone
+    pub fn battery_backup(&self) -> Option<&[u8]> {
+        if self.is_exists_battery_backed_ram {
+            Some(self.mem.battery_packed_ram.as_slice())
+        } else {
+            None
+        }
+    }
+    /// .savファイルなどから読み込んだ内容でカセット内RAMを復元します
+    /// サイズが違う場合は先頭から入る分だけ書き込む。バッテリーを持たないカセットの場合はfalse
+    pub fn restore_battery_backup(&mut self, src: &[u8]) -> bool {
+        if !self.is_exists_battery_backed_ram {
+            return false;
+        }
+        let len = core::cmp::min(src.len(), self.mem.battery_packed_ram.len());
+        self.mem.battery_packed_ram.as_mut_slice()[..len].copy_from_slice(&src[..len]);
+        self.mem.is_battery_packed_ram_dirty = false;
         true
     }
+    /// 前回保存してからカセット内RAMが書き換えられていればtrue
+    pub fn is_battery_backup_dirty(&self) -> bool {
+        self.is_exists_battery_backed_ram && self.mem.is_battery_packed_ram_dirty
+    }
+    /// `battery_backup`の内容を保存し終わったら呼びます
+    pub fn clear_battery_backup_dirty(&mut self) {
+        self.mem.is_battery_packed_ram_dirty = false;
+    }
 }
 
 impl SystemBus for Cassette {
-    fn read_u8(&mut self, addr: u16, _is_nondestructive: bool) -> u8 {
-        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
-            debug_assert!(addr >= BATTERY_PACKED_RAM_BASE_ADDR);
-
-            let index = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR);
-            arr_read!(self.battery_packed_ram, index)
+    fn read_u8(&mut self, addr: u16, is_nondestructive: bool) -> u8 {
+        if addr < BATTERY_PACKED_RAM_BASE_ADDR {
+            // 0x4020 - 0x5fff: Mapperが応答しなければopen bus(直前にbusに乗っていたaddrの上位byte)
+            self.mapper
+                .as_mapper_mut()
+                .read_expansion_u8(&mut self.mem, addr, is_nondestructive)
+                .unwrap_or((addr >> 8) as u8)
         } else {
-            debug_assert!(addr >= PRG_ROM_SYSTEM_BASE_ADDR);
-
-            let index = usize::from(addr - PRG_ROM_SYSTEM_BASE_ADDR);
-            // ROMが16KB場合のミラーリング
-            if index < self.prg_rom_bytes {
-                arr_read!(self.prg_rom, index)
-            } else {
-                arr_read!(self.prg_rom, index - self.prg_rom_bytes)
-            }
+            self.mapper
+                .as_mapper_mut()
+                .read_u8(&mut self.mem, addr, is_nondestructive)
         }
     }
-    fn write_u8(&mut self, addr: u16, data: u8, _is_nondestructive: bool) {
-        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
-            debug_assert!(addr >= BATTERY_PACKED_RAM_BASE_ADDR);
-
-            let index = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR);
-            arr_write!(self.battery_packed_ram, index, data)
+    fn write_u8(&mut self, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < BATTERY_PACKED_RAM_BASE_ADDR {
+            self.mapper.as_mapper_mut().write_expansion_u8(
+                &mut self.mem,
+                addr,
+                data,
+                is_nondestructive,
+            )
         } else {
-            debug_assert!(addr >= PRG_ROM_SYSTEM_BASE_ADDR);
-
-            let index = usize::from(addr - PRG_ROM_SYSTEM_BASE_ADDR);
-            // ROMが16KB場合のミラーリング
-            if index < self.prg_rom_bytes {
-                arr_write!(self.prg_rom, index, data);
-            } else {
-                arr_write!(self.prg_rom, index - self.prg_rom_bytes, data);
-            }
+            self.mapper
+                .as_mapper_mut()
+                .write_u8(&mut self.mem, addr, data, is_nondestructive)
         }
     }
 }
 impl VideoBus for Cassette {
     fn read_video_u8(&mut self, addr: u16) -> u8 {
-        let index = usize::from(addr);
-        debug_assert!(index < CHR_ROM_MAX_SIZE);
-        arr_read!(self.chr_rom, index)
+        self.mapper
+            .as_mapper_mut()
+            .read_video_u8(&mut self.mem, addr)
     }
-    /// CHR_RAM対応も込めて書き換え可能にしておく
     fn write_video_u8(&mut self, addr: u16, data: u8) {
-        let index = usize::from(addr);
-        debug_assert!(index < CHR_ROM_MAX_SIZE);
-        arr_write!(self.chr_rom, index, data);
+        self.mapper
+            .as_mapper_mut()
+            .write_video_u8(&mut self.mem, addr, data)
     }
 }
 
 impl EmulateControl for Cassette {
     fn reset(&mut self) {
-        self.mapper = Mapper::Unknown;
+        self.header = RomHeader::default();
+        self.mapper = MapperBoard::default();
         self.nametable_mirror = NameTableMirror::Unknown;
         self.is_exists_battery_backed_ram = false;
-        self.prg_rom_bytes = 0;
-        self.chr_rom_bytes = 0;
-        self.prg_rom = [0; PRG_ROM_MAX_SIZE];
-        self.chr_rom = [0; CHR_ROM_MAX_SIZE];
-        self.battery_packed_ram = [0; BATTERY_PACKED_RAM_MAX_SIZE];
+        self.rom_crc32 = 0;
+        self.rom_db_entry = None;
+        self.mem.reset();
     }
 }
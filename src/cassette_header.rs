@@ -0,0 +1,395 @@
+use super::cassette::*;
+
+pub const INES_HEADER_SIZE: usize = 16;
+
+/// headerの形式
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum HeaderFormat {
+    /// iNES 1.0 (byte 7 ~ 15は信用できないことが多い)
+    #[default]
+    INes,
+    /// NES 2.0
+    Nes20,
+    /// UNIF, chunkの内容から組み立てたheader
+    Unif,
+}
+
+/// CPU/PPUのタイミング
+/// https://wiki.nesdev.com/w/index.php/NES_2.0#CPU.2FPPU_Timing
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum TimingRegion {
+    #[default]
+    Ntsc,
+    Pal,
+    /// NTSC/PALどちらでも動く
+    MultiRegion,
+    Dendy,
+}
+
+/// 本体の種類
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ConsoleType {
+    #[default]
+    Famicom,
+    /// `ppu` - Vs. PPU type, `hardware` - Vs. Hardware type
+    VsSystem {
+        ppu: u8,
+        hardware: u8,
+    },
+    PlayChoice10,
+    /// byte 13の拡張コンソール番号
+    Extended(u8),
+}
+
+/// 標準で接続されている入力デバイス
+/// https://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ExpansionDevice {
+    #[default]
+    Unspecified,
+    StandardController,
+    FourScore,
+    FamicomFourPlayers,
+    VsSystem,
+    Zapper,
+    ArkanoidNes,
+    ArkanoidFamicom,
+    PowerPad,
+    FamilyBasicKeyboard,
+    /// 上記以外、番号をそのまま保持する
+    Other(u8),
+}
+
+impl ExpansionDevice {
+    fn from_u8(data: u8) -> ExpansionDevice {
+        match data {
+            0x00 => ExpansionDevice::Unspecified,
+            0x01 => ExpansionDevice::StandardController,
+            0x02 => ExpansionDevice::FourScore,
+            0x03 => ExpansionDevice::FamicomFourPlayers,
+            0x04 => ExpansionDevice::VsSystem,
+            0x08 => ExpansionDevice::Zapper,
+            0x0a => ExpansionDevice::ArkanoidNes,
+            0x0b => ExpansionDevice::ArkanoidFamicom,
+            0x0c => ExpansionDevice::PowerPad,
+            0x23 => ExpansionDevice::FamilyBasicKeyboard,
+            _ => ExpansionDevice::Other(data),
+        }
+    }
+}
+
+/// ROMイメージの読み込みに失敗した理由
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RomLoadError {
+    /// 先頭が"NES\x1a"ではない
+    BadMagic,
+    /// 16byteのheaderが揃っていない
+    TruncatedHeader,
+    /// headerにtrainerありと書かれているが512byte揃っていない
+    TruncatedTrainer,
+    /// PRG-ROMがheaderに書かれたサイズより短い
+    TruncatedPrgRom { expected: usize, actual: usize },
+    /// CHR-ROMがheaderに書かれたサイズより短い
+    TruncatedChrRom { expected: usize, actual: usize },
+    /// PRG-ROMのサイズが0
+    EmptyPrgRom,
+    /// PRG_ROM_MAX_SIZEを超えている
+    OversizePrgRom(usize),
+    /// CHR_ROM_MAX_SIZEを超えている
+    OversizeChrRom(usize),
+    /// CHR_RAM_MAX_SIZEを超えている
+    OversizeChrRam(usize),
+    /// CART_NAME_TABLE_RAM_MAX_SIZEを超えている(heapが使えない環境でのfour-screenやMMC5)
+    OversizeNameTableRam(usize),
+    /// 対応していないMapper番号
+    UnsupportedMapper(u16),
+    /// .fds/.qdとして解釈できないサイズ
+    BadFdsImage(usize),
+    /// FDSのBIOSが8KBではない
+    BadFdsBiosSize(usize),
+    /// UNIFのchunkが書かれた長さより短い
+    TruncatedUnifChunk,
+    /// UNIFのMAPR chunkがない、または対応していないboard
+    UnsupportedUnifBoard,
+}
+
+impl core::fmt::Display for RomLoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            RomLoadError::BadMagic => write!(f, "not an iNES image (bad magic)"),
+            RomLoadError::TruncatedHeader => write!(f, "truncated iNES header"),
+            RomLoadError::TruncatedTrainer => write!(f, "truncated trainer"),
+            RomLoadError::TruncatedPrgRom { expected, actual } => write!(
+                f,
+                "truncated PRG-ROM (expected {} bytes, actual {} bytes)",
+                expected, actual
+            ),
+            RomLoadError::TruncatedChrRom { expected, actual } => write!(
+                f,
+                "truncated CHR-ROM (expected {} bytes, actual {} bytes)",
+                expected, actual
+            ),
+            RomLoadError::EmptyPrgRom => write!(f, "PRG-ROM size is zero"),
+            RomLoadError::OversizePrgRom(bytes) => {
+                write!(f, "PRG-ROM too large ({} bytes)", bytes)
+            }
+            RomLoadError::OversizeChrRom(bytes) => {
+                write!(f, "CHR-ROM too large ({} bytes)", bytes)
+            }
+            RomLoadError::OversizeChrRam(bytes) => {
+                write!(f, "CHR-RAM too large ({} bytes)", bytes)
+            }
+            RomLoadError::OversizeNameTableRam(bytes) => {
+                write!(f, "cartridge VRAM too large ({} bytes)", bytes)
+            }
+            RomLoadError::UnsupportedMapper(number) => {
+                write!(f, "unsupported mapper {}", number)
+            }
+            RomLoadError::BadFdsImage(bytes) => {
+                write!(f, "not a FDS disk image ({} bytes)", bytes)
+            }
+            RomLoadError::BadFdsBiosSize(bytes) => {
+                write!(f, "FDS BIOS must be 8192 bytes ({} bytes)", bytes)
+            }
+            RomLoadError::TruncatedUnifChunk => write!(f, "truncated UNIF chunk"),
+            RomLoadError::UnsupportedUnifBoard => write!(f, "unsupported UNIF board"),
+        }
+    }
+}
+
+/// iNES/NES 2.0 headerの内容
+/// https://wiki.nesdev.com/w/index.php/INES
+/// https://wiki.nesdev.com/w/index.php/NES_2.0
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    /// Mapper番号(NES 2.0は12bit)
+    pub mapper_number: u16,
+    pub submapper_number: u8,
+    pub prg_rom_bytes: usize,
+    pub chr_rom_bytes: usize,
+    /// 電源を切ると消えるPRG-RAM
+    pub prg_ram_bytes: usize,
+    /// バッテリーバックアップされたPRG-RAM
+    pub prg_nvram_bytes: usize,
+    pub chr_ram_bytes: usize,
+    pub chr_nvram_bytes: usize,
+    /// flags6 bit0のミラーリング設定
+    pub nametable_mirror: NameTableMirror,
+    /// flags6 bit3, カセット上に4画面分のVRAMを持つ
+    pub is_four_screen: bool,
+    /// flags6 bit1, 不揮発メモリを持つ
+    pub is_battery: bool,
+    /// flags6 bit2, 512byteのtrainerがPRG-ROMの前にある
+    pub is_trainer: bool,
+    pub timing: TimingRegion,
+    pub console_type: ConsoleType,
+    /// NES 2.0 byte 14, PRG/CHR-ROMの後ろに続くROMの数
+    pub misc_rom_count: u8,
+    pub expansion_device: ExpansionDevice,
+}
+
+impl RomHeader {
+    /// headerの16byteを解析します
+    pub fn parse(header: &[u8; INES_HEADER_SIZE]) -> Result<RomHeader, RomLoadError> {
+        // "NES" + character break
+        if header[0..4] != [0x4e, 0x45, 0x53, 0x1a] {
+            return Err(RomLoadError::BadMagic);
+        }
+        let flags6 = header[6];
+        let flags7 = header[7];
+        let format = if (flags7 & 0x0c) == 0x08 {
+            HeaderFormat::Nes20
+        } else {
+            HeaderFormat::INes
+        };
+
+        let mut result = RomHeader {
+            format,
+            nametable_mirror: if (flags6 & 0x01) == 0x01 {
+                NameTableMirror::Vertical
+            } else {
+                NameTableMirror::Horizontal
+            },
+            is_four_screen: (flags6 & 0x08) == 0x08,
+            is_battery: (flags6 & 0x02) == 0x02,
+            is_trainer: (flags6 & 0x04) == 0x04,
+            ..Default::default()
+        };
+
+        match format {
+            HeaderFormat::Nes20 => {
+                result.mapper_number = (u16::from(header[8] & 0x0f) << 8)
+                    | u16::from(flags7 & 0xf0)
+                    | u16::from(flags6 >> 4);
+                result.submapper_number = header[8] >> 4;
+                result.prg_rom_bytes = Self::rom_size_nes20(header[4], header[9] & 0x0f, 0x4000);
+                result.chr_rom_bytes = Self::rom_size_nes20(header[5], header[9] >> 4, 0x2000);
+                result.prg_ram_bytes = Self::ram_size_nes20(header[10] & 0x0f);
+                result.prg_nvram_bytes = Self::ram_size_nes20(header[10] >> 4);
+                result.chr_ram_bytes = Self::ram_size_nes20(header[11] & 0x0f);
+                result.chr_nvram_bytes = Self::ram_size_nes20(header[11] >> 4);
+                result.timing = match header[12] & 0x03 {
+                    0 => TimingRegion::Ntsc,
+                    1 => TimingRegion::Pal,
+                    2 => TimingRegion::MultiRegion,
+                    _ => TimingRegion::Dendy,
+                };
+                result.console_type = match flags7 & 0x03 {
+                    0 => ConsoleType::Famicom,
+                    1 => ConsoleType::VsSystem {
+                        ppu: header[13] & 0x0f,
+                        hardware: header[13] >> 4,
+                    },
+                    2 => ConsoleType::PlayChoice10,
+                    _ => ConsoleType::Extended(header[13] & 0x0f),
+                };
+                result.misc_rom_count = header[14] & 0x03;
+                result.expansion_device = ExpansionDevice::from_u8(header[15] & 0x3f);
+            }
+            _ => {
+                // iNES 1.0
+                // byte 12 ~ 15にゴミ("DiskDude!"など)が書かれている古いdumpはbyte 7 ~ 15を信用しない
+                let is_dirty = header[12..16].iter().any(|d| *d != 0);
+                let (flags7, flags8, flags9) = if is_dirty {
+                    (0, 0, 0)
+                } else {
+                    (flags7, header[8], header[9])
+                };
+                result.mapper_number = u16::from(flags7 & 0xf0) | u16::from(flags6 >> 4);
+                result.prg_rom_bytes = usize::from(header[4]) * 0x4000;
+                result.chr_rom_bytes = usize::from(header[5]) * 0x2000;
+                // PRG-RAMのサイズ指定はほぼ使われていないので0なら8KBとみなす
+                let prg_ram_bytes = core::cmp::max(1, usize::from(flags8)) * 0x2000;
+                if result.is_battery {
+                    result.prg_nvram_bytes = prg_ram_bytes;
+                } else {
+                    result.prg_ram_bytes = prg_ram_bytes;
+                }
+                if result.chr_rom_bytes == 0 {
+                    result.chr_ram_bytes = CHR_RAM_SIZE;
+                }
+                result.timing = if (flags9 & 0x01) == 0x01 {
+                    TimingRegion::Pal
+                } else {
+                    TimingRegion::Ntsc
+                };
+                result.console_type = if (flags7 & 0x01) == 0x01 {
+                    ConsoleType::VsSystem {
+                        ppu: 0,
+                        hardware: 0,
+                    }
+                } else if (flags7 & 0x02) == 0x02 {
+                    ConsoleType::PlayChoice10
+                } else {
+                    ConsoleType::Famicom
+                };
+            }
+        }
+        Ok(result)
+    }
+    /// NES 2.0のROMサイズ
+    /// MSBが0xfの場合はLSBがEEEEEEMMのexponent-multiplier表記になる
+    fn rom_size_nes20(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0x0f {
+            let exponent = u32::from(lsb >> 2);
+            let multiplier = usize::from(lsb & 0x03) * 2 + 1;
+            1usize
+                .checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .unwrap_or(usize::MAX)
+        } else {
+            ((usize::from(msb) << 8) | usize::from(lsb)) * unit
+        }
+    }
+    /// NES 2.0のRAMサイズ、0以外は64 << shift byte
+    fn ram_size_nes20(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64usize << shift
+        }
+    }
+    /// trainerを含めたheaderの後ろからPRG-ROMが始まる位置
+    pub fn prg_rom_offset(&self) -> usize {
+        INES_HEADER_SIZE
+            + if self.is_trainer {
+                INES_TRAINER_DATA_SIZE
+            } else {
+                0
+            }
+    }
+    /// CHR-ROMが始まる位置
+    pub fn chr_rom_offset(&self) -> usize {
+        self.prg_rom_offset() + self.prg_rom_bytes
+    }
+    /// カセットに載せるCHR-RAMのサイズ
+    /// CHR-ROMを持つ場合は0, どちらも書かれていない場合は8KBとみなす
+    pub fn chr_ram_total_bytes(&self) -> usize {
+        if self.chr_rom_bytes > 0 {
+            0
+        } else if self.chr_ram_bytes + self.chr_nvram_bytes > 0 {
+            self.chr_ram_bytes + self.chr_nvram_bytes
+        } else {
+            CHR_RAM_SIZE
+        }
+    }
+    /// カセットに載せるPRG-RAM(バッテリーバックアップ含む)のサイズ
+    /// 0x6000 - 0x7fffの8KBを下限に、bank切り替えで使える上限までに収める
+    pub fn battery_packed_ram_bytes(&self) -> usize {
+        (self.prg_ram_bytes + self.prg_nvram_bytes)
+            .clamp(BATTERY_PACKED_RAM_SIZE, BATTERY_PACKED_RAM_MAX_SIZE)
+    }
+    /// カセットに載せるNameTable用VRAMのサイズ
+    /// four-screenは2KB, MMC5(Mapper5)はExRAMの1KBをここに置く
+    pub fn nametable_ram_bytes(&self) -> usize {
+        if self.is_four_screen {
+            2 * CART_NAME_TABLE_SLOT_SIZE
+        } else if self.mapper_number == 5 {
+            CART_NAME_TABLE_SLOT_SIZE
+        } else {
+            0
+        }
+    }
+    /// サイズ上限と、長さ`len`のイメージにheaderが示す領域が収まっているかを確認します
+    pub fn validate(&self, len: usize) -> Result<(), RomLoadError> {
+        self.validate_size()?;
+        if len < self.prg_rom_offset() {
+            return Err(RomLoadError::TruncatedTrainer);
+        }
+        if len < self.chr_rom_offset() {
+            return Err(RomLoadError::TruncatedPrgRom {
+                expected: self.prg_rom_bytes,
+                actual: len - self.prg_rom_offset(),
+            });
+        }
+        if len < self.chr_rom_offset() + self.chr_rom_bytes {
+            return Err(RomLoadError::TruncatedChrRom {
+                expected: self.chr_rom_bytes,
+                actual: len - self.chr_rom_offset(),
+            });
+        }
+        Ok(())
+    }
+    /// PRG-ROMがあることと、各サイズが上限に収まっているかを確認します
+    pub fn validate_size(&self) -> Result<(), RomLoadError> {
+        if self.prg_rom_bytes == 0 {
+            return Err(RomLoadError::EmptyPrgRom);
+        }
+        if self.prg_rom_bytes > PRG_ROM_MAX_SIZE {
+            return Err(RomLoadError::OversizePrgRom(self.prg_rom_bytes));
+        }
+        if self.chr_rom_bytes > CHR_ROM_MAX_SIZE {
+            return Err(RomLoadError::OversizeChrRom(self.chr_rom_bytes));
+        }
+        if self.chr_ram_total_bytes() > CHR_RAM_MAX_SIZE {
+            return Err(RomLoadError::OversizeChrRam(self.chr_ram_total_bytes()));
+        }
+        if self.nametable_ram_bytes() > CART_NAME_TABLE_RAM_MAX_SIZE {
+            return Err(RomLoadError::OversizeNameTableRam(
+                self.nametable_ram_bytes(),
+            ));
+        }
+        Ok(())
+    }
+}
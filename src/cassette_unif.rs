@@ -0,0 +1,187 @@
+use super::cassette::*;
+use super::cassette_header::*;
+
+/// "UNIF" + revision + 予約領域
+pub const UNIF_HEADER_SIZE: usize = 32;
+/// chunk ID(4byte) + 長さ(4byte little endian)
+pub const UNIF_CHUNK_HEADER_SIZE: usize = 8;
+/// PRG0 - PRGF, CHR0 - CHRF
+pub const UNIF_NUM_OF_ROM_CHUNK: usize = 16;
+/// MAPR chunkのboard名として読む最大長
+const BOARD_NAME_MAX_SIZE: usize = 64;
+
+/// PRGn/CHRn chunkのデータ部分の位置
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct UnifChunk {
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// UNIFファイルを解析した結果
+/// ROMの実体は読まず、chunkの位置だけを控えておく
+/// https://wiki.nesdev.com/w/index.php/UNIF
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnifImage {
+    /// board名などから組み立てたiNES相当のheader
+    pub header: RomHeader,
+    /// PRG0 - PRGF, この順に連結してPRG-ROMにする
+    pub prg_chunks: [Option<UnifChunk>; UNIF_NUM_OF_ROM_CHUNK],
+    /// CHR0 - CHRF, この順に連結してCHR-ROMにする
+    pub chr_chunks: [Option<UnifChunk>; UNIF_NUM_OF_ROM_CHUNK],
+}
+
+impl UnifImage {
+    /// 長さ`len`のUNIFファイルを解析します
+    pub fn parse(len: usize, read_func: &impl Fn(usize) -> u8) -> Result<UnifImage, RomLoadError> {
+        if len < UNIF_HEADER_SIZE {
+            return Err(RomLoadError::TruncatedHeader);
+        }
+        // "UNIF"
+        if [read_func(0), read_func(1), read_func(2), read_func(3)] != [0x55, 0x4e, 0x49, 0x46] {
+            return Err(RomLoadError::BadMagic);
+        }
+        let mut header = RomHeader {
+            format: HeaderFormat::Unif,
+            nametable_mirror: NameTableMirror::Unknown,
+            ..Default::default()
+        };
+        let mut prg_chunks = [None; UNIF_NUM_OF_ROM_CHUNK];
+        let mut chr_chunks = [None; UNIF_NUM_OF_ROM_CHUNK];
+        let mut mapper_number = None;
+
+        let mut offset = UNIF_HEADER_SIZE;
+        while offset + UNIF_CHUNK_HEADER_SIZE <= len {
+            let id = [
+                read_func(offset),
+                read_func(offset + 1),
+                read_func(offset + 2),
+                read_func(offset + 3),
+            ];
+            let chunk_len = u32::from_le_bytes([
+                read_func(offset + 4),
+                read_func(offset + 5),
+                read_func(offset + 6),
+                read_func(offset + 7),
+            ]) as usize;
+            let data_offset = offset + UNIF_CHUNK_HEADER_SIZE;
+            if len - data_offset < chunk_len {
+                return Err(RomLoadError::TruncatedUnifChunk);
+            }
+            let chunk = UnifChunk {
+                offset: data_offset,
+                len: chunk_len,
+            };
+            // 1byteの値を持つchunkで使う
+            let value = if chunk_len > 0 {
+                read_func(data_offset)
+            } else {
+                0
+            };
+            match &id {
+                b"MAPR" => {
+                    let mut name = [0u8; BOARD_NAME_MAX_SIZE];
+                    let mut name_len = 0;
+                    while name_len < core::cmp::min(chunk_len, BOARD_NAME_MAX_SIZE) {
+                        let c = read_func(data_offset + name_len);
+                        if c == 0 {
+                            break;
+                        }
+                        name[name_len] = c;
+                        name_len += 1;
+                    }
+                    mapper_number = unif_board_to_mapper(&name[..name_len]);
+                }
+                [b'P', b'R', b'G', n] => {
+                    if let Some(index) = hex_digit(*n) {
+                        prg_chunks[index] = Some(chunk);
+                    }
+                }
+                [b'C', b'H', b'R', n] => {
+                    if let Some(index) = hex_digit(*n) {
+                        chr_chunks[index] = Some(chunk);
+                    }
+                }
+                b"MIRR" => {
+                    header.nametable_mirror = match value {
+                        0 => NameTableMirror::Horizontal,
+                        1 => NameTableMirror::Vertical,
+                        2 => NameTableMirror::SingleScreenLower,
+                        3 => NameTableMirror::SingleScreenUpper,
+                        4 => NameTableMirror::FourScreen,
+                        // 5: Mapperが制御する
+                        _ => NameTableMirror::Unknown,
+                    };
+                    header.is_four_screen = value == 4;
+                }
+                // chunkがあればbattery付き
+                b"BATR" => header.is_battery = true,
+                b"TVCI" => {
+                    header.timing = match value {
+                        0 => TimingRegion::Ntsc,
+                        1 => TimingRegion::Pal,
+                        _ => TimingRegion::MultiRegion,
+                    };
+                }
+                // NAME, READ, DINF, CTRL, PCKn, CCKnなどは使わない
+                _ => {}
+            }
+            offset = data_offset + chunk_len;
+        }
+
+        header.mapper_number = mapper_number.ok_or(RomLoadError::UnsupportedUnifBoard)?;
+        header.prg_rom_bytes = prg_chunks.iter().flatten().map(|c| c.len).sum();
+        header.chr_rom_bytes = chr_chunks.iter().flatten().map(|c| c.len).sum();
+        // サイズの情報はないので、iNES 1.0と同じく8KBのPRG-RAM, CHR-ROMがなければ8KBのCHR-RAMとみなす
+        if header.is_battery {
+            header.prg_nvram_bytes = BATTERY_PACKED_RAM_SIZE;
+        } else {
+            header.prg_ram_bytes = BATTERY_PACKED_RAM_SIZE;
+        }
+        if header.chr_rom_bytes == 0 {
+            header.chr_ram_bytes = CHR_RAM_SIZE;
+        }
+        header.validate_size()?;
+        Ok(UnifImage {
+            header,
+            prg_chunks,
+            chr_chunks,
+        })
+    }
+}
+
+/// chunk ID末尾の0 - Fを数値にします
+fn hex_digit(c: u8) -> Option<usize> {
+    match c {
+        b'0'..=b'9' => Some(usize::from(c - b'0')),
+        b'A'..=b'F' => Some(usize::from(c - b'A' + 10)),
+        _ => None,
+    }
+}
+
+/// UNIFのboard名から対応するiNESのMapper番号を返します
+/// "NES-", "HVC-"などの接頭辞は無視する
+/// https://wiki.nesdev.com/w/index.php/UNIF_to_NES_2.0_Mapping
+pub fn unif_board_to_mapper(name: &[u8]) -> Option<u16> {
+    let board = [&b"NES-"[..], b"HVC-", b"UNL-", b"BTL-", b"BMC-"]
+        .iter()
+        .find(|prefix| name.starts_with(prefix))
+        .map_or(name, |prefix| &name[prefix.len()..]);
+    match board {
+        b"NROM" | b"NROM-128" | b"NROM-256" | b"RROM" | b"RROM-128" => Some(0),
+        b"SAROM" | b"SBROM" | b"SCROM" | b"SEROM" | b"SFROM" | b"SGROM" | b"SHROM" | b"SJROM"
+        | b"SKROM" | b"SLROM" | b"SL1ROM" | b"SL2ROM" | b"SL3ROM" | b"SLRROM" | b"SMROM"
+        | b"SNROM" | b"SOROM" | b"SUROM" | b"SXROM" => Some(1),
+        b"UNROM" | b"UOROM" => Some(2),
+        b"CNROM" => Some(3),
+        b"TBROM" | b"TEROM" | b"TFROM" | b"TGROM" | b"TKROM" | b"TLROM" | b"TL1ROM" | b"TL2ROM"
+        | b"TNROM" | b"TR1ROM" | b"TSROM" | b"TVROM" | b"HKROM" | b"B4" => Some(4),
+        b"EKROM" | b"ELROM" | b"ETROM" | b"EWROM" => Some(5),
+        b"AMROM" | b"ANROM" | b"AN1ROM" | b"AOROM" => Some(7),
+        b"PEEOROM" | b"PNROM" => Some(9),
+        b"FJROM" | b"FKROM" => Some(10),
+        b"BNROM" => Some(34),
+        b"GNROM" | b"MHROM" => Some(66),
+        b"BTR" | b"JLROM" | b"JSROM" => Some(69),
+        _ => None,
+    }
+}
@@ -1,4 +1,5 @@
 use super::interface::*;
+use super::ppu::OAM_SIZE;
 use super::system::System;
 
 pub const CPU_FREQ: u32 = 1790000;
@@ -10,8 +11,12 @@ pub const IRQ_READ_LOWER: u16 = 0xfffe;
 pub const IRQ_READ_UPPER: u16 = 0xffff;
 pub const BRK_READ_LOWER: u16 = 0xfffe;
 pub const BRK_READ_UPPER: u16 = 0xffff;
+/// OAM DMAでCPUが止まるcycle数(奇数cycleから始まると+1)
+pub const OAM_DMA_STALL_CYCLES: u16 = 513;
+/// OAM DMAの書き込み先, OAM_DATA($2004)
+pub const OAM_DMA_DST_ADDR: u16 = 0x2004;
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum Interrupt {
     NMI,
     RESET,
@@ -19,6 +24,39 @@ pub enum Interrupt {
     BRK,
 }
 
+/// CPUの動作状態
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum CpuState {
+    Running,
+    /// JAM(KIL)命令を実行して止まっている。RESETするまで命令を実行しない
+    Halted,
+}
+
+/// IRQ lineをassertする要因
+/// IRQ lineはwired-ORなので、どれか一つでもassertしていればIRQを要求する
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum IrqSource {
+    /// APU Frame Counter
+    FrameCounter,
+    /// APU DMC
+    Dmc,
+    /// MapperのIRQ counter
+    Mapper,
+    /// FDSのtimer IRQ, disk転送IRQ
+    Fds,
+}
+
+impl IrqSource {
+    fn bit(self) -> u8 {
+        match self {
+            IrqSource::FrameCounter => 0x01,
+            IrqSource::Dmc => 0x02,
+            IrqSource::Mapper => 0x04,
+            IrqSource::Fds => 0x08,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Cpu {
     /// Accumulator
@@ -35,6 +73,23 @@ pub struct Cpu {
     /// Processor Status Register
     /// Negative, oVerflow, Reserved(1固定), Break, Decimal, Interrupt, Zero, Carry
     pub p: u8,
+    /// JAMで止まっていればHalted
+    pub state: CpuState,
+    /// 電源投入からの累計cycle数(DMAで止まっていた分を含む), RESETしてもクリアしない
+    pub cycles: u64,
+
+    /* 割り込み入力 */
+    /// IRQ lineをassertしている要因(IrqSourceのbit)
+    pub irq_sources: u8,
+    /// NMI入力の現在の状態、エッジ検出に使う
+    pub is_nmi_line_active: bool,
+    /// NMIのエッジを検出したがまだ処理していない
+    pub is_nmi_pending: bool,
+    /// 前の命令の最後で割り込みをpollしたときのI flag
+    /// CLI, SEI, PLPはI flagを最後のcycleで書き換えるので、1命令遅れて効く
+    pub is_poll_interrupt_disabled: bool,
+    /// 前の命令の最後のcycleでpollした割り込み、次のstepの最初に処理する(cycle-accurate core)
+    pub pending_interrupt: Option<Interrupt>,
 }
 
 impl Default for Cpu {
@@ -46,6 +101,13 @@ impl Default for Cpu {
             pc: 0,
             sp: 0,
             p: 0,
+            state: CpuState::Running,
+            cycles: 0,
+            irq_sources: 0,
+            is_nmi_line_active: false,
+            is_nmi_pending: false,
+            is_poll_interrupt_disabled: false,
+            pending_interrupt: None,
         }
     }
 }
@@ -58,6 +120,12 @@ impl EmulateControl for Cpu {
         self.pc = 0;
         self.sp = 0x01fd;
         self.p = 0x34;
+        self.state = CpuState::Running;
+        self.irq_sources = 0;
+        self.is_nmi_line_active = false;
+        self.is_nmi_pending = false;
+        self.is_poll_interrupt_disabled = true;
+        self.pending_interrupt = None;
     }
 }
 
@@ -82,13 +150,63 @@ impl Cpu {
         // data fetch
         system.read_u8(self.sp, false)
     }
+    /// IRQ lineを要因ごとにassert/deassertします(レベルトリガ)
+    pub fn write_irq_line(&mut self, source: IrqSource, is_asserted: bool) {
+        if is_asserted {
+            self.irq_sources |= source.bit();
+        } else {
+            self.irq_sources &= !source.bit();
+        }
+    }
+    /// どれかの要因がIRQ lineをassertしていればtrue
+    pub fn read_irq_line(&self) -> bool {
+        self.irq_sources != 0
+    }
+    /// NMI入力を更新します
+    /// 非アクティブからアクティブに変わったときだけNMIを受け付ける(エッジトリガ)
+    pub fn write_nmi_line(&mut self, is_active: bool) {
+        if is_active && !self.is_nmi_line_active {
+            self.is_nmi_pending = true;
+        }
+        self.is_nmi_line_active = is_active;
+    }
+    /// PPUのNMI出力とカセットのIRQ出力をCPUの割り込み入力に反映します
+    /// APUのFrame Counter, DMCのIRQもSystemから読み取る
+    pub fn update_interrupt_lines(&mut self, system: &System) {
+        // VBLANKフラグとNMI enableのAND, $2002を読んでフラグが下りると非アクティブになる
+        self.write_nmi_line(system.read_ppu_nmi_enable() && system.read_ppu_is_vblank());
+        let is_irq_asserted = system.cassette.is_irq_asserted();
+        let source = system.cassette.irq_source();
+        self.write_irq_line(
+            IrqSource::Mapper,
+            is_irq_asserted && source == IrqSource::Mapper,
+        );
+        self.write_irq_line(IrqSource::Fds, is_irq_asserted && source == IrqSource::Fds);
+        self.write_irq_line(IrqSource::FrameCounter, system.frame_counter.is_irq);
+        self.write_irq_line(IrqSource::Dmc, system.dmc.is_irq);
+    }
+    /// 割り込み要求をpollします
+    /// NMIを優先し、IRQは`is_interrupt_disabled`(I flag)が立っていたら受け付けない
+    pub fn poll_interrupt(&self, is_interrupt_disabled: bool) -> Option<Interrupt> {
+        if self.is_nmi_pending {
+            Some(Interrupt::NMI)
+        } else if self.read_irq_line() && !is_interrupt_disabled {
+            Some(Interrupt::IRQ)
+        } else {
+            None
+        }
+    }
     /// 割り込みを処理します
     pub fn interrupt(&mut self, system: &mut System, irq_type: Interrupt) {
-        let is_nested_interrupt = self.read_interrupt_flag();
-        // RESET, NMI以外は多重割り込みを許容しない
-        if is_nested_interrupt && (irq_type == Interrupt::IRQ) || (irq_type == Interrupt::BRK) {
+        // IRQだけはI flagでマスクできる
+        if irq_type == Interrupt::IRQ && self.read_interrupt_flag() {
             return;
         }
+        self.run_interrupt(system, irq_type);
+    }
+    /// 割り込みシーケンスを実行します
+    /// stepでpollした割り込みはI flagの判定が済んでいるので、直接こちらを呼ぶ
+    pub(crate) fn run_interrupt(&mut self, system: &mut System, irq_type: Interrupt) {
         // 割り込み種類別の処理
         match irq_type {
             Interrupt::NMI => {
@@ -101,6 +219,7 @@ impl Cpu {
             }
             Interrupt::RESET => {
                 self.write_interrupt_flag(true);
+                self.state = CpuState::Running;
             }
             Interrupt::IRQ => {
                 self.write_break_flag(false);
@@ -120,6 +239,14 @@ impl Cpu {
                 self.write_interrupt_flag(true);
             }
         }
+        // vectorを読む前にNMIが来ていたら、IRQ, BRKはNMIのvectorに飛ぶ(NMI hijacking)
+        let irq_type = match irq_type {
+            Interrupt::IRQ | Interrupt::BRK if self.is_nmi_pending => Interrupt::NMI,
+            _ => irq_type,
+        };
+        if irq_type == Interrupt::NMI {
+            self.is_nmi_pending = false;
+        }
         // Program Counterの書き換え
         let lower_addr = match irq_type {
             Interrupt::NMI => NMI_READ_LOWER,
@@ -138,4 +265,30 @@ impl Cpu {
         let upper = system.read_u8(upper_addr, false);
         self.pc = (lower as u16) | ((upper as u16) << 8);
     }
+    /// 命令のあとにDMAでCPUが止まる分を処理します
+    /// DMCはサンプルを1byte読むたびに4cycle, OAM DMAは513cycle(奇数cycleから始まると514cycle)止まる
+    /// `cyc` - 命令にかかったcycle数, DMCはこの間も進める
+    /// ret: 止まったcycle数
+    pub(crate) fn run_dma_stall(&mut self, system: &mut System, cyc: u16) -> u16 {
+        let mut stall_cyc = 0;
+        // 止まっている間もDMCは進む
+        let mut remain_cyc = cyc;
+        while remain_cyc > 0 {
+            let dmc_stall_cyc = system.step_apu();
+            stall_cyc += dmc_stall_cyc;
+            remain_cyc = remain_cyc - 1 + dmc_stall_cyc;
+        }
+        let (is_oam_dma_req, src_addr) = system.read_oam_dma();
+        if is_oam_dma_req {
+            // 256byteをbus経由で$2004に書く。CPUは止まっているので途中のOAMが見えることはない
+            for offset in 0..OAM_SIZE as u16 {
+                let data = system.read_u8(src_addr | offset, false);
+                system.write_u8(OAM_DMA_DST_ADDR, data, false);
+            }
+            let current_cycles = self.cycles + u64::from(cyc + stall_cyc);
+            let oam_dma_cyc = OAM_DMA_STALL_CYCLES + (current_cycles & 0x01) as u16;
+            stall_cyc += oam_dma_cyc + self.run_dma_stall(system, oam_dma_cyc);
+        }
+        stall_cyc
+    }
 }
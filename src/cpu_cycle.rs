@@ -0,0 +1,634 @@
+use super::cpu::*;
+use super::cpu_instruction::*;
+use super::interface::SystemBus;
+use super::ppu::OAM_SIZE;
+use super::system::System;
+
+/// 命令がオペランドのアドレスに対してどうアクセスするか
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum AccessKind {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+impl AccessKind {
+    fn from(opcode: Opcode) -> AccessKind {
+        match opcode {
+            Opcode::STA
+            | Opcode::STX
+            | Opcode::STY
+            | Opcode::SAX
+            | Opcode::TAS
+            | Opcode::AHX
+            | Opcode::SHX
+            | Opcode::SHY => AccessKind::Write,
+            Opcode::ASL
+            | Opcode::LSR
+            | Opcode::ROL
+            | Opcode::ROR
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::SLO
+            | Opcode::SRE
+            | Opcode::RLA
+            | Opcode::RRA
+            | Opcode::DCP
+            | Opcode::ISC => AccessKind::ReadModifyWrite,
+            _ => AccessKind::Read,
+        }
+    }
+}
+
+/// 1命令を実行している間の状態
+/// busアクセス1回が1cycleで、アクセスするたびにtickを呼ぶ
+struct CycleStep<'a, F> {
+    cpu: &'a mut Cpu,
+    system: &'a mut System,
+    tick: &'a mut F,
+    /// この命令で進んだcycle数
+    cyc: u16,
+    /// 最後のcycleの直前の状態でpollした割り込み
+    polled: Option<Interrupt>,
+    /// DMCがサンプルを読んだので、命令のあとにCPUを止めるcycle数
+    dma_stall_cyc: u16,
+}
+
+impl<'a, F> CycleStep<'a, F>
+where
+    F: FnMut(&mut System),
+{
+    /// 1cycle分PPU, APU, Mapperを進めます
+    fn end_cycle(&mut self) {
+        // 6502は最後のcycleの直前までの状態で割り込みを判定するので、tickする前に控えておく
+        self.polled = self.cpu.poll_interrupt(self.cpu.read_interrupt_flag());
+        self.cyc += 1;
+        (self.tick)(self.system);
+        self.dma_stall_cyc += self.system.step_apu();
+        self.cpu.update_interrupt_lines(self.system);
+    }
+    /// DMAでCPUが止まっている分のcycleを進めます
+    /// DMCは命令の途中でもサンプルを読むが、CPUを止めるのは命令のあとにまとめて行う
+    /// OAM DMAは1cycle(奇数cycleから始まるとさらに1cycle)待ってから、読み書きを256回交互に行う
+    fn run_dma_stall(&mut self) {
+        loop {
+            while self.dma_stall_cyc > 0 {
+                self.dma_stall_cyc -= 1;
+                self.end_cycle();
+            }
+            let (is_oam_dma_req, src_addr) = self.system.read_oam_dma();
+            if !is_oam_dma_req {
+                break;
+            }
+            let current_cycles = self.cpu.cycles + u64::from(self.cyc);
+            let wait_cyc =
+                OAM_DMA_STALL_CYCLES + (current_cycles & 0x01) as u16 - 2 * OAM_SIZE as u16;
+            for _ in 0..wait_cyc {
+                self.end_cycle();
+            }
+            for offset in 0..OAM_SIZE as u16 {
+                let data = self.read(src_addr | offset);
+                self.write(OAM_DMA_DST_ADDR, data);
+            }
+        }
+    }
+    /// 割り込みvectorを読んでPCを書き換えます
+    /// vectorを読む前にNMIが来ていたら、IRQ, BRKもNMIのvectorに飛ぶ(NMI hijacking)
+    fn jump_to_vector(&mut self, interrupt: Interrupt) {
+        let (lower_addr, upper_addr) = if interrupt == Interrupt::NMI || self.cpu.is_nmi_pending {
+            self.cpu.is_nmi_pending = false;
+            (NMI_READ_LOWER, NMI_READ_UPPER)
+        } else {
+            (IRQ_READ_LOWER, IRQ_READ_UPPER)
+        };
+        let lower = self.read(lower_addr);
+        let upper = self.read(upper_addr);
+        self.cpu.pc = u16::from(lower) | (u16::from(upper) << 8);
+    }
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.system.read_u8(addr, false);
+        self.end_cycle();
+        data
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.system.write_u8(addr, data, false);
+        self.end_cycle();
+    }
+    /// PCから1byteフェッチして、PCを一つ進めます
+    fn fetch(&mut self) -> u8 {
+        let data = self.read(self.cpu.pc);
+        self.cpu.pc = self.cpu.pc.wrapping_add(1);
+        data
+    }
+    /// 1byte命令でも次のbyteを読んでいる。PCは進めない
+    fn dummy_read_pc(&mut self) {
+        self.read(self.cpu.pc);
+    }
+    /// pullの前にSPの指す場所を一度読んでいる
+    fn dummy_read_stack(&mut self) {
+        self.read(self.cpu.sp);
+    }
+    fn push(&mut self, data: u8) {
+        self.write(self.cpu.sp, data);
+        self.cpu.sp = 0x0100 | (self.cpu.sp.wrapping_sub(1) & 0xff);
+    }
+    fn pull(&mut self) -> u8 {
+        self.cpu.sp = 0x0100 | (self.cpu.sp.wrapping_add(1) & 0xff);
+        self.read(self.cpu.sp)
+    }
+
+    /// base + indexを求めます
+    /// 下位byteだけ足したアドレスを一度読んでから上位byteを直すので、
+    /// ページをまたいだ場合とWrite, Read-Modify-Write命令はdummy readが入る
+    fn indexed_addr(&mut self, base: u16, index: u8, kind: AccessKind) -> u16 {
+        let addr = base.wrapping_add(u16::from(index));
+        let unfixed_addr = (base & 0xff00) | (addr & 0x00ff);
+        if kind != AccessKind::Read || unfixed_addr != addr {
+            self.read(unfixed_addr);
+        }
+        addr
+    }
+    /// オペランドのアドレスを求めます
+    /// Implied, Accumulator, Immediate, Relative, Indirectは命令側で処理する
+    fn operand_addr(&mut self, mode: AddressingMode, kind: AccessKind) -> u16 {
+        match mode {
+            AddressingMode::ZeroPage => u16::from(self.fetch()),
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let index = if mode == AddressingMode::ZeroPageX {
+                    self.cpu.x
+                } else {
+                    self.cpu.y
+                };
+                let base = self.fetch();
+                // indexを足す前のアドレスを読んでいる
+                self.read(u16::from(base));
+                u16::from(base.wrapping_add(index))
+            }
+            AddressingMode::Absolute => {
+                let lower = self.fetch();
+                let upper = self.fetch();
+                u16::from(lower) | (u16::from(upper) << 8)
+            }
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                let index = if mode == AddressingMode::AbsoluteX {
+                    self.cpu.x
+                } else {
+                    self.cpu.y
+                };
+                let lower = self.fetch();
+                let upper = self.fetch();
+                let base = u16::from(lower) | (u16::from(upper) << 8);
+                self.indexed_addr(base, index, kind)
+            }
+            AddressingMode::IndirectX => {
+                let base = self.fetch();
+                self.read(u16::from(base));
+                let ptr = base.wrapping_add(self.cpu.x);
+                let lower = self.read(u16::from(ptr));
+                let upper = self.read(u16::from(ptr.wrapping_add(1)));
+                u16::from(lower) | (u16::from(upper) << 8)
+            }
+            AddressingMode::IndirectY => {
+                let ptr = self.fetch();
+                let lower = self.read(u16::from(ptr));
+                let upper = self.read(u16::from(ptr.wrapping_add(1)));
+                let base = u16::from(lower) | (u16::from(upper) << 8);
+                self.indexed_addr(base, self.cpu.y, kind)
+            }
+            _ => unreachable!("operand_addr: {:?}", mode),
+        }
+    }
+
+    /// 割り込みシーケンス(7cycle)を実行します
+    fn interrupt(&mut self, interrupt: Interrupt) {
+        // opcode fetchの代わりに2回読み捨てる
+        self.dummy_read_pc();
+        self.dummy_read_pc();
+        self.push((self.cpu.pc >> 8) as u8);
+        self.push((self.cpu.pc & 0xff) as u8);
+        // B flagは立てずにpushする
+        self.push((self.cpu.p & !0x10) | 0x20);
+        self.cpu.write_interrupt_flag(true);
+        self.jump_to_vector(interrupt);
+    }
+
+    /// 1命令を実行します
+    fn instruction(&mut self) {
+        let inst_code = self.fetch();
+        let Instruction(opcode, mode) = Instruction::from(inst_code);
+
+        match opcode {
+            /* *************** jump/return ***************  */
+            Opcode::JMP => {
+                let lower = self.fetch();
+                let upper = self.fetch();
+                let addr = u16::from(lower) | (u16::from(upper) << 8);
+                self.cpu.pc = if mode == AddressingMode::Indirect {
+                    // 下位byteが0xffのときページをまたがないバグ
+                    let addr_next = (addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff);
+                    let dst_lower = self.read(addr);
+                    let dst_upper = self.read(addr_next);
+                    u16::from(dst_lower) | (u16::from(dst_upper) << 8)
+                } else {
+                    addr
+                };
+            }
+            Opcode::JSR => {
+                let lower = self.fetch();
+                self.dummy_read_stack();
+                // PCは上位byteのオペランドを指しているので、これが戻り先-1になる
+                self.push((self.cpu.pc >> 8) as u8);
+                self.push((self.cpu.pc & 0xff) as u8);
+                let upper = self.read(self.cpu.pc);
+                self.cpu.pc = u16::from(lower) | (u16::from(upper) << 8);
+            }
+            Opcode::RTI => {
+                self.dummy_read_pc();
+                self.dummy_read_stack();
+                let p = self.pull();
+                self.cpu.p = (p & !0x10) | 0x20;
+                let lower = self.pull();
+                let upper = self.pull();
+                self.cpu.pc = u16::from(lower) | (u16::from(upper) << 8);
+            }
+            Opcode::RTS => {
+                self.dummy_read_pc();
+                self.dummy_read_stack();
+                let lower = self.pull();
+                let upper = self.pull();
+                self.cpu.pc = u16::from(lower) | (u16::from(upper) << 8);
+                self.fetch();
+            }
+            Opcode::BRK => {
+                // BRKの次の1byteは読み飛ばす
+                self.fetch();
+                self.push((self.cpu.pc >> 8) as u8);
+                self.push((self.cpu.pc & 0xff) as u8);
+                self.push(self.cpu.p | 0x30);
+                self.cpu.write_interrupt_flag(true);
+                self.jump_to_vector(Interrupt::BRK);
+            }
+
+            /* *************** branch ***************  */
+            Opcode::BCC
+            | Opcode::BCS
+            | Opcode::BEQ
+            | Opcode::BNE
+            | Opcode::BMI
+            | Opcode::BPL
+            | Opcode::BVC
+            | Opcode::BVS => {
+                let offset = self.fetch();
+                let is_taken = match opcode {
+                    Opcode::BCC => !self.cpu.read_carry_flag(),
+                    Opcode::BCS => self.cpu.read_carry_flag(),
+                    Opcode::BEQ => self.cpu.read_zero_flag(),
+                    Opcode::BNE => !self.cpu.read_zero_flag(),
+                    Opcode::BMI => self.cpu.read_negative_flag(),
+                    Opcode::BPL => !self.cpu.read_negative_flag(),
+                    Opcode::BVC => !self.cpu.read_overflow_flag(),
+                    _ => self.cpu.read_overflow_flag(),
+                };
+                if !is_taken {
+                    return;
+                }
+                // ページをまたがずに分岐した場合は、3cycle目では割り込みをpollしない
+                let polled = self.polled;
+                self.dummy_read_pc();
+                let pc = self.cpu.pc;
+                let dst = pc.wrapping_add((offset as i8) as u16);
+                if (pc & 0xff00) == (dst & 0xff00) {
+                    self.polled = polled;
+                } else {
+                    self.read((pc & 0xff00) | (dst & 0x00ff));
+                }
+                self.cpu.pc = dst;
+            }
+
+            /* *************** push/pop ***************  */
+            Opcode::PHA => {
+                self.dummy_read_pc();
+                self.push(self.cpu.a);
+            }
+            Opcode::PHP => {
+                self.dummy_read_pc();
+                self.push(self.cpu.p | 0x30);
+            }
+            Opcode::PLA => {
+                self.dummy_read_pc();
+                self.dummy_read_stack();
+                let data = self.pull();
+                self.cpu.a = data;
+                self.update_nz(data);
+            }
+            Opcode::PLP => {
+                self.dummy_read_pc();
+                self.dummy_read_stack();
+                let p = self.pull();
+                self.cpu.p = (p & !0x10) | 0x20;
+            }
+
+            Opcode::JAM => {
+                // RESETされるまで止まる。PCはJAMを指したままにする
+                self.dummy_read_pc();
+                self.cpu.state = CpuState::Halted;
+                self.cpu.pc = self.cpu.pc.wrapping_sub(1);
+            }
+
+            /* *************** 1byte命令 ***************  */
+            _ if mode == AddressingMode::Implied => {
+                self.dummy_read_pc();
+                self.implied(opcode);
+            }
+            _ if mode == AddressingMode::Accumulator => {
+                self.dummy_read_pc();
+                let data = self.cpu.a;
+                self.cpu.a = self.modify(opcode, data);
+            }
+            _ if mode == AddressingMode::Immediate => {
+                let data = self.fetch();
+                self.load(opcode, data);
+            }
+
+            /* *************** メモリアクセス ***************  */
+            _ => {
+                let kind = AccessKind::from(opcode);
+                let addr = self.operand_addr(mode, kind);
+                match kind {
+                    AccessKind::Read => {
+                        let data = self.read(addr);
+                        self.load(opcode, data);
+                    }
+                    AccessKind::Write => {
+                        let (addr, data) = match opcode {
+                            Opcode::STA => (addr, self.cpu.a),
+                            Opcode::STX => (addr, self.cpu.x),
+                            Opcode::STY => (addr, self.cpu.y),
+                            Opcode::SAX => (addr, self.cpu.a & self.cpu.x),
+                            Opcode::TAS => {
+                                self.cpu.sp = u16::from(self.cpu.a & self.cpu.x) | 0x0100;
+                                unstable_store(addr, self.cpu.y, self.cpu.a & self.cpu.x)
+                            }
+                            Opcode::AHX => {
+                                unstable_store(addr, self.cpu.y, self.cpu.a & self.cpu.x)
+                            }
+                            Opcode::SHX => unstable_store(addr, self.cpu.y, self.cpu.x),
+                            _ => unstable_store(addr, self.cpu.x, self.cpu.y),
+                        };
+                        self.write(addr, data);
+                    }
+                    AccessKind::ReadModifyWrite => {
+                        // 読んだ値を一度そのまま書き戻してから、計算結果を書く
+                        let data = self.read(addr);
+                        self.write(addr, data);
+                        let result = self.modify(opcode, data);
+                        self.write(addr, result);
+                    }
+                }
+            }
+        }
+    }
+
+    fn update_nz(&mut self, data: u8) {
+        self.cpu.write_zero_flag(data == 0);
+        self.cpu.write_negative_flag((data & 0x80) == 0x80);
+    }
+    fn adc(&mut self, arg: u8) {
+        let a = self.cpu.a;
+        let tmp = u16::from(a) + u16::from(arg) + u16::from(self.cpu.read_carry_flag());
+        let result = (tmp & 0xff) as u8;
+        self.cpu.write_carry_flag(tmp > 0x00ff);
+        self.cpu
+            .write_overflow_flag(((a ^ result) & (arg ^ result) & 0x80) == 0x80);
+        self.update_nz(result);
+        self.cpu.a = result;
+    }
+    fn compare(&mut self, reg: u8, arg: u8) {
+        self.cpu.write_carry_flag(reg >= arg);
+        self.update_nz(reg.wrapping_sub(arg));
+    }
+
+    /// オペランドを読むだけの命令
+    fn load(&mut self, opcode: Opcode, arg: u8) {
+        match opcode {
+            Opcode::ADC => self.adc(arg),
+            // borrowはcarryの反転なので、反転した値を足せばよい
+            Opcode::SBC => self.adc(!arg),
+            Opcode::AND => {
+                self.cpu.a &= arg;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::EOR => {
+                self.cpu.a ^= arg;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::ORA => {
+                self.cpu.a |= arg;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::LDA => {
+                self.cpu.a = arg;
+                self.update_nz(arg);
+            }
+            Opcode::LDX => {
+                self.cpu.x = arg;
+                self.update_nz(arg);
+            }
+            Opcode::LDY => {
+                self.cpu.y = arg;
+                self.update_nz(arg);
+            }
+            Opcode::LAX => {
+                self.cpu.a = arg;
+                self.cpu.x = arg;
+                self.update_nz(arg);
+            }
+            Opcode::CMP => self.compare(self.cpu.a, arg),
+            Opcode::CPX => self.compare(self.cpu.x, arg),
+            Opcode::CPY => self.compare(self.cpu.y, arg),
+            Opcode::BIT => {
+                self.cpu.write_negative_flag((arg & 0x80) == 0x80);
+                self.cpu.write_overflow_flag((arg & 0x40) == 0x40);
+                self.cpu.write_zero_flag((self.cpu.a & arg) == 0x00);
+            }
+            Opcode::ALR => {
+                let src = self.cpu.a & arg;
+                self.cpu.write_carry_flag((src & 0x01) == 0x01);
+                self.cpu.a = src >> 1;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::ANC => {
+                self.cpu.a &= arg;
+                self.update_nz(self.cpu.a);
+                self.cpu.write_carry_flag((self.cpu.a & 0x80) == 0x80);
+            }
+            Opcode::ARR => {
+                let src = self.cpu.a & arg;
+                let result = (src >> 1)
+                    | if self.cpu.read_carry_flag() {
+                        0x80
+                    } else {
+                        0x00
+                    };
+                self.cpu.write_carry_flag((result & 0x40) == 0x40);
+                self.cpu
+                    .write_overflow_flag((((result >> 6) ^ (result >> 5)) & 0x01) == 0x01);
+                self.update_nz(result);
+                self.cpu.a = result;
+            }
+            Opcode::AXS => {
+                // X = (A & X) - #IMM, carryは見ない
+                let src = self.cpu.a & self.cpu.x;
+                self.compare(src, arg);
+                self.cpu.x = src.wrapping_sub(arg);
+            }
+            Opcode::XAA => {
+                self.cpu.a = (self.cpu.a | XAA_MAGIC) & self.cpu.x & arg;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::LXA => {
+                let result = (self.cpu.a | LXA_MAGIC) & arg;
+                self.cpu.a = result;
+                self.cpu.x = result;
+                self.update_nz(result);
+            }
+            Opcode::LAS => {
+                let result = arg & ((self.cpu.sp & 0xff) as u8);
+                self.cpu.a = result;
+                self.cpu.x = result;
+                self.cpu.sp = u16::from(result) | 0x0100;
+                self.update_nz(result);
+            }
+            // SKB, IGN, NOPは読むだけ
+            _ => {}
+        }
+    }
+
+    /// Read-Modify-Write命令の計算をします
+    /// ret: 書き戻す値
+    fn modify(&mut self, opcode: Opcode, arg: u8) -> u8 {
+        let carry = self.cpu.read_carry_flag();
+        let result = match opcode {
+            Opcode::ASL | Opcode::SLO => {
+                self.cpu.write_carry_flag((arg & 0x80) == 0x80);
+                arg << 1
+            }
+            Opcode::LSR | Opcode::SRE => {
+                self.cpu.write_carry_flag((arg & 0x01) == 0x01);
+                arg >> 1
+            }
+            Opcode::ROL | Opcode::RLA => {
+                self.cpu.write_carry_flag((arg & 0x80) == 0x80);
+                (arg << 1) | if carry { 0x01 } else { 0x00 }
+            }
+            Opcode::ROR | Opcode::RRA => {
+                self.cpu.write_carry_flag((arg & 0x01) == 0x01);
+                (arg >> 1) | if carry { 0x80 } else { 0x00 }
+            }
+            Opcode::INC | Opcode::ISC => arg.wrapping_add(1),
+            _ => arg.wrapping_sub(1),
+        };
+        self.update_nz(result);
+        // unofficialはもう一つの命令を続けて実行する
+        match opcode {
+            Opcode::SLO => self.load(Opcode::ORA, result),
+            Opcode::SRE => self.load(Opcode::EOR, result),
+            Opcode::RLA => self.load(Opcode::AND, result),
+            Opcode::RRA => self.load(Opcode::ADC, result),
+            Opcode::DCP => self.load(Opcode::CMP, result),
+            Opcode::ISC => self.load(Opcode::SBC, result),
+            _ => {}
+        }
+        result
+    }
+
+    /// Impliedの命令
+    fn implied(&mut self, opcode: Opcode) {
+        match opcode {
+            Opcode::INX => {
+                self.cpu.x = self.cpu.x.wrapping_add(1);
+                self.update_nz(self.cpu.x);
+            }
+            Opcode::INY => {
+                self.cpu.y = self.cpu.y.wrapping_add(1);
+                self.update_nz(self.cpu.y);
+            }
+            Opcode::DEX => {
+                self.cpu.x = self.cpu.x.wrapping_sub(1);
+                self.update_nz(self.cpu.x);
+            }
+            Opcode::DEY => {
+                self.cpu.y = self.cpu.y.wrapping_sub(1);
+                self.update_nz(self.cpu.y);
+            }
+            Opcode::TAX => {
+                self.cpu.x = self.cpu.a;
+                self.update_nz(self.cpu.x);
+            }
+            Opcode::TAY => {
+                self.cpu.y = self.cpu.a;
+                self.update_nz(self.cpu.y);
+            }
+            Opcode::TSX => {
+                self.cpu.x = (self.cpu.sp & 0xff) as u8;
+                self.update_nz(self.cpu.x);
+            }
+            Opcode::TXA => {
+                self.cpu.a = self.cpu.x;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::TXS => self.cpu.sp = u16::from(self.cpu.x) | 0x0100,
+            Opcode::TYA => {
+                self.cpu.a = self.cpu.y;
+                self.update_nz(self.cpu.a);
+            }
+            Opcode::SEC => self.cpu.write_carry_flag(true),
+            Opcode::SED => self.cpu.write_decimal_flag(true),
+            Opcode::SEI => self.cpu.write_interrupt_flag(true),
+            Opcode::CLC => self.cpu.write_carry_flag(false),
+            Opcode::CLD => self.cpu.write_decimal_flag(false),
+            Opcode::CLI => self.cpu.write_interrupt_flag(false),
+            Opcode::CLV => self.cpu.write_overflow_flag(false),
+            // NOP
+            _ => {}
+        }
+    }
+}
+
+impl Cpu {
+    /// 1命令をcycle単位で実行します(cycle-accurate core)
+    /// busアクセス(dummy read, Read-Modify-Writeの2回書きを含む)ごとに`tick`を1回呼ぶので、
+    /// `tick`ではPPU, APU, Mapperを1cycle分進めること
+    /// NMI, IRQはtickのたびにSystemから読み取り、命令の最後のcycleの直前の状態でpollして次のstepの最初に処理する
+    /// DMAでCPUが止まる間もtickを呼ぶ
+    /// ret: cycle数(DMAで止まった分を含む)
+    pub fn step_cycle_accurate<F>(&mut self, system: &mut System, mut tick: F) -> u16
+    where
+        F: FnMut(&mut System),
+    {
+        let mut step = CycleStep {
+            cpu: self,
+            system,
+            tick: &mut tick,
+            cyc: 0,
+            polled: None,
+            dma_stall_cyc: 0,
+        };
+        if step.cpu.state == CpuState::Halted {
+            // JAMで止まっている間は割り込みも受け付けず、address busは$ffffのまま
+            step.read(0xffff);
+            step.run_dma_stall();
+        } else if let Some(interrupt) = step.cpu.pending_interrupt.take() {
+            // 割り込みハンドラの最初の1命令は必ず実行する
+            step.interrupt(interrupt);
+            step.run_dma_stall();
+        } else {
+            step.instruction();
+            // DMAが終わるまで割り込みは処理しない
+            step.run_dma_stall();
+            step.cpu.pending_interrupt = step.polled;
+        }
+        step.cpu.cycles += u64::from(step.cyc);
+        step.cyc
+    }
+}
@@ -3,7 +3,8 @@ use super::interface::SystemBus;
 use super::system::System;
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
-enum Opcode {
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum Opcode {
     // binary op
     ADC,
     SBC,
@@ -87,12 +88,22 @@ enum Opcode {
     SRE,
     SKB,
     IGN,
+    // unofficial(unstable)
+    // https://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+    XAA,
+    LXA,
+    LAS,
+    TAS,
+    AHX,
+    SHX,
+    SHY,
+    JAM,
     // unofficial2
     //ADC, SBC, NOP,
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
-enum AddressingMode {
+pub(crate) enum AddressingMode {
     Implied,
     Accumulator,
     Immediate,
@@ -112,7 +123,7 @@ enum AddressingMode {
 struct Operand(u16, u8);
 
 #[derive(Copy, Clone, Debug)]
-struct Instruction(Opcode, AddressingMode);
+pub(crate) struct Instruction(pub(crate) Opcode, pub(crate) AddressingMode);
 
 impl Instruction {
     /// romのコードを命令に変換します
@@ -315,6 +326,7 @@ impl Instruction {
             /* *************** unofficial1 ***************  */
             0x4b => Instruction(Opcode::ALR, AddressingMode::Immediate),
             0x0b => Instruction(Opcode::ANC, AddressingMode::Immediate),
+            0x2b => Instruction(Opcode::ANC, AddressingMode::Immediate),
             0x6b => Instruction(Opcode::ARR, AddressingMode::Immediate),
             0xcb => Instruction(Opcode::AXS, AddressingMode::Immediate),
 
@@ -404,6 +416,29 @@ impl Instruction {
             0xd4 => Instruction(Opcode::IGN, AddressingMode::ZeroPageX),
             0xf4 => Instruction(Opcode::IGN, AddressingMode::ZeroPageX),
 
+            /* *************** unofficial(unstable) ***************  */
+            0x8b => Instruction(Opcode::XAA, AddressingMode::Immediate),
+            0xab => Instruction(Opcode::LXA, AddressingMode::Immediate),
+            0xbb => Instruction(Opcode::LAS, AddressingMode::AbsoluteY),
+            0x9b => Instruction(Opcode::TAS, AddressingMode::AbsoluteY),
+            0x93 => Instruction(Opcode::AHX, AddressingMode::IndirectY),
+            0x9f => Instruction(Opcode::AHX, AddressingMode::AbsoluteY),
+            0x9e => Instruction(Opcode::SHX, AddressingMode::AbsoluteY),
+            0x9c => Instruction(Opcode::SHY, AddressingMode::AbsoluteX),
+
+            0x02 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x12 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x22 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x32 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x42 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x52 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x62 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x72 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0x92 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0xb2 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0xd2 => Instruction(Opcode::JAM, AddressingMode::Implied),
+            0xf2 => Instruction(Opcode::JAM, AddressingMode::Implied),
+
             /* *************** unofficial2(既存の命令) ***************  */
             0xeb => Instruction(Opcode::SBC, AddressingMode::Immediate),
 
@@ -413,8 +448,6 @@ impl Instruction {
             0x7a => Instruction(Opcode::NOP, AddressingMode::Implied),
             0xda => Instruction(Opcode::NOP, AddressingMode::Implied),
             0xfa => Instruction(Opcode::NOP, AddressingMode::Implied),
-
-            _ => panic!("Invalid inst_code:{:08x}", inst_code),
         }
     }
 }
@@ -451,23 +484,23 @@ impl Cpu {
                 Operand(u16::from(self.fetch_u8(system).wrapping_add(self.y)), 3)
             }
             AddressingMode::AbsoluteX => {
-                let data = self.fetch_u16(system).wrapping_add(u16::from(self.x));
-                let additional_cyc =
-                    if (data & 0xff00u16) != (data.wrapping_add(u16::from(self.x)) & 0xff00u16) {
-                        1
-                    } else {
-                        0
-                    };
+                let base_data = self.fetch_u16(system);
+                let data = base_data.wrapping_add(u16::from(self.x));
+                let additional_cyc = if (base_data & 0xff00u16) != (data & 0xff00u16) {
+                    1
+                } else {
+                    0
+                };
                 Operand(data, 3 + additional_cyc)
             }
             AddressingMode::AbsoluteY => {
-                let data = self.fetch_u16(system).wrapping_add(u16::from(self.y));
-                let additional_cyc =
-                    if (data & 0xff00u16) != (data.wrapping_add(u16::from(self.y)) & 0xff00u16) {
-                        1
-                    } else {
-                        0
-                    };
+                let base_data = self.fetch_u16(system);
+                let data = base_data.wrapping_add(u16::from(self.y));
+                let additional_cyc = if (base_data & 0xff00u16) != (data & 0xff00u16) {
+                    1
+                } else {
+                    0
+                };
                 Operand(data, 3 + additional_cyc)
             }
             AddressingMode::Relative => {
@@ -530,6 +563,28 @@ impl Cpu {
             }
         }
     }
+    /// 書き込み命令用のfetch_operand
+    /// indexを足すときは、ページをまたがなくても上位byteを直す1cycleが必ず入る
+    fn fetch_operand_for_write(&mut self, system: &mut System, mode: AddressingMode) -> Operand {
+        let Operand(addr, cyc) = self.fetch_operand(system, mode);
+        match mode {
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => Operand(addr, 4),
+            AddressingMode::IndirectY => Operand(addr, 5),
+            _ => Operand(addr, cyc),
+        }
+    }
+    /// Read-Modify-Write命令用のfetch_args
+    /// 書き込み命令と同じく、indexを足すときは必ず1cycle入る
+    fn fetch_args_for_modify(&mut self, system: &mut System, mode: AddressingMode) -> (Operand, u8) {
+        match mode {
+            AddressingMode::Accumulator => self.fetch_args(system, mode),
+            _ => {
+                let Operand(addr, cyc) = self.fetch_operand_for_write(system, mode);
+                let data = system.read_u8(addr, false);
+                (Operand(addr, cyc), data)
+            }
+        }
+    }
     /// addressだけでなくデータまで一発で引きたい場合
     /// ret: (Operand(引いだ即値もしくはアドレス, clock数), データ)
     fn fetch_args(&mut self, system: &mut System, mode: AddressingMode) -> (Operand, u8) {
@@ -554,15 +609,56 @@ impl Cpu {
     }
 
     /// 命令を実行します
+    /// 前の命令の最後でpollした割り込みがあれば、命令の代わりに割り込みを処理する
+    /// ret: cycle数(DMAで止まった分を含む)
+    pub fn step(&mut self, system: &mut System) -> u16 {
+        let cyc = u16::from(self.step_instruction(system));
+        let cyc = cyc + self.run_dma_stall(system, cyc);
+        self.cycles += u64::from(cyc);
+        cyc
+    }
+    /// 命令か割り込みを1つ実行します
     /// ret: cycle数
-    /// http://obelisk.me.uk/6502/reference.html
-    pub fn step(&mut self, system: &mut System) -> u8 {
+    fn step_instruction(&mut self, system: &mut System) -> u8 {
+        // JAMで止まっている間は割り込みも受け付けず、時間だけ進める
+        if self.state == CpuState::Halted {
+            return 1;
+        }
+        // 前の命令を実行している間にPPUやMapperが出した割り込みを見る
+        self.update_interrupt_lines(system);
+        if let Some(interrupt) = self.poll_interrupt(self.is_poll_interrupt_disabled) {
+            self.run_interrupt(system, interrupt);
+            // I flagが立つので、ハンドラの最初の命令を実行するまでIRQは受け付けない
+            self.is_poll_interrupt_disabled = true;
+            return 7;
+        }
+
         // 命令がおいてあるところのaddress
         let inst_pc = self.pc;
         let inst_code = self.fetch_u8(system);
 
         let Instruction(opcode, mode) = Instruction::from(inst_code);
 
+        let is_interrupt_disabled = self.read_interrupt_flag();
+        let cyc = self.execute(system, inst_pc, opcode, mode);
+        // CLI, SEI, PLPはI flagを最後のcycleで書き換えるので、pollには変更前の値を使う
+        self.is_poll_interrupt_disabled = match opcode {
+            Opcode::CLI | Opcode::SEI | Opcode::PLP => is_interrupt_disabled,
+            _ => self.read_interrupt_flag(),
+        };
+        cyc
+    }
+
+    /// 1命令を実行します
+    /// ret: cycle数
+    /// http://obelisk.me.uk/6502/reference.html
+    fn execute(
+        &mut self,
+        system: &mut System,
+        inst_pc: u16,
+        opcode: Opcode,
+        mode: AddressingMode,
+    ) -> u8 {
         match opcode {
             /* *************** binary op ***************  */
             // 結果はaレジスタに格納するので、operandのアドレスは使わない
@@ -643,7 +739,7 @@ impl Cpu {
             /* *************** shift/rotate op ***************  */
             // aレジスタを操作する場合があるので注意
             Opcode::ASL => {
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 let result = arg.wrapping_shl(1);
 
@@ -665,7 +761,7 @@ impl Cpu {
                 }
             },
             Opcode::LSR => {
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 let result = arg.wrapping_shr(1);
 
@@ -687,7 +783,7 @@ impl Cpu {
                 }
             },
             Opcode::ROL => {
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 let result = arg.wrapping_shl(1) | (if self.read_carry_flag() { 0x01 } else { 0x00 } );
 
@@ -709,7 +805,7 @@ impl Cpu {
                 }
             },
             Opcode::ROR => {
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 let result = arg.wrapping_shr(1) | (if self.read_carry_flag() { 0x80 } else { 0x00 } );
 
@@ -733,7 +829,7 @@ impl Cpu {
             /* *************** inc/dec op ***************  */
             // accumulatorは使わない, x,yレジスタを使うバージョンはImplied
             Opcode::INC => {
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 let result = arg.wrapping_add(1);
 
@@ -768,7 +864,7 @@ impl Cpu {
                 2
             },
             Opcode::DEC => {
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 let result = arg.wrapping_sub(1);
 
@@ -840,19 +936,19 @@ impl Cpu {
                 1 + cyc
             },
             Opcode::STA => {
-                let Operand(addr, cyc) = self.fetch_operand(system, mode);
+                let Operand(addr, cyc) = self.fetch_operand_for_write(system, mode);
 
                 system.write_u8(addr, self.a, false);
                 1 + cyc
             },
             Opcode::STX => {
-                let Operand(addr, cyc) = self.fetch_operand(system, mode);
+                let Operand(addr, cyc) = self.fetch_operand_for_write(system, mode);
 
                 system.write_u8(addr, self.x, false);
                 1 + cyc
             },
             Opcode::STY => {
-                let Operand(addr, cyc) = self.fetch_operand(system, mode);
+                let Operand(addr, cyc) = self.fetch_operand_for_write(system, mode);
 
                 system.write_u8(addr, self.y, false);
                 1 + cyc
@@ -954,7 +1050,7 @@ impl Cpu {
                 6
             },
             Opcode::RTI => {
-                self.p = self.stack_pop(system);
+                self.p = (self.stack_pop(system) & !0x10) | 0x20;
                 let pc_lower = self.stack_pop(system);
                 let pc_upper = self.stack_pop(system);
                 self.pc = ((pc_upper as u16) << 8) | (pc_lower as u16);
@@ -968,7 +1064,7 @@ impl Cpu {
             },
 
             /* *************** branch ***************  */
-            // Relativeのみ
+            // Relativeのみ、分岐しなければ2cycle, 分岐したら+1, 分岐先がページをまたいだらさらに+1
             Opcode::BCC => {
                 debug_assert!(mode == AddressingMode::Relative);
                 let Operand(addr, cyc) = self.fetch_operand(system, mode);
@@ -976,7 +1072,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BCS => {
@@ -986,7 +1082,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BEQ => {
@@ -996,7 +1092,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BNE => {
@@ -1006,7 +1102,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BMI => {
@@ -1016,7 +1112,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BPL => {
@@ -1026,7 +1122,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BVC => {
@@ -1036,7 +1132,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
             Opcode::BVS => {
@@ -1046,7 +1142,7 @@ impl Cpu {
                     self.pc = addr;
                     1 + cyc + 1
                 } else {
-                    1 + cyc
+                    2
                 }
             },
 
@@ -1057,9 +1153,9 @@ impl Cpu {
                 3
             },
             Opcode::PHP => {
-                self.stack_push(system, self.p);
+                // B flagとbit5は常に1をpushする
+                self.stack_push(system, self.p | 0x30);
                 3
-
             },
             Opcode::PLA => {
                 let result = self.stack_pop(system);
@@ -1073,7 +1169,8 @@ impl Cpu {
                 4
             },
             Opcode::PLP => {
-                self.p = self.stack_pop(system);
+                // B flagはレジスタには存在しない
+                self.p = (self.stack_pop(system) & !0x10) | 0x20;
                 4
             },
 
@@ -1153,7 +1250,7 @@ impl Cpu {
                 self.write_negative_flag(is_negative);
                 self.write_zero_flag(is_zero);
                 self.write_overflow_flag(is_overflow);
-                2 + cyc
+                1 + cyc
             },
             Opcode::NOP => {
                 //なにもしない、Implied
@@ -1180,14 +1277,14 @@ impl Cpu {
                 1 + cyc
             },
             Opcode::ANC => {
-                // Immediateのみ、A=A & #IMM, Carryは前回状態のNegativeをコピー
+                // Immediateのみ、A=A & #IMM, Carryは結果のNegativeをコピー
                 debug_assert!(mode == AddressingMode::Immediate);
                 let (Operand(_, cyc), arg) = self.fetch_args(system, mode);
 
                 let result = self.a & arg;
                 let is_zero     = result == 0;
                 let is_negative = (result & 0x80) == 0x80;
-                let is_carry    = self.read_negative_flag();
+                let is_carry    = is_negative;
 
                 self.write_zero_flag(is_zero);
                 self.write_negative_flag(is_negative);
@@ -1222,9 +1319,10 @@ impl Cpu {
                 debug_assert!(mode == AddressingMode::Immediate);
                 let (Operand(_, cyc), arg) = self.fetch_args(system, mode);
 
-                let src = self.a & arg;
+                let src = self.a & self.x;
 
-                let (result, is_carry) = self.a.overflowing_sub(src);
+                let result   = src.wrapping_sub(arg);
+                let is_carry = src >= arg;
 
                 let is_zero     = result == 0;
                 let is_negative = (result & 0x80) == 0x80;
@@ -1259,7 +1357,7 @@ impl Cpu {
             },
             Opcode::DCP => {
                 // DEC->CMPっぽい
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 // DEC
                 let dec_result = arg.wrapping_sub(1);
@@ -1279,7 +1377,7 @@ impl Cpu {
             },
             Opcode::ISC => {
                 // INC->SBC
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 // INC
                 let inc_result = arg.wrapping_add(1);
@@ -1299,11 +1397,11 @@ impl Cpu {
                 self.write_negative_flag(is_negative);
                 self.write_overflow_flag(is_overflow);
                 self.a = result;
-                1 + cyc
+                3 + cyc
             },
             Opcode::RLA => {
                 // ROL -> AND
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 // ROL
                 let result_rol = arg.wrapping_shl(1) | (if self.read_carry_flag() { 0x01 } else { 0x00 } );
@@ -1328,7 +1426,7 @@ impl Cpu {
             },
             Opcode::RRA => {
                 // ROR -> ADC
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 // ROR
                 let result_ror = arg.wrapping_shr(1) | (if self.read_carry_flag() { 0x80 } else { 0x00 } );
@@ -1357,7 +1455,7 @@ impl Cpu {
             },
             Opcode::SLO => {
                 // ASL -> ORA
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 // ASL
                 let result_asl = arg.wrapping_shl(1);
@@ -1381,7 +1479,7 @@ impl Cpu {
             },
             Opcode::SRE => {
                 // LSR -> EOR
-                let (Operand(addr, cyc), arg) = self.fetch_args(system, mode);
+                let (Operand(addr, cyc), arg) = self.fetch_args_for_modify(system, mode);
 
                 // LSR
                 let result_lsr = arg.wrapping_shr(1);
@@ -1416,6 +1514,98 @@ impl Cpu {
 
                 1 + cyc
             },
+            /* *************** unofficial(unstable) ***************  */
+            Opcode::XAA => {
+                // Immediateのみ、A = (A | magic) & X & #IMM
+                debug_assert!(mode == AddressingMode::Immediate);
+                let (Operand(_, cyc), arg) = self.fetch_args(system, mode);
+
+                let result = (self.a | XAA_MAGIC) & self.x & arg;
+
+                let is_zero     = result == 0;
+                let is_negative = (result & 0x80) == 0x80;
+
+                self.write_zero_flag(is_zero);
+                self.write_negative_flag(is_negative);
+                self.a = result;
+                1 + cyc
+            },
+            Opcode::LXA => {
+                // Immediateのみ、A = X = (A | magic) & #IMM
+                debug_assert!(mode == AddressingMode::Immediate);
+                let (Operand(_, cyc), arg) = self.fetch_args(system, mode);
+
+                let result = (self.a | LXA_MAGIC) & arg;
+
+                let is_zero     = result == 0;
+                let is_negative = (result & 0x80) == 0x80;
+
+                self.write_zero_flag(is_zero);
+                self.write_negative_flag(is_negative);
+                self.a = result;
+                self.x = result;
+                1 + cyc
+            },
+            Opcode::LAS => {
+                // AbsoluteYのみ、A = X = SP = memory & SP
+                let (Operand(_, cyc), arg) = self.fetch_args(system, mode);
+
+                let result = arg & ((self.sp & 0xff) as u8);
+
+                let is_zero     = result == 0;
+                let is_negative = (result & 0x80) == 0x80;
+
+                self.write_zero_flag(is_zero);
+                self.write_negative_flag(is_negative);
+                self.a = result;
+                self.x = result;
+                self.sp = u16::from(result) | 0x0100u16;
+                1 + cyc
+            },
+            Opcode::TAS | Opcode::AHX | Opcode::SHX | Opcode::SHY => {
+                // memory = reg & (addressの上位byte + 1), flag操作はなし
+                let Operand(addr, _cyc) = self.fetch_operand(system, mode);
+
+                let (index, data) = match opcode {
+                    Opcode::TAS => {
+                        // SP = A & Xしてから書き込む
+                        self.sp = u16::from(self.a & self.x) | 0x0100u16;
+                        (self.y, self.a & self.x)
+                    },
+                    Opcode::AHX => (self.y, self.a & self.x),
+                    Opcode::SHX => (self.y, self.x),
+                    _           => (self.x, self.y),
+                };
+                let (dst_addr, result) = unstable_store(addr, index, data);
+
+                system.write_u8(dst_addr, result, false);
+                if mode == AddressingMode::IndirectY { 6 } else { 5 }
+            },
+            Opcode::JAM => {
+                // Impliedのみ、RESETされるまでCPUが止まる。PCはJAMを指したままにする
+                self.state = CpuState::Halted;
+                self.pc = inst_pc;
+                2
+            },
         }
     }
 }
+
+/// XAAのmagic constant, CPUの個体差や温度で変わるので一般的な値を使う
+pub(crate) const XAA_MAGIC: u8 = 0xee;
+/// LXAのmagic constant, NESの2A03では0xffとして扱われることが多い
+pub(crate) const LXA_MAGIC: u8 = 0xff;
+
+/// TAS, AHX, SHX, SHYの書き込み先と書き込む値を求めます
+/// 値は(index加算前のアドレスの上位byte + 1)とのANDになり、
+/// index加算でページをまたいだ場合は書き込み先の上位byteが書き込む値に置き換わる
+/// ret: (書き込み先, 書き込む値)
+pub(crate) fn unstable_store(addr: u16, index: u8, data: u8) -> (u16, u8) {
+    let base_addr = addr.wrapping_sub(u16::from(index));
+    let result = data & ((base_addr >> 8) as u8).wrapping_add(1);
+    if (base_addr & 0xff00) != (addr & 0xff00) {
+        ((u16::from(result) << 8) | (addr & 0x00ff), result)
+    } else {
+        (addr, result)
+    }
+}
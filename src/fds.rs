@@ -0,0 +1,216 @@
+use super::cassette_header::*;
+use super::mapper::*;
+
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+/// .fdsファイル先頭のheader
+pub const FDS_HEADER_SIZE: usize = 0x10;
+/// .fdsの1面分のサイズ, gapとCRCは含まれない
+pub const FDS_DISK_SIDE_SIZE: usize = 65500;
+/// .qdの1面分のサイズ, gapは含まれないがCRCは含まれる
+pub const QD_DISK_SIDE_SIZE: usize = 0x10000;
+/// 0xe000 - 0xffffに置かれるBIOS(disksys.rom)
+pub const FDS_BIOS_SIZE: usize = 0x2000;
+/// 0x6000 - 0xdfffのRAM Adapter上のRAM
+pub const FDS_PRG_RAM_SIZE: usize = 0x8000;
+/// FDSとして扱うMapper番号
+pub const FDS_MAPPER_NUMBER: u16 = 20;
+
+/// 面の先頭に置かれる28300bit分のgap
+const LEAD_IN_GAP_BYTES: usize = 28300 / 8;
+/// blockの後ろに置かれる976bit分のgap
+const BLOCK_GAP_BYTES: usize = 976 / 8;
+/// block開始を示すmark
+const BLOCK_START_MARK: u8 = 0x80;
+/// .fdsはCRCを持たないので、適当な値を詰めておく(BIOSはCRCを検査しない)
+const DUMMY_CRC: [u8; 2] = [0x4d, 0x62];
+/// 末尾に新しいfileを書き足せるように、面の長さはこれ以上にしておく
+const DISK_STREAM_MIN_SIZE: usize = FDS_DISK_SIDE_SIZE + LEAD_IN_GAP_BYTES * 2;
+
+/// Famicom Disk Systemのdisk
+/// 各面はheadが読み書きするbyte列(gap, block開始mark, CRCを含む)に展開して持つ
+/// 書き込みはdiffとして別に持ち、元のimageは書き換えない
+/// https://wiki.nesdev.com/w/index.php/FDS_disk_format
+#[derive(Clone, Default)]
+pub struct FdsDisk {
+    sides: Vec<Rc<[u8]>>,
+    /// (面, 位置) -> 書き込まれた値
+    diff: BTreeMap<(usize, usize), u8>,
+    /// diffが書き換えられたらtrue, 保存先に書き出したらfalseに戻す
+    pub is_dirty: bool,
+}
+
+impl FdsDisk {
+    /// .fds(header有無どちらも)/.qdファイルを解析します
+    pub fn parse(image: &[u8]) -> Result<FdsDisk, RomLoadError> {
+        let (body, side_size, is_qd) =
+            if image.len() >= 4 && image[0..4] == [0x46, 0x44, 0x53, 0x1a] {
+                // "FDS" + character break, byte 4が面数
+                let body = &image[FDS_HEADER_SIZE.min(image.len())..];
+                let num_of_side = usize::from(image.get(4).copied().unwrap_or(0));
+                if num_of_side == 0 || body.len() < num_of_side * FDS_DISK_SIDE_SIZE {
+                    return Err(RomLoadError::BadFdsImage(image.len()));
+                }
+                (
+                    &body[..num_of_side * FDS_DISK_SIDE_SIZE],
+                    FDS_DISK_SIDE_SIZE,
+                    false,
+                )
+            } else if !image.is_empty() && image.len().is_multiple_of(FDS_DISK_SIDE_SIZE) {
+                (image, FDS_DISK_SIDE_SIZE, false)
+            } else if !image.is_empty() && image.len().is_multiple_of(QD_DISK_SIDE_SIZE) {
+                (image, QD_DISK_SIDE_SIZE, true)
+            } else {
+                return Err(RomLoadError::BadFdsImage(image.len()));
+            };
+        let sides = body
+            .chunks(side_size)
+            .map(|side| Rc::from(Self::build_side_stream(side, is_qd)))
+            .collect();
+        Ok(FdsDisk {
+            sides,
+            diff: BTreeMap::new(),
+            is_dirty: false,
+        })
+    }
+    /// 1面分のblock列にgap, block開始mark, CRCを挿入します
+    /// 不正なblock typeが出てきたらそこで打ち切る
+    fn build_side_stream(side: &[u8], is_qd: bool) -> Vec<u8> {
+        let crc_bytes = if is_qd { 2 } else { 0 };
+        let mut stream = Vec::with_capacity(DISK_STREAM_MIN_SIZE);
+        stream.resize(LEAD_IN_GAP_BYTES, 0);
+        let mut index = 0;
+        while index < side.len() {
+            let block_len = match side[index] {
+                1 => 56, // disk info
+                2 => 2,  // file amount
+                3 => 16, // file header
+                4 => {
+                    // file data, 直前のfile headerにsizeが書かれている
+                    if index < 3 + crc_bytes {
+                        break;
+                    }
+                    let size_index = index - 3 - crc_bytes;
+                    1 + usize::from(side[size_index]) + (usize::from(side[size_index + 1]) << 8)
+                }
+                _ => break,
+            };
+            let end = index + block_len;
+            if end > side.len() {
+                break;
+            }
+            stream.push(BLOCK_START_MARK);
+            stream.extend_from_slice(&side[index..end]);
+            if is_qd {
+                stream.extend_from_slice(&side[end..(end + crc_bytes).min(side.len())]);
+            } else {
+                stream.extend_from_slice(&DUMMY_CRC);
+            }
+            stream.resize(stream.len() + BLOCK_GAP_BYTES, 0);
+            index = end + crc_bytes;
+        }
+        if stream.len() < DISK_STREAM_MIN_SIZE {
+            stream.resize(DISK_STREAM_MIN_SIZE, 0);
+        }
+        stream
+    }
+    /// 面の数
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+    /// headが読み書きする1面分の長さ
+    pub fn side_len(&self, side: usize) -> usize {
+        self.sides.get(side).map_or(0, |s| s.len())
+    }
+    /// 書き込み済の内容を反映して読み出します
+    pub fn read(&self, side: usize, pos: usize) -> u8 {
+        match self.diff.get(&(side, pos)) {
+            Some(data) => *data,
+            None => self
+                .sides
+                .get(side)
+                .and_then(|s| s.get(pos))
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+    /// diffに書き込みます。元のimageと同じ値に戻った場合はdiffから取り除く
+    pub fn write(&mut self, side: usize, pos: usize, data: u8) {
+        let original = match self.sides.get(side).and_then(|s| s.get(pos)) {
+            Some(original) => *original,
+            None => return,
+        };
+        let prev = self.read(side, pos);
+        if original == data {
+            self.diff.remove(&(side, pos));
+        } else {
+            self.diff.insert((side, pos), data);
+        }
+        if prev != data {
+            self.is_dirty = true;
+        }
+    }
+    /// 元のimageから書き換わっているbyte数
+    pub fn diff_len(&self) -> usize {
+        self.diff.len()
+    }
+    /// diffを捨ててimageの状態に戻します
+    pub fn clear_diff(&mut self) {
+        if !self.diff.is_empty() {
+            self.diff.clear();
+            self.is_dirty = true;
+        }
+    }
+    /// diffをファイルに保存できる形式で書き出します
+    /// 1entryごとに 面(1byte), 位置(4byte little endian), 値(1byte)
+    pub fn serialize_diff(&self) -> Vec<u8> {
+        let mut dst = Vec::with_capacity(self.diff.len() * 6);
+        for ((side, pos), data) in self.diff.iter() {
+            dst.push(*side as u8);
+            dst.extend_from_slice(&(*pos as u32).to_le_bytes());
+            dst.push(*data);
+        }
+        dst
+    }
+    /// `serialize_diff`で書き出した内容を復元します。壊れたentryがあればfalse
+    pub fn restore_diff(&mut self, src: &[u8]) -> bool {
+        self.diff.clear();
+        let mut is_valid = src.len().is_multiple_of(6);
+        for entry in src.chunks_exact(6) {
+            let side = usize::from(entry[0]);
+            let pos = u32::from_le_bytes([entry[1], entry[2], entry[3], entry[4]]) as usize;
+            if pos < self.side_len(side) {
+                self.write(side, pos, entry[5]);
+            } else {
+                is_valid = false;
+            }
+        }
+        self.is_dirty = false;
+        is_valid
+    }
+    /// diffを書き出します
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.diff.len() as u32);
+        for ((side, pos), data) in self.diff.iter() {
+            writer.write_u8(*side as u8);
+            writer.write_u32(*pos as u32);
+            writer.write_u8(*data);
+        }
+    }
+    /// diffを復元します
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.diff.clear();
+        let len = reader.read_u32() as usize;
+        // 壊れたデータで延々と読まないように、面の容量を上限にする
+        let max_len = self.sides.iter().map(|s| s.len()).sum();
+        for _ in 0..core::cmp::min(len, max_len) {
+            let side = usize::from(reader.read_u8());
+            let pos = reader.read_u32() as usize;
+            let data = reader.read_u8();
+            self.write(side, pos, data);
+        }
+        self.is_dirty = true;
+    }
+}
@@ -1,17 +1,40 @@
 #![crate_type = "lib"]
 #![crate_name = "rust_nes_emulator"]
 #![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 #[macro_use]
 pub mod interface;
 
 pub mod apu;
 pub mod cassette;
+pub mod cassette_header;
+pub mod cassette_unif;
 pub mod cpu;
+#[cfg(feature = "cycle-accurate")]
+pub mod cpu_cycle;
 pub mod cpu_instruction;
 pub mod cpu_register;
+#[cfg(feature = "alloc")]
+pub mod fds;
+pub mod mapper;
+pub mod mapper_discrete;
+#[cfg(feature = "alloc")]
+pub mod mapper_fds;
+pub mod mapper_fme7;
+pub mod mapper_mmc1;
+pub mod mapper_mmc2;
+pub mod mapper_mmc3;
+pub mod mapper_mmc5;
+pub mod mapper_namco163;
+pub mod mapper_nrom;
+pub mod mapper_vrc;
+pub mod mapper_vrc6;
 pub mod pad;
 pub mod ppu;
 pub mod prelude;
+pub mod rom_db;
 pub mod system;
 pub mod system_apu_reg;
 pub mod system_ppu_reg;
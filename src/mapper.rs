@@ -0,0 +1,407 @@
+use super::cassette::*;
+use super::mapper_discrete::*;
+#[cfg(feature = "alloc")]
+use super::mapper_fds::*;
+use super::mapper_fme7::*;
+use super::mapper_mmc1::*;
+use super::mapper_mmc2::*;
+use super::mapper_mmc3::*;
+use super::mapper_mmc5::*;
+use super::mapper_namco163::*;
+use super::mapper_nrom::*;
+use super::mapper_vrc::*;
+use super::mapper_vrc6::*;
+
+/// save/load stateで書き出すカーソル
+/// バッファが足りない場合は書き込みをやめてoverflowを記録する
+pub struct StateWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    is_overflow: bool,
+}
+
+impl<'a> StateWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> StateWriter<'a> {
+        StateWriter {
+            buf,
+            pos: 0,
+            is_overflow: false,
+        }
+    }
+    pub fn write_bytes(&mut self, src: &[u8]) {
+        let end = self.pos + src.len();
+        if self.is_overflow || end > self.buf.len() {
+            self.is_overflow = true;
+            return;
+        }
+        self.buf[self.pos..end].copy_from_slice(src);
+        self.pos = end;
+    }
+    pub fn write_u8(&mut self, data: u8) {
+        self.write_bytes(&[data]);
+    }
+    pub fn write_u16(&mut self, data: u16) {
+        self.write_bytes(&data.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, data: u32) {
+        self.write_bytes(&data.to_le_bytes());
+    }
+    pub fn write_bool(&mut self, data: bool) {
+        self.write_u8(if data { 1 } else { 0 });
+    }
+    pub fn write_mirror(&mut self, data: Option<NameTableMirror>) {
+        self.write_u8(match data {
+            None => 0,
+            Some(NameTableMirror::Unknown) => 1,
+            Some(NameTableMirror::Horizontal) => 2,
+            Some(NameTableMirror::Vertical) => 3,
+            Some(NameTableMirror::SingleScreenLower) => 4,
+            Some(NameTableMirror::SingleScreenUpper) => 5,
+            Some(NameTableMirror::FourScreen) => 6,
+        });
+    }
+    /// 書き込んだbyte数を返します。バッファが足りなかった場合はNone
+    pub fn finish(self) -> Option<usize> {
+        if self.is_overflow {
+            None
+        } else {
+            Some(self.pos)
+        }
+    }
+}
+
+/// save/load stateで読み出すカーソル
+/// データが足りない場合は0を返してunderflowを記録する
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    is_underflow: bool,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            buf,
+            pos: 0,
+            is_underflow: false,
+        }
+    }
+    pub fn read_bytes(&mut self, dst: &mut [u8]) {
+        let end = self.pos + dst.len();
+        if self.is_underflow || end > self.buf.len() {
+            self.is_underflow = true;
+            for d in dst.iter_mut() {
+                *d = 0;
+            }
+            return;
+        }
+        dst.copy_from_slice(&self.buf[self.pos..end]);
+        self.pos = end;
+    }
+    pub fn read_u8(&mut self) -> u8 {
+        let mut dst = [0u8; 1];
+        self.read_bytes(&mut dst);
+        dst[0]
+    }
+    pub fn read_u16(&mut self) -> u16 {
+        let mut dst = [0u8; 2];
+        self.read_bytes(&mut dst);
+        u16::from_le_bytes(dst)
+    }
+    pub fn read_u32(&mut self) -> u32 {
+        let mut dst = [0u8; 4];
+        self.read_bytes(&mut dst);
+        u32::from_le_bytes(dst)
+    }
+    pub fn read_bool(&mut self) -> bool {
+        self.read_u8() != 0
+    }
+    pub fn read_mirror(&mut self) -> Option<NameTableMirror> {
+        match self.read_u8() {
+            1 => Some(NameTableMirror::Unknown),
+            2 => Some(NameTableMirror::Horizontal),
+            3 => Some(NameTableMirror::Vertical),
+            4 => Some(NameTableMirror::SingleScreenLower),
+            5 => Some(NameTableMirror::SingleScreenUpper),
+            6 => Some(NameTableMirror::FourScreen),
+            _ => None,
+        }
+    }
+    /// 読み込んだbyte数を返します。データが足りなかった場合はNone
+    pub fn finish(self) -> Option<usize> {
+        if self.is_underflow {
+            None
+        } else {
+            Some(self.pos)
+        }
+    }
+}
+
+/// NameTable 1KB slotごとの参照先
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NameTableSource {
+    /// 本体のVRAM(CIRAM), 0: A, 1: B
+    Ciram(usize),
+    /// カセット上のVRAM, 1KB単位のindex
+    CartRam(usize),
+    /// Mapperが読み書きに応答する(MMC5のfill-modeやExRAMなど)
+    Mapper,
+}
+
+/// PPUがpattern tableをfetchしている対象
+/// MMC5のようにBGとSpriteで別のCHR bankを使うMapperが参照する
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PpuFetchPhase {
+    /// 描画していない(CPUからの$2007アクセスのみ)
+    Idle,
+    /// Spriteのpatternをfetchしている
+    Sprite,
+    /// BGのnametable, attribute, patternをfetchしている
+    Background,
+}
+
+/// カセット上のMapper(基板)が実装する機能
+/// ROM/RAMの実体はCassetteMemoryが持ち、Mapperはbank切り替えなどのレジスタだけを持つ
+pub trait Mapper {
+    /// CPU空間 0x6000 - 0xffffの読み出し
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, is_nondestructive: bool) -> u8;
+    /// CPU空間 0x6000 - 0xffffの書き込み
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool);
+    /// CPU空間 0x4020 - 0x5fffの読み出し
+    /// MMC5やFDSのようにこの領域にレジスタを持つMapperだけが実装し、Noneの場合はopen busになる
+    fn read_expansion_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        _addr: u16,
+        _is_nondestructive: bool,
+    ) -> Option<u8> {
+        None
+    }
+    /// CPU空間 0x4020 - 0x5fffの書き込み、デフォルトでは無視する
+    fn write_expansion_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        _addr: u16,
+        _data: u8,
+        _is_nondestructive: bool,
+    ) {
+    }
+    /// PPU空間 0x0000 - 0x1fffの読み出し
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8;
+    /// PPU空間 0x0000 - 0x1fffの書き込み
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8);
+    /// Mapperが制御しているNameTable Mirrorを返します
+    /// Noneの場合はiNES headerの設定に従う
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        None
+    }
+    /// NameTableの`slot`番目(0x2000 + 0x400 * slot)の参照先を返します
+    /// Noneの場合はnametable_mirrorの設定に従う
+    fn nametable_source(&self, _slot: usize) -> Option<NameTableSource> {
+        None
+    }
+    /// NameTableSource::Mapperを返したslotの読み出し
+    fn read_nametable_u8(&mut self, _mem: &mut CassetteMemory, _slot: usize, _offset: usize) -> u8 {
+        0
+    }
+    /// NameTableSource::Mapperを返したslotの書き込み
+    fn write_nametable_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        _slot: usize,
+        _offset: usize,
+        _data: u8,
+    ) {
+    }
+    /// PPUがNameTable(0x2000 - 0x2fff)を読んだ結果を差し替えます
+    /// MMC5の拡張属性や画面分割のようにfetchを横取りするMapperだけが実装する
+    fn filter_nametable_read(&mut self, _mem: &mut CassetteMemory, _addr: u16, data: u8) -> u8 {
+        data
+    }
+    /// CPUがPPUレジスタ(0x2000 - 0x2007)に書き込んだことを通知します
+    /// MMC5は$2000/$2001を監視している
+    fn notify_ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+    /// PPUのfetch対象が切り替わったことを通知します
+    fn notify_ppu_fetch_phase(&mut self, _phase: PpuFetchPhase) {}
+    /// IRQ出力がアクティブならtrue
+    fn is_irq_asserted(&self) -> bool {
+        false
+    }
+    /// PPUが1line描画するごとに呼ばれます(PPU A12の立ち上がり相当)
+    fn notify_scanline(&mut self) {}
+    /// CPUがcpu_cyc分進んだことを通知します(M2 clock相当)
+    /// CPU cycleで動くIRQ counterや拡張音源、FDSのdisk driveはここで進める
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, _cpu_cyc: usize) {}
+    /// 拡張音源の現在の出力、APUの出力と同じスケールでmixされる
+    fn expansion_audio_output(&self) -> f32 {
+        0.0
+    }
+    /// レジスタの状態を書き出します
+    fn save_state(&self, writer: &mut StateWriter);
+    /// レジスタの状態を復元します
+    fn load_state(&mut self, reader: &mut StateReader);
+}
+
+/// iNESのMapper番号から選ばれる基板の実装
+/// no_stdでもClone(snapshot)できるようにenumで持ち、traitへdispatchする
+/// https://wiki.nesdev.com/w/index.php/List_of_mappers
+#[derive(Clone)]
+pub enum MapperBoard {
+    /// Mapper0: no mapper
+    Nrom(Nrom),
+    /// Mapper1: MMC1
+    Mmc1(Mmc1),
+    /// Mapper9, 10: MMC2/MMC4
+    Mmc2(Mmc2),
+    /// Mapper4: MMC3
+    Mmc3(Mmc3),
+    /// Mapper5: MMC5
+    Mmc5(Mmc5),
+    /// Mapper2, 3, 7, 11, 34, 66: latchだけの基板
+    Discrete(Discrete),
+    /// Mapper19: Namco 163
+    Namco163(Namco163),
+    /// Mapper21, 22, 23, 25: VRC2/VRC4
+    Vrc4(Vrc4),
+    /// Mapper24, 26: VRC6
+    Vrc6(Vrc6),
+    /// Mapper69: Sunsoft FME-7/5B
+    Fme7(Fme7),
+    /// Famicom Disk System, iNESではなく.fds/.qdから読み込む
+    #[cfg(feature = "alloc")]
+    Fds(Fds),
+}
+
+impl Default for MapperBoard {
+    fn default() -> Self {
+        MapperBoard::Nrom(Nrom::default())
+    }
+}
+
+impl MapperBoard {
+    /// iNESのMapper番号から基板を選択します。未対応の場合はNone
+    /// `submapper_number` - NES 2.0のsubmapper, 配線違いの基板を区別するのに使う
+    pub fn from_mapper_number(mapper_number: u16, submapper_number: u8) -> Option<MapperBoard> {
+        match mapper_number {
+            0 => Some(MapperBoard::Nrom(Nrom::default())),
+            1 => Some(MapperBoard::Mmc1(Mmc1::default())),
+            2 => Some(MapperBoard::Discrete(Discrete::new(
+                DiscreteBoard::Uxrom,
+                submapper_number,
+            ))),
+            3 => Some(MapperBoard::Discrete(Discrete::new(
+                DiscreteBoard::Cnrom,
+                submapper_number,
+            ))),
+            4 => Some(MapperBoard::Mmc3(Mmc3::default())),
+            5 => Some(MapperBoard::Mmc5(Mmc5::default())),
+            7 => Some(MapperBoard::Discrete(Discrete::new(
+                DiscreteBoard::Axrom,
+                submapper_number,
+            ))),
+            9 => Some(MapperBoard::Mmc2(Mmc2::new(false))),
+            10 => Some(MapperBoard::Mmc2(Mmc2::new(true))),
+            11 => Some(MapperBoard::Discrete(Discrete::new(
+                DiscreteBoard::ColorDreams,
+                submapper_number,
+            ))),
+            19 => Some(MapperBoard::Namco163(Namco163::default())),
+            21 => Some(MapperBoard::Vrc4(Vrc4::new(
+                VrcBoard::Vrc4ac,
+                submapper_number,
+            ))),
+            22 => Some(MapperBoard::Vrc4(Vrc4::new(
+                VrcBoard::Vrc2a,
+                submapper_number,
+            ))),
+            23 => Some(MapperBoard::Vrc4(Vrc4::new(
+                VrcBoard::Vrc2bVrc4ef,
+                submapper_number,
+            ))),
+            24 => Some(MapperBoard::Vrc6(Vrc6::new(false))),
+            25 => Some(MapperBoard::Vrc4(Vrc4::new(
+                VrcBoard::Vrc2cVrc4bd,
+                submapper_number,
+            ))),
+            26 => Some(MapperBoard::Vrc6(Vrc6::new(true))),
+            34 => Some(MapperBoard::Discrete(Discrete::new(
+                DiscreteBoard::Bnrom,
+                submapper_number,
+            ))),
+            66 => Some(MapperBoard::Discrete(Discrete::new(
+                DiscreteBoard::Gxrom,
+                submapper_number,
+            ))),
+            69 => Some(MapperBoard::Fme7(Fme7::default())),
+            _ => None,
+        }
+    }
+    /// iNESのMapper番号
+    pub fn mapper_number(&self) -> u16 {
+        match self {
+            MapperBoard::Nrom(_) => 0,
+            MapperBoard::Mmc1(_) => 1,
+            MapperBoard::Mmc2(m) => {
+                if m.is_mmc4 {
+                    10
+                } else {
+                    9
+                }
+            }
+            MapperBoard::Mmc3(_) => 4,
+            MapperBoard::Mmc5(_) => 5,
+            MapperBoard::Discrete(m) => match m.board {
+                DiscreteBoard::Uxrom => 2,
+                DiscreteBoard::Cnrom => 3,
+                DiscreteBoard::Axrom => 7,
+                DiscreteBoard::Gxrom => 66,
+                DiscreteBoard::Bnrom => 34,
+                DiscreteBoard::ColorDreams => 11,
+            },
+            MapperBoard::Namco163(_) => 19,
+            MapperBoard::Vrc4(m) => m.mapper_number(),
+            MapperBoard::Vrc6(m) => {
+                if m.is_swap_address_line {
+                    26
+                } else {
+                    24
+                }
+            }
+            MapperBoard::Fme7(_) => 69,
+            #[cfg(feature = "alloc")]
+            MapperBoard::Fds(_) => 20,
+        }
+    }
+    pub fn as_mapper(&self) -> &dyn Mapper {
+        match self {
+            MapperBoard::Nrom(m) => m,
+            MapperBoard::Mmc1(m) => m,
+            MapperBoard::Mmc2(m) => m,
+            MapperBoard::Mmc3(m) => m,
+            MapperBoard::Mmc5(m) => m,
+            MapperBoard::Discrete(m) => m,
+            MapperBoard::Namco163(m) => m,
+            MapperBoard::Vrc4(m) => m,
+            MapperBoard::Vrc6(m) => m,
+            MapperBoard::Fme7(m) => m,
+            #[cfg(feature = "alloc")]
+            MapperBoard::Fds(m) => m,
+        }
+    }
+    pub fn as_mapper_mut(&mut self) -> &mut dyn Mapper {
+        match self {
+            MapperBoard::Nrom(m) => m,
+            MapperBoard::Mmc1(m) => m,
+            MapperBoard::Mmc2(m) => m,
+            MapperBoard::Mmc3(m) => m,
+            MapperBoard::Mmc5(m) => m,
+            MapperBoard::Discrete(m) => m,
+            MapperBoard::Namco163(m) => m,
+            MapperBoard::Vrc4(m) => m,
+            MapperBoard::Vrc6(m) => m,
+            MapperBoard::Fme7(m) => m,
+            #[cfg(feature = "alloc")]
+            MapperBoard::Fds(m) => m,
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// 74系ロジックのlatchだけで構成された基板の種類
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DiscreteBoard {
+    /// Mapper2: 0x8000に16KB切り替え、0xc000は最後のbank固定
+    Uxrom,
+    /// Mapper3: 8KB CHR切り替え
+    Cnrom,
+    /// Mapper7: 32KB PRG切り替え、single-screen mirroring選択
+    Axrom,
+    /// Mapper66: 32KB PRG, 8KB CHR切り替え
+    Gxrom,
+    /// Mapper34: BNROM(32KB PRG切り替え)とNINA-001(0x7ffd-0x7fffのレジスタ)
+    Bnrom,
+    /// Mapper11: 32KB PRG, 8KB CHR切り替え
+    ColorDreams,
+}
+
+/// Mapper2, 3, 7, 11, 34, 66
+/// https://wiki.nesdev.com/w/index.php/UxROM
+/// https://wiki.nesdev.com/w/index.php/INES_Mapper_003
+/// https://wiki.nesdev.com/w/index.php/AxROM
+/// https://wiki.nesdev.com/w/index.php/GxROM
+/// https://wiki.nesdev.com/w/index.php/INES_Mapper_034
+/// https://wiki.nesdev.com/w/index.php/Color_Dreams
+#[derive(Clone)]
+pub struct Discrete {
+    pub board: DiscreteBoard,
+    /// 選択中のPRG bank(boardによって16KB/32KB単位)
+    pub prg_bank: u8,
+    /// 選択中のCHR bank(8KB単位, NINA-001のみ0x0000側の4KB単位)
+    pub chr_bank0: u8,
+    /// NINA-001の0x1000側の4KB CHR bank
+    pub chr_bank1: u8,
+    /// AxROMのsingle-screen選択
+    pub mirroring: Option<NameTableMirror>,
+    /// 書き込み時にROMの出力と衝突してANDされる(bus conflict)のをエミュレーションする
+    pub is_bus_conflict: bool,
+    /// Mapper34がNINA-001ならSome(true), BNROMならSome(false)
+    /// submapperで区別できない場合はNoneにして、CHR-ROMのサイズから判断する
+    pub is_nina001: Option<bool>,
+}
+
+impl Discrete {
+    /// `submapper_number` - NES 2.0のsubmapper
+    /// Mapper2, 3, 7は1でbus conflictなし、2でbus conflictあり
+    /// Mapper34は1でNINA-001, 2でBNROM(bus conflictあり)
+    /// GxROM(66)とColor Dreams(11)はsubmapperでbus conflictの有無を区別できず、
+    /// 書き込み値をROMと揃えていないソフトや互換基板があるので無効にしておく
+    pub fn new(board: DiscreteBoard, submapper_number: u8) -> Discrete {
+        let is_bus_conflict = match (board, submapper_number) {
+            (DiscreteBoard::Uxrom, 2)
+            | (DiscreteBoard::Cnrom, 2)
+            | (DiscreteBoard::Axrom, 2)
+            | (DiscreteBoard::Bnrom, 2) => true,
+            // CNROMの基板はほとんどがbus conflictを持つ
+            (DiscreteBoard::Cnrom, 0) => true,
+            _ => false,
+        };
+        let is_nina001 = match (board, submapper_number) {
+            (DiscreteBoard::Bnrom, 1) => Some(true),
+            (DiscreteBoard::Bnrom, 2) => Some(false),
+            _ => None,
+        };
+        Discrete {
+            board,
+            prg_bank: 0,
+            chr_bank0: 0,
+            chr_bank1: 1,
+            mirroring: if board == DiscreteBoard::Axrom {
+                Some(NameTableMirror::SingleScreenLower)
+            } else {
+                None
+            },
+            is_bus_conflict,
+            is_nina001,
+        }
+    }
+    /// submapperで指定されていなければ、CHR-ROMを8KBより多く持っているものをNINA-001とみなす
+    /// (BNROMはCHR-RAMしか持たない)
+    fn is_nina001(&self, mem: &CassetteMemory) -> bool {
+        self.board == DiscreteBoard::Bnrom && self.is_nina001.unwrap_or(mem.chr_rom_bytes > 0x2000)
+    }
+    /// 0x8000 - 0xffffへの書き込みをlatchに反映します
+    fn write_latch(&mut self, data: u8) {
+        match self.board {
+            DiscreteBoard::Uxrom => self.prg_bank = data,
+            DiscreteBoard::Cnrom => self.chr_bank0 = data,
+            DiscreteBoard::Axrom => {
+                self.prg_bank = data & 0x07;
+                self.mirroring = Some(if (data & 0x10) == 0x10 {
+                    NameTableMirror::SingleScreenUpper
+                } else {
+                    NameTableMirror::SingleScreenLower
+                });
+            }
+            DiscreteBoard::Gxrom => {
+                self.prg_bank = (data >> 4) & 0x03;
+                self.chr_bank0 = data & 0x03;
+            }
+            DiscreteBoard::Bnrom => self.prg_bank = data,
+            DiscreteBoard::ColorDreams => {
+                self.prg_bank = data & 0x03;
+                self.chr_bank0 = data >> 4;
+            }
+        }
+    }
+}
+
+impl Mapper for Discrete {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            return mem.read_battery_packed_ram(addr);
+        }
+        let offset = usize::from(addr - PRG_ROM_SYSTEM_BASE_ADDR);
+        match self.board {
+            DiscreteBoard::Uxrom => {
+                if addr < 0xc000 {
+                    mem.read_prg_rom(0x4000, usize::from(self.prg_bank), offset)
+                } else {
+                    // 最後のbankに固定
+                    let last = mem.prg_rom_bytes.saturating_sub(0x4000) / 0x4000;
+                    mem.read_prg_rom(0x4000, last, offset & 0x3fff)
+                }
+            }
+            DiscreteBoard::Cnrom => mem.read_prg_rom(0x8000, 0, offset),
+            _ => mem.read_prg_rom(0x8000, usize::from(self.prg_bank), offset),
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            mem.write_battery_packed_ram(addr, data);
+            // NINA-001のレジスタはRAMと同じ空間に重なっている
+            if !is_nondestructive && self.is_nina001(mem) {
+                match addr {
+                    0x7ffd => self.prg_bank = data & 0x01,
+                    0x7ffe => self.chr_bank0 = data & 0x0f,
+                    0x7fff => self.chr_bank1 = data & 0x0f,
+                    _ => {}
+                }
+            }
+            return;
+        }
+        if is_nondestructive || self.is_nina001(mem) {
+            return;
+        }
+        // bus conflict: ROMの出力とのAND
+        let value = if self.is_bus_conflict {
+            data & self.read_u8(mem, addr, true)
+        } else {
+            data
+        };
+        self.write_latch(value);
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let offset = usize::from(addr);
+        match self.board {
+            DiscreteBoard::Cnrom | DiscreteBoard::Gxrom | DiscreteBoard::ColorDreams => {
+                mem.read_chr(0x2000, usize::from(self.chr_bank0), offset)
+            }
+            DiscreteBoard::Bnrom if self.is_nina001(mem) => {
+                let bank = if addr < 0x1000 {
+                    self.chr_bank0
+                } else {
+                    self.chr_bank1
+                };
+                mem.read_chr(0x1000, usize::from(bank), offset & 0x0fff)
+            }
+            _ => mem.read_chr(0x2000, 0, offset),
+        }
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let offset = usize::from(addr);
+        match self.board {
+            DiscreteBoard::Cnrom | DiscreteBoard::Gxrom | DiscreteBoard::ColorDreams => {
+                mem.write_chr(0x2000, usize::from(self.chr_bank0), offset, data)
+            }
+            DiscreteBoard::Bnrom if self.is_nina001(mem) => {
+                let bank = if addr < 0x1000 {
+                    self.chr_bank0
+                } else {
+                    self.chr_bank1
+                };
+                mem.write_chr(0x1000, usize::from(bank), offset & 0x0fff, data)
+            }
+            _ => mem.write_chr(0x2000, 0, offset, data),
+        }
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        self.mirroring
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank);
+        writer.write_u8(self.chr_bank0);
+        writer.write_u8(self.chr_bank1);
+        writer.write_mirror(self.mirroring);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.prg_bank = reader.read_u8();
+        self.chr_bank0 = reader.read_u8();
+        self.chr_bank1 = reader.read_u8();
+        self.mirroring = reader.read_mirror();
+    }
+}
@@ -0,0 +1,746 @@
+use super::cassette::*;
+use super::fds::*;
+use super::mapper::*;
+
+/// 拡張音源の1step分の出力レベル
+pub const FDS_AUDIO_LEVEL_PER_STEP: f32 = 0.0024;
+/// 波形メモリ、変調テーブルのサイズ
+pub const FDS_WAVE_TABLE_SIZE: usize = 0x40;
+/// motorを回し始めてからheadが面の先頭に着くまでのCPU cycle
+pub const FDS_HEAD_SEEK_CPU_CYCLE: u32 = 50000;
+/// 1byte転送するのにかかるCPU cycle
+pub const FDS_BYTE_TRANSFER_CPU_CYCLE: u32 = 150;
+/// 面を入れ替えるときにdiskを抜いておくCPU cycle, BIOSが入れ替えに気付けるように約2秒待つ
+pub const FDS_DISK_INSERT_DELAY_CPU_CYCLE: u32 = 3_600_000;
+/// 0x4089のmaster volumeごとの倍率
+const MASTER_VOLUME_TABLE: [u32; 4] = [36, 24, 17, 14];
+/// 変調テーブルの値ごとのcounterの増減、4はcounterを0に戻す
+const MOD_COUNTER_STEP: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+/// 変調テーブルでcounterを0に戻す値
+const MOD_COUNTER_RESET: u8 = 4;
+
+/// FDS音源の音量/変調それぞれが持つenvelope
+#[derive(Clone, Default)]
+pub struct FdsEnvelope {
+    /// 0x4080, 0x4084: MDSS_SSSS
+    /// M - envelope off
+    /// D - 1で増加、0で減少
+    /// S - speed
+    pub control: u8,
+    /// 0x4082-0x4083, 0x4086-0x4087: 12bit frequency
+    pub frequency: u16,
+    pub gain: u8,
+    pub timer: u32,
+}
+
+impl FdsEnvelope {
+    fn is_envelope_off(&self) -> bool {
+        (self.control & 0x80) == 0x80
+    }
+    fn write_control(&mut self, data: u8, master_speed: u8) {
+        self.control = data;
+        self.reset_timer(master_speed);
+        // envelopeを使わない場合はspeedがそのままgainになる
+        if self.is_envelope_off() {
+            self.gain = data & 0x3f;
+        }
+    }
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (u32::from(self.control & 0x3f) + 1) * u32::from(master_speed);
+    }
+    fn step(&mut self, master_speed: u8) {
+        if self.is_envelope_off() || master_speed == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.reset_timer(master_speed);
+            if (self.control & 0x40) == 0x40 {
+                if self.gain < 32 {
+                    self.gain += 1;
+                }
+            } else if self.gain > 0 {
+                self.gain -= 1;
+            }
+        }
+    }
+}
+
+/// FDSの波形メモリ音源
+/// 64step * 6bitの波形を、変調テーブルで周波数を揺らしながら再生する
+/// https://wiki.nesdev.com/w/index.php/FDS_audio
+#[derive(Clone)]
+pub struct FdsAudio {
+    /// 0x4040 - 0x407f: 6bit sample
+    pub wave_table: [u8; FDS_WAVE_TABLE_SIZE],
+    pub volume: FdsEnvelope,
+    pub modulator: FdsEnvelope,
+    /// 0x4083: HE-- ----
+    /// H - 波形の再生を止めて先頭に戻す
+    /// E - envelopeを止める
+    pub wave_control: u8,
+    /// 0x4085: 7bit signed counter
+    pub mod_counter: i8,
+    /// 0x4087 bit7: 変調を止める、この間だけ変調テーブルに書き込める
+    pub is_mod_disabled: bool,
+    /// 0x4088: 3bit値, 2回ずつ書き込まれる
+    pub mod_table: [u8; FDS_WAVE_TABLE_SIZE],
+    pub mod_table_pos: u8,
+    pub mod_accumulator: u32,
+    /// 0x4089: W--- --VV
+    /// W - 波形メモリ書き込み許可(再生は止まる)
+    /// V - master volume
+    pub master_control: u8,
+    /// 0x408a: envelopeの速度の倍率
+    pub master_speed: u8,
+    pub wave_pos: u8,
+    pub wave_accumulator: u32,
+    /// 最後の出力(0 - 63)
+    pub output: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self {
+            wave_table: [0; FDS_WAVE_TABLE_SIZE],
+            volume: FdsEnvelope::default(),
+            modulator: FdsEnvelope::default(),
+            wave_control: 0,
+            mod_counter: 0,
+            is_mod_disabled: true,
+            mod_table: [0; FDS_WAVE_TABLE_SIZE],
+            mod_table_pos: 0,
+            mod_accumulator: 0,
+            master_control: 0,
+            // BIOSが初期化時に書き込む値
+            master_speed: 0xe8,
+            wave_pos: 0,
+            wave_accumulator: 0,
+            output: 0,
+        }
+    }
+}
+
+impl FdsAudio {
+    fn is_wave_writable(&self) -> bool {
+        (self.master_control & 0x80) == 0x80
+    }
+    fn is_wave_halt(&self) -> bool {
+        (self.wave_control & 0x80) == 0x80
+    }
+    fn read_register(&self, addr: u16) -> u8 {
+        // 上位2bitはopen bus
+        let open_bus = (addr >> 8) as u8 & 0xc0;
+        match addr {
+            0x4040..=0x407f => open_bus | self.wave_table[usize::from(addr & 0x3f)],
+            0x4090 => open_bus | self.volume.gain,
+            0x4092 => open_bus | self.modulator.gain,
+            _ => (addr >> 8) as u8,
+        }
+    }
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407f if self.is_wave_writable() => {
+                self.wave_table[usize::from(addr & 0x3f)] = data & 0x3f;
+            }
+            0x4080 => self.volume.write_control(data, self.master_speed),
+            0x4082 => self.volume.frequency = (self.volume.frequency & 0x0f00) | u16::from(data),
+            0x4083 => {
+                self.volume.frequency =
+                    (self.volume.frequency & 0x00ff) | (u16::from(data & 0x0f) << 8);
+                self.wave_control = data;
+                if self.is_wave_halt() {
+                    self.wave_pos = 0;
+                    self.wave_accumulator = 0;
+                }
+                if (data & 0x40) == 0x40 {
+                    self.volume.reset_timer(self.master_speed);
+                    self.modulator.reset_timer(self.master_speed);
+                }
+            }
+            0x4084 => self.modulator.write_control(data, self.master_speed),
+            0x4085 => self.set_mod_counter(data & 0x7f),
+            0x4086 => {
+                self.modulator.frequency = (self.modulator.frequency & 0x0f00) | u16::from(data)
+            }
+            0x4087 => {
+                self.modulator.frequency =
+                    (self.modulator.frequency & 0x00ff) | (u16::from(data & 0x0f) << 8);
+                self.is_mod_disabled = (data & 0x80) == 0x80;
+                if self.is_mod_disabled {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 if self.is_mod_disabled => {
+                let pos = usize::from(self.mod_table_pos);
+                self.mod_table[pos & 0x3f] = data & 0x07;
+                self.mod_table[(pos + 1) & 0x3f] = data & 0x07;
+                self.mod_table_pos = ((pos + 2) & 0x3f) as u8;
+            }
+            0x4089 => self.master_control = data,
+            0x408a => self.master_speed = data,
+            _ => {}
+        }
+    }
+    /// 7bit値を符号付きで変調counterに設定します
+    fn set_mod_counter(&mut self, data: u8) {
+        self.mod_counter = ((data << 1) as i8) >> 1;
+    }
+    /// 変調counterとgainから、波形の周波数に加える値を求めます
+    /// https://wiki.nesdev.com/w/index.php/FDS_audio#Frequency_calculation
+    fn mod_pitch(&self) -> i32 {
+        let counter = i32::from(self.mod_counter);
+        let mut temp = counter * i32::from(self.modulator.gain);
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && (temp & 0x80) == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= i32::from(self.volume.frequency);
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        temp
+    }
+    /// 1CPU cycle分進めます
+    fn step(&mut self) {
+        if !self.is_wave_halt() && (self.wave_control & 0x40) == 0x00 {
+            self.volume.step(self.master_speed);
+            self.modulator.step(self.master_speed);
+        }
+        // 変調counterを16bitのaccumulatorがあふれるごとに進める
+        if !self.is_mod_disabled && self.modulator.frequency > 0 {
+            self.mod_accumulator += u32::from(self.modulator.frequency);
+            if self.mod_accumulator > 0xffff {
+                self.mod_accumulator &= 0xffff;
+                let step = self.mod_table[usize::from(self.mod_table_pos)];
+                let next = if step == MOD_COUNTER_RESET {
+                    0
+                } else {
+                    i16::from(self.mod_counter) + i16::from(MOD_COUNTER_STEP[usize::from(step)])
+                };
+                self.set_mod_counter(next as u8 & 0x7f);
+                self.mod_table_pos = (self.mod_table_pos + 1) & 0x3f;
+            }
+        }
+        if self.is_wave_halt() {
+            self.wave_pos = 0;
+        } else if !self.is_wave_writable() {
+            let pitch = i32::from(self.volume.frequency) + self.mod_pitch();
+            if pitch > 0 {
+                self.wave_accumulator += pitch as u32;
+                if self.wave_accumulator > 0xffff {
+                    self.wave_accumulator &= 0xffff;
+                    self.wave_pos = (self.wave_pos + 1) & 0x3f;
+                }
+            }
+        }
+        // 波形メモリ書き込み中は最後の出力を保持する
+        if !self.is_wave_writable() {
+            let gain = u32::from(core::cmp::min(self.volume.gain, 32));
+            let level = gain * MASTER_VOLUME_TABLE[usize::from(self.master_control & 0x03)];
+            let sample = u32::from(self.wave_table[usize::from(self.wave_pos)]);
+            self.output = (sample * level / 1152) as u8;
+        }
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.wave_table);
+        for envelope in [&self.volume, &self.modulator].iter() {
+            writer.write_u8(envelope.control);
+            writer.write_u16(envelope.frequency);
+            writer.write_u8(envelope.gain);
+            writer.write_u32(envelope.timer);
+        }
+        writer.write_u8(self.wave_control);
+        writer.write_u8(self.mod_counter as u8);
+        writer.write_bool(self.is_mod_disabled);
+        writer.write_bytes(&self.mod_table);
+        writer.write_u8(self.mod_table_pos);
+        writer.write_u32(self.mod_accumulator);
+        writer.write_u8(self.master_control);
+        writer.write_u8(self.master_speed);
+        writer.write_u8(self.wave_pos);
+        writer.write_u32(self.wave_accumulator);
+        writer.write_u8(self.output);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        reader.read_bytes(&mut self.wave_table);
+        for envelope in [&mut self.volume, &mut self.modulator].iter_mut() {
+            envelope.control = reader.read_u8();
+            envelope.frequency = reader.read_u16();
+            envelope.gain = reader.read_u8();
+            envelope.timer = reader.read_u32();
+        }
+        self.wave_control = reader.read_u8();
+        self.mod_counter = reader.read_u8() as i8;
+        self.is_mod_disabled = reader.read_bool();
+        reader.read_bytes(&mut self.mod_table);
+        self.mod_table_pos = reader.read_u8();
+        self.mod_accumulator = reader.read_u32();
+        self.master_control = reader.read_u8();
+        self.master_speed = reader.read_u8();
+        self.wave_pos = reader.read_u8();
+        self.wave_accumulator = reader.read_u32();
+        self.output = reader.read_u8();
+    }
+}
+
+/// Famicom Disk System(RAM Adapter)
+/// 0xe000 - 0xffffにBIOS, 0x6000 - 0xdfffに32KB RAM, 8KB CHR-RAM
+/// timer IRQ, disk drive, 波形メモリ音源を持つ。diskの実体はCassetteMemory::diskにある
+/// https://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+#[derive(Clone)]
+pub struct Fds {
+    /// 0x4020 - 0x4021: timer IRQ reload value
+    pub irq_reload: u16,
+    pub irq_counter: u16,
+    /// 0x4022: ---- --ER
+    /// E - timer IRQ enable
+    /// R - repeat
+    pub irq_control: u8,
+    pub is_timer_irq_pending: bool,
+    /// 0x4023: ---- --SD
+    /// S - sound register enable
+    /// D - disk register enable
+    pub master_io_enable: u8,
+    /// 0x4024: 書き込むデータ
+    pub write_data: u8,
+    /// 0x4025: IRCD_MRTM
+    /// I - 転送完了でIRQを出す
+    /// R - 1でデータの読み書きを始める(0の間はgap)
+    /// C - CRCを書き込む
+    /// D - mirroring(1: horizontal, 0: vertical)
+    /// R - 1で読み出し、0で書き込み
+    /// T - 転送をリセットする
+    /// M - motor on
+    pub control: u8,
+    /// 0x4031: 読み出したデータ
+    pub read_data: u8,
+    pub is_transfer_complete: bool,
+    pub is_disk_irq_pending: bool,
+    /// 入っている面, 取り出されていればNone
+    pub inserted_side: Option<usize>,
+    /// 入れ替え待ちの面、insert_delayが0になったら入る
+    pub next_side: Option<usize>,
+    pub insert_delay: u32,
+    /// headが面の終わりまで行った(motorを回すと先頭に戻る)
+    pub is_end_of_head: bool,
+    /// headが面を走査中
+    pub is_scanning: bool,
+    /// 読み出し中、gapを抜けてblockに入ったらtrue
+    pub is_gap_ended: bool,
+    pub head_position: usize,
+    pub head_delay: u32,
+    pub crc: u16,
+    /// 前のbyteを転送したときの0x4025 bit4
+    pub is_prev_crc_control: bool,
+    pub audio: FdsAudio,
+}
+
+impl Default for Fds {
+    fn default() -> Self {
+        Self {
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_control: 0,
+            is_timer_irq_pending: false,
+            master_io_enable: 0,
+            write_data: 0,
+            control: 0,
+            read_data: 0,
+            is_transfer_complete: false,
+            is_disk_irq_pending: false,
+            inserted_side: None,
+            next_side: None,
+            insert_delay: 0,
+            is_end_of_head: true,
+            is_scanning: false,
+            is_gap_ended: false,
+            head_position: 0,
+            head_delay: 0,
+            crc: 0,
+            is_prev_crc_control: false,
+            audio: FdsAudio::default(),
+        }
+    }
+}
+
+impl Fds {
+    /// `side`面を入れます。BIOSが入れ替えに気付けるように、一度取り出してしばらく待ってから入る
+    /// `is_immediate` - 電源投入時のように待たずに入れる
+    pub fn insert_side(&mut self, side: usize, is_immediate: bool) {
+        if is_immediate {
+            self.inserted_side = Some(side);
+            self.next_side = None;
+            self.insert_delay = 0;
+        } else {
+            self.inserted_side = None;
+            self.next_side = Some(side);
+            self.insert_delay = FDS_DISK_INSERT_DELAY_CPU_CYCLE;
+        }
+    }
+    /// diskを取り出します
+    pub fn eject(&mut self) {
+        self.inserted_side = None;
+        self.next_side = None;
+        self.insert_delay = 0;
+    }
+    fn is_disk_reg_enable(&self) -> bool {
+        (self.master_io_enable & 0x01) == 0x01
+    }
+    fn is_sound_reg_enable(&self) -> bool {
+        (self.master_io_enable & 0x02) == 0x02
+    }
+    fn is_motor_on(&self) -> bool {
+        (self.control & 0x01) == 0x01
+    }
+    fn is_transfer_reset(&self) -> bool {
+        (self.control & 0x02) == 0x02
+    }
+    fn is_read_mode(&self) -> bool {
+        (self.control & 0x04) == 0x04
+    }
+    fn is_crc_control(&self) -> bool {
+        (self.control & 0x10) == 0x10
+    }
+    fn is_disk_ready(&self) -> bool {
+        (self.control & 0x40) == 0x40
+    }
+    fn is_disk_irq_enable(&self) -> bool {
+        (self.control & 0x80) == 0x80
+    }
+    /// CRC-16(多項式0x8408)に1byte加えます
+    fn update_crc(&mut self, data: u8) {
+        for bit in 0..8 {
+            let is_carry = (self.crc & 0x01) == 0x01;
+            self.crc >>= 1;
+            if is_carry {
+                self.crc ^= 0x8408;
+            }
+            if ((data >> bit) & 0x01) == 0x01 {
+                self.crc ^= 0x8000;
+            }
+        }
+    }
+    /// timer IRQを1CPU cycle分進めます
+    fn step_timer(&mut self) {
+        if (self.irq_control & 0x02) == 0x00 {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.is_timer_irq_pending = true;
+            self.irq_counter = self.irq_reload;
+            if (self.irq_control & 0x01) == 0x00 {
+                self.irq_control &= !0x02;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+    /// disk driveを1CPU cycle分進めます
+    fn step_drive(&mut self, mem: &mut CassetteMemory) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            if self.insert_delay == 0 {
+                self.inserted_side = self.next_side.take();
+            }
+            return;
+        }
+        let side = match self.inserted_side {
+            Some(side) if self.is_motor_on() && mem.disk.is_some() => side,
+            _ => {
+                self.is_end_of_head = true;
+                self.is_scanning = false;
+                return;
+            }
+        };
+        if self.is_transfer_reset() && !self.is_scanning {
+            return;
+        }
+        if self.is_end_of_head {
+            // 面の先頭まで戻す
+            self.head_delay = FDS_HEAD_SEEK_CPU_CYCLE;
+            self.is_end_of_head = false;
+            self.head_position = 0;
+            self.is_gap_ended = false;
+            return;
+        }
+        if self.head_delay > 0 {
+            self.head_delay -= 1;
+            return;
+        }
+        self.is_scanning = true;
+        let disk = match &mut mem.disk {
+            Some(disk) => disk,
+            None => return,
+        };
+        let mut is_need_irq = self.is_disk_irq_enable();
+        if self.is_read_mode() {
+            let data = disk.read(side, self.head_position);
+            if !self.is_prev_crc_control {
+                self.update_crc(data);
+            }
+            if !self.is_disk_ready() {
+                self.is_gap_ended = false;
+                self.crc = 0;
+            } else if data != 0x00 && !self.is_gap_ended {
+                // block開始markは読み出さない
+                self.is_gap_ended = true;
+                is_need_irq = false;
+            }
+            if self.is_gap_ended {
+                self.is_transfer_complete = true;
+                self.read_data = data;
+                if is_need_irq {
+                    self.is_disk_irq_pending = true;
+                }
+            }
+        } else {
+            let mut data = 0x00;
+            if !self.is_crc_control() {
+                self.is_transfer_complete = true;
+                data = self.write_data;
+                if is_need_irq {
+                    self.is_disk_irq_pending = true;
+                }
+            }
+            if !self.is_disk_ready() {
+                data = 0x00;
+            }
+            if !self.is_crc_control() {
+                self.update_crc(data);
+            } else {
+                if !self.is_prev_crc_control {
+                    self.update_crc(0x00);
+                    self.update_crc(0x00);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            // 書き込みはheadに届くまで2byte遅れる
+            if self.head_position >= 2 {
+                disk.write(side, self.head_position - 2, data);
+            }
+            self.is_gap_ended = false;
+        }
+        self.is_prev_crc_control = self.is_crc_control();
+        self.head_position += 1;
+        if self.head_position >= disk.side_len(side) {
+            // 面の終わりでmotorが止まる
+            self.control &= !0x01;
+            if is_need_irq {
+                self.is_disk_irq_pending = true;
+            }
+        } else {
+            self.head_delay = FDS_BYTE_TRANSFER_CPU_CYCLE;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < 0xe000 {
+            // RAM Adapter上のRAMはカセット内RAMとして確保している
+            let offset = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR);
+            mem.read_battery_packed_ram_bank(0, offset)
+        } else {
+            mem.read_prg_rom(FDS_BIOS_SIZE, 0, usize::from(addr & 0x1fff))
+        }
+    }
+    fn write_u8(
+        &mut self,
+        mem: &mut CassetteMemory,
+        addr: u16,
+        data: u8,
+        _is_nondestructive: bool,
+    ) {
+        // BIOSへの書き込みは無視
+        if addr < 0xe000 {
+            let offset = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR);
+            mem.write_battery_packed_ram_bank(0, offset, data);
+        }
+    }
+    fn read_expansion_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        addr: u16,
+        is_nondestructive: bool,
+    ) -> Option<u8> {
+        match addr {
+            0x4030 if self.is_disk_reg_enable() => {
+                // -E-C --BT
+                // E - end of head, C - CRC error(未実装), B - 転送完了, T - timer IRQ
+                let mut data = 0x00;
+                if self.is_timer_irq_pending {
+                    data |= 0x01;
+                }
+                if self.is_transfer_complete {
+                    data |= 0x02;
+                }
+                if self.is_end_of_head {
+                    data |= 0x40;
+                }
+                // 読むとIRQを取り下げる
+                if !is_nondestructive {
+                    self.is_transfer_complete = false;
+                    self.is_timer_irq_pending = false;
+                    self.is_disk_irq_pending = false;
+                }
+                Some(data)
+            }
+            0x4031 if self.is_disk_reg_enable() => {
+                if !is_nondestructive {
+                    self.is_transfer_complete = false;
+                    self.is_disk_irq_pending = false;
+                }
+                Some(self.read_data)
+            }
+            0x4032 if self.is_disk_reg_enable() => {
+                // ---- -PRS  P - write protect, R - not ready, S - no disk
+                let mut data = 0x40;
+                if self.inserted_side.is_none() {
+                    data |= 0x07;
+                } else if !self.is_scanning {
+                    data |= 0x02;
+                }
+                Some(data)
+            }
+            // 拡張端子、bit7はバッテリー良好
+            0x4033 if self.is_disk_reg_enable() => Some(0x80),
+            0x4040..=0x4092 if self.is_sound_reg_enable() => Some(self.audio.read_register(addr)),
+            _ => None,
+        }
+    }
+    fn write_expansion_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        addr: u16,
+        data: u8,
+        is_nondestructive: bool,
+    ) {
+        if is_nondestructive {
+            return;
+        }
+        match addr {
+            0x4023 => {
+                self.master_io_enable = data;
+                if !self.is_disk_reg_enable() {
+                    self.irq_control &= !0x02;
+                    self.is_timer_irq_pending = false;
+                    self.is_disk_irq_pending = false;
+                }
+            }
+            0x4020..=0x4025 if self.is_disk_reg_enable() => match addr {
+                0x4020 => self.irq_reload = (self.irq_reload & 0xff00) | u16::from(data),
+                0x4021 => self.irq_reload = (self.irq_reload & 0x00ff) | (u16::from(data) << 8),
+                0x4022 => {
+                    self.irq_control = data & 0x03;
+                    if (data & 0x02) == 0x02 {
+                        self.irq_counter = self.irq_reload;
+                    } else {
+                        self.is_timer_irq_pending = false;
+                    }
+                }
+                0x4024 => {
+                    self.write_data = data;
+                    self.is_transfer_complete = false;
+                    self.is_disk_irq_pending = false;
+                }
+                _ => {
+                    self.control = data;
+                    self.is_disk_irq_pending = false;
+                }
+            },
+            0x4040..=0x408a if self.is_sound_reg_enable() => self.audio.write_register(addr, data),
+            _ => {}
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        mem.read_chr(CHR_RAM_SIZE, 0, usize::from(addr))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        mem.write_chr(CHR_RAM_SIZE, 0, usize::from(addr), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        Some(if (self.control & 0x08) == 0x08 {
+            NameTableMirror::Horizontal
+        } else {
+            NameTableMirror::Vertical
+        })
+    }
+    fn is_irq_asserted(&self) -> bool {
+        self.is_timer_irq_pending || self.is_disk_irq_pending
+    }
+    fn notify_cpu_cycles(&mut self, mem: &mut CassetteMemory, cpu_cyc: usize) {
+        for _ in 0..cpu_cyc {
+            self.step_timer();
+            self.step_drive(mem);
+            self.audio.step();
+        }
+    }
+    fn expansion_audio_output(&self) -> f32 {
+        f32::from(self.audio.output) * FDS_AUDIO_LEVEL_PER_STEP
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.irq_reload);
+        writer.write_u16(self.irq_counter);
+        writer.write_u8(self.irq_control);
+        writer.write_bool(self.is_timer_irq_pending);
+        writer.write_u8(self.master_io_enable);
+        writer.write_u8(self.write_data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.read_data);
+        writer.write_bool(self.is_transfer_complete);
+        writer.write_bool(self.is_disk_irq_pending);
+        // 面番号は0xffをNoneとして書き出す
+        writer.write_u8(self.inserted_side.map_or(0xff, |side| side as u8));
+        writer.write_u8(self.next_side.map_or(0xff, |side| side as u8));
+        writer.write_u32(self.insert_delay);
+        writer.write_bool(self.is_end_of_head);
+        writer.write_bool(self.is_scanning);
+        writer.write_bool(self.is_gap_ended);
+        writer.write_u32(self.head_position as u32);
+        writer.write_u32(self.head_delay);
+        writer.write_u16(self.crc);
+        writer.write_bool(self.is_prev_crc_control);
+        self.audio.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.irq_reload = reader.read_u16();
+        self.irq_counter = reader.read_u16();
+        self.irq_control = reader.read_u8();
+        self.is_timer_irq_pending = reader.read_bool();
+        self.master_io_enable = reader.read_u8();
+        self.write_data = reader.read_u8();
+        self.control = reader.read_u8();
+        self.read_data = reader.read_u8();
+        self.is_transfer_complete = reader.read_bool();
+        self.is_disk_irq_pending = reader.read_bool();
+        self.inserted_side = match reader.read_u8() {
+            0xff => None,
+            side => Some(usize::from(side)),
+        };
+        self.next_side = match reader.read_u8() {
+            0xff => None,
+            side => Some(usize::from(side)),
+        };
+        self.insert_delay = reader.read_u32();
+        self.is_end_of_head = reader.read_bool();
+        self.is_scanning = reader.read_bool();
+        self.is_gap_ended = reader.read_bool();
+        self.head_position = reader.read_u32() as usize;
+        self.head_delay = reader.read_u32();
+        self.crc = reader.read_u16();
+        self.is_prev_crc_control = reader.read_bool();
+        self.audio.load_state(reader);
+    }
+}
@@ -0,0 +1,385 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// 拡張音源1channelの最大出力レベル(APUのpulse最大音量と同程度)
+pub const SUNSOFT_5B_CHANNEL_LEVEL: f32 = 0.113;
+/// 5BはCPU clockを2分周したものを更に16分周してtone/noise/envelopeを進める
+pub const SUNSOFT_5B_CLOCK_DIVIDER: u8 = 16;
+/// 5Bの音量は1stepあたり3dB、0は無音
+const SUNSOFT_5B_VOLUME_TABLE: [f32; 16] = [
+    0.0, 0.0079, 0.0112, 0.0158, 0.0224, 0.0316, 0.0447, 0.0631, 0.0891, 0.1259, 0.1778, 0.2512,
+    0.3548, 0.5012, 0.7079, 1.0,
+];
+
+/// Sunsoft 5B拡張音源(AY-3-8910互換)
+/// 矩形波 * 3, noise, envelope
+/// https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+#[derive(Clone)]
+pub struct Sunsoft5b {
+    /// 0xc000: 次に0xe000で書き換えるregister
+    pub register_select: u8,
+    /// 0x00 - 0x05: channel A, B, Cの12bit period
+    pub tone_periods: [u16; 3],
+    /// 0x06: 5bit noise period
+    pub noise_period: u8,
+    /// 0x07: xxNN_NTTT
+    /// N - channelごとのnoise無効
+    /// T - channelごとのtone無効
+    pub mixer: u8,
+    /// 0x08 - 0x0a: xxxE_VVVV
+    /// E - envelopeを使う
+    /// V - volume
+    pub volumes: [u8; 3],
+    /// 0x0b, 0x0c: 16bit envelope period
+    pub envelope_period: u16,
+    /// 0x0d: xxxx_CAAH
+    /// C - continue, A - attack, A - alternate, H - hold
+    pub envelope_shape: u8,
+
+    pub clock_divider: u8,
+    pub tone_timers: [u16; 3],
+    pub tone_outputs: [bool; 3],
+    pub noise_timer: u8,
+    /// 17bit LFSR
+    pub noise_lfsr: u32,
+    pub envelope_timer: u32,
+    pub envelope_step: u8,
+    pub is_envelope_attack: bool,
+    pub is_envelope_holding: bool,
+}
+
+impl Default for Sunsoft5b {
+    fn default() -> Self {
+        Self {
+            register_select: 0,
+            tone_periods: [0; 3],
+            noise_period: 0,
+            // 起動時は全channel無効
+            mixer: 0x3f,
+            volumes: [0; 3],
+            envelope_period: 0,
+            envelope_shape: 0,
+            clock_divider: 0,
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+            noise_timer: 0,
+            noise_lfsr: 0x0001,
+            envelope_timer: 0,
+            envelope_step: 0,
+            is_envelope_attack: false,
+            is_envelope_holding: true,
+        }
+    }
+}
+
+impl Sunsoft5b {
+    /// 0xe000: register_selectで選んだregisterに書き込みます
+    fn write(&mut self, data: u8) {
+        match self.register_select {
+            index @ 0x00..=0x05 => {
+                let ch = usize::from(index >> 1);
+                self.tone_periods[ch] = if (index & 0x01) == 0x00 {
+                    (self.tone_periods[ch] & 0x0f00) | u16::from(data)
+                } else {
+                    (self.tone_periods[ch] & 0x00ff) | (u16::from(data & 0x0f) << 8)
+                };
+            }
+            0x06 => self.noise_period = data & 0x1f,
+            0x07 => self.mixer = data,
+            index @ 0x08..=0x0a => self.volumes[usize::from(index - 0x08)] = data & 0x1f,
+            0x0b => self.envelope_period = (self.envelope_period & 0xff00) | u16::from(data),
+            0x0c => self.envelope_period = (self.envelope_period & 0x00ff) | (u16::from(data) << 8),
+            0x0d => {
+                // 書き込むとenvelopeを最初からやり直す
+                self.envelope_shape = data & 0x0f;
+                self.envelope_timer = 0;
+                self.envelope_step = 0;
+                self.is_envelope_attack = (data & 0x04) == 0x04;
+                self.is_envelope_holding = false;
+            }
+            // 0x0e, 0x0fはI/O portで5Bには繋がっていない
+            _ => {}
+        }
+    }
+    /// 1周期終えたenvelopeをshapeに従って次に進めます
+    fn finish_envelope_cycle(&mut self) {
+        let is_continue = (self.envelope_shape & 0x08) == 0x08;
+        let is_alternate = (self.envelope_shape & 0x02) == 0x02;
+        let is_hold = (self.envelope_shape & 0x01) == 0x01;
+        if !is_continue {
+            // 0で止まる
+            self.is_envelope_attack = false;
+            self.envelope_step = 15;
+            self.is_envelope_holding = true;
+        } else if is_hold {
+            if is_alternate {
+                self.is_envelope_attack = !self.is_envelope_attack;
+            }
+            self.envelope_step = 15;
+            self.is_envelope_holding = true;
+        } else {
+            if is_alternate {
+                self.is_envelope_attack = !self.is_envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+    fn envelope_volume(&self) -> u8 {
+        if self.is_envelope_attack {
+            self.envelope_step
+        } else {
+            15 - self.envelope_step
+        }
+    }
+    /// 分周後のclockごとに呼ぶ
+    fn clock(&mut self) {
+        for ch in 0..3 {
+            self.tone_timers[ch] += 1;
+            if self.tone_timers[ch] >= self.tone_periods[ch] {
+                self.tone_timers[ch] = 0;
+                self.tone_outputs[ch] = !self.tone_outputs[ch];
+            }
+        }
+        // noiseはtoneの半分の速度
+        self.noise_timer += 1;
+        if self.noise_timer >= self.noise_period.max(1) * 2 {
+            self.noise_timer = 0;
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 0x01;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+        // envelopeは1step進めるのに32clock * period
+        if !self.is_envelope_holding {
+            self.envelope_timer += 1;
+            if self.envelope_timer >= u32::from(self.envelope_period.max(1)) * 32 {
+                self.envelope_timer = 0;
+                if self.envelope_step >= 15 {
+                    self.finish_envelope_cycle();
+                } else {
+                    self.envelope_step += 1;
+                }
+            }
+        }
+    }
+    fn step(&mut self, cpu_cyc: usize) {
+        for _ in 0..cpu_cyc {
+            self.clock_divider += 1;
+            if self.clock_divider >= SUNSOFT_5B_CLOCK_DIVIDER {
+                self.clock_divider = 0;
+                self.clock();
+            }
+        }
+    }
+    fn output(&self) -> f32 {
+        let is_noise = (self.noise_lfsr & 0x01) == 0x01;
+        let mut sum = 0.0;
+        for ch in 0..3 {
+            // 無効にしたtone/noiseは常に1として扱う
+            let is_tone_pass = ((self.mixer >> ch) & 0x01) == 0x01 || self.tone_outputs[ch];
+            let is_noise_pass = ((self.mixer >> (ch + 3)) & 0x01) == 0x01 || is_noise;
+            if is_tone_pass && is_noise_pass {
+                let volume = if (self.volumes[ch] & 0x10) == 0x10 {
+                    self.envelope_volume()
+                } else {
+                    self.volumes[ch] & 0x0f
+                };
+                sum += SUNSOFT_5B_VOLUME_TABLE[usize::from(volume)];
+            }
+        }
+        sum * SUNSOFT_5B_CHANNEL_LEVEL
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register_select);
+        for period in self.tone_periods.iter() {
+            writer.write_u16(*period);
+        }
+        writer.write_u8(self.noise_period);
+        writer.write_u8(self.mixer);
+        writer.write_bytes(&self.volumes);
+        writer.write_u16(self.envelope_period);
+        writer.write_u8(self.envelope_shape);
+        writer.write_u8(self.clock_divider);
+        for (timer, output) in self.tone_timers.iter().zip(self.tone_outputs.iter()) {
+            writer.write_u16(*timer);
+            writer.write_bool(*output);
+        }
+        writer.write_u8(self.noise_timer);
+        writer.write_u32(self.noise_lfsr);
+        writer.write_u32(self.envelope_timer);
+        writer.write_u8(self.envelope_step);
+        writer.write_bool(self.is_envelope_attack);
+        writer.write_bool(self.is_envelope_holding);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.register_select = reader.read_u8();
+        for period in self.tone_periods.iter_mut() {
+            *period = reader.read_u16();
+        }
+        self.noise_period = reader.read_u8();
+        self.mixer = reader.read_u8();
+        reader.read_bytes(&mut self.volumes);
+        self.envelope_period = reader.read_u16();
+        self.envelope_shape = reader.read_u8();
+        self.clock_divider = reader.read_u8();
+        for (timer, output) in self
+            .tone_timers
+            .iter_mut()
+            .zip(self.tone_outputs.iter_mut())
+        {
+            *timer = reader.read_u16();
+            *output = reader.read_bool();
+        }
+        self.noise_timer = reader.read_u8();
+        self.noise_lfsr = reader.read_u32();
+        self.envelope_timer = reader.read_u32();
+        self.envelope_step = reader.read_u8();
+        self.is_envelope_attack = reader.read_bool();
+        self.is_envelope_holding = reader.read_bool();
+    }
+}
+
+/// Mapper69: Sunsoft FME-7/5A/5B
+/// 8KB PRG bank * 4(0x6000はRAM/ROM切り替え), 1KB CHR bank * 8, 16bit CPU cycle IRQ counter
+/// 5Bは拡張音源を持つ
+/// https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+#[derive(Clone, Default)]
+pub struct Fme7 {
+    /// 0x8000 - 0x9fff: 次に0xa000で書き換えるcommand
+    pub command: u8,
+    /// command 0x0 - 0x7: 1KB CHR bank
+    pub chr_banks: [u8; 8],
+    /// command 0x8: 0x6000のPRG bank
+    /// ERBB_BBBB
+    /// E - RAM enable
+    /// R - 1ならRAM, 0ならROM
+    /// B - ROMのbank
+    pub prg_bank_6000: u8,
+    /// command 0x9 - 0xb: 0x8000, 0xa000, 0xc000の8KB PRG bank
+    pub prg_banks: [u8; 3],
+    /// command 0xc: mirroring
+    pub mirroring: u8,
+    /// command 0xd: C000_000I
+    /// C - counter enable
+    /// I - IRQ enable
+    pub irq_control: u8,
+    /// command 0xe, 0xf: 16bit counter
+    pub irq_counter: u16,
+    pub is_irq_pending: bool,
+    pub audio: Sunsoft5b,
+}
+
+impl Fme7 {
+    fn write_command(&mut self, data: u8) {
+        match self.command {
+            index @ 0x0..=0x7 => self.chr_banks[usize::from(index)] = data,
+            0x8 => self.prg_bank_6000 = data,
+            index @ 0x9..=0xb => self.prg_banks[usize::from(index - 0x9)] = data,
+            0xc => self.mirroring = data & 0x03,
+            0xd => {
+                // 書き込むとIRQを取り下げる
+                self.irq_control = data;
+                self.is_irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | u16::from(data),
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (u16::from(data) << 8),
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            let is_ram = (self.prg_bank_6000 & 0x40) == 0x40;
+            let is_ram_enable = (self.prg_bank_6000 & 0x80) == 0x80;
+            match (is_ram, is_ram_enable) {
+                (true, true) => mem.read_battery_packed_ram(addr),
+                // open bus
+                (true, false) => (addr >> 8) as u8,
+                (false, _) => mem.read_prg_rom(
+                    0x2000,
+                    usize::from(self.prg_bank_6000 & 0x3f),
+                    usize::from(addr & 0x1fff),
+                ),
+            }
+        } else {
+            let bank = match addr {
+                0x8000..=0x9fff => usize::from(self.prg_banks[0] & 0x3f),
+                0xa000..=0xbfff => usize::from(self.prg_banks[1] & 0x3f),
+                0xc000..=0xdfff => usize::from(self.prg_banks[2] & 0x3f),
+                _ => core::cmp::max(1, mem.prg_rom_bytes / 0x2000) - 1,
+            };
+            mem.read_prg_rom(0x2000, bank, usize::from(addr & 0x1fff))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if (self.prg_bank_6000 & 0xc0) == 0xc0 {
+                mem.write_battery_packed_ram(addr, data);
+            }
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        match addr {
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_command(data),
+            0xc000..=0xdfff => self.audio.register_select = data & 0x0f,
+            _ => self.audio.write(data),
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        mem.read_chr(0x0400, bank, usize::from(addr & 0x03ff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        mem.write_chr(0x0400, bank, usize::from(addr & 0x03ff), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        Some(match self.mirroring {
+            0 => NameTableMirror::Vertical,
+            1 => NameTableMirror::Horizontal,
+            2 => NameTableMirror::SingleScreenLower,
+            _ => NameTableMirror::SingleScreenUpper,
+        })
+    }
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+    /// counterはCPU cycleごとにdecrementし、0xffffにwrapしたらIRQ
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, cpu_cyc: usize) {
+        if (self.irq_control & 0x80) == 0x80 {
+            let cyc = cpu_cyc as u32;
+            if cyc > u32::from(self.irq_counter) && (self.irq_control & 0x01) == 0x01 {
+                self.is_irq_pending = true;
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(cyc as u16);
+        }
+        self.audio.step(cpu_cyc);
+    }
+    fn expansion_audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.command);
+        writer.write_bytes(&self.chr_banks);
+        writer.write_u8(self.prg_bank_6000);
+        writer.write_bytes(&self.prg_banks);
+        writer.write_u8(self.mirroring);
+        writer.write_u8(self.irq_control);
+        writer.write_u16(self.irq_counter);
+        writer.write_bool(self.is_irq_pending);
+        self.audio.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.command = reader.read_u8();
+        reader.read_bytes(&mut self.chr_banks);
+        self.prg_bank_6000 = reader.read_u8();
+        reader.read_bytes(&mut self.prg_banks);
+        self.mirroring = reader.read_u8();
+        self.irq_control = reader.read_u8();
+        self.irq_counter = reader.read_u16();
+        self.is_irq_pending = reader.read_bool();
+        self.audio.load_state(reader);
+    }
+}
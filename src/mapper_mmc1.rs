@@ -0,0 +1,190 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// shift registerの初期値、5回書くとbit0に1が降りてくる
+const MMC1_SHIFT_REG_INIT: u8 = 0x10;
+
+/// Mapper1: MMC1 (SxROM)
+/// 0x8000 - 0xffffへのserial書き込みで5bitずつレジスタを設定する
+/// https://wiki.nesdev.com/w/index.php/MMC1
+#[derive(Clone)]
+pub struct Mmc1 {
+    /// 5bit shift register
+    pub shift_reg: u8,
+    /// 0x8000 - 0x9fff: Control
+    /// CPPMM
+    /// C - CHR bank mode(0: 8KB, 1: 4KB*2)
+    /// P - PRG bank mode(0,1: 32KB, 2: 0x8000固定, 3: 0xc000固定)
+    /// M - Mirroring(0: one-screen lower, 1: one-screen upper, 2: vertical, 3: horizontal)
+    pub control: u8,
+    /// 0xa000 - 0xbfff: CHR bank 0
+    pub chr_bank0: u8,
+    /// 0xc000 - 0xdfff: CHR bank 1
+    pub chr_bank1: u8,
+    /// 0xe000 - 0xffff: PRG bank
+    /// RPPPP
+    /// R - PRG-RAM enable(0: enable)
+    /// P - 16KB PRG bank
+    pub prg_bank: u8,
+    /// 直前のserial書き込みから進んだCPU cycle数
+    /// 1なら連続したcycleでの書き込み(Read-Modify-Write命令の2回書き)なので無視する
+    pub cycles_since_write: usize,
+}
+
+impl Default for Mmc1 {
+    fn default() -> Self {
+        Self {
+            shift_reg: MMC1_SHIFT_REG_INIT,
+            // 電源投入時は最後のbankが0xc000に固定されている
+            control: 0x0c,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+            cycles_since_write: 0,
+        }
+    }
+}
+
+impl Mmc1 {
+    /// 5回書き終わったshift registerをaddrに応じたレジスタに反映します
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9fff => self.control = data,
+            0xa000..=0xbfff => self.chr_bank0 = data,
+            0xc000..=0xdfff => self.chr_bank1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+    fn is_prg_ram_enable(&self) -> bool {
+        (self.prg_bank & 0x10) == 0x00
+    }
+    /// 512KBのSUROMではCHR bankのbit4で256KB単位のPRG bankを選ぶ
+    fn prg_outer_bank(&self, mem: &CassetteMemory) -> usize {
+        if mem.prg_rom_bytes > 0x40000 {
+            usize::from(self.chr_bank0 & 0x10)
+        } else {
+            0
+        }
+    }
+    /// 0x8000 - 0xffffのアドレスから16KB bank番号を求めます
+    fn prg_bank_index(&self, mem: &CassetteMemory, addr: u16) -> usize {
+        let is_upper = addr >= 0xc000;
+        let bank = usize::from(self.prg_bank & 0x0f);
+        let outer = self.prg_outer_bank(mem);
+        let index = match (self.control >> 2) & 0x03 {
+            // 32KB切り替え、下位bitは無視
+            0 | 1 => (bank & 0x0e) | (if is_upper { 1 } else { 0 }),
+            // 0x8000に最初のbankを固定, 0xc000を切り替え
+            2 => {
+                if is_upper {
+                    bank
+                } else {
+                    0
+                }
+            }
+            // 0xc000に最後のbankを固定, 0x8000を切り替え
+            _ => {
+                if is_upper {
+                    0x0f
+                } else {
+                    bank
+                }
+            }
+        };
+        outer | index
+    }
+    /// 0x0000 - 0x1fffのアドレスから4KB bank番号を求めます
+    fn chr_bank_index(&self, addr: u16) -> usize {
+        let is_upper = addr >= 0x1000;
+        if (self.control & 0x10) == 0x10 {
+            // 4KB * 2
+            usize::from(if is_upper {
+                self.chr_bank1
+            } else {
+                self.chr_bank0
+            })
+        } else {
+            // 8KB、下位bitは無視
+            usize::from(self.chr_bank0 & 0x1e) | (if is_upper { 1 } else { 0 })
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_enable() {
+                mem.read_battery_packed_ram(addr)
+            } else {
+                // open bus
+                (addr >> 8) as u8
+            }
+        } else {
+            let bank = self.prg_bank_index(mem, addr);
+            mem.read_prg_rom(0x4000, bank, usize::from(addr & 0x3fff))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_enable() {
+                mem.write_battery_packed_ram(addr, data);
+            }
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        let is_consecutive = self.cycles_since_write == 1;
+        self.cycles_since_write = 0;
+        if is_consecutive {
+            return;
+        }
+        // bit7が立っていたらshift registerをリセットしてPRG bank modeを3にする
+        if (data & 0x80) == 0x80 {
+            self.shift_reg = MMC1_SHIFT_REG_INIT;
+            self.control |= 0x0c;
+            return;
+        }
+        // LSBから順に詰める。初期値の1がbit0まで降りてきたら5回目
+        let is_complete = (self.shift_reg & 0x01) == 0x01;
+        self.shift_reg = (self.shift_reg >> 1) | ((data & 0x01) << 4);
+        if is_complete {
+            let value = self.shift_reg & 0x1f;
+            self.write_register(addr, value);
+            self.shift_reg = MMC1_SHIFT_REG_INIT;
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = self.chr_bank_index(addr);
+        mem.read_chr(0x1000, bank, usize::from(addr & 0x0fff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = self.chr_bank_index(addr);
+        mem.write_chr(0x1000, bank, usize::from(addr & 0x0fff), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        Some(match self.control & 0x03 {
+            0 => NameTableMirror::SingleScreenLower,
+            1 => NameTableMirror::SingleScreenUpper,
+            2 => NameTableMirror::Vertical,
+            _ => NameTableMirror::Horizontal,
+        })
+    }
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, cpu_cyc: usize) {
+        self.cycles_since_write = self.cycles_since_write.saturating_add(cpu_cyc);
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.shift_reg);
+        writer.write_u8(self.control);
+        writer.write_u8(self.chr_bank0);
+        writer.write_u8(self.chr_bank1);
+        writer.write_u8(self.prg_bank);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.shift_reg = reader.read_u8();
+        self.control = reader.read_u8();
+        self.chr_bank0 = reader.read_u8();
+        self.chr_bank1 = reader.read_u8();
+        self.prg_bank = reader.read_u8();
+    }
+}
@@ -0,0 +1,149 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// Mapper9: MMC2 (PxROM), Mapper10: MMC4 (FxROM)
+/// PPUが特定のtile($FD/$FE)をfetchするとlatchが切り替わり、4KB CHR bankが入れ替わる
+/// MMC2は8KB PRG bank + 固定24KB, MMC4は16KB PRG bank + 固定16KBとPRG RAM
+/// https://wiki.nesdev.com/w/index.php/MMC2
+/// https://wiki.nesdev.com/w/index.php/MMC4
+#[derive(Clone)]
+pub struct Mmc2 {
+    /// Mapper10(MMC4)ならtrue
+    pub is_mmc4: bool,
+    /// 0xa000 - 0xafff: PRG bank select
+    pub prg_bank: u8,
+    /// 0xb000 - 0xefff: 4KB CHR bank
+    /// [0x0000/$FD, 0x0000/$FE, 0x1000/$FD, 0x1000/$FE]
+    pub chr_banks: [u8; 4],
+    /// 0x0000, 0x1000それぞれのlatch, $FEを選択していればtrue
+    pub is_latch_fe: [bool; 2],
+    /// 0xf000 - 0xffff: Mirroring、書かれるまではiNES headerに従う
+    pub mirroring: Option<NameTableMirror>,
+}
+
+impl Mmc2 {
+    pub fn new(is_mmc4: bool) -> Mmc2 {
+        Mmc2 {
+            is_mmc4,
+            prg_bank: 0,
+            chr_banks: [0; 4],
+            // 電源投入時のlatchは不定だが、$FE側で始めておく
+            is_latch_fe: [true; 2],
+            mirroring: None,
+        }
+    }
+    /// 0x8000 - 0xffffのアドレスからbankサイズとbank番号を求めます
+    fn prg_bank_index(&self, mem: &CassetteMemory, addr: u16) -> (usize, usize) {
+        if self.is_mmc4 {
+            let num_of_bank = core::cmp::max(1, mem.prg_rom_bytes / 0x4000);
+            let bank = if addr < 0xc000 {
+                usize::from(self.prg_bank & 0x0f)
+            } else {
+                num_of_bank - 1
+            };
+            (0x4000, bank)
+        } else {
+            // 後半24KBは最後の3bankに固定
+            let num_of_bank = core::cmp::max(3, mem.prg_rom_bytes / 0x2000);
+            let bank = match addr {
+                0x8000..=0x9fff => usize::from(self.prg_bank & 0x0f),
+                0xa000..=0xbfff => num_of_bank - 3,
+                0xc000..=0xdfff => num_of_bank - 2,
+                _ => num_of_bank - 1,
+            };
+            (0x2000, bank)
+        }
+    }
+    /// 0x0000 - 0x1fffのアドレスから4KB bank番号を求めます
+    fn chr_bank_index(&self, addr: u16) -> usize {
+        let table = usize::from(addr >> 12) & 0x01;
+        let is_fe = self.is_latch_fe[table];
+        usize::from(self.chr_banks[table * 2 + if is_fe { 1 } else { 0 }] & 0x1f)
+    }
+    /// PPUのfetchを見てlatchを更新します。切り替えはfetchした後に反映される
+    /// MMC2の0x0000側は$0FD8/$0FE8ちょうどのみ反応し、それ以外は8byteの範囲で反応する
+    fn update_latch(&mut self, addr: u16) {
+        let table = usize::from(addr >> 12) & 0x01;
+        let is_range = self.is_mmc4 || table == 1;
+        let tile_addr = if is_range {
+            addr & 0x0ff8
+        } else {
+            addr & 0x0fff
+        };
+        match tile_addr {
+            0x0fd8 => self.is_latch_fe[table] = false,
+            0x0fe8 => self.is_latch_fe[table] = true,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            // PRG RAMはMMC4のみ(MMC2はPlayChoice版以外持たない)
+            if self.is_mmc4 {
+                mem.read_battery_packed_ram(addr)
+            } else {
+                // open bus
+                (addr >> 8) as u8
+            }
+        } else {
+            let (bank_size, bank) = self.prg_bank_index(mem, addr);
+            mem.read_prg_rom(bank_size, bank, usize::from(addr) & (bank_size - 1))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_mmc4 {
+                mem.write_battery_packed_ram(addr, data);
+            }
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        match addr {
+            0xa000..=0xafff => self.prg_bank = data,
+            0xb000..=0xbfff => self.chr_banks[0] = data,
+            0xc000..=0xcfff => self.chr_banks[1] = data,
+            0xd000..=0xdfff => self.chr_banks[2] = data,
+            0xe000..=0xefff => self.chr_banks[3] = data,
+            0xf000..=0xffff => {
+                self.mirroring = Some(if (data & 0x01) == 0x01 {
+                    NameTableMirror::Horizontal
+                } else {
+                    NameTableMirror::Vertical
+                });
+            }
+            _ => {}
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = self.chr_bank_index(addr);
+        let data = mem.read_chr(0x1000, bank, usize::from(addr & 0x0fff));
+        self.update_latch(addr);
+        data
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = self.chr_bank_index(addr);
+        mem.write_chr(0x1000, bank, usize::from(addr & 0x0fff), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        self.mirroring
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank);
+        writer.write_bytes(&self.chr_banks);
+        writer.write_bool(self.is_latch_fe[0]);
+        writer.write_bool(self.is_latch_fe[1]);
+        writer.write_mirror(self.mirroring);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.prg_bank = reader.read_u8();
+        reader.read_bytes(&mut self.chr_banks);
+        self.is_latch_fe[0] = reader.read_bool();
+        self.is_latch_fe[1] = reader.read_bool();
+        self.mirroring = reader.read_mirror();
+    }
+}
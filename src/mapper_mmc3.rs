@@ -0,0 +1,204 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// Mapper4: MMC3 (TxROM)
+/// 8KB PRG bank * 4, 1KB/2KB CHR bank * 6, scanline counterによるIRQ
+/// https://wiki.nesdev.com/w/index.php/MMC3
+#[derive(Clone)]
+pub struct Mmc3 {
+    /// 0x8000(even): Bank select
+    /// CPxx_xRRR
+    /// C - CHR A12 inversion
+    /// P - PRG ROM bank mode
+    /// R - 次に0x8001で書き換えるbank register
+    pub bank_select: u8,
+    /// 0x8001(odd): R0 ~ R7
+    pub bank_regs: [u8; 8],
+    /// 0xa000(even): Mirroring、書かれるまではiNES headerに従う
+    pub mirroring: Option<NameTableMirror>,
+    /// 0xa001(odd): PRG RAM protect
+    /// EWxx_xxxx
+    /// E - PRG RAM enable
+    /// W - write protect
+    pub prg_ram_protect: u8,
+
+    /// 0xc000(even): IRQ latch
+    pub irq_latch: u8,
+    /// 0xc001(odd)で立てて、次のscanlineでlatchの値をreloadする
+    pub is_irq_reload: bool,
+    /// 0xe000(even)/0xe001(odd): IRQ disable/enable
+    pub is_irq_enable: bool,
+    /// scanline counter
+    pub irq_counter: u8,
+    /// IRQ出力, 0xe000に書かれるまで保持する
+    pub is_irq_pending: bool,
+}
+
+impl Default for Mmc3 {
+    fn default() -> Self {
+        Self {
+            bank_select: 0,
+            bank_regs: [0; 8],
+            mirroring: None,
+            // 0xa001を書かないタイトルもあるのでRAMは有効にしておく
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            is_irq_reload: false,
+            is_irq_enable: false,
+            irq_counter: 0,
+            is_irq_pending: false,
+        }
+    }
+}
+
+impl Mmc3 {
+    fn is_prg_ram_enable(&self) -> bool {
+        (self.prg_ram_protect & 0x80) == 0x80
+    }
+    fn is_prg_ram_writable(&self) -> bool {
+        self.is_prg_ram_enable() && ((self.prg_ram_protect & 0x40) == 0x00)
+    }
+    /// 0x8000 - 0xffffのアドレスから8KB bank番号を求めます
+    fn prg_bank_index(&self, mem: &CassetteMemory, addr: u16) -> usize {
+        let num_of_bank = core::cmp::max(1, mem.prg_rom_bytes / 0x2000);
+        let second_last = num_of_bank.saturating_sub(2);
+        let last = num_of_bank - 1;
+        let r6 = usize::from(self.bank_regs[6] & 0x3f);
+        let r7 = usize::from(self.bank_regs[7] & 0x3f);
+        let is_swap = (self.bank_select & 0x40) == 0x40;
+        match (addr >> 13) & 0x03 {
+            0 => {
+                if is_swap {
+                    second_last
+                } else {
+                    r6
+                }
+            }
+            1 => r7,
+            2 => {
+                if is_swap {
+                    r6
+                } else {
+                    second_last
+                }
+            }
+            _ => last,
+        }
+    }
+    /// 0x0000 - 0x1fffのアドレスから1KB bank番号を求めます
+    fn chr_bank_index(&self, addr: u16) -> usize {
+        // A12 inversionが有効なら前後半を入れ替えて考える
+        let is_invert = (self.bank_select & 0x80) == 0x80;
+        let target = if is_invert { addr ^ 0x1000 } else { addr };
+        let slot = usize::from(target >> 10) & 0x07;
+        match slot {
+            // R0, R1は2KB bank(下位bitは無視)
+            0 | 1 => usize::from(self.bank_regs[0] & 0xfe) | (slot & 0x01),
+            2 | 3 => usize::from(self.bank_regs[1] & 0xfe) | (slot & 0x01),
+            // R2 ~ R5は1KB bank
+            _ => usize::from(self.bank_regs[slot - 2]),
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_enable() {
+                mem.read_battery_packed_ram(addr)
+            } else {
+                // open bus
+                (addr >> 8) as u8
+            }
+        } else {
+            let bank = self.prg_bank_index(mem, addr);
+            mem.read_prg_rom(0x2000, bank, usize::from(addr & 0x1fff))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_writable() {
+                mem.write_battery_packed_ram(addr, data);
+            }
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        let is_even = (addr & 0x01) == 0x00;
+        match (addr, is_even) {
+            (0x8000..=0x9fff, true) => self.bank_select = data,
+            (0x8000..=0x9fff, false) => {
+                let index = usize::from(self.bank_select & 0x07);
+                self.bank_regs[index] = data;
+            }
+            (0xa000..=0xbfff, true) => {
+                self.mirroring = Some(if (data & 0x01) == 0x01 {
+                    NameTableMirror::Horizontal
+                } else {
+                    NameTableMirror::Vertical
+                });
+            }
+            (0xa000..=0xbfff, false) => self.prg_ram_protect = data,
+            (0xc000..=0xdfff, true) => self.irq_latch = data,
+            (0xc000..=0xdfff, false) => {
+                self.irq_counter = 0;
+                self.is_irq_reload = true;
+            }
+            (_, true) => {
+                // disableと同時にpendingしているIRQも取り下げる
+                self.is_irq_enable = false;
+                self.is_irq_pending = false;
+            }
+            (_, false) => self.is_irq_enable = true,
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = self.chr_bank_index(addr);
+        mem.read_chr(0x0400, bank, usize::from(addr & 0x03ff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = self.chr_bank_index(addr);
+        mem.write_chr(0x0400, bank, usize::from(addr & 0x03ff), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        self.mirroring
+    }
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+    /// counterが0ならreload, それ以外はdecrementして0になったらIRQ
+    fn notify_scanline(&mut self) {
+        if self.irq_counter == 0 || self.is_irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.is_irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.is_irq_enable {
+            self.is_irq_pending = true;
+        }
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bank_select);
+        writer.write_bytes(&self.bank_regs);
+        writer.write_mirror(self.mirroring);
+        writer.write_u8(self.prg_ram_protect);
+        writer.write_u8(self.irq_latch);
+        writer.write_bool(self.is_irq_reload);
+        writer.write_bool(self.is_irq_enable);
+        writer.write_u8(self.irq_counter);
+        writer.write_bool(self.is_irq_pending);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.bank_select = reader.read_u8();
+        reader.read_bytes(&mut self.bank_regs);
+        self.mirroring = reader.read_mirror();
+        self.prg_ram_protect = reader.read_u8();
+        self.irq_latch = reader.read_u8();
+        self.is_irq_reload = reader.read_bool();
+        self.is_irq_enable = reader.read_bool();
+        self.irq_counter = reader.read_u8();
+        self.is_irq_pending = reader.read_bool();
+    }
+}
@@ -0,0 +1,743 @@
+use super::cassette::*;
+use super::mapper::*;
+use super::video_system::*;
+
+/// 拡張音源pulseの1step分の出力レベル(APUのpulse 1step分と同程度)
+pub const MMC5_PULSE_LEVEL_PER_STEP: f32 = 0.00752;
+/// 拡張音源PCMの1step分の出力レベル
+pub const MMC5_PCM_LEVEL_PER_STEP: f32 = 0.0012;
+/// envelopeとlength counterを進める周期(240Hz相当)
+pub const MMC5_FRAME_CPU_CYCLE: u16 = 7457;
+/// 画面分割で使うscroll値の周期(30tile)
+pub const MMC5_SPLIT_SCROLL_HEIGHT: u16 = 240;
+/// 描画されるscanline数、これを超えたらframe外
+pub const MMC5_VISIBLE_SCANLINES: u8 = 240;
+
+/// APUと同じlength counterのテーブル
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+/// APUと同じpulseのduty
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// MMC5 pulse channel, sweepがない以外はAPUのpulseと同じ
+#[derive(Clone, Default)]
+pub struct Mmc5Pulse {
+    /// 0x5000/0x5004: DDLC_VVVV
+    /// D - duty
+    /// L - length counter halt/envelope loop
+    /// C - constant volume
+    /// V - volume/envelope period
+    pub control: u8,
+    /// 0x5002/0x5006, 0x5003/0x5007: 11bit period
+    pub period: u16,
+    /// 0x5015で有効化されている
+    pub is_enable: bool,
+    pub length_counter: u8,
+    pub timer: u16,
+    pub duty_step: u8,
+    pub is_envelope_start: bool,
+    pub envelope_divider: u8,
+    pub envelope_decay: u8,
+}
+
+impl Mmc5Pulse {
+    fn write(&mut self, index: u16, data: u8) {
+        match index {
+            0 => self.control = data,
+            1 => {} // sweepはない
+            2 => self.period = (self.period & 0x0700) | u16::from(data),
+            _ => {
+                self.period = (self.period & 0x00ff) | (u16::from(data & 0x07) << 8);
+                if self.is_enable {
+                    self.length_counter = LENGTH_TABLE[usize::from(data >> 3)];
+                }
+                self.duty_step = 0;
+                self.is_envelope_start = true;
+            }
+        }
+    }
+    fn write_enable(&mut self, is_enable: bool) {
+        self.is_enable = is_enable;
+        if !is_enable {
+            self.length_counter = 0;
+        }
+    }
+    fn is_halt(&self) -> bool {
+        (self.control & 0x20) == 0x20
+    }
+    /// APU cycle(CPU 2cycle)ごとに呼ぶ
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    /// envelopeとlength counterを進める
+    fn clock_frame(&mut self) {
+        if self.is_envelope_start {
+            self.is_envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = self.control & 0x0f;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.control & 0x0f;
+            if self.envelope_decay > 0 {
+                self.envelope_decay -= 1;
+            } else if self.is_halt() {
+                self.envelope_decay = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+        if self.length_counter > 0 && !self.is_halt() {
+            self.length_counter -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        let duty = usize::from(self.control >> 6);
+        if self.length_counter == 0 || DUTY_TABLE[duty][usize::from(self.duty_step)] == 0 {
+            0
+        } else if (self.control & 0x10) == 0x10 {
+            self.control & 0x0f
+        } else {
+            self.envelope_decay
+        }
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.control);
+        writer.write_u16(self.period);
+        writer.write_bool(self.is_enable);
+        writer.write_u8(self.length_counter);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.duty_step);
+        writer.write_bool(self.is_envelope_start);
+        writer.write_u8(self.envelope_divider);
+        writer.write_u8(self.envelope_decay);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.control = reader.read_u8();
+        self.period = reader.read_u16();
+        self.is_enable = reader.read_bool();
+        self.length_counter = reader.read_u8();
+        self.timer = reader.read_u16();
+        self.duty_step = reader.read_u8();
+        self.is_envelope_start = reader.read_bool();
+        self.envelope_divider = reader.read_u8();
+        self.envelope_decay = reader.read_u8();
+    }
+}
+
+/// BGの1tile分のfetchをどう差し替えるか
+/// nametableのfetchで決まり、続くattribute, patternのfetchで使う
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mmc5TileFetch {
+    /// 通常通りbank registerに従う
+    Normal,
+    /// ExRAM mode1: ExRAMの値でpaletteと4KB CHR bankを選ぶ
+    ExtendedAttribute(u8),
+    /// 画面分割の領域, ExRAMをnametableとして使う
+    Split { column: u8, split_y: u8 },
+}
+
+/// Mapper5: MMC5 (ExROM)
+/// 8KB ~ 32KB PRG bank, 1KB ~ 8KB CHR bank(BG/Sprite別), 1KB ExRAM, 画面分割, scanline IRQ,
+/// 8bit乗算器, 拡張音源(pulse * 2, PCM)
+/// ExRAMの実体はカセット上のNameTable用VRAMの先頭1KBを使う
+/// https://wiki.nesdev.com/w/index.php/MMC5
+#[derive(Clone)]
+pub struct Mmc5 {
+    /// 0x5100: PRG mode(0: 32KB, 1: 16KB, 2: 16KB + 8KB, 3: 8KB)
+    pub prg_mode: u8,
+    /// 0x5101: CHR mode(0: 8KB, 1: 4KB, 2: 2KB, 3: 1KB)
+    pub chr_mode: u8,
+    /// 0x5102, 0x5103: PRG RAM protect, 0x02, 0x01の時だけ書き込める
+    pub prg_ram_protect: [u8; 2],
+    /// 0x5104: ExRAM mode
+    /// 0 - nametable, 1 - 拡張属性, 2 - CPUから読み書き, 3 - CPUから読み出しのみ
+    pub exram_mode: u8,
+    /// 0x5105: nametable mapping, 2bitずつslot0から
+    /// 0 - CIRAM A, 1 - CIRAM B, 2 - ExRAM, 3 - fill-mode
+    pub nametable_mapping: u8,
+    /// 0x5106: fill-modeのtile
+    pub fill_tile: u8,
+    /// 0x5107: fill-modeのattribute(下位2bit)
+    pub fill_attr: u8,
+    /// 0x5113 - 0x5117: PRG bank
+    /// 0x5113は0x6000 - 0x7fffのPRG RAM bank, 0x5114 - 0x5116はbit7が0ならPRG RAMを割り当てる
+    pub prg_banks: [u8; 5],
+    /// 0x5120 - 0x5127: Sprite(8x8 spriteの場合はBGも)向けCHR bank, 0x5130の上位bit込み
+    pub chr_banks_sprite: [u16; 8],
+    /// 0x5128 - 0x512b: 8x16 sprite時のBG向けCHR bank, 0x5130の上位bit込み
+    pub chr_banks_bg: [u16; 4],
+    /// 最後に書いたのが0x5128 - 0x512bならtrue
+    pub is_last_write_bg_bank: bool,
+    /// 0x5130: CHR bankの上位2bit
+    pub chr_upper: u8,
+
+    /// 0x5200: 画面分割
+    /// ESxT_TTTT
+    /// E - enable
+    /// S - 0なら左側, 1なら右側を分割領域にする
+    /// T - 分割位置のtile
+    pub split_control: u8,
+    /// 0x5201: 分割領域のy scroll
+    pub split_scroll: u8,
+    /// 0x5202: 分割領域の4KB CHR bank
+    pub split_bank: u8,
+
+    /// 0x5203: IRQを発生させるscanline
+    pub irq_target: u8,
+    /// 0x5204(write): bit7
+    pub is_irq_enable: bool,
+    /// 0x5204(read): bit7, 読み出すと下ろす
+    pub is_irq_pending: bool,
+    /// 0x5204(read): bit6, 描画中ならtrue
+    pub is_in_frame: bool,
+    /// 現在のscanline
+    pub scanline: u8,
+
+    /// 0x5205, 0x5206: 乗算器
+    pub multiplicand: u8,
+    pub multiplier: u8,
+
+    /// $2000 bit5を監視して得た8x16 spriteの有効状態
+    pub is_large_sprite: bool,
+    /// PPUがfetchしている対象、描画中だけ有効なのでsave stateしない
+    pub fetch_phase: PpuFetchPhase,
+    /// BGをfetchしたtile数
+    pub fetch_tile_count: u8,
+    /// fetch中のBG tileの差し替え方法
+    pub fetch_tile: Mmc5TileFetch,
+
+    pub pulse1: Mmc5Pulse,
+    pub pulse2: Mmc5Pulse,
+    /// 0x5010(write): PCM mode/IRQ
+    /// Ixxx_xxxM
+    /// I - IRQ enable
+    /// M - 0: write mode, 1: read mode
+    pub pcm_control: u8,
+    /// 0x5011: PCM出力
+    pub pcm_output: u8,
+    /// 0x5010(read): bit7, read modeで0を読んだら立ち、読み出すと下ろす
+    pub is_pcm_irq_pending: bool,
+    /// envelopeとlength counterを進めるためのcycle数
+    pub frame_cycles: u16,
+    /// pulseのtimerはCPU 2cycleで1回進める
+    pub is_odd_cycle: bool,
+}
+
+impl Default for Mmc5 {
+    fn default() -> Self {
+        Self {
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            // 起動直後は0x5117が0xffで、最後のbankがリセットベクタに見える
+            prg_banks: [0, 0x80, 0x80, 0x80, 0xff],
+            chr_banks_sprite: [0; 8],
+            chr_banks_bg: [0; 4],
+            is_last_write_bg_bank: false,
+            chr_upper: 0,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_target: 0,
+            is_irq_enable: false,
+            is_irq_pending: false,
+            is_in_frame: false,
+            scanline: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+            is_large_sprite: false,
+            fetch_phase: PpuFetchPhase::Idle,
+            fetch_tile_count: 0,
+            fetch_tile: Mmc5TileFetch::Normal,
+            pulse1: Mmc5Pulse::default(),
+            pulse2: Mmc5Pulse::default(),
+            pcm_control: 0,
+            pcm_output: 0,
+            is_pcm_irq_pending: false,
+            frame_cycles: 0,
+            is_odd_cycle: false,
+        }
+    }
+}
+
+impl Mmc5 {
+    fn is_prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] == 0x02 && self.prg_ram_protect[1] == 0x01
+    }
+    /// 0x8000 - 0xffffのアドレスから(RAMならtrue, 8KB bank番号)を求めます
+    fn prg_bank_index(&self, addr: u16) -> (bool, usize) {
+        let slot = usize::from((addr >> 13) & 0x03);
+        // (bank register, 8KB bank何個分のbankか)
+        let (reg_index, num_of_slot) = match (self.prg_mode & 0x03, slot) {
+            (0, _) => (4, 4),
+            (1, 0) | (1, 1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0) | (2, 1) => (2, 2),
+            (2, 2) => (3, 1),
+            (2, _) => (4, 1),
+            (_, _) => (1 + slot, 1),
+        };
+        let reg = self.prg_banks[reg_index];
+        // 0x5117はROM固定
+        let is_ram = reg_index != 4 && (reg & 0x80) == 0x00;
+        let bank = (usize::from(reg & 0x7f) & !(num_of_slot - 1)) | (slot & (num_of_slot - 1));
+        (is_ram, bank)
+    }
+    /// PRG RAMのbank registerの値から、カセット内RAMの8KB bank番号を求めます
+    /// bit2はchip selectで、8KB * 2chipの構成では2つ目のchipを選ぶ。それ以外のサイズはwrapさせる
+    fn prg_ram_bank_index(&self, mem: &CassetteMemory, bank: usize) -> usize {
+        let bank = bank & 0x07;
+        if mem.battery_packed_ram.len() == 2 * BATTERY_PACKED_RAM_SIZE {
+            bank >> 2
+        } else {
+            bank
+        }
+    }
+    /// read modeでは0x8000 - 0xbfffから読んだ値がPCMの出力になり、0を読んだらIRQを立てる
+    fn feed_pcm(&mut self, data: u8) {
+        if (self.pcm_control & 0x01) == 0x00 {
+            return;
+        }
+        if data == 0 {
+            self.is_pcm_irq_pending = true;
+        } else {
+            self.pcm_output = data;
+        }
+    }
+    /// 0x0000 - 0x1fffのアドレスから1KB bank番号を求めます
+    fn chr_bank_index(&self, addr: u16) -> usize {
+        let slot = usize::from(addr >> 10) & 0x07;
+        // 8x16 spriteの場合はBGとSpriteで別のbankを使う、$2007からは最後に書いた方
+        // 8x8 spriteの場合は0x5120 - 0x5127だけを使う
+        let is_bg = self.is_large_sprite
+            && match self.fetch_phase {
+                PpuFetchPhase::Background => true,
+                PpuFetchPhase::Sprite => false,
+                PpuFetchPhase::Idle => self.is_last_write_bg_bank,
+            };
+        if is_bg {
+            let regs = &self.chr_banks_bg;
+            match self.chr_mode & 0x03 {
+                0 => usize::from(regs[3]) * 8 + slot,
+                1 => usize::from(regs[3]) * 4 + (slot & 0x03),
+                2 => usize::from(regs[1 | (slot & 0x02)]) * 2 + (slot & 0x01),
+                _ => usize::from(regs[slot & 0x03]),
+            }
+        } else {
+            let regs = &self.chr_banks_sprite;
+            match self.chr_mode & 0x03 {
+                0 => usize::from(regs[7]) * 8 + slot,
+                1 => usize::from(regs[3 | (slot & 0x04)]) * 4 + (slot & 0x03),
+                2 => usize::from(regs[1 | (slot & 0x06)]) * 2 + (slot & 0x01),
+                _ => usize::from(regs[slot]),
+            }
+        }
+    }
+    /// ExRAMをnametableや拡張属性として使うmodeならtrue
+    fn is_exram_for_ppu(&self) -> bool {
+        self.exram_mode <= 1
+    }
+    /// BGの`column`番目のtileが画面分割の領域ならtrue
+    fn is_split_column(&self, column: u8) -> bool {
+        if (self.split_control & 0x80) == 0x00 || !self.is_exram_for_ppu() {
+            return false;
+        }
+        let split_tile = self.split_control & 0x1f;
+        if (self.split_control & 0x40) == 0x40 {
+            column >= split_tile
+        } else {
+            column < split_tile
+        }
+    }
+    /// 分割領域で使うy座標
+    fn split_y(&self) -> u8 {
+        ((u16::from(self.split_scroll) + u16::from(self.scanline)) % MMC5_SPLIT_SCROLL_HEIGHT) as u8
+    }
+    /// 0x5c00 - 0x5fff: ExRAMの読み出し
+    fn read_exram(&self, mem: &CassetteMemory, addr: u16) -> Option<u8> {
+        if self.is_exram_for_ppu() {
+            // open bus
+            None
+        } else {
+            Some(mem.read_nametable_ram(0, usize::from(addr & 0x03ff)))
+        }
+    }
+    /// 0x5c00 - 0x5fff: ExRAMへの書き込み
+    fn write_exram(&self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let offset = usize::from(addr & 0x03ff);
+        match self.exram_mode {
+            // 描画中以外は0が書かれる
+            0 | 1 => mem.write_nametable_ram(0, offset, if self.is_in_frame { data } else { 0 }),
+            2 => mem.write_nametable_ram(0, offset, data),
+            _ => {}
+        }
+    }
+    /// 0x5015の読み出し
+    fn read_audio_status(&self) -> u8 {
+        (if self.pulse1.length_counter > 0 {
+            0x01
+        } else {
+            0x00
+        }) | (if self.pulse2.length_counter > 0 {
+            0x02
+        } else {
+            0x00
+        })
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, is_nondestructive: bool) -> u8 {
+        let offset = usize::from(addr & 0x1fff);
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            let bank = self.prg_ram_bank_index(mem, usize::from(self.prg_banks[0]));
+            mem.read_battery_packed_ram_bank(bank, offset)
+        } else {
+            let data = match self.prg_bank_index(addr) {
+                (true, bank) => {
+                    let bank = self.prg_ram_bank_index(mem, bank);
+                    mem.read_battery_packed_ram_bank(bank, offset)
+                }
+                (false, bank) => mem.read_prg_rom(0x2000, bank, offset),
+            };
+            if !is_nondestructive && addr < 0xc000 {
+                self.feed_pcm(data);
+            }
+            data
+        }
+    }
+    fn write_u8(
+        &mut self,
+        mem: &mut CassetteMemory,
+        addr: u16,
+        data: u8,
+        _is_nondestructive: bool,
+    ) {
+        if !self.is_prg_ram_writable() {
+            return;
+        }
+        let offset = usize::from(addr & 0x1fff);
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            let bank = self.prg_ram_bank_index(mem, usize::from(self.prg_banks[0]));
+            mem.write_battery_packed_ram_bank(bank, offset, data);
+        } else if let (true, bank) = self.prg_bank_index(addr) {
+            let bank = self.prg_ram_bank_index(mem, bank);
+            mem.write_battery_packed_ram_bank(bank, offset, data);
+        }
+    }
+    fn read_expansion_u8(
+        &mut self,
+        mem: &mut CassetteMemory,
+        addr: u16,
+        is_nondestructive: bool,
+    ) -> Option<u8> {
+        match addr {
+            0x5010 => {
+                let data =
+                    (if self.is_pcm_irq_pending { 0x80 } else { 0x00 }) | (self.pcm_control & 0x01);
+                if !is_nondestructive {
+                    self.is_pcm_irq_pending = false;
+                }
+                Some(data)
+            }
+            0x5015 => Some(self.read_audio_status()),
+            0x5204 => {
+                let data = (if self.is_irq_pending { 0x80 } else { 0x00 })
+                    | (if self.is_in_frame { 0x40 } else { 0x00 });
+                if !is_nondestructive {
+                    self.is_irq_pending = false;
+                }
+                Some(data)
+            }
+            0x5205 => Some((u16::from(self.multiplicand) * u16::from(self.multiplier)) as u8),
+            0x5206 => {
+                Some(((u16::from(self.multiplicand) * u16::from(self.multiplier)) >> 8) as u8)
+            }
+            0x5c00..=0x5fff => self.read_exram(mem, addr),
+            _ => None,
+        }
+    }
+    fn write_expansion_u8(
+        &mut self,
+        mem: &mut CassetteMemory,
+        addr: u16,
+        data: u8,
+        is_nondestructive: bool,
+    ) {
+        if is_nondestructive {
+            return;
+        }
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write(addr & 0x03, data),
+            0x5004..=0x5007 => self.pulse2.write(addr & 0x03, data),
+            0x5010 => self.pcm_control = data,
+            // 0を書いた場合は無視される(read modeのIRQ用), read modeでは書き込みも無視
+            0x5011 if data != 0 && (self.pcm_control & 0x01) == 0x00 => self.pcm_output = data,
+            0x5015 => {
+                self.pulse1.write_enable((data & 0x01) == 0x01);
+                self.pulse2.write_enable((data & 0x02) == 0x02);
+            }
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attr = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[usize::from(addr - 0x5113)] = data,
+            0x5120..=0x5127 => {
+                let bank = (u16::from(self.chr_upper) << 8) | u16::from(data);
+                self.chr_banks_sprite[usize::from(addr - 0x5120)] = bank;
+                self.is_last_write_bg_bank = false;
+            }
+            0x5128..=0x512b => {
+                let bank = (u16::from(self.chr_upper) << 8) | u16::from(data);
+                self.chr_banks_bg[usize::from(addr - 0x5128)] = bank;
+                self.is_last_write_bg_bank = true;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_target = data,
+            0x5204 => self.is_irq_enable = (data & 0x80) == 0x80,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5c00..=0x5fff => self.write_exram(mem, addr, data),
+            _ => {}
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        if self.fetch_phase == PpuFetchPhase::Background {
+            match self.fetch_tile {
+                // 分割領域はtileのy位置も差し替える
+                Mmc5TileFetch::Split { split_y, .. } => {
+                    let offset = (addr & 0x0ff8) | u16::from(split_y & 0x07);
+                    return mem.read_chr(0x1000, usize::from(self.split_bank), usize::from(offset));
+                }
+                Mmc5TileFetch::ExtendedAttribute(ex) => {
+                    let bank = usize::from(ex & 0x3f) | (usize::from(self.chr_upper) << 6);
+                    return mem.read_chr(0x1000, bank, usize::from(addr & 0x0fff));
+                }
+                Mmc5TileFetch::Normal => {}
+            }
+        }
+        let bank = self.chr_bank_index(addr);
+        mem.read_chr(0x0400, bank, usize::from(addr & 0x03ff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = self.chr_bank_index(addr);
+        mem.write_chr(0x0400, bank, usize::from(addr & 0x03ff), data);
+    }
+    fn nametable_source(&self, slot: usize) -> Option<NameTableSource> {
+        Some(match (self.nametable_mapping >> (slot * 2)) & 0x03 {
+            0 => NameTableSource::Ciram(0),
+            1 => NameTableSource::Ciram(1),
+            _ => NameTableSource::Mapper,
+        })
+    }
+    fn read_nametable_u8(&mut self, mem: &mut CassetteMemory, slot: usize, offset: usize) -> u8 {
+        match (self.nametable_mapping >> (slot * 2)) & 0x03 {
+            2 if self.is_exram_for_ppu() => mem.read_nametable_ram(0, offset),
+            // fill-mode: attributeは4箇所同じ値にする
+            3 if offset < usize::from(ATTRIBUTE_TABLE_OFFSET) => self.fill_tile,
+            3 => self.fill_attr * 0x55,
+            _ => 0,
+        }
+    }
+    fn write_nametable_u8(
+        &mut self,
+        mem: &mut CassetteMemory,
+        slot: usize,
+        offset: usize,
+        data: u8,
+    ) {
+        if ((self.nametable_mapping >> (slot * 2)) & 0x03) == 2 && self.is_exram_for_ppu() {
+            mem.write_nametable_ram(0, offset, data);
+        }
+    }
+    /// BGのfetch中はtileごとに拡張属性と画面分割を反映する
+    /// nametable -> attribute -> patternの順でfetchされる前提
+    fn filter_nametable_read(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) -> u8 {
+        if self.fetch_phase != PpuFetchPhase::Background {
+            return data;
+        }
+        let offset = addr & 0x03ff;
+        if offset < ATTRIBUTE_TABLE_OFFSET {
+            // nametable: 次のtileに進んだ
+            let column = self.fetch_tile_count;
+            self.fetch_tile_count = self.fetch_tile_count.wrapping_add(1);
+            if self.is_split_column(column) {
+                let split_y = self.split_y();
+                self.fetch_tile = Mmc5TileFetch::Split { column, split_y };
+                let index = (usize::from(split_y) >> 3) * 32 + (usize::from(column) & 0x1f);
+                mem.read_nametable_ram(0, index)
+            } else {
+                self.fetch_tile = if self.exram_mode == 1 {
+                    Mmc5TileFetch::ExtendedAttribute(mem.read_nametable_ram(0, usize::from(offset)))
+                } else {
+                    Mmc5TileFetch::Normal
+                };
+                data
+            }
+        } else {
+            // attribute: PPUがどの2bitを使っても良いように4箇所同じ値にする
+            match self.fetch_tile {
+                Mmc5TileFetch::Split { column, split_y } => {
+                    let index = usize::from(ATTRIBUTE_TABLE_OFFSET)
+                        + (usize::from(split_y) >> 5) * 8
+                        + (usize::from(column & 0x1f) >> 2);
+                    let shift = ((split_y >> 4) & 0x01) * 4 + ((column >> 1) & 0x01) * 2;
+                    ((mem.read_nametable_ram(0, index) >> shift) & 0x03) * 0x55
+                }
+                Mmc5TileFetch::ExtendedAttribute(ex) => (ex >> 6) * 0x55,
+                Mmc5TileFetch::Normal => data,
+            }
+        }
+    }
+    fn notify_ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x2000 => self.is_large_sprite = (data & 0x20) == 0x20,
+            // 描画を止めたらframe外扱い
+            0x2001 if (data & 0x18) == 0x00 => self.is_in_frame = false,
+            _ => {}
+        }
+    }
+    fn notify_ppu_fetch_phase(&mut self, phase: PpuFetchPhase) {
+        self.fetch_phase = phase;
+        self.fetch_tile_count = 0;
+        self.fetch_tile = Mmc5TileFetch::Normal;
+    }
+    fn is_irq_asserted(&self) -> bool {
+        (self.is_irq_pending && self.is_irq_enable)
+            || (self.is_pcm_irq_pending && (self.pcm_control & 0x80) == 0x80)
+    }
+    /// pre-renderで描画開始を検出し、以降1lineごとにcounterを進めて0x5203と比較する
+    fn notify_scanline(&mut self) {
+        if !self.is_in_frame {
+            self.is_in_frame = true;
+            self.scanline = 0;
+            return;
+        }
+        self.scanline = self.scanline.wrapping_add(1);
+        if self.scanline >= MMC5_VISIBLE_SCANLINES {
+            // 可視領域を描き終えた
+            self.is_in_frame = false;
+        } else if self.scanline == self.irq_target {
+            self.is_irq_pending = true;
+        }
+    }
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, cpu_cyc: usize) {
+        for _ in 0..cpu_cyc {
+            if self.is_odd_cycle {
+                self.pulse1.clock_timer();
+                self.pulse2.clock_timer();
+            }
+            self.is_odd_cycle = !self.is_odd_cycle;
+
+            self.frame_cycles += 1;
+            if self.frame_cycles >= MMC5_FRAME_CPU_CYCLE {
+                self.frame_cycles = 0;
+                self.pulse1.clock_frame();
+                self.pulse2.clock_frame();
+            }
+        }
+    }
+    fn expansion_audio_output(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        f32::from(pulse) * MMC5_PULSE_LEVEL_PER_STEP
+            + f32::from(self.pcm_output) * MMC5_PCM_LEVEL_PER_STEP
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_mode);
+        writer.write_u8(self.chr_mode);
+        writer.write_bytes(&self.prg_ram_protect);
+        writer.write_u8(self.exram_mode);
+        writer.write_u8(self.nametable_mapping);
+        writer.write_u8(self.fill_tile);
+        writer.write_u8(self.fill_attr);
+        writer.write_bytes(&self.prg_banks);
+        for bank in self.chr_banks_sprite.iter() {
+            writer.write_u16(*bank);
+        }
+        for bank in self.chr_banks_bg.iter() {
+            writer.write_u16(*bank);
+        }
+        writer.write_bool(self.is_last_write_bg_bank);
+        writer.write_u8(self.chr_upper);
+        writer.write_u8(self.split_control);
+        writer.write_u8(self.split_scroll);
+        writer.write_u8(self.split_bank);
+        writer.write_u8(self.irq_target);
+        writer.write_bool(self.is_irq_enable);
+        writer.write_bool(self.is_irq_pending);
+        writer.write_bool(self.is_in_frame);
+        writer.write_u8(self.scanline);
+        writer.write_u8(self.multiplicand);
+        writer.write_u8(self.multiplier);
+        writer.write_bool(self.is_large_sprite);
+        self.pulse1.save_state(writer);
+        self.pulse2.save_state(writer);
+        writer.write_u8(self.pcm_control);
+        writer.write_u8(self.pcm_output);
+        writer.write_bool(self.is_pcm_irq_pending);
+        writer.write_u16(self.frame_cycles);
+        writer.write_bool(self.is_odd_cycle);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.prg_mode = reader.read_u8();
+        self.chr_mode = reader.read_u8();
+        reader.read_bytes(&mut self.prg_ram_protect);
+        self.exram_mode = reader.read_u8();
+        self.nametable_mapping = reader.read_u8();
+        self.fill_tile = reader.read_u8();
+        self.fill_attr = reader.read_u8();
+        reader.read_bytes(&mut self.prg_banks);
+        for bank in self.chr_banks_sprite.iter_mut() {
+            *bank = reader.read_u16();
+        }
+        for bank in self.chr_banks_bg.iter_mut() {
+            *bank = reader.read_u16();
+        }
+        self.is_last_write_bg_bank = reader.read_bool();
+        self.chr_upper = reader.read_u8();
+        self.split_control = reader.read_u8();
+        self.split_scroll = reader.read_u8();
+        self.split_bank = reader.read_u8();
+        self.irq_target = reader.read_u8();
+        self.is_irq_enable = reader.read_bool();
+        self.is_irq_pending = reader.read_bool();
+        self.is_in_frame = reader.read_bool();
+        self.scanline = reader.read_u8();
+        self.multiplicand = reader.read_u8();
+        self.multiplier = reader.read_u8();
+        self.is_large_sprite = reader.read_bool();
+        self.pulse1.load_state(reader);
+        self.pulse2.load_state(reader);
+        self.pcm_control = reader.read_u8();
+        self.pcm_output = reader.read_u8();
+        self.is_pcm_irq_pending = reader.read_bool();
+        self.frame_cycles = reader.read_u16();
+        self.is_odd_cycle = reader.read_bool();
+        // fetchの途中状態は描画中しか使わない
+        self.fetch_phase = PpuFetchPhase::Idle;
+        self.fetch_tile_count = 0;
+        self.fetch_tile = Mmc5TileFetch::Normal;
+    }
+}
@@ -0,0 +1,294 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// 拡張音源の1step分の出力レベル
+pub const NAMCO163_AUDIO_LEVEL_PER_STEP: f32 = 0.0008;
+/// 内蔵sound RAMのサイズ
+pub const NAMCO163_SOUND_RAM_SIZE: usize = 0x80;
+/// 1channelを更新するのにかかるCPU cycle
+pub const NAMCO163_CHANNEL_CPU_CYCLE: u8 = 15;
+/// channel registerの先頭(channel 8が0x78 - 0x7f, channel 1が0x40 - 0x47)
+const CHANNEL_REG_BASE_ADDR: usize = 0x40;
+/// IRQ counterの最大値, ここで止まってIRQを出す
+const IRQ_COUNTER_MAX: u16 = 0x7fff;
+
+/// Mapper19: Namco 163
+/// 8KB PRG bank * 3, 1KB CHR bank * 8, CHR-ROMをnametableとして使える
+/// 15bit CPU cycle IRQ counter, 最大8channelの波形メモリ音源
+/// https://wiki.nesdev.com/w/index.php/INES_Mapper_019
+/// https://wiki.nesdev.com/w/index.php/Namco_163_audio
+#[derive(Clone)]
+pub struct Namco163 {
+    /// 0x8000 - 0xbfff: 1KB CHR bank(0x800ごと)
+    /// 0xe0以上はCIRAMを選択するが、pattern tableとしての利用には未対応
+    pub chr_banks: [u8; 8],
+    /// 0xc000 - 0xdfff: nametable(0x800ごと)
+    /// 0xe0以上ならCIRAM(bit0でA/B)、それ以外はCHR-ROMの1KB bank
+    pub nametable_banks: [u8; 4],
+    /// 0xe000, 0xe800, 0xf000: 8KB PRG bank
+    /// 0xe000 bit6 - sound disable
+    /// 0xe800 bit6, 7 - CHR RAM disable
+    pub prg_banks: [u8; 3],
+    /// 0xf800: sound RAM address, PRG RAM write protect
+    /// IAAA_AAAA
+    /// I - auto increment
+    /// A - address
+    pub sound_addr: u8,
+    /// 0x5000, 0x5800: ECCC_CCCC_CCCC_CCCC
+    /// E - counter enable
+    /// C - 15bit counter
+    pub irq_counter: u16,
+    pub is_irq_pending: bool,
+    /// 0x4800: 内蔵sound RAM, 0x40以降はchannel registerを兼ねる
+    pub sound_ram: [u8; NAMCO163_SOUND_RAM_SIZE],
+    pub channel_cycles: u8,
+    /// 次に更新するchannel(7から降順)
+    pub current_channel: u8,
+    /// channelごとの最後の出力
+    pub channel_outputs: [i8; 8],
+}
+
+impl Default for Namco163 {
+    fn default() -> Self {
+        Self {
+            chr_banks: [0; 8],
+            nametable_banks: [0; 4],
+            prg_banks: [0; 3],
+            sound_addr: 0,
+            irq_counter: 0,
+            is_irq_pending: false,
+            sound_ram: [0; NAMCO163_SOUND_RAM_SIZE],
+            channel_cycles: 0,
+            current_channel: 7,
+            channel_outputs: [0; 8],
+        }
+    }
+}
+
+impl Namco163 {
+    /// 0x8000 - 0xffffのアドレスから8KB bank番号を求めます
+    fn prg_bank_index(&self, mem: &CassetteMemory, addr: u16) -> usize {
+        match addr {
+            0x8000..=0x9fff => usize::from(self.prg_banks[0] & 0x3f),
+            0xa000..=0xbfff => usize::from(self.prg_banks[1] & 0x3f),
+            0xc000..=0xdfff => usize::from(self.prg_banks[2] & 0x3f),
+            _ => core::cmp::max(1, mem.prg_rom_bytes / 0x2000) - 1,
+        }
+    }
+    /// 0x6000 - 0x7fffを2KBごとに書き込み保護している
+    fn is_prg_ram_writable(&self, addr: u16) -> bool {
+        let slot = (addr - BATTERY_PACKED_RAM_BASE_ADDR) >> 11;
+        (self.sound_addr & 0xf0) == 0x40 && ((self.sound_addr >> slot) & 0x01) == 0x00
+    }
+    fn is_sound_enable(&self) -> bool {
+        (self.prg_banks[0] & 0x40) == 0x00
+    }
+    /// 0x4800の読み書き後にaddressを進めます
+    fn increment_sound_addr(&mut self) {
+        if (self.sound_addr & 0x80) == 0x80 {
+            self.sound_addr = 0x80 | (self.sound_addr.wrapping_add(1) & 0x7f);
+        }
+    }
+    /// 有効なchannel数(1 - 8), 0x7fのbit4 - 6で決まる
+    fn num_of_channel(&self) -> u8 {
+        ((self.sound_ram[0x7f] >> 4) & 0x07) + 1
+    }
+    /// channelの位相を進めて出力を更新します
+    fn update_channel(&mut self, ch: usize) {
+        let base = CHANNEL_REG_BASE_ADDR + ch * 8;
+        let freq = u32::from(self.sound_ram[base])
+            | (u32::from(self.sound_ram[base + 2]) << 8)
+            | (u32::from(self.sound_ram[base + 4] & 0x03) << 16);
+        let phase = u32::from(self.sound_ram[base + 1])
+            | (u32::from(self.sound_ram[base + 3]) << 8)
+            | (u32::from(self.sound_ram[base + 5]) << 16);
+        let length = 0x100 - u32::from(self.sound_ram[base + 4] & 0xfc);
+        let next_phase = (phase + freq) % (length << 16);
+        self.sound_ram[base + 1] = next_phase as u8;
+        self.sound_ram[base + 3] = (next_phase >> 8) as u8;
+        self.sound_ram[base + 5] = (next_phase >> 16) as u8;
+        // 4bit sampleが下位nibbleから順に詰まっている
+        let sample_addr =
+            ((next_phase >> 16) as usize + usize::from(self.sound_ram[base + 6])) & 0xff;
+        let data = self.sound_ram[sample_addr >> 1];
+        let sample = if (sample_addr & 0x01) == 0x00 {
+            data & 0x0f
+        } else {
+            data >> 4
+        };
+        let volume = (self.sound_ram[base + 7] & 0x0f) as i8;
+        self.channel_outputs[ch] = (sample as i8 - 8) * volume;
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            mem.read_battery_packed_ram(addr)
+        } else {
+            let bank = self.prg_bank_index(mem, addr);
+            mem.read_prg_rom(0x2000, bank, usize::from(addr & 0x1fff))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_writable(addr) {
+                mem.write_battery_packed_ram(addr, data);
+            }
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        let index = usize::from((addr >> 11) & 0x03);
+        match addr {
+            0x8000..=0xbfff => self.chr_banks[usize::from((addr - 0x8000) >> 11)] = data,
+            0xc000..=0xdfff => self.nametable_banks[index] = data,
+            0xe000..=0xf7ff => self.prg_banks[index] = data,
+            _ => self.sound_addr = data,
+        }
+    }
+    fn read_expansion_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        addr: u16,
+        is_nondestructive: bool,
+    ) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => {
+                let data = self.sound_ram[usize::from(self.sound_addr & 0x7f)];
+                if !is_nondestructive {
+                    self.increment_sound_addr();
+                }
+                Some(data)
+            }
+            0x5000..=0x57ff => Some(self.irq_counter as u8),
+            0x5800..=0x5fff => Some((self.irq_counter >> 8) as u8),
+            _ => None,
+        }
+    }
+    fn write_expansion_u8(
+        &mut self,
+        _mem: &mut CassetteMemory,
+        addr: u16,
+        data: u8,
+        is_nondestructive: bool,
+    ) {
+        if is_nondestructive {
+            return;
+        }
+        match addr {
+            0x4800..=0x4fff => {
+                self.sound_ram[usize::from(self.sound_addr & 0x7f)] = data;
+                self.increment_sound_addr();
+            }
+            // counterに書き込むとIRQを取り下げる
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0xff00) | u16::from(data);
+                self.is_irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | (u16::from(data) << 8);
+                self.is_irq_pending = false;
+            }
+            _ => {}
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        mem.read_chr(0x0400, bank, usize::from(addr & 0x03ff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        mem.write_chr(0x0400, bank, usize::from(addr & 0x03ff), data);
+    }
+    fn nametable_source(&self, slot: usize) -> Option<NameTableSource> {
+        let bank = self.nametable_banks[slot & 0x03];
+        Some(if bank >= 0xe0 {
+            NameTableSource::Ciram(usize::from(bank & 0x01))
+        } else {
+            NameTableSource::Mapper
+        })
+    }
+    /// CHR-ROMをnametableとして読み出す
+    fn read_nametable_u8(&mut self, mem: &mut CassetteMemory, slot: usize, offset: usize) -> u8 {
+        let bank = usize::from(self.nametable_banks[slot & 0x03]);
+        mem.read_chr(0x0400, bank, offset)
+    }
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+    /// counterはCPU cycleごとにincrementし、0x7fffで止まってIRQを出す
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, cpu_cyc: usize) {
+        if (self.irq_counter & 0x8000) == 0x8000 {
+            let counter = self.irq_counter & IRQ_COUNTER_MAX;
+            if counter < IRQ_COUNTER_MAX {
+                let next =
+                    core::cmp::min(usize::from(counter) + cpu_cyc, usize::from(IRQ_COUNTER_MAX));
+                self.irq_counter = 0x8000 | next as u16;
+                if next == usize::from(IRQ_COUNTER_MAX) {
+                    self.is_irq_pending = true;
+                }
+            }
+        }
+        if !self.is_sound_enable() {
+            return;
+        }
+        // 15cycleごとに1channelずつ、有効なchannelを7から順に更新する
+        for _ in 0..cpu_cyc {
+            self.channel_cycles += 1;
+            if self.channel_cycles >= NAMCO163_CHANNEL_CPU_CYCLE {
+                self.channel_cycles = 0;
+                let ch = usize::from(self.current_channel);
+                self.update_channel(ch);
+                let lowest = 8 - self.num_of_channel();
+                self.current_channel = if self.current_channel <= lowest {
+                    7
+                } else {
+                    self.current_channel - 1
+                };
+            }
+        }
+    }
+    /// 実機は時分割で出力するので、有効なchannelの平均を出す
+    fn expansion_audio_output(&self) -> f32 {
+        if !self.is_sound_enable() {
+            return 0.0;
+        }
+        let num_of_channel = self.num_of_channel();
+        let lowest = usize::from(8 - num_of_channel);
+        let sum: i16 = self.channel_outputs[lowest..]
+            .iter()
+            .map(|x| i16::from(*x))
+            .sum();
+        f32::from(sum) / f32::from(num_of_channel) * NAMCO163_AUDIO_LEVEL_PER_STEP
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.chr_banks);
+        writer.write_bytes(&self.nametable_banks);
+        writer.write_bytes(&self.prg_banks);
+        writer.write_u8(self.sound_addr);
+        writer.write_u16(self.irq_counter);
+        writer.write_bool(self.is_irq_pending);
+        writer.write_bytes(&self.sound_ram);
+        writer.write_u8(self.channel_cycles);
+        writer.write_u8(self.current_channel);
+        for output in self.channel_outputs.iter() {
+            writer.write_u8(*output as u8);
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        reader.read_bytes(&mut self.chr_banks);
+        reader.read_bytes(&mut self.nametable_banks);
+        reader.read_bytes(&mut self.prg_banks);
+        self.sound_addr = reader.read_u8();
+        self.irq_counter = reader.read_u16();
+        self.is_irq_pending = reader.read_bool();
+        reader.read_bytes(&mut self.sound_ram);
+        self.channel_cycles = reader.read_u8();
+        self.current_channel = reader.read_u8();
+        for output in self.channel_outputs.iter_mut() {
+            *output = reader.read_u8() as i8;
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// Mapper0: NROM
+/// bank切り替えなし、PRG-ROM 16KB/32KB, CHR 8KB
+/// https://wiki.nesdev.com/w/index.php/NROM
+#[derive(Clone, Default)]
+pub struct Nrom {}
+
+impl Mapper for Nrom {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            mem.read_battery_packed_ram(addr)
+        } else {
+            // ROMが16KB場合のミラーリングはbank wrapで処理される
+            let index = usize::from(addr - PRG_ROM_SYSTEM_BASE_ADDR);
+            mem.read_prg_rom(0x8000, 0, index)
+        }
+    }
+    fn write_u8(
+        &mut self,
+        mem: &mut CassetteMemory,
+        addr: u16,
+        data: u8,
+        _is_nondestructive: bool,
+    ) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            mem.write_battery_packed_ram(addr, data);
+        }
+        // PRG-ROMへの書き込みは無視
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        mem.read_chr(0x2000, 0, usize::from(addr))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        mem.write_chr(0x2000, 0, usize::from(addr), data);
+    }
+    fn save_state(&self, _writer: &mut StateWriter) {}
+    fn load_state(&mut self, _reader: &mut StateReader) {}
+}
@@ -0,0 +1,295 @@
+use super::cassette::*;
+use super::mapper::*;
+
+/// VRC IRQのprescaler, 341/3 CPU cycleで1scanline
+const VRC_IRQ_PRESCALER_PERIOD: i16 = 341;
+
+/// VRC4/VRC6/VRC7で共通のIRQ counter
+/// https://wiki.nesdev.com/w/index.php/VRC_IRQ
+#[derive(Clone, Default)]
+pub struct VrcIrq {
+    pub latch: u8,
+    /// xxxx_xMEA
+    /// M - 0: scanline mode, 1: cycle mode
+    /// E - IRQ enable
+    /// A - acknowledge時にEへコピーされる
+    pub control: u8,
+    pub counter: u8,
+    pub prescaler: i16,
+    pub is_pending: bool,
+}
+
+impl VrcIrq {
+    pub fn write_latch_low(&mut self, data: u8) {
+        self.latch = (self.latch & 0xf0) | (data & 0x0f);
+    }
+    pub fn write_latch_high(&mut self, data: u8) {
+        self.latch = (self.latch & 0x0f) | ((data & 0x0f) << 4);
+    }
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+    /// IRQ control, Eが立っていたらcounterをreloadする
+    pub fn write_control(&mut self, data: u8) {
+        self.control = data & 0x07;
+        self.is_pending = false;
+        if self.is_enable() {
+            self.counter = self.latch;
+            self.prescaler = VRC_IRQ_PRESCALER_PERIOD;
+        }
+    }
+    /// IRQ acknowledge, AをEにコピーする
+    pub fn acknowledge(&mut self) {
+        self.is_pending = false;
+        let a = self.control & 0x01;
+        self.control = (self.control & 0x05) | (a << 1);
+    }
+    fn is_enable(&self) -> bool {
+        (self.control & 0x02) == 0x02
+    }
+    fn is_cycle_mode(&self) -> bool {
+        (self.control & 0x04) == 0x04
+    }
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.is_pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+    /// CPU cycleを進めます
+    pub fn step(&mut self, cpu_cyc: usize) {
+        if !self.is_enable() {
+            return;
+        }
+        for _ in 0..cpu_cyc {
+            if self.is_cycle_mode() {
+                self.clock_counter();
+            } else {
+                // scanline mode: 3ずつ引いて341ごとに1clock
+                self.prescaler -= 3;
+                if self.prescaler <= 0 {
+                    self.prescaler += VRC_IRQ_PRESCALER_PERIOD;
+                    self.clock_counter();
+                }
+            }
+        }
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.latch);
+        writer.write_u8(self.control);
+        writer.write_u8(self.counter);
+        writer.write_u16(self.prescaler as u16);
+        writer.write_bool(self.is_pending);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.latch = reader.read_u8();
+        self.control = reader.read_u8();
+        self.counter = reader.read_u8();
+        self.prescaler = reader.read_u16() as i16;
+        self.is_pending = reader.read_bool();
+    }
+}
+
+/// VRC2/VRC4の基板ごとの違い
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VrcBoard {
+    /// Mapper21: VRC4a(A1, A2), VRC4c(A6, A7)
+    Vrc4ac,
+    /// Mapper22: VRC2a(A1, A0), CHR bankの最下位bitが無視される
+    Vrc2a,
+    /// Mapper23: VRC2b/VRC4f(A0, A1), VRC4e(A2, A3)
+    Vrc2bVrc4ef,
+    /// Mapper25: VRC2c/VRC4b(A1, A0), VRC4d(A3, A2)
+    Vrc2cVrc4bd,
+}
+
+/// Mapper21, 22, 23, 25: Konami VRC2/VRC4
+/// 基板によってレジスタ選択に使うアドレス線が違うので、submapperから決める
+/// submapperがない場合は両方の配線をORして扱う
+/// https://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+#[derive(Clone)]
+pub struct Vrc4 {
+    pub board: VrcBoard,
+    /// レジスタのbit0として扱うアドレス線(複数立っていたらOR)
+    pub a0_mask: u16,
+    /// レジスタのbit1として扱うアドレス線(複数立っていたらOR)
+    pub a1_mask: u16,
+    /// VRC2はPRG swap modeとIRQを持たない
+    pub is_vrc2: bool,
+    /// 0x8000 - 0x8003, 0xa000 - 0xa003: 8KB PRG bank
+    pub prg_banks: [u8; 2],
+    /// 0x9002 bit1: PRG swap mode
+    pub is_prg_swap: bool,
+    /// 0xb000 - 0xe003: 1KB CHR bank, 下位/上位4bitずつ書く
+    pub chr_banks: [u16; 8],
+    pub mirroring: Option<NameTableMirror>,
+    pub irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(board: VrcBoard, submapper_number: u8) -> Vrc4 {
+        let (a0_mask, a1_mask, is_vrc2) = match (board, submapper_number) {
+            (VrcBoard::Vrc4ac, 1) => (0x0002, 0x0004, false),
+            (VrcBoard::Vrc4ac, 2) => (0x0040, 0x0080, false),
+            (VrcBoard::Vrc4ac, _) => (0x0042, 0x0084, false),
+            (VrcBoard::Vrc2a, _) => (0x0002, 0x0001, true),
+            (VrcBoard::Vrc2bVrc4ef, 1) => (0x0001, 0x0002, false),
+            (VrcBoard::Vrc2bVrc4ef, 2) => (0x0004, 0x0008, false),
+            (VrcBoard::Vrc2bVrc4ef, 3) => (0x0001, 0x0002, true),
+            (VrcBoard::Vrc2bVrc4ef, _) => (0x0005, 0x000a, false),
+            (VrcBoard::Vrc2cVrc4bd, 1) => (0x0002, 0x0001, false),
+            (VrcBoard::Vrc2cVrc4bd, 2) => (0x0008, 0x0004, false),
+            (VrcBoard::Vrc2cVrc4bd, 3) => (0x0002, 0x0001, true),
+            (VrcBoard::Vrc2cVrc4bd, _) => (0x000a, 0x0005, false),
+        };
+        Vrc4 {
+            board,
+            a0_mask,
+            a1_mask,
+            is_vrc2,
+            prg_banks: [0; 2],
+            is_prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: None,
+            irq: VrcIrq::default(),
+        }
+    }
+    /// iNESのMapper番号
+    pub fn mapper_number(&self) -> u16 {
+        match self.board {
+            VrcBoard::Vrc4ac => 21,
+            VrcBoard::Vrc2a => 22,
+            VrcBoard::Vrc2bVrc4ef => 23,
+            VrcBoard::Vrc2cVrc4bd => 25,
+        }
+    }
+    /// 基板ごとの配線を吸収して0x?000 - 0x?003のレジスタ番号に変換します
+    fn register_index(&self, addr: u16) -> u16 {
+        let a0 = if (addr & self.a0_mask) != 0 { 1 } else { 0 };
+        let a1 = if (addr & self.a1_mask) != 0 { 2 } else { 0 };
+        (addr & 0xf000) | a1 | a0
+    }
+    /// 0x8000 - 0xffffのアドレスから8KB bank番号を求めます
+    fn prg_bank_index(&self, mem: &CassetteMemory, addr: u16) -> usize {
+        let num_of_bank = core::cmp::max(1, mem.prg_rom_bytes / 0x2000);
+        let second_last = num_of_bank.saturating_sub(2);
+        let r0 = usize::from(self.prg_banks[0] & 0x1f);
+        let r1 = usize::from(self.prg_banks[1] & 0x1f);
+        match (addr >> 13) & 0x03 {
+            0 => {
+                if self.is_prg_swap {
+                    second_last
+                } else {
+                    r0
+                }
+            }
+            1 => r1,
+            2 => {
+                if self.is_prg_swap {
+                    r0
+                } else {
+                    second_last
+                }
+            }
+            _ => num_of_bank - 1,
+        }
+    }
+    fn chr_bank_index(&self, addr: u16) -> usize {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        if self.board == VrcBoard::Vrc2a {
+            bank >> 1
+        } else {
+            bank
+        }
+    }
+    fn write_chr_bank(&mut self, index: usize, is_high: bool, data: u8) {
+        let bank = self.chr_banks[index];
+        self.chr_banks[index] = if is_high {
+            // VRC2は4bit, VRC4は5bit
+            let mask = if self.is_vrc2 { 0x0f } else { 0x1f };
+            (bank & 0x000f) | (u16::from(data & mask) << 4)
+        } else {
+            (bank & 0x01f0) | u16::from(data & 0x0f)
+        };
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            mem.read_battery_packed_ram(addr)
+        } else {
+            let bank = self.prg_bank_index(mem, addr);
+            mem.read_prg_rom(0x2000, bank, usize::from(addr & 0x1fff))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            mem.write_battery_packed_ram(addr, data);
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        match self.register_index(addr) {
+            0x8000..=0x8003 => self.prg_banks[0] = data,
+            0x9000 | 0x9001 => {
+                self.mirroring = Some(match data & (if self.is_vrc2 { 0x01 } else { 0x03 }) {
+                    0 => NameTableMirror::Vertical,
+                    1 => NameTableMirror::Horizontal,
+                    2 => NameTableMirror::SingleScreenLower,
+                    _ => NameTableMirror::SingleScreenUpper,
+                });
+            }
+            0x9002 | 0x9003 if !self.is_vrc2 => self.is_prg_swap = (data & 0x02) == 0x02,
+            0xa000..=0xa003 => self.prg_banks[1] = data,
+            reg @ 0xb000..=0xefff => {
+                // 0xb000: CHR0/1, 0xc000: CHR2/3, 0xd000: CHR4/5, 0xe000: CHR6/7
+                let index = usize::from((reg >> 12) - 0xb) * 2 + usize::from((reg >> 1) & 0x01);
+                self.write_chr_bank(index, (reg & 0x01) == 0x01, data);
+            }
+            0xf000 if !self.is_vrc2 => self.irq.write_latch_low(data),
+            0xf001 if !self.is_vrc2 => self.irq.write_latch_high(data),
+            0xf002 if !self.is_vrc2 => self.irq.write_control(data),
+            0xf003 if !self.is_vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = self.chr_bank_index(addr);
+        mem.read_chr(0x0400, bank, usize::from(addr & 0x03ff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = self.chr_bank_index(addr);
+        mem.write_chr(0x0400, bank, usize::from(addr & 0x03ff), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        self.mirroring
+    }
+    fn is_irq_asserted(&self) -> bool {
+        self.irq.is_pending
+    }
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, cpu_cyc: usize) {
+        self.irq.step(cpu_cyc);
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.prg_banks);
+        writer.write_bool(self.is_prg_swap);
+        for bank in self.chr_banks.iter() {
+            writer.write_u16(*bank);
+        }
+        writer.write_mirror(self.mirroring);
+        self.irq.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        reader.read_bytes(&mut self.prg_banks);
+        self.is_prg_swap = reader.read_bool();
+        for bank in self.chr_banks.iter_mut() {
+            *bank = reader.read_u16();
+        }
+        self.mirroring = reader.read_mirror();
+        self.irq.load_state(reader);
+    }
+}
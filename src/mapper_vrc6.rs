@@ -0,0 +1,321 @@
+use super::cassette::*;
+use super::mapper::*;
+use super::mapper_vrc::*;
+
+/// 拡張音源の1step分の出力レベル(APUのpulse 1step分と同程度)
+pub const VRC6_AUDIO_LEVEL_PER_STEP: f32 = 0.00752;
+
+/// VRC6 pulse channel
+#[derive(Clone, Default)]
+pub struct Vrc6Pulse {
+    /// 0x9000/0xa000: MDDD_VVVV
+    /// M - 1ならdutyを無視して常にvolumeを出力
+    /// D - duty
+    /// V - volume
+    pub control: u8,
+    /// 0x9001/0xa001, 0x9002/0xa002: 12bit period
+    pub period: u16,
+    /// 0x9002/0xa002 bit7
+    pub is_enable: bool,
+    pub timer: u16,
+    /// 15から0に向かってカウントする
+    pub duty_step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, index: u16, data: u8) {
+        match index {
+            0 => self.control = data,
+            1 => self.period = (self.period & 0x0f00) | u16::from(data),
+            _ => {
+                self.period = (self.period & 0x00ff) | (u16::from(data & 0x0f) << 8);
+                self.is_enable = (data & 0x80) == 0x80;
+                if !self.is_enable {
+                    self.duty_step = 15;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.is_enable {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.duty_step = if self.duty_step == 0 {
+                15
+            } else {
+                self.duty_step - 1
+            };
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        let is_mode = (self.control & 0x80) == 0x80;
+        let duty = (self.control >> 4) & 0x07;
+        if self.is_enable && (is_mode || self.duty_step <= duty) {
+            self.control & 0x0f
+        } else {
+            0
+        }
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.control);
+        writer.write_u16(self.period);
+        writer.write_bool(self.is_enable);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.duty_step);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.control = reader.read_u8();
+        self.period = reader.read_u16();
+        self.is_enable = reader.read_bool();
+        self.timer = reader.read_u16();
+        self.duty_step = reader.read_u8();
+    }
+}
+
+/// VRC6 sawtooth channel
+#[derive(Clone, Default)]
+pub struct Vrc6Saw {
+    /// 0xb000: accumulator rate(6bit)
+    pub rate: u8,
+    /// 0xb001, 0xb002: 12bit period
+    pub period: u16,
+    /// 0xb002 bit7
+    pub is_enable: bool,
+    pub timer: u16,
+    /// timer 2回ごとにrateを加算し、14回目で0に戻る
+    pub step: u8,
+    pub accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, index: u16, data: u8) {
+        match index {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | u16::from(data),
+            _ => {
+                self.period = (self.period & 0x00ff) | (u16::from(data & 0x0f) << 8);
+                self.is_enable = (data & 0x80) == 0x80;
+                if !self.is_enable {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.is_enable {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step >= 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if (self.step & 0x01) == 0x00 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    /// 上位5bitが出力される
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.rate);
+        writer.write_u16(self.period);
+        writer.write_bool(self.is_enable);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.step);
+        writer.write_u8(self.accumulator);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.rate = reader.read_u8();
+        self.period = reader.read_u16();
+        self.is_enable = reader.read_bool();
+        self.timer = reader.read_u16();
+        self.step = reader.read_u8();
+        self.accumulator = reader.read_u8();
+    }
+}
+
+/// Mapper24, 26: Konami VRC6
+/// 16KB + 8KB PRG bank, 1KB CHR bank * 8, VRC IRQ, 拡張音源(pulse * 2, sawtooth)
+/// Mapper26はA0とA1が入れ替わっている
+/// https://wiki.nesdev.com/w/index.php/VRC6
+#[derive(Clone)]
+pub struct Vrc6 {
+    /// Mapper26(VRC6b)ならtrue
+    pub is_swap_address_line: bool,
+    /// 0x8000 - 0x8003: 16KB PRG bank at 0x8000
+    pub prg_bank16: u8,
+    /// 0xc000 - 0xc003: 8KB PRG bank at 0xc000
+    pub prg_bank8: u8,
+    /// 0xd000 - 0xe003: 1KB CHR bank
+    pub chr_banks: [u8; 8],
+    /// 0xb003: PPU banking style
+    /// Rxxx_MMxx
+    /// R - PRG-RAM enable
+    /// M - mirroring
+    pub banking_style: u8,
+    /// 0x9003: frequency control
+    /// xxxx_xABH
+    /// H - halt, B - 16倍速, A - 256倍速
+    pub freq_control: u8,
+    pub pulse1: Vrc6Pulse,
+    pub pulse2: Vrc6Pulse,
+    pub saw: Vrc6Saw,
+    pub irq: VrcIrq,
+}
+
+impl Vrc6 {
+    pub fn new(is_swap_address_line: bool) -> Vrc6 {
+        Vrc6 {
+            is_swap_address_line,
+            prg_bank16: 0,
+            prg_bank8: 0,
+            chr_banks: [0; 8],
+            banking_style: 0,
+            freq_control: 0,
+            pulse1: Vrc6Pulse::default(),
+            pulse2: Vrc6Pulse::default(),
+            saw: Vrc6Saw::default(),
+            irq: VrcIrq::default(),
+        }
+    }
+    /// 基板ごとの配線を吸収して0x?000 - 0x?003のレジスタ番号に変換します
+    fn register_index(&self, addr: u16) -> u16 {
+        let index = if self.is_swap_address_line {
+            ((addr & 0x01) << 1) | ((addr & 0x02) >> 1)
+        } else {
+            addr & 0x03
+        };
+        (addr & 0xf000) | index
+    }
+    fn is_prg_ram_enable(&self) -> bool {
+        (self.banking_style & 0x80) == 0x80
+    }
+    /// 0x8000 - 0xffffのアドレスから8KB bank番号を求めます
+    fn prg_bank_index(&self, mem: &CassetteMemory, addr: u16) -> usize {
+        let bank16 = usize::from(self.prg_bank16 & 0x0f);
+        match addr {
+            0x8000..=0xbfff => bank16 * 2 + usize::from((addr >> 13) & 0x01),
+            0xc000..=0xdfff => usize::from(self.prg_bank8 & 0x1f),
+            _ => core::cmp::max(1, mem.prg_rom_bytes / 0x2000) - 1,
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn read_u8(&mut self, mem: &mut CassetteMemory, addr: u16, _is_nondestructive: bool) -> u8 {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_enable() {
+                mem.read_battery_packed_ram(addr)
+            } else {
+                // open bus
+                (addr >> 8) as u8
+            }
+        } else {
+            let bank = self.prg_bank_index(mem, addr);
+            mem.read_prg_rom(0x2000, bank, usize::from(addr & 0x1fff))
+        }
+    }
+    fn write_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8, is_nondestructive: bool) {
+        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+            if self.is_prg_ram_enable() {
+                mem.write_battery_packed_ram(addr, data);
+            }
+            return;
+        }
+        if is_nondestructive {
+            return;
+        }
+        let reg = self.register_index(addr);
+        let index = reg & 0x03;
+        match reg {
+            0x8000..=0x8003 => self.prg_bank16 = data,
+            0x9003 => self.freq_control = data & 0x07,
+            0x9000..=0x9002 => self.pulse1.write(index, data),
+            0xa000..=0xa002 => self.pulse2.write(index, data),
+            0xb003 => self.banking_style = data,
+            0xb000..=0xb002 => self.saw.write(index, data),
+            0xc000..=0xc003 => self.prg_bank8 = data,
+            0xd000..=0xd003 => self.chr_banks[usize::from(index)] = data,
+            0xe000..=0xe003 => self.chr_banks[4 + usize::from(index)] = data,
+            0xf000 => self.irq.write_latch(data),
+            0xf001 => self.irq.write_control(data),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn read_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16) -> u8 {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        mem.read_chr(0x0400, bank, usize::from(addr & 0x03ff))
+    }
+    fn write_video_u8(&mut self, mem: &mut CassetteMemory, addr: u16, data: u8) {
+        let bank = usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]);
+        mem.write_chr(0x0400, bank, usize::from(addr & 0x03ff), data);
+    }
+    fn nametable_mirror(&self) -> Option<NameTableMirror> {
+        Some(match (self.banking_style >> 2) & 0x03 {
+            0 => NameTableMirror::Vertical,
+            1 => NameTableMirror::Horizontal,
+            2 => NameTableMirror::SingleScreenLower,
+            _ => NameTableMirror::SingleScreenUpper,
+        })
+    }
+    fn is_irq_asserted(&self) -> bool {
+        self.irq.is_pending
+    }
+    fn notify_cpu_cycles(&mut self, _mem: &mut CassetteMemory, cpu_cyc: usize) {
+        self.irq.step(cpu_cyc);
+        // haltしている間は音源が止まる
+        if (self.freq_control & 0x01) == 0x01 {
+            return;
+        }
+        let shift = if (self.freq_control & 0x04) == 0x04 {
+            8
+        } else if (self.freq_control & 0x02) == 0x02 {
+            4
+        } else {
+            0
+        };
+        for _ in 0..cpu_cyc {
+            self.pulse1.clock(shift);
+            self.pulse2.clock(shift);
+            self.saw.clock(shift);
+        }
+    }
+    fn expansion_audio_output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        f32::from(sum) * VRC6_AUDIO_LEVEL_PER_STEP
+    }
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank16);
+        writer.write_u8(self.prg_bank8);
+        writer.write_bytes(&self.chr_banks);
+        writer.write_u8(self.banking_style);
+        writer.write_u8(self.freq_control);
+        self.pulse1.save_state(writer);
+        self.pulse2.save_state(writer);
+        self.saw.save_state(writer);
+        self.irq.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) {
+        self.prg_bank16 = reader.read_u8();
+        self.prg_bank8 = reader.read_u8();
+        reader.read_bytes(&mut self.chr_banks);
+        self.banking_style = reader.read_u8();
+        self.freq_control = reader.read_u8();
+        self.pulse1.load_state(reader);
+        self.pulse2.load_state(reader);
+        self.saw.load_state(reader);
+        self.irq.load_state(reader);
+    }
+}
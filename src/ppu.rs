@@ -1,10 +1,14 @@
-use super::cpu::*;
 use super::interface::*;
+use super::mapper::*;
 use super::system::*;
 use super::video_system::*;
 
-/// 1lineあたりかかるCPUサイクル
-pub const CPU_CYCLE_PER_LINE: usize = (341 / 3); // ppu cyc -> cpu cyc
+/// 1lineあたりかかるPPUサイクル(dot)
+pub const PPU_CYCLE_PER_LINE: usize = 341;
+/// CPU 1cycleあたりのPPUサイクル(NTSC)
+pub const PPU_CYCLE_PER_CPU_CYCLE: usize = 3;
+/// line 241とpre-render lineでVBLANKフラグを更新するdot(cycle-accurate core)
+pub const PPU_VBLANK_UPDATE_DOT: usize = 1;
 /// 色の種類(RGB)
 pub const NUM_OF_COLOR: usize = 3;
 /// ユーザーに表示される領域幅
@@ -29,9 +33,6 @@ pub const ATTRIBUTE_TABLE_WIDTH: u16 =
 
 /// PPU内部のOAMの容量 dmaの転送サイズと等しい
 pub const OAM_SIZE: usize = 0x100;
-/// DMA転送を2line処理で終えようと思ったときの1回目で転送するバイト数
-/// 341cyc/513cyc*256byte=170.1byte
-pub const OAM_DMA_COPY_SIZE_PER_PPU_STEP: u8 = 0xaa;
 /// pattern1個あたりのエントリサイズ
 pub const PATTERN_TABLE_ENTRY_BYTE: u16 = 16;
 
@@ -45,8 +46,6 @@ pub const SPRITE_SIZE: usize = 4;
 pub const SPRITE_WIDTH: usize = 8;
 pub const SPRITE_NORMAL_HEIGHT: usize = 8;
 pub const SPRITE_LARGE_HEIGHT: usize = 16;
-/// 1frame書くのにかかるサイクル数
-pub const CYCLE_PER_DRAW_FRAME: usize = CPU_CYCLE_PER_LINE * ((RENDER_SCREEN_HEIGHT + 1) as usize);
 
 #[derive(Copy, Clone)]
 pub struct Position(pub u8, pub u8);
@@ -183,108 +182,65 @@ impl LineStatus {
 
 #[derive(Clone)]
 pub struct Ppu {
-    /// Object Attribute Memoryの実態
-    pub oam: [u8; OAM_SIZE],
     /// 次の描画で使うスプライトを格納する
     pub sprite_temps: [Option<Sprite>; SPRITE_TEMP_SIZE],
 
-    /// 積もり積もったcpu cycle, 341を超えたらクリアして1行処理しよう
-    pub cumulative_cpu_cyc: usize,
+    /// 積もり積もったppu cycle, 1line分(341)を超えたら1行処理しよう
+    pub cumulative_ppu_cyc: usize,
     /// 次処理するy_index
     pub current_line: u16,
+    /// 描画したframe数, 240line目(post-render)を処理したら増える
+    pub frame_count: u64,
+    /// 奇数frameで描画が有効なら、pre-render lineが1dot短くなる
+    /// 1frameは89342dot, 89341dotを繰り返すので平均29780.5 CPU cycle
+    pub is_odd_frame: bool,
+    /// 処理中のlineでVBLANKフラグをdot単位で更新済ならtrue(cycle-accurate core)
+    pub is_vblank_updated: bool,
 
     // scrollレジスタは1lineごとに更新
     pub fetch_scroll_x: u8,
     pub fetch_scroll_y: u8,
     pub current_scroll_x: u8,
     pub current_scroll_y: u8,
-
-    /// DMAが稼働中か示す
-    /// DMAには513cycかかるが、Emulation上ppuのstep2回341cyc*2で完了するので実行中フラグで処理する
-    /// 先頭でDMA開始されたとして、前半341cycで67%(170byte/256byte)処理できる(ので、次のstepで残りを処理したら次のDMA要求を受けても行ける)
-    pub is_dma_running: bool,
-    /// DMAのCPU側のベースアドレス。ページ指定なのでlower byteは0
-    pub dma_cpu_src_addr: u16,
-    /// DMAのOAM側のベースアドレス。256byteしたらwrapする(あまり使われないらしい)
-    pub dma_oam_dst_addr: u8,
 }
 
 impl Default for Ppu {
     fn default() -> Self {
         Self {
-            oam: [0; OAM_SIZE],
             sprite_temps: [None; SPRITE_TEMP_SIZE],
 
-            cumulative_cpu_cyc: 0,
+            cumulative_ppu_cyc: 0,
             current_line: 241,
+            frame_count: 0,
+            is_odd_frame: false,
+            is_vblank_updated: false,
 
             fetch_scroll_x: 0,
             fetch_scroll_y: 0,
             current_scroll_x: 0,
             current_scroll_y: 0,
-
-            is_dma_running: false,
-            dma_cpu_src_addr: 0,
-            dma_oam_dst_addr: 0,
         }
     }
 }
 
 impl EmulateControl for Ppu {
     fn reset(&mut self) {
-        self.oam = [0; OAM_SIZE];
         self.sprite_temps = [None; SPRITE_TEMP_SIZE];
 
         self.current_line = 241;
-        self.cumulative_cpu_cyc = 0;
+        self.cumulative_ppu_cyc = 0;
+        self.frame_count = 0;
+        self.is_odd_frame = false;
+        self.is_vblank_updated = false;
 
         self.fetch_scroll_x = 0;
         self.fetch_scroll_y = 0;
         self.current_scroll_x = 0;
         self.current_scroll_y = 0;
-
-        self.is_dma_running = false;
-        self.dma_cpu_src_addr = 0;
-        self.dma_oam_dst_addr = 0;
     }
 }
 
 impl Ppu {
-    /// DMA転送を(2回に分けて)行います
-    /// `is_pre_transfer` - 受領直後の転送ならtrue, ppu 1stepあとならfalse
-    fn run_dma(&mut self, system: &mut System, is_pre_transfer: bool) {
-        debug_assert!(
-            (!self.is_dma_running && is_pre_transfer) || (self.is_dma_running && !is_pre_transfer)
-        );
-        debug_assert!((self.dma_cpu_src_addr & 0x00ff) == 0x0000);
-
-        // address計算
-        let start_offset: u8 = if is_pre_transfer {
-            0
-        } else {
-            OAM_DMA_COPY_SIZE_PER_PPU_STEP
-        };
-        let cpu_start_addr: u16 = self.dma_cpu_src_addr.wrapping_add(u16::from(start_offset));
-        let oam_start_addr: u8 = self.dma_oam_dst_addr.wrapping_add(start_offset);
-        // 転送サイズ
-        let transfer_size: u16 = if is_pre_transfer {
-            OAM_DMA_COPY_SIZE_PER_PPU_STEP as u16
-        } else {
-            (OAM_SIZE as u16) - u16::from(OAM_DMA_COPY_SIZE_PER_PPU_STEP)
-        };
-
-        // 転送
-        for offset in 0..transfer_size {
-            let cpu_addr = cpu_start_addr.wrapping_add(offset);
-            let oam_addr = usize::from(oam_start_addr.wrapping_add(offset as u8));
-
-            let cpu_data = system.read_u8(cpu_addr, false);
-            self.oam[oam_addr] = cpu_data;
-        }
-
-        // ステータス更新
-        self.is_dma_running = is_pre_transfer;
-    }
     /// 1行書きます
     ///
     /// `tile_base`   - スクロールオフセット加算なしの現在のタイル位置
@@ -318,10 +274,23 @@ impl Ppu {
 
         // 描画座標系でループさせる
         let pixel_y = usize::from(self.current_line);
+        // Sprite: pattern tableのfetchは行の最初にまとめて行う
+        // 実機では前の行の終わりにfetchしているので、BGよりも先に読む
+        system
+            .cassette
+            .notify_ppu_fetch_phase(PpuFetchPhase::Sprite);
+        let sprite_patterns = self.fetch_sprite_patterns(system, pixel_y);
+        system
+            .cassette
+            .notify_ppu_fetch_phase(PpuFetchPhase::Background);
+        // BG: tileの境界を跨いだときだけfetchする
+        let mut bg_palette_id = 0;
+        let mut bg_data_lower = 0;
+        let mut bg_data_upper = 0;
         for pixel_x in 0..VISIBLE_SCREEN_WIDTH {
             // Sprite: 探索したテンポラリレジスタから描画するデータを取得する
             let (sprite_palette_data_back, sprite_palette_data_front) =
-                self.get_sprite_draw_data(system, pixel_x, pixel_y);
+                self.get_sprite_draw_data(system, pixel_x, &sprite_patterns);
 
             // BG(Nametable): 座標に該当するNametableと属性テーブルからデータを取得する
             let offset_x = ((pixel_x as u16) + u16::from(self.current_scroll_x)) & 0x07;
@@ -331,42 +300,47 @@ impl Ppu {
             let tile_local_x = tile_global_x % SCREEN_TILE_WIDTH; // 1 tile内での絶対座標
             let is_nametable_position_left = tile_global_x < SCREEN_TILE_WIDTH; // 4面ある内、右側にある場合false
 
-            // 4面あるうちのどれかがわかるので、該当する面のベースアドレスを返します
-            let target_nametable_base_addr = nametable_base_addr +
-                (if is_nametable_position_left { 0x0000 } else { 0x0400 }) + // 左右面の広域offset
-                (if is_nametable_position_top  { 0x0000 } else { 0x0800 }); // 上下面の広域offset
-
-            // attribute tableはNametableの後32byteにいるのでアドレス計算して読み出す。縦横4*4tileで1attrになっている
-            // scroll対応のためにoffset計算はglobal位置を使っている（もしかしたら1Nametableでクリッピングがいるかも)
-            let attribute_base_addr = target_nametable_base_addr + ATTRIBUTE_TABLE_OFFSET; // 23c0, 27c0, 2bc0, 2fc0のどれか
-            let attribute_x_offset = (tile_global_x >> 2) & 0x7;
-            let attribute_y_offset = tile_global_y >> 2;
-            let attribute_addr =
-                attribute_base_addr + (attribute_y_offset << 3) + attribute_x_offset;
-
-            // attribute読み出し, BGパレット選択に使う。4*4の位置で使うパレット情報を変える
-            let raw_attribute = system.video.read_u8(&mut system.cassette, attribute_addr);
-            let bg_palette_id = match (tile_local_x & 0x03 < 0x2, tile_local_y & 0x03 < 0x2) {
-                (true, true) => (raw_attribute >> 0) & 0x03,  // top left
-                (false, true) => (raw_attribute >> 2) & 0x03, // top right
-                (true, false) => (raw_attribute >> 4) & 0x03, // bottom left
-                (false, false) => (raw_attribute >> 6) & 0x03, // bottom right
-            };
-
-            // Nametableからtile_id読み出し->pattern tableからデータ構築
-            let nametable_addr = target_nametable_base_addr + (tile_local_y << 5) + tile_local_x;
-            let bg_tile_id = u16::from(system.video.read_u8(&mut system.cassette, nametable_addr));
-
-            // pattern_table 1entryは16byte, 0行目だったら0,8番目のデータを使えば良い
-            let bg_pattern_table_base_addr = pattern_table_addr + (bg_tile_id << 4);
-            let bg_pattern_table_addr_lower = bg_pattern_table_base_addr + offset_y;
-            let bg_pattern_table_addr_upper = bg_pattern_table_addr_lower + 8;
-            let bg_data_lower = system
-                .video
-                .read_u8(&mut system.cassette, bg_pattern_table_addr_lower);
-            let bg_data_upper = system
-                .video
-                .read_u8(&mut system.cassette, bg_pattern_table_addr_upper);
+            // 左端か次のtileに差し掛かったら、nametable -> attribute -> pattern tableの順でfetchする
+            if pixel_x == 0 || offset_x == 0 {
+                // 4面あるうちのどれかがわかるので、該当する面のベースアドレスを返します
+                let target_nametable_base_addr = nametable_base_addr +
+                    (if is_nametable_position_left { 0x0000 } else { 0x0400 }) + // 左右面の広域offset
+                    (if is_nametable_position_top  { 0x0000 } else { 0x0800 }); // 上下面の広域offset
+
+                // Nametableからtile_id読み出し
+                let nametable_addr =
+                    target_nametable_base_addr + (tile_local_y << 5) + tile_local_x;
+                let bg_tile_id =
+                    u16::from(system.video.read_u8(&mut system.cassette, nametable_addr));
+
+                // attribute tableはNametableの後32byteにいるのでアドレス計算して読み出す。縦横4*4tileで1attrになっている
+                // scroll対応のためにoffset計算はglobal位置を使っている（もしかしたら1Nametableでクリッピングがいるかも)
+                let attribute_base_addr = target_nametable_base_addr + ATTRIBUTE_TABLE_OFFSET; // 23c0, 27c0, 2bc0, 2fc0のどれか
+                let attribute_x_offset = (tile_global_x >> 2) & 0x7;
+                let attribute_y_offset = tile_global_y >> 2;
+                let attribute_addr =
+                    attribute_base_addr + (attribute_y_offset << 3) + attribute_x_offset;
+
+                // attribute読み出し, BGパレット選択に使う。4*4の位置で使うパレット情報を変える
+                let raw_attribute = system.video.read_u8(&mut system.cassette, attribute_addr);
+                bg_palette_id = match (tile_local_x & 0x03 < 0x2, tile_local_y & 0x03 < 0x2) {
+                    (true, true) => (raw_attribute >> 0) & 0x03,  // top left
+                    (false, true) => (raw_attribute >> 2) & 0x03, // top right
+                    (true, false) => (raw_attribute >> 4) & 0x03, // bottom left
+                    (false, false) => (raw_attribute >> 6) & 0x03, // bottom right
+                };
+
+                // pattern_table 1entryは16byte, 0行目だったら0,8番目のデータを使えば良い
+                let bg_pattern_table_base_addr = pattern_table_addr + (bg_tile_id << 4);
+                let bg_pattern_table_addr_lower = bg_pattern_table_base_addr + offset_y;
+                let bg_pattern_table_addr_upper = bg_pattern_table_addr_lower + 8;
+                bg_data_lower = system
+                    .video
+                    .read_u8(&mut system.cassette, bg_pattern_table_addr_lower);
+                bg_data_upper = system
+                    .video
+                    .read_u8(&mut system.cassette, bg_pattern_table_addr_upper);
+            }
 
             // bgの描画色を作る
             let bg_palette_offset = (((bg_data_upper >> (7 - offset_x)) & 0x01) << 1)
@@ -417,16 +391,84 @@ impl Ppu {
                 fb[pixel_y][pixel_x][2] = data;
             }
         }
+        // 以降は$2007からのアクセスになる
+        system.cassette.notify_ppu_fetch_phase(PpuFetchPhase::Idle);
+    }
+    /// 描画する行にあるスプライトのpattern tableを読み出します
+    /// 実機と同様にsprite 1つにつき1回だけfetchする(MMC2などfetchを監視するMapperのため)
+    /// `pixel_y` - 描画対象の表示するリーンにおけるy座標
+    /// retval - sprite_tempsと同じ並びの(lower, upper)
+    fn fetch_sprite_patterns(
+        &mut self,
+        system: &mut System,
+        pixel_y: usize,
+    ) -> [(u8, u8); SPRITE_TEMP_SIZE] {
+        let mut sprite_patterns = [(0, 0); SPRITE_TEMP_SIZE];
+        // Sprite描画無効化されていたら即終了
+        if !system.read_ppu_is_write_sprite() {
+            return sprite_patterns;
+        }
+        for (index, &s) in self.sprite_temps.iter().enumerate() {
+            // sprite tempsは前詰めなのでもう処理はいらない
+            let sprite = match s {
+                Some(sprite) => sprite,
+                None => break,
+            };
+            // sprite上での相対座標
+            let sprite_y = usize::from(sprite.y);
+            let sprite_offset_y: usize = pixel_y - sprite_y - 1; // 0-7 or 0-15 (largeの場合, tile参照前に0-7に詰める)
+            debug_assert!(sprite_offset_y < usize::from(system.read_ppu_sprite_height()));
+            // pattern table addrと、tile idはサイズで決まる
+            let (sprite_pattern_table_addr, sprite_tile_id): (u16, u8) = match sprite.tile_id {
+                TileId::Normal { id } => (system.read_ppu_sprite_pattern_table_addr(), id),
+                // 8*16 spriteなので上下でidが別れている
+                TileId::Large {
+                    pattern_table_addr,
+                    upper_tile_id,
+                    lower_tile_id,
+                } => {
+                    let is_upper = sprite_offset_y < SPRITE_NORMAL_HEIGHT; // 上8pixelの座標?
+                    let is_vflip = sprite.attr.is_vert_flip; // 上下反転してる?
+                    let id = match (is_upper, is_vflip) {
+                        (true, false) => upper_tile_id,  // 描画座標は上8pixel、Flipなし
+                        (false, false) => lower_tile_id, // 描画座標は下8pixel、Flipなし
+                        (true, true) => lower_tile_id,   // 描画座標は上8pixel、Flipあり
+                        (false, true) => upper_tile_id,  // 描画座標は下8pixel、Flipあり
+                    };
+                    (pattern_table_addr, id)
+                }
+            };
+            // y flipを考慮してtile上のデータ位置を決定する
+            let tile_offset_y: usize = if !sprite.attr.is_vert_flip {
+                sprite_offset_y % SPRITE_NORMAL_HEIGHT
+            } else {
+                SPRITE_NORMAL_HEIGHT - 1 - (sprite_offset_y % SPRITE_NORMAL_HEIGHT)
+            };
+            // tile addrを計算する
+            let sprite_pattern_table_base_addr = u16::from(sprite_pattern_table_addr)
+                + (u16::from(sprite_tile_id) * PATTERN_TABLE_ENTRY_BYTE);
+            let sprite_pattern_table_addr_lower =
+                sprite_pattern_table_base_addr + (tile_offset_y as u16);
+            let sprite_pattern_table_addr_upper = sprite_pattern_table_addr_lower + 8;
+            let sprite_data_lower = system
+                .video
+                .read_u8(&mut system.cassette, sprite_pattern_table_addr_lower);
+            let sprite_data_upper = system
+                .video
+                .read_u8(&mut system.cassette, sprite_pattern_table_addr_upper);
+            sprite_patterns[index] = (sprite_data_lower, sprite_data_upper);
+        }
+        sprite_patterns
     }
     /// 指定されたpixelにあるスプライトを描画します
     /// `pixel_x` - 描画対象の表示するリーンにおけるx座標
-    /// `pixel_y` - 描画対象の表示するリーンにおけるy座標
+    /// `sprite_patterns` - `fetch_sprite_patterns`で読み出したpattern
     /// retval - (bgよりも後ろに描画するデータ, bgより前に描画するデータ)
     fn get_sprite_draw_data(
         &mut self,
         system: &mut System,
         pixel_x: usize,
-        pixel_y: usize,
+        sprite_patterns: &[(u8, u8); SPRITE_TEMP_SIZE],
     ) -> (Option<u8>, Option<u8>) {
         // Sprite描画無効化されていたら即終了
         if !system.read_ppu_is_write_sprite() {
@@ -435,11 +477,12 @@ impl Ppu {
         // Spriteを探索する (y位置的に描画しなければならないSpriteは事前に読み込み済)
         let mut sprite_palette_data_back: Option<u8> = None; // 背面
         let mut sprite_palette_data_front: Option<u8> = None; // 全面
-        'draw_sprite: for &s in self.sprite_temps.iter() {
+        'draw_sprite: for (&s, &(sprite_data_lower, sprite_data_upper)) in
+            self.sprite_temps.iter().zip(sprite_patterns.iter())
+        {
             if let Some(sprite) = s {
                 // めんどいのでusizeにしておく
                 let sprite_x = usize::from(sprite.x);
-                let sprite_y = usize::from(sprite.y);
                 // 左端sprite clippingが有効な場合表示しない
                 let is_sprite_clipping = system.read_ppu_is_clip_sprite_leftend() && (pixel_x < 8);
                 // X位置が描画範囲の場合
@@ -449,54 +492,13 @@ impl Ppu {
                 {
                     // sprite上での相対座標
                     let sprite_offset_x: usize = pixel_x - sprite_x; // 0-7
-                    let sprite_offset_y: usize = pixel_y - sprite_y - 1; // 0-7 or 0-15 (largeの場合, tile参照前に0-7に詰める)
                     debug_assert!(sprite_offset_x < SPRITE_WIDTH);
-                    debug_assert!(sprite_offset_y < usize::from(system.read_ppu_sprite_height()));
-                    // pattern table addrと、tile idはサイズで決まる
-                    let (sprite_pattern_table_addr, sprite_tile_id): (u16, u8) = match sprite
-                        .tile_id
-                    {
-                        TileId::Normal { id } => (system.read_ppu_sprite_pattern_table_addr(), id),
-                        // 8*16 spriteなので上下でidが別れている
-                        TileId::Large {
-                            pattern_table_addr,
-                            upper_tile_id,
-                            lower_tile_id,
-                        } => {
-                            let is_upper = sprite_offset_y < SPRITE_NORMAL_HEIGHT; // 上8pixelの座標?
-                            let is_vflip = sprite.attr.is_vert_flip; // 上下反転してる?
-                            let id = match (is_upper, is_vflip) {
-                                (true, false) => upper_tile_id,  // 描画座標は上8pixel、Flipなし
-                                (false, false) => lower_tile_id, // 描画座標は下8pixel、Flipなし
-                                (true, true) => lower_tile_id,   // 描画座標は上8pixel、Flipあり
-                                (false, true) => upper_tile_id,  // 描画座標は下8pixel、Flipあり
-                            };
-                            (pattern_table_addr, id)
-                        }
-                    };
-                    // x,y flipを考慮してtile上のデータ位置を決定する
+                    // x flipを考慮してtile上のデータ位置を決定する
                     let tile_offset_x: usize = if !sprite.attr.is_hor_flip {
                         sprite_offset_x
                     } else {
                         SPRITE_WIDTH - 1 - sprite_offset_x
                     };
-                    let tile_offset_y: usize = if !sprite.attr.is_vert_flip {
-                        sprite_offset_y % SPRITE_NORMAL_HEIGHT
-                    } else {
-                        SPRITE_NORMAL_HEIGHT - 1 - (sprite_offset_y % SPRITE_NORMAL_HEIGHT)
-                    };
-                    // tile addrを計算する
-                    let sprite_pattern_table_base_addr = u16::from(sprite_pattern_table_addr)
-                        + (u16::from(sprite_tile_id) * PATTERN_TABLE_ENTRY_BYTE);
-                    let sprite_pattern_table_addr_lower =
-                        sprite_pattern_table_base_addr + (tile_offset_y as u16);
-                    let sprite_pattern_table_addr_upper = sprite_pattern_table_addr_lower + 8;
-                    let sprite_data_lower = system
-                        .video
-                        .read_u8(&mut system.cassette, sprite_pattern_table_addr_lower);
-                    let sprite_data_upper = system
-                        .video
-                        .read_u8(&mut system.cassette, sprite_pattern_table_addr_upper);
                     // 該当するx位置のpixel patternを作る
                     let sprite_palette_offset =
                         (((sprite_data_upper >> (7 - tile_offset_x)) & 0x01) << 1)
@@ -547,7 +549,7 @@ impl Ppu {
         'search_sprite: for sprite_index in 0..NUM_OF_SPRITE {
             let target_oam_addr = sprite_index << 2;
             // yの値と等しい
-            let sprite_y = u16::from(self.oam[target_oam_addr]);
+            let sprite_y = u16::from(system.oam[target_oam_addr]);
             let sprite_end_y = sprite_y + sprite_height;
             // 描画範囲内(y+1)~(y+1+ 8or16)
             if (sprite_y < sprite_begin_y) && (sprite_begin_y <= sprite_end_y) {
@@ -565,10 +567,10 @@ impl Ppu {
                     // tmp regに格納する
                     self.sprite_temps[tmp_index] = Some(Sprite::from(
                         is_large,
-                        self.oam[target_oam_addr],
-                        self.oam[target_oam_addr + 1],
-                        self.oam[target_oam_addr + 2],
-                        self.oam[target_oam_addr + 3],
+                        system.oam[target_oam_addr],
+                        system.oam[target_oam_addr + 1],
+                        system.oam[target_oam_addr + 2],
+                        system.oam[target_oam_addr + 3],
                     ));
                     tmp_index = tmp_index + 1;
                 }
@@ -582,25 +584,18 @@ impl Ppu {
         &mut self,
         system: &mut System,
         fb: &mut [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
-    ) -> Option<Interrupt> {
+    ) {
         // scroll更新
         self.current_scroll_x = self.fetch_scroll_x;
         self.current_scroll_y = self.fetch_scroll_y;
-        // OAM DMA
-        if self.is_dma_running {
-            // 前回のOAM DMAのこりをやる
-            self.run_dma(system, false);
-        }
-        let (is_dma_req, dma_cpu_src_addr) = system.read_oam_dma();
-        if is_dma_req {
-            // 新しいDMAのディスクリプタをセットして実行
-            self.dma_cpu_src_addr = dma_cpu_src_addr;
-            self.dma_oam_dst_addr = system.read_ppu_oam_addr();
-            self.run_dma(system, true);
-        }
         // ステータスを初期化
         system.write_ppu_is_hit_sprite0(false);
         system.write_ppu_is_sprite_overflow(false);
+        // 描画が有効な間はMapperのscanline counterを進める(MMC3のA12立ち上がり相当)
+        let is_rendering = system.read_ppu_is_write_bg() || system.read_ppu_is_write_sprite();
+        // dot単位で更新済なら、line単位ではVBLANKフラグを触らない
+        let is_vblank_updated = self.is_vblank_updated;
+        self.is_vblank_updated = false;
 
         // 行の更新
         match LineStatus::from(self.current_line) {
@@ -609,40 +604,40 @@ impl Ppu {
                 self.fetch_sprite(system);
                 // 1行描く
                 self.draw_line(system, fb);
+                if is_rendering {
+                    system.cassette.notify_scanline();
+                }
                 // 行カウンタを更新して終わり
                 self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
-
-                None
             }
             LineStatus::PostRender => {
                 self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
-                None
+                // 画面を描き終えたので1frameとする
+                self.frame_count += 1;
             }
             LineStatus::VerticalBlanking(is_first) => {
                 self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
-                if is_first {
+                // VBLANKフラグとNMI enableからCPUがNMIを検出する($2002を読んでフラグをおろしてもらう)
+                if is_first && !is_vblank_updated {
                     system.write_ppu_is_vblank(true);
                 }
-                // VBLANKフラグが立っていれば割り込みを発生させる($2002を読んでフラグをおろしてもらう)
-                if system.read_ppu_nmi_enable() && system.read_ppu_is_vblank() {
-                    Some(Interrupt::NMI)
-                } else {
-                    None
-                }
             }
             LineStatus::PreRender => {
                 self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
+                self.is_odd_frame = !self.is_odd_frame;
                 // VBLANKフラグを下ろす
-                system.write_ppu_is_vblank(false);
-
-                None
+                if !is_vblank_updated {
+                    system.write_ppu_is_vblank(false);
+                }
+                if is_rendering {
+                    system.cassette.notify_scanline();
+                }
             }
         }
     }
 
-    /// PPUの処理を進めます(1line進めるまでには341 cpu cycleかかります)
+    /// PPUの処理を進めます(1line進めるまでには341 ppu cycle = 113.67 cpu cycleかかります)
     /// `cpu_cyc` - cpuが何clock処理したか入れる(cpu 1stepごとに呼ぶこと)
-    /// `cpu` - Interruptの要求が必要
     /// `system` - レジスタ読み書きする
     /// `video_system` - レジスタ読み書きする
     /// `videoout_func` - pixelごとのデータが決まるごとに呼ぶ(NESは出力ダブルバッファとかない)
@@ -651,7 +646,7 @@ impl Ppu {
         cpu_cyc: usize,
         system: &mut System,
         fb: &mut [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
-    ) -> Option<Interrupt> {
+    ) {
         // PPU_SCROLL書き込み
         let (_, scroll_x, scroll_y) = system.read_ppu_scroll();
         self.fetch_scroll_x = scroll_x;
@@ -673,25 +668,44 @@ impl Ppu {
             system.increment_ppu_addr();
         }
 
-        // OAM R/W (おおよそはDMAでやられるから使わないらしい)
-        let oam_addr = system.read_ppu_oam_addr();
-        let (is_read_oam_req, is_write_oam_req, oam_data) = system.read_oam_data();
-        if is_write_oam_req {
-            self.oam[usize::from(oam_addr)] = oam_data;
+        // Mapperのcycle IRQや拡張音源もCPUのclockで進める
+        system.cassette.notify_cpu_cycles(cpu_cyc);
+
+        // clock cycle判定して行更新, DMAで止まっていた分などで複数line進むこともある
+        // NMI, IRQはCPUがstepの中でSystemから読み取る(Cpu::update_interrupt_lines)
+        self.cumulative_ppu_cyc += cpu_cyc * PPU_CYCLE_PER_CPU_CYCLE;
+        loop {
+            #[cfg(feature = "cycle-accurate")]
+            self.update_vblank_dot(system);
+            let line_cyc = self.line_cycle(system);
+            if self.cumulative_ppu_cyc < line_cyc {
+                break;
+            }
+            self.cumulative_ppu_cyc -= line_cyc;
+            self.update_line(system, fb);
         }
-        if is_read_oam_req {
-            let data = self.oam[usize::from(oam_addr)];
-            system.write_oam_data(data);
+    }
+    /// line 241のdot 1でVBLANKフラグを立て、pre-render lineのdot 1で下ろします
+    /// 1cycleずつ進めるcycle-accurate coreでは、$2002の読み出しやNMIがline単位より細かく見える
+    #[cfg(feature = "cycle-accurate")]
+    fn update_vblank_dot(&mut self, system: &mut System) {
+        if self.is_vblank_updated || self.cumulative_ppu_cyc <= PPU_VBLANK_UPDATE_DOT {
+            return;
         }
-
-        // clock cycle判定して行更新
-        let total_cyc = self.cumulative_cpu_cyc + cpu_cyc;
-        if total_cyc >= CPU_CYCLE_PER_LINE {
-            self.cumulative_cpu_cyc = total_cyc - CPU_CYCLE_PER_LINE;
-            self.update_line(system, fb)
-        } else {
-            self.cumulative_cpu_cyc = total_cyc;
-            None
+        match LineStatus::from(self.current_line) {
+            LineStatus::VerticalBlanking(true) => system.write_ppu_is_vblank(true),
+            LineStatus::PreRender => system.write_ppu_is_vblank(false),
+            _ => return,
+        }
+        self.is_vblank_updated = true;
+    }
+    /// 次に処理するlineのppu cycle数を返します
+    /// 奇数frameで描画が有効なら、pre-render lineは1dot飛ばす
+    fn line_cycle(&self, system: &System) -> usize {
+        let is_rendering = system.read_ppu_is_write_bg() || system.read_ppu_is_write_sprite();
+        match LineStatus::from(self.current_line) {
+            LineStatus::PreRender if self.is_odd_frame && is_rendering => PPU_CYCLE_PER_LINE - 1,
+            _ => PPU_CYCLE_PER_LINE,
         }
     }
 }
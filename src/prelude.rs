@@ -1,7 +1,11 @@
 pub use super::apu::*;
 pub use super::cassette::*;
+pub use super::cassette_header::*;
+pub use super::cassette_unif::*;
 pub use super::cpu::*;
 pub use super::interface::*;
+pub use super::mapper::*;
 pub use super::pad::*;
 pub use super::ppu::*;
+pub use super::rom_db::*;
 pub use super::system::*;
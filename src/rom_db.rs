@@ -0,0 +1,217 @@
+use super::cassette::*;
+use super::cassette_header::*;
+
+/// ROM databaseの1タイトル分
+/// 古いiNES dumpはMapper番号やミラーリング、バッテリーの有無が間違っていることが多いので、
+/// PRG-ROM + CHR-ROMのhashで引いてheaderを補正する
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomDbEntry {
+    /// PRG-ROM + CHR-ROMのCRC32
+    pub crc32: u32,
+    /// PRG-ROM + CHR-ROMのSHA-1, 全部0なら照合しない
+    pub sha1: [u8; 20],
+    pub title: &'static str,
+    pub mapper_number: u16,
+    pub submapper_number: u8,
+    /// Mapperが制御する場合などはNone(headerの値を使う)
+    pub nametable_mirror: Option<NameTableMirror>,
+    pub is_four_screen: bool,
+    pub is_battery: bool,
+    pub prg_ram_bytes: usize,
+    pub prg_nvram_bytes: usize,
+    pub chr_ram_bytes: usize,
+    pub chr_nvram_bytes: usize,
+    pub timing: TimingRegion,
+    pub console_type: ConsoleType,
+}
+
+/// src/rom_db.xml(NES 2.0 XML database形式)からbuild時に生成した表, crc32順に並んでいる
+/// 環境変数NES_ROM_DBでnes20db.xmlなど別のdatabaseを指定してbuildできる
+pub static ROM_DATABASE: &[RomDbEntry] = &include!(concat!(env!("OUT_DIR"), "/rom_db_table.rs"));
+
+impl RomDbEntry {
+    /// headerをdatabaseの内容で上書きします。ROMのサイズはイメージの読み出しに使うので変えない
+    pub fn apply(&self, header: &mut RomHeader) {
+        header.mapper_number = self.mapper_number;
+        header.submapper_number = self.submapper_number;
+        if let Some(mirror) = self.nametable_mirror {
+            header.nametable_mirror = mirror;
+        }
+        header.is_four_screen = self.is_four_screen;
+        header.is_battery = self.is_battery;
+        header.prg_ram_bytes = self.prg_ram_bytes;
+        header.prg_nvram_bytes = self.prg_nvram_bytes;
+        header.chr_ram_bytes = self.chr_ram_bytes;
+        header.chr_nvram_bytes = self.chr_nvram_bytes;
+        header.timing = self.timing;
+        header.console_type = self.console_type;
+    }
+}
+
+/// PRG-ROM + CHR-ROMのhashで`database`(crc32順)を引きます
+/// `offset`から`len`byteを`read_func`で読み出してCRC32を求め、一致する候補があればSHA-1でも照合する
+/// 戻り値はCRC32と見つかったentry
+pub fn lookup_rom_db(
+    database: &'static [RomDbEntry],
+    offset: usize,
+    len: usize,
+    read_func: &impl Fn(usize) -> u8,
+) -> (u32, Option<&'static RomDbEntry>) {
+    let mut crc = Crc32::default();
+    for addr in offset..offset + len {
+        crc.update(read_func(addr));
+    }
+    let crc32 = crc.finish();
+    let first = database.partition_point(|entry| entry.crc32 < crc32);
+    let candidates = database[first..]
+        .iter()
+        .take_while(|entry| entry.crc32 == crc32);
+    // SHA-1は重いので、CRC32が一致したときだけ計算する
+    let mut sha1 = None;
+    for entry in candidates {
+        if entry.sha1 == [0; 20] {
+            return (crc32, Some(entry));
+        }
+        let digest = *sha1.get_or_insert_with(|| {
+            let mut hasher = Sha1::default();
+            for addr in offset..offset + len {
+                hasher.update(read_func(addr));
+            }
+            hasher.finish()
+        });
+        if entry.sha1 == digest {
+            return (crc32, Some(entry));
+        }
+    }
+    (crc32, None)
+}
+
+/// CRC32(多項式0xedb88320)の1byte分のtable
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if (crc & 0x01) == 0x01 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// 1byteずつ計算するCRC32
+#[derive(Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self { crc: 0xffff_ffff }
+    }
+}
+
+impl Crc32 {
+    pub fn update(&mut self, data: u8) {
+        let index = usize::from((self.crc as u8) ^ data);
+        self.crc = (self.crc >> 8) ^ CRC32_TABLE[index];
+    }
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// 1byteずつ計算するSHA-1
+/// https://tools.ietf.org/html/rfc3174
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Self {
+            state: [
+                0x6745_2301,
+                0xefcd_ab89,
+                0x98ba_dcfe,
+                0x1032_5476,
+                0xc3d2_e1f0,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+}
+
+impl Sha1 {
+    pub fn update(&mut self, data: u8) {
+        self.block[self.block_len] = data;
+        self.block_len += 1;
+        self.total_len += 1;
+        if self.block_len == 64 {
+            self.process_block();
+        }
+    }
+    pub fn finish(mut self) -> [u8; 20] {
+        let bit_len = self.total_len * 8;
+        // 0x80, 0埋め, 最後の8byteにbit長
+        self.update(0x80);
+        while self.block_len != 56 {
+            self.update(0x00);
+        }
+        for d in bit_len.to_be_bytes().iter() {
+            self.update(*d);
+        }
+        let mut digest = [0u8; 20];
+        for (dst, s) in digest.chunks_mut(4).zip(self.state.iter()) {
+            dst.copy_from_slice(&s.to_be_bytes());
+        }
+        digest
+    }
+    fn process_block(&mut self) {
+        let mut w = [0u32; 80];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+        self.block_len = 0;
+    }
+}
@@ -1,6 +1,8 @@
+use super::apu::*;
 use super::cassette::*;
 use super::interface::*;
 use super::pad::*;
+use super::ppu::OAM_SIZE;
 use super::video_system::*;
 
 pub const WRAM_SIZE: usize = 0x0800;
@@ -29,7 +31,7 @@ pub struct System {
 
     /// カセットへのR/W要求は呼び出し先でEmulation, 実機を切り替えるようにする
     /// 引数に渡されるaddrは、CPU命令そのままのアドレスを渡す
-    ///  0x4020 - 0x5fff: Extended ROM
+    ///  0x4020 - 0x5fff: Expansion area(Mapperが使わなければopen bus)
     ///  0x6000 - 0x7FFF: Extended RAM
     ///  0x8000 - 0xbfff: PRG-ROM switchable
     ///  0xc000 - 0xffff: PRG-ROM fixed to the last bank or switchable
@@ -37,19 +39,25 @@ pub struct System {
 
     /// PPUが描画に使うメモリ空間
     pub video: VideoSystem,
+    /// Object Attribute Memoryの実態
+    /// $2004とOAM DMAでCPUから直接書くので、PPUではなくこちらに置く
+    pub oam: [u8; OAM_SIZE],
 
     /// コントローラへのアクセスは以下のモジュールにやらせる
     /// 0x4016, 0x4017
     pub pad1: Pad,
     pub pad2: Pad,
 
+    /// DMCのサンプル読み出し, 読むたびにCPUを止める
+    pub dmc: DmcReader,
+    /// $4017 Frame Counter, 4-step modeでframe IRQを出す
+    pub frame_counter: FrameCounter,
+
     /* PPUのアドレス空間に対する要求トリガ */
-    pub written_oam_data: bool,   // OAM_DATAがかかれた
     pub written_ppu_scroll: bool, // PPU_SCROLLが2回書かれた
     pub written_ppu_addr: bool,   // PPU_ADDRが2回書かれた
     pub written_ppu_data: bool,   // PPU_DATAがかかれた
     pub written_oam_dma: bool,    // OAM_DMAが書かれた
-    pub read_oam_data: bool,      // OAM_DATAが読まれた
     pub read_ppu_data: bool,      // PPU_DATAが読まれた
 
     /* 2回海ができるPPU register対応 */
@@ -68,15 +76,16 @@ impl Default for System {
 
             cassette: Default::default(),
             video: Default::default(),
+            oam: [0; OAM_SIZE],
             pad1: Default::default(),
             pad2: Default::default(),
+            dmc: Default::default(),
+            frame_counter: Default::default(),
 
-            written_oam_data: false,
             written_ppu_scroll: false,
             written_ppu_addr: false,
             written_ppu_data: false,
             written_oam_dma: false,
-            read_oam_data: false,
             read_ppu_data: false,
 
             ppu_is_second_write: false,
@@ -91,17 +100,18 @@ impl EmulateControl for System {
         self.video.reset();
         self.pad1.reset();
         self.pad2.reset();
+        self.dmc = Default::default();
+        self.frame_counter = Default::default();
 
         self.wram = [0; WRAM_SIZE];
         self.ppu_reg = [0; PPU_REG_SIZE];
+        self.oam = [0; OAM_SIZE];
         self.io_reg = [0; APU_IO_REG_SIZE];
 
-        self.written_oam_data = false;
         self.written_ppu_scroll = false;
         self.written_ppu_addr = false;
         self.written_ppu_data = false;
         self.written_oam_dma = false;
-        self.read_oam_data = false;
         self.read_ppu_data = false;
 
         self.ppu_is_second_write = false;
@@ -130,13 +140,8 @@ impl SystemBus for System {
                     }
                     data
                 }
-                // OAM_DATAの読み出しフラグ
-                0x04 => {
-                    if !is_nondestructive {
-                        self.read_oam_data = true;
-                    }
-                    arr_read!(self.ppu_reg, index)
-                }
+                // OAM_DATA OAMADDRの位置を読む(OAMADDRは進まない)
+                0x04 => self.read_oam_data(),
                 // PPU_DATA update/address incrementのためにフラグを立てる
                 // バッファが入るので1step遅れで結果が入る
                 0x07 => {
@@ -150,15 +155,12 @@ impl SystemBus for System {
             }
         } else if addr < CASSETTE_BASE_ADDR {
             let index = usize::from(addr - APU_IO_REG_BASE_ADDR);
-            if !is_nondestructive {
-                match index {
-                    // TODO: APU
-                    0x16 => self.pad1.read_out(), // pad1
-                    0x17 => self.pad2.read_out(), // pad2
-                    _ => arr_read!(self.io_reg, index),
-                }
-            } else {
-                arr_read!(self.io_reg, index)
+            match index {
+                // APU Status 読むとframe IRQが下りる
+                0x15 => self.read_apu_status(is_nondestructive),
+                0x16 if !is_nondestructive => self.pad1.read_out(), // pad1
+                0x17 if !is_nondestructive => self.pad2.read_out(), // pad2
+                _ => arr_read!(self.io_reg, index),
             }
         } else {
             self.cassette.read_u8(addr, is_nondestructive)
@@ -173,10 +175,10 @@ impl SystemBus for System {
             // mirror support
             let index = usize::from(addr - PPU_REG_BASE_ADDR) % self.ppu_reg.len();
             match index {
-                // $2004 OAM_DATAに書いたら書き込みフラグを立てる(使わないだろうけど)
+                // $2004 OAM_DATA OAMADDRの位置に書いてOAMADDRを進める
                 0x04 => {
                     if !is_nondestructive {
-                        self.written_oam_data = true
+                        self.write_oam_data(data);
                     }
                     arr_write!(self.ppu_reg, index, data);
                 }
@@ -225,14 +227,30 @@ impl SystemBus for System {
                     arr_write!(self.ppu_reg, index, data);
                 }
             };
+            // $2000/$2001を監視しているMapperがいる(MMC5)
+            if !is_nondestructive {
+                self.cassette
+                    .notify_ppu_register_write(PPU_REG_BASE_ADDR + index as u16, data);
+            }
         } else if addr < CASSETTE_BASE_ADDR {
             let index = usize::from(addr - APU_IO_REG_BASE_ADDR);
             if !is_nondestructive {
                 match index {
                     // TODO: APU
-                    0x14 => self.written_oam_dma = true, // OAM DMA
-                    0x16 => self.pad1.write_strobe((data & 0x01) == 0x01), // pad1
-                    0x17 => self.pad2.write_strobe((data & 0x01) == 0x01), // pad2
+                    // DMC
+                    0x10 => self.write_apu_dmc_control(data),
+                    0x14 => {
+                        // OAM DMA
+                        self.written_oam_dma = true;
+                    }
+                    0x15 => self.write_apu_status(data),
+                    // strobeはpad1/pad2で共通
+                    0x16 => {
+                        self.pad1.write_strobe((data & 0x01) == 0x01);
+                        self.pad2.write_strobe((data & 0x01) == 0x01);
+                    }
+                    // Frame Counter
+                    0x17 => self.write_apu_frame_counter(data),
                     _ => {}
                 }
             }
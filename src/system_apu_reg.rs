@@ -1,4 +1,5 @@
 use super::apu::*;
+use super::interface::*;
 use super::system::*;
 
 pub const APU_PULSE_1_OFFSET: usize = 0x00;
@@ -7,7 +8,7 @@ pub const APU_TRIANGLE_OFFSET: usize = 0x08;
 pub const APU_NOISE_OFFSET: usize = 0x0c;
 pub const APU_DMC_OFFSET: usize = 0x10;
 pub const APU_STATUS_OFFSET: usize = 0x15;
-pub const APU_FRAMECOUNTER_OFFSET: usize = 0x15;
+pub const APU_FRAMECOUNTER_OFFSET: usize = 0x17;
 
 /// APU & I/O(PAD) Register Implement
 /// APUのみ(DMAはsystem_ppu_reg.rs, padはレジスタの変数を使わない)
@@ -116,6 +117,27 @@ impl System {
         Some(dst)
     }
 
+    /// $4015を読んだときの値を返します
+    /// IF-D----, I: DMC IRQ, F: frame IRQ, D: DMCの読み残しがある
+    /// length counterは未実装なので、下位4bitは0を返す
+    /// `is_nondestructive` - falseならframe IRQを下ろす
+    pub fn read_apu_status(&mut self, is_nondestructive: bool) -> u8 {
+        let mut data = 0;
+        if self.dmc.is_irq {
+            data |= 0x80;
+        }
+        if self.frame_counter.is_irq {
+            data |= 0x40;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            data |= 0x10;
+        }
+        if !is_nondestructive {
+            self.frame_counter.is_irq = false;
+        }
+        data
+    }
+
     // $4015 Status
     // ---DNTPP
     pub fn read_apu_is_enable_dmc(&self) -> bool {
@@ -136,5 +158,40 @@ impl System {
         }
     }
 
-    // TODO: $4017 Frame Counter
+    /// $4010を書いたとき、IRQを無効にしたらDMCのIRQを下ろします
+    pub fn write_apu_dmc_control(&mut self, data: u8) {
+        if (data & 0x80) == 0x00 {
+            self.dmc.is_irq = false;
+        }
+    }
+    /// $4015を書いたとき、DMCを開始/停止してIRQを下ろします
+    pub fn write_apu_status(&mut self, data: u8) {
+        if (data & 0x10) == 0x10 {
+            self.dmc.start(
+                self.io_reg[APU_DMC_OFFSET + 2],
+                self.io_reg[APU_DMC_OFFSET + 3],
+            );
+        } else {
+            self.dmc.stop();
+        }
+        self.dmc.is_irq = false;
+    }
+    /// $4017を書いたとき、Frame Counterのmodeを切り替えます
+    pub fn write_apu_frame_counter(&mut self, data: u8) {
+        self.frame_counter.write(data);
+    }
+    /// Frame CounterとDMCを1cycle進めます
+    /// ret: DMCがサンプルを読んだらCPUが止まるcycle数
+    pub fn step_apu(&mut self) -> u16 {
+        self.frame_counter.step();
+        let rate_index = self.io_reg[APU_DMC_OFFSET] & 0x0f;
+        if let Some(addr) = self.dmc.step(rate_index) {
+            if let Some(config) = self.read_apu_dmc_config() {
+                let data = self.read_u8(addr, false);
+                self.dmc.fill(data, &config);
+                return DMC_FETCH_STALL_CYCLES;
+            }
+        }
+        0
+    }
 }
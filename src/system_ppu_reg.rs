@@ -130,23 +130,16 @@ impl System {
         self.ppu_reg[PPU_OAMADDR_OFFSET]
     }
     /*************************** 0x2004: OAMDATA ***************************/
-    /// OAM_DATAの書き換えがあったかを示すフラグもついてくるよ(自動で揮発します)
-    /// is_read, is_write, data
-    pub fn read_oam_data(&mut self) -> (bool, bool, u8) {
-        // Write優先でフラグ管理して返してあげる
-        if self.written_oam_data {
-            self.written_oam_data = false;
-            (false, true, self.ppu_reg[PPU_OAMDATA_OFFSET])
-        } else if self.read_oam_data {
-            self.read_oam_data = false;
-            (true, false, self.ppu_reg[PPU_OAMDATA_OFFSET])
-        } else {
-            (false, false, self.ppu_reg[PPU_OAMDATA_OFFSET])
-        }
+    /// OAMADDRの位置のOAMを返す
+    pub fn read_oam_data(&self) -> u8 {
+        self.oam[usize::from(self.read_ppu_oam_addr())]
     }
-
+    /// OAMADDRの位置に書いて、OAMADDRを1進める(0xffの次は0x00)
+    /// OAM DMAもここを256回通る
     pub fn write_oam_data(&mut self, data: u8) {
-        self.ppu_reg[PPU_OAMDATA_OFFSET] = data;
+        let oam_addr = self.read_ppu_oam_addr();
+        self.oam[usize::from(oam_addr)] = data;
+        self.ppu_reg[PPU_OAMADDR_OFFSET] = oam_addr.wrapping_add(1);
     }
 
     /*************************** 0x2005: PPUSCROLL ***************************/
@@ -210,7 +203,7 @@ impl System {
     }
     /*************************** 0x4014: OAM_DMA ***************************/
     /// DMA開始が必要かどうかと、転送元アドレスを返す
-    /// 面倒なので読み取ったらtriggerは揮発させる
+    /// CPUが命令のあとに読んでDMAを行う。面倒なので読み取ったらtriggerは揮発させる
     pub fn read_oam_dma(&mut self) -> (bool, u16) {
         let start_addr = u16::from(self.io_reg[APU_IO_OAM_DMA_OFFSET]) << 8;
         if self.written_oam_dma {
@@ -1,5 +1,6 @@
 use super::cassette::*;
 use super::interface::*;
+use super::mapper::*;
 
 pub const PATTERN_TABLE_BASE_ADDR: u16 = 0x0000;
 pub const NAME_TABLE_BASE_ADDR: u16 = 0x2000;
@@ -24,7 +25,8 @@ pub struct VideoSystem {
     // cassetteのCHR-RAMを読む
     /// 0x2000-0x2fff
     /// name table 0/1/2/3 (0x400が4面)
-    /// 実際には2面しか持っていないのでカセットのミラーリング設定を引用
+    /// 本体は2面(CIRAM)しか持っていないのでカセットのミラーリング設定を引用
+    /// four-screenなどカセット側のVRAMを使う場合はCassetteが持つ
     /// 0x3000-0x3effは0x2000からのミラー
     pub nametables: [[u8; NAME_TABLE_SIZE]; NUM_OF_NAME_TABLE],
 
@@ -51,53 +53,32 @@ impl EmulateControl for VideoSystem {
 }
 
 impl VideoSystem {
-    /// NameTable Mirrorのアドレス変換をします
-    /// 戻り値: (table_index[0,1,2,3のどれか], offset[中身のindex])
-    ///
-    /// [A, B]: A-0x2000, B-0x2400
-    /// [C, D]: C-0x2800, D-0x2c00
-    fn convert_name_table_addr(&self, mirror_mode: NameTableMirror, addr: u16) -> (usize, usize) {
+    /// 0x2000 - 0x2fffのアドレスを(slot[0,1,2,3のどれか], offset[中身のindex])に変換します
+    fn split_name_table_addr(addr: u16) -> (usize, usize) {
         debug_assert!(addr >= NAME_TABLE_BASE_ADDR);
         debug_assert!(addr < NAME_TABLE_MIRROR_BASE_ADDR);
 
-        // offsetはすぐわかるはず
-        let offset = usize::from(addr - NAME_TABLE_BASE_ADDR) % NAME_TABLE_SIZE;
-        // でかいとこはは頑張らないとわからんな
-        let table_index = match mirror_mode {
-            NameTableMirror::Horizontal => {
-                // [A, A]
-                // [B, B]
-                if addr < 0x2800 {
-                    0
-                } else {
-                    1
-                }
-            }
-            NameTableMirror::Vertical => {
-                // [A, B]
-                // [A, B]
-                let tmp_addr = if addr >= 0x2800 { addr - 0x800 } else { addr }; // とりあえず上の領域で考える
-                if tmp_addr < 0x2400 {
-                    0
-                } else {
-                    1
-                }
-            }
-            NameTableMirror::SingleScreen => {
-                // [A, A]
-                // [A, A]
-                0
-            }
-            NameTableMirror::FourScreen => {
-                // [A, B]
-                // [C, D]
-                usize::from((addr - 0x2000) / 4)
-            }
-            _ => {
-                unimplemented!();
-            }
+        let index = usize::from(addr - NAME_TABLE_BASE_ADDR);
+        ((index / NAME_TABLE_SIZE) & 0x03, index % NAME_TABLE_SIZE)
+    }
+    /// NameTableを読み出します
+    /// カセットのミラーリング設定やMapperの指定に従って本体VRAMかカセット側を読む
+    /// 読み出した結果はMapperが差し替える場合がある(MMC5の拡張属性など)
+    fn read_name_table(&self, cassette: &mut Cassette, addr: u16) -> u8 {
+        let (slot, offset) = Self::split_name_table_addr(addr);
+        let data = match cassette.nametable_source(slot) {
+            NameTableSource::Ciram(index) => self.nametables[index & 0x01][offset],
+            _ => cassette.read_nametable_u8(slot, offset),
         };
-        (table_index, offset)
+        cassette.filter_nametable_read(addr, data)
+    }
+    /// NameTableに書き込みます
+    fn write_name_table(&mut self, cassette: &mut Cassette, addr: u16, data: u8) {
+        let (slot, offset) = Self::split_name_table_addr(addr);
+        match cassette.nametable_source(slot) {
+            NameTableSource::Ciram(index) => self.nametables[index & 0x01][offset] = data,
+            _ => cassette.write_nametable_u8(slot, offset, data),
+        }
     }
     pub fn read_u8(&self, cassette: &mut Cassette, addr: u16) -> u8 {
         debug_assert!(addr < VIDEO_ADDRESS_SIZE);
@@ -105,13 +86,10 @@ impl VideoSystem {
         if addr < NAME_TABLE_BASE_ADDR {
             cassette.read_video_u8(addr)
         } else if addr < NAME_TABLE_MIRROR_BASE_ADDR {
-            let (index, offset) = self.convert_name_table_addr(cassette.nametable_mirror, addr);
-            self.nametables[index][offset]
+            self.read_name_table(cassette, addr)
         } else if addr < PALETTE_TABLE_BASE_ADDR {
             // 0x3000 -> 0x2000にミラーする
-            let (index, offset) =
-                self.convert_name_table_addr(cassette.nametable_mirror, addr - 0x1000);
-            self.nametables[index][offset]
+            self.read_name_table(cassette, addr - 0x1000)
         } else {
             // Palette with mirroring
             let index = usize::from(addr - PALETTE_TABLE_BASE_ADDR) % PALETTE_SIZE;
@@ -131,13 +109,10 @@ impl VideoSystem {
         if addr < NAME_TABLE_BASE_ADDR {
             cassette.write_video_u8(addr, data);
         } else if addr < NAME_TABLE_MIRROR_BASE_ADDR {
-            let (index, offset) = self.convert_name_table_addr(cassette.nametable_mirror, addr);
-            self.nametables[index][offset] = data;
+            self.write_name_table(cassette, addr, data);
         } else if addr < PALETTE_TABLE_BASE_ADDR {
             // 0x3000 -> 0x2000にミラーする
-            let (index, offset) =
-                self.convert_name_table_addr(cassette.nametable_mirror, addr - 0x1000);
-            self.nametables[index][offset] = data;
+            self.write_name_table(cassette, addr - 0x1000, data);
         } else {
             // Palette with mirroring
             let index = usize::from(addr - PALETTE_TABLE_BASE_ADDR) % PALETTE_SIZE;
@@ -18,8 +18,8 @@ fn load_cassette(cassette: &mut Cassette, path: String) {
     let mut buf: Vec<u8> = Vec::new();
     let _ = file.read_to_end(&mut buf).unwrap();
     // casseteに展開
-    if !cassette.from_ines_binary(|addr: usize| buf[addr]) {
-        panic!("ines binary read error");
+    if let Err(e) = cassette.from_ines_binary(buf.len(), |addr: usize| buf[addr]) {
+        panic!("ines binary read error: {}", e);
     }
 }
 
@@ -101,6 +101,28 @@ fn run_cpu_only(rom_path: String, cpu_steps: usize, validate: impl Fn(&Cpu, &Sys
     validate(&cpu, &cpu_sys);
 }
 
+/// cpuを1命令進めて、ppuもその分進めます
+/// cycle-accurate featureが有効なら、busアクセスごとにppuを1cycleずつ進める
+/// ret: cpu cycle数
+#[allow(dead_code)]
+fn step_cpu_ppu(
+    cpu: &mut Cpu,
+    cpu_sys: &mut System,
+    ppu: &mut Ppu,
+    fb: &mut [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+) -> usize {
+    #[cfg(feature = "cycle-accurate")]
+    {
+        usize::from(cpu.step_cycle_accurate(cpu_sys, |sys| ppu.step(1, sys, fb)))
+    }
+    #[cfg(not(feature = "cycle-accurate"))]
+    {
+        let cpu_cycle = usize::from(cpu.step(cpu_sys));
+        ppu.step(cpu_cycle, cpu_sys, fb);
+        cpu_cycle
+    }
+}
+
 /// 指定したフレーム数だけ流す
 #[allow(dead_code)]
 fn run_cpu_ppu(
@@ -124,14 +146,9 @@ fn run_cpu_ppu(
 
     // cpuを基準にppuを動かしてあげる
     for _i in 0..frame_count {
-        let mut total_cycle: usize = 0;
-        while total_cycle < CYCLE_PER_DRAW_FRAME {
-            let cpu_cycle = usize::from(cpu.step(&mut cpu_sys));
-            if let Some(interrupt) = ppu.step(cpu_cycle, &mut cpu_sys, &mut fb) {
-                cpu.interrupt(&mut cpu_sys, interrupt);
-            }
-
-            total_cycle = total_cycle + cpu_cycle;
+        let current_frame = ppu.frame_count;
+        while ppu.frame_count == current_frame {
+            step_cpu_ppu(&mut cpu, &mut cpu_sys, &mut ppu, &mut fb);
         }
     }
 
@@ -159,14 +176,9 @@ fn run_nestest(rom_path: String) {
 
     // cpuを基準にppuを動かしてあげる
     for i in 0..60 {
-        let mut total_cycle: usize = 0;
-        while total_cycle < CYCLE_PER_DRAW_FRAME {
-            let cpu_cycle = usize::from(cpu.step(&mut cpu_sys));
-            if let Some(interrupt) = ppu.step(cpu_cycle, &mut cpu_sys, &mut fb) {
-                cpu.interrupt(&mut cpu_sys, interrupt);
-            }
-
-            total_cycle = total_cycle + cpu_cycle;
+        let current_frame = ppu.frame_count;
+        while ppu.frame_count == current_frame {
+            step_cpu_ppu(&mut cpu, &mut cpu_sys, &mut ppu, &mut fb);
         }
         match i {
             4 => {
@@ -211,6 +223,69 @@ fn run_nestest(rom_path: String) {
     }
 }
 
+/// blarggのテストROMを実行して、$6000に書かれる結果を確認する
+/// $6001-$6003にDE B0 61が書かれていれば、$6000は0x80が実行中、0x81がRESET要求、それ以外が結果コード
+/// $6004からは結果のメッセージが0終端で書かれる
+#[allow(dead_code)]
+fn run_blargg_test(rom_path: String, max_frame_count: usize) {
+    let mut cpu: Cpu = Default::default();
+    let mut cpu_sys: System = Default::default();
+    let mut ppu: Ppu = Default::default();
+
+    load_cassette(&mut cpu_sys.cassette, rom_path);
+
+    cpu.reset();
+    cpu_sys.reset();
+    ppu.reset();
+    cpu.interrupt(&mut cpu_sys, Interrupt::RESET);
+
+    let mut fb = [[[0; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT];
+
+    let mut status = 0x80;
+    for _i in 0..max_frame_count {
+        let current_frame = ppu.frame_count;
+        while ppu.frame_count == current_frame {
+            step_cpu_ppu(&mut cpu, &mut cpu_sys, &mut ppu, &mut fb);
+        }
+        let signature = [
+            cpu_sys.read_u8(0x6001, true),
+            cpu_sys.read_u8(0x6002, true),
+            cpu_sys.read_u8(0x6003, true),
+        ];
+        if signature != [0xde, 0xb0, 0x61] {
+            continue;
+        }
+        status = cpu_sys.read_u8(0x6000, true);
+        match status {
+            0x80 => {}
+            0x81 => cpu.interrupt(&mut cpu_sys, Interrupt::RESET),
+            _ => break,
+        }
+    }
+
+    let mut message = String::new();
+    for addr in 0x6004..0x7000 {
+        let c = cpu_sys.read_u8(addr, true);
+        if c == 0 {
+            break;
+        }
+        message.push(char::from(c));
+    }
+    println!("{}", message);
+    // JAMで止まっていたら結果は書かれない
+    assert_eq!(CpuState::Running, cpu.state);
+    assert_eq!(0x00, status, "{}", message);
+}
+
+#[cfg(test)]
+mod test_apu;
+#[cfg(test)]
+mod test_cassette;
+#[cfg(test)]
+mod test_cpu;
+#[cfg(test)]
+mod test_mapper;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -256,6 +331,60 @@ mod tests {
         run_nestest("../roms/nes-test-roms/other/nestest.nes".to_string())
     }
 
+    /// unofficialを含む全命令の動作が正しいことを確認する
+    #[test]
+    fn test_run_instr_test() {
+        run_blargg_test(
+            "../roms/nes-test-roms/instr_test-v5/all_instrs.nes".to_string(),
+            3000,
+        )
+    }
+
+    /// PPUのI/O領域に置いたコードを実行できることを確認する
+    #[test]
+    fn test_run_cpu_exec_space_ppuio() {
+        run_blargg_test(
+            "../roms/nes-test-roms/cpu_exec_space/test_cpu_exec_space_ppuio.nes".to_string(),
+            600,
+        )
+    }
+
+    /// APUのI/O領域に置いたコードを実行できることを確認する
+    #[test]
+    fn test_run_cpu_exec_space_apu() {
+        run_blargg_test(
+            "../roms/nes-test-roms/cpu_exec_space/test_cpu_exec_space_apu.nes".to_string(),
+            600,
+        )
+    }
+
+    /// $2004の読み書きとOAM DMAの転送結果が正しいことを確認する
+    #[test]
+    fn test_run_oam_read() {
+        run_blargg_test(
+            "../roms/nes-test-roms/oam_read/oam_read.nes".to_string(),
+            600,
+        )
+    }
+
+    /// 全命令のcycle数(page cross, 分岐のpenaltyを含む)が正しいことを確認する
+    #[test]
+    fn test_run_instr_timing() {
+        run_blargg_test(
+            "../roms/nes-test-roms/instr_timing/instr_timing.nes".to_string(),
+            1800,
+        )
+    }
+
+    /// OAM DMAの途中でDMCがサンプルを読んだときのcycle数が正しいことを確認する
+    #[test]
+    fn test_run_sprdma_and_dmc_dma() {
+        run_blargg_test(
+            "../roms/nes-test-roms/sprdma_and_dmc_dma/sprdma_and_dmc_dma.nes".to_string(),
+            600,
+        )
+    }
+
     /// マリオのタイトル画面が正しく表示されること
     #[test]
     #[ignore]
@@ -285,6 +414,52 @@ mod tests {
             },
         )
     }
+
+    /// VBLANKフラグがline 241とpre-render lineで更新されること
+    /// cycle-accurate featureが有効ならdot 1、無効ならline単位で更新される
+    #[test]
+    fn test_ppu_vblank_timing() {
+        let mut cpu_sys: System = Default::default();
+        let mut ppu: Ppu = Default::default();
+        load_cassette(&mut cpu_sys.cassette, "../roms/other/hello.nes".to_string());
+        cpu_sys.reset();
+        ppu.reset();
+        let mut fb = [[[0; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT];
+
+        // line 241の先頭から1cpu cycle(3dot)進める
+        assert_eq!(241, ppu.current_line);
+        ppu.step(1, &mut cpu_sys, &mut fb);
+        assert_eq!(
+            cfg!(feature = "cycle-accurate"),
+            cpu_sys.read_ppu_is_vblank()
+        );
+        // line 242に入るまでにはどちらでも立っている
+        while ppu.current_line == 241 {
+            ppu.step(1, &mut cpu_sys, &mut fb);
+        }
+        assert!(cpu_sys.read_ppu_is_vblank());
+
+        // pre-render lineの先頭まで進めてから1cpu cycle進める
+        while ppu.current_line != 261 {
+            ppu.step(1, &mut cpu_sys, &mut fb);
+        }
+        let ppu_cyc = ppu.cumulative_ppu_cyc;
+        assert!(ppu_cyc < PPU_CYCLE_PER_CPU_CYCLE);
+        assert_eq!(
+            !cfg!(feature = "cycle-accurate") || ppu_cyc <= PPU_VBLANK_UPDATE_DOT,
+            cpu_sys.read_ppu_is_vblank()
+        );
+        ppu.step(1, &mut cpu_sys, &mut fb);
+        assert_eq!(
+            !cfg!(feature = "cycle-accurate"),
+            cpu_sys.read_ppu_is_vblank()
+        );
+        // line 0に入るまでにはどちらでも下りている
+        while ppu.current_line == 261 {
+            ppu.step(1, &mut cpu_sys, &mut fb);
+        }
+        assert!(!cpu_sys.read_ppu_is_vblank());
+    }
 }
 
 #[cfg(all(feature = "bench", test))]
@@ -315,14 +490,9 @@ mod bench {
 
         b.iter(|| {
             for _ in 0..60 {
-                let mut total_cycle: usize = 0;
-                while total_cycle < CYCLE_PER_DRAW_FRAME {
-                    let cpu_cycle = usize::from(cpu.step(&mut cpu_sys));
-                    if let Some(interrupt) = ppu.step(cpu_cycle, &mut cpu_sys, &mut fb) {
-                        cpu.interrupt(&mut cpu_sys, interrupt);
-                    }
-
-                    total_cycle = total_cycle + cpu_cycle;
+                let current_frame = ppu.frame_count;
+                while ppu.frame_count == current_frame {
+                    step_cpu_ppu(&mut cpu, &mut cpu_sys, &mut ppu, &mut fb);
                 }
             }
         });
@@ -0,0 +1,121 @@
+//! APUレジスタ($4015, $4017)とIRQの確認
+
+use super::load_cassette;
+use rust_nes_emulator::prelude::*;
+
+/// hello worldのromを読んでresetしたSystem
+/// DMCがサンプルを読めるようにPRG-ROMを載せておく
+fn reset_system() -> System {
+    let mut system: System = Default::default();
+    load_cassette(&mut system.cassette, "../roms/other/hello.nes".to_string());
+    system.reset();
+    system
+}
+
+/// APUを指定したcycle数だけ進めます
+fn step_apu(system: &mut System, cycles: usize) {
+    for _ in 0..cycles {
+        system.step_apu();
+    }
+}
+
+/// 4-step modeでは1周するたびにframe IRQが立ち、$4015を読むと下りる
+#[test]
+fn test_frame_counter_irq() {
+    let mut system = reset_system();
+    system.write_u8(0x4017, 0x00, false);
+    step_apu(&mut system, usize::from(FRAME_COUNTER_IRQ_CYCLE) - 1);
+    assert_eq!(0x00, system.read_u8(0x4015, false));
+    step_apu(&mut system, 1);
+    assert!(system.frame_counter.is_irq);
+
+    let mut cpu: Cpu = Default::default();
+    cpu.update_interrupt_lines(&system);
+    assert!(cpu.read_irq_line());
+
+    // 非破壊読み出しでは下りない
+    assert_eq!(0x40, system.read_u8(0x4015, true));
+    assert_eq!(0x40, system.read_u8(0x4015, false));
+    assert_eq!(0x00, system.read_u8(0x4015, false));
+    cpu.update_interrupt_lines(&system);
+    assert!(!cpu.read_irq_line());
+
+    // 周期の最後のcycleでもう一度立って、次の周期は最初から数え直す
+    step_apu(&mut system, 1);
+    assert_eq!(0x40, system.read_u8(0x4015, false));
+    step_apu(&mut system, usize::from(FRAME_COUNTER_IRQ_CYCLE) - 1);
+    assert_eq!(0x00, system.read_u8(0x4015, false));
+    step_apu(&mut system, 1);
+    assert_eq!(0x40, system.read_u8(0x4015, false));
+}
+
+/// IRQ inhibitを立てるとframe IRQは立たず、立っていたものも下りる
+#[test]
+fn test_frame_counter_irq_inhibit() {
+    let mut system = reset_system();
+    step_apu(&mut system, usize::from(FRAME_COUNTER_IRQ_CYCLE));
+    assert!(system.frame_counter.is_irq);
+    system.write_u8(0x4017, 0x40, false);
+    assert!(!system.frame_counter.is_irq);
+    step_apu(&mut system, 2 * usize::from(FRAME_COUNTER_PERIOD[0]));
+    assert_eq!(0x00, system.read_u8(0x4015, false));
+
+    // inhibitを下ろしても、立っていなかったIRQは出ない
+    system.write_u8(0x4017, 0x00, false);
+    assert!(!system.frame_counter.is_irq);
+    step_apu(&mut system, usize::from(FRAME_COUNTER_IRQ_CYCLE));
+    assert!(system.frame_counter.is_irq);
+}
+
+/// 5-step modeではframe IRQを出さない
+#[test]
+fn test_frame_counter_five_step() {
+    let mut system = reset_system();
+    system.write_u8(0x4017, 0x80, false);
+    step_apu(&mut system, 2 * usize::from(FRAME_COUNTER_PERIOD[1]));
+    assert!(!system.frame_counter.is_irq);
+    // $4017を書くとsequencerは最初から数え直す
+    step_apu(&mut system, 100);
+    system.write_u8(0x4017, 0x00, false);
+    assert_eq!(0, system.frame_counter.cycle);
+}
+
+/// $4015はDMCの読み残しとDMC IRQを返し、$4015を書くとDMC IRQが下りる
+#[test]
+fn test_apu_status_dmc() {
+    let mut system = reset_system();
+    system.write_u8(0x4017, 0x40, false);
+    // IRQ有効, loopなし, 最速のrate, 17byte
+    system.write_u8(0x4010, 0x8f, false);
+    system.write_u8(0x4012, 0x00, false);
+    system.write_u8(0x4013, 0x01, false);
+    system.write_u8(0x4015, 0x10, false);
+    assert_eq!(0x10, system.read_u8(0x4015, false));
+
+    // 1byte目はすぐ読み、残り16byteは8bit出力するごとに読む
+    step_apu(&mut system, 1 + 16 * 8 * usize::from(DMC_RATE_TABLE[0x0f]));
+    assert_eq!(0x80, system.read_u8(0x4015, false));
+    let mut cpu: Cpu = Default::default();
+    cpu.update_interrupt_lines(&system);
+    assert!(cpu.read_irq_line());
+
+    // DMC IRQは$4015を読んでも下りない
+    assert_eq!(0x80, system.read_u8(0x4015, false));
+    system.write_u8(0x4015, 0x00, false);
+    assert_eq!(0x00, system.read_u8(0x4015, false));
+    cpu.update_interrupt_lines(&system);
+    assert!(!cpu.read_irq_line());
+}
+
+/// $4016のstrobeはpad1/pad2の両方をreloadし、$4017を書いてもpad2は変わらない
+#[test]
+fn test_pad_strobe() {
+    let mut system = reset_system();
+    system.pad2.push_button(PadButton::A);
+    system.write_u8(0x4016, 0x01, false);
+    system.write_u8(0x4016, 0x00, false);
+    assert_eq!(0x01, system.read_u8(0x4017, false));
+    assert_eq!(0x00, system.read_u8(0x4017, false));
+    system.write_u8(0x4017, 0x01, false);
+    assert_eq!(0x00, system.read_u8(0x4017, false));
+}
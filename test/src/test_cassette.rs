@@ -0,0 +1,268 @@
+//! ROMイメージのheader解析と読み込みの確認
+
+use rust_nes_emulator::prelude::*;
+
+/// iNES 1.0のheader
+fn ines_header(mapper: u8, flags6: u8) -> [u8; INES_HEADER_SIZE] {
+    let mut header = [0u8; INES_HEADER_SIZE];
+    header[0..4].copy_from_slice(b"NES\x1a");
+    header[4] = 2;
+    header[5] = 1;
+    header[6] = (mapper << 4) | flags6;
+    header[7] = mapper & 0xf0;
+    header
+}
+
+/// byte 12 ~ 15にゴミがある古いdumpはbyte 7 ~ 15を使わず、iNES 1.0の初期値にする
+#[test]
+fn test_ines_dirty_header() {
+    let mut header = ines_header(0x01, 0x02);
+    header[7] = 0x40;
+    header[8] = 0x04;
+    header[9] = 0x01;
+    header[7..16].copy_from_slice(b"DiskDude!");
+    let result = RomHeader::parse(&header).unwrap();
+    assert_eq!(HeaderFormat::INes, result.format);
+    assert_eq!(1, result.mapper_number);
+    assert_eq!(0, result.prg_ram_bytes);
+    assert_eq!(0x2000, result.prg_nvram_bytes);
+    assert_eq!(TimingRegion::Ntsc, result.timing);
+    assert_eq!(ConsoleType::Famicom, result.console_type);
+}
+
+/// CHR-ROMが0bankならheaderのサイズでCHR-RAMを確保し、save stateには確保した分だけ書き出す
+#[test]
+fn test_chr_ram_state_size() {
+    let load = |chr_8k_banks: u8| {
+        let mut image = ines_header(0x00, 0x00).to_vec();
+        image[5] = chr_8k_banks;
+        image.resize(image.len() + 0x8000 + usize::from(chr_8k_banks) * 0x2000, 0);
+        let mut cassette = Cassette::default();
+        cassette
+            .from_ines_binary(image.len(), |addr: usize| image[addr])
+            .unwrap();
+        cassette
+    };
+    let state_len = |cassette: &Cassette| {
+        let mut buf = vec![0u8; 0x1_0000];
+        let mut writer = StateWriter::new(&mut buf);
+        cassette.save_state(&mut writer);
+        writer.finish().unwrap()
+    };
+    let chr_rom = load(1);
+    let mut chr_ram = load(0);
+    assert!(!chr_rom.mem.is_chr_ram());
+    assert_eq!(CHR_RAM_SIZE, chr_ram.mem.chr_ram.len());
+    assert_eq!(state_len(&chr_rom) + CHR_RAM_SIZE, state_len(&chr_ram));
+
+    chr_ram.write_video_u8(0x1fff, 0x5a);
+    let mut buf = vec![0u8; 0x1_0000];
+    let mut writer = StateWriter::new(&mut buf);
+    chr_ram.save_state(&mut writer);
+    chr_ram.write_video_u8(0x1fff, 0x00);
+    chr_ram.load_state(&mut StateReader::new(&buf));
+    assert_eq!(0x5a, chr_ram.read_video_u8(0x1fff));
+}
+
+/// byte 12 ~ 15が0ならbyte 7 ~ 9を使う
+#[test]
+fn test_ines_clean_header() {
+    let mut header = ines_header(0x41, 0x00);
+    header[8] = 0x04;
+    header[9] = 0x01;
+    let result = RomHeader::parse(&header).unwrap();
+    assert_eq!(0x41, result.mapper_number);
+    assert_eq!(0x8000, result.prg_ram_bytes);
+    assert_eq!(TimingRegion::Pal, result.timing);
+}
+
+/// UNIFのheaderとchunkを組み立てます
+fn build_unif_image(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut image = vec![0u8; UNIF_HEADER_SIZE];
+    image[0..4].copy_from_slice(b"UNIF");
+    image[4] = 7;
+    for (id, data) in chunks {
+        image.extend_from_slice(&id[..]);
+        image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        image.extend_from_slice(data);
+    }
+    image
+}
+
+fn load_unif(image: &[u8]) -> Result<(Cassette, RomHeader), RomLoadError> {
+    let mut cassette = Cassette {
+        is_ignore_rom_db: true,
+        ..Default::default()
+    };
+    let header = cassette.from_unif_binary(image.len(), |addr: usize| image[addr])?;
+    Ok((cassette, header))
+}
+
+/// PRGn/CHRnはファイル中の順番ではなく番号順に連結する
+#[test]
+fn test_unif_chunk_order() {
+    let image = build_unif_image(&[
+        (b"PRG1", vec![0xa1; 0x4000]),
+        (b"MAPR", b"NES-NROM-256\0".to_vec()),
+        (b"CHR0", vec![0xc0; 0x2000]),
+        (b"PRG0", vec![0xa0; 0x4000]),
+    ]);
+    let (mut cassette, header) = load_unif(&image).unwrap();
+    assert_eq!(HeaderFormat::Unif, header.format);
+    assert_eq!(0, header.mapper_number);
+    assert_eq!(0x8000, header.prg_rom_bytes);
+    assert_eq!(0x2000, header.chr_rom_bytes);
+    assert_eq!(0xa0, cassette.read_u8(0x8000, false));
+    assert_eq!(0xa1, cassette.read_u8(0xc000, false));
+    assert_eq!(0xc0, cassette.read_video_u8(0x0000));
+}
+
+/// MIRRでmirroring, BATRがあればbattery付き
+#[test]
+fn test_unif_mirr_batr() {
+    let image = build_unif_image(&[
+        (b"MAPR", b"NES-SNROM\0".to_vec()),
+        (b"PRG0", vec![0; 0x4000]),
+        (b"MIRR", vec![1]),
+        (b"BATR", vec![0]),
+        (b"TVCI", vec![1]),
+    ]);
+    let (cassette, header) = load_unif(&image).unwrap();
+    assert_eq!(1, header.mapper_number);
+    assert_eq!(NameTableMirror::Vertical, header.nametable_mirror);
+    assert!(header.is_battery);
+    assert_eq!(0x2000, header.prg_nvram_bytes);
+    assert_eq!(0, header.prg_ram_bytes);
+    assert_eq!(0x2000, header.chr_ram_bytes);
+    assert_eq!(TimingRegion::Pal, header.timing);
+    assert!(cassette.battery_backup().is_some());
+
+    let image = build_unif_image(&[
+        (b"MAPR", b"NES-NROM-128\0".to_vec()),
+        (b"PRG0", vec![0; 0x4000]),
+        (b"MIRR", vec![4]),
+    ]);
+    let (cassette, header) = load_unif(&image).unwrap();
+    assert!(header.is_four_screen);
+    assert!(!header.is_battery);
+    assert_eq!(0x2000, header.prg_ram_bytes);
+    assert_eq!(
+        NameTableMirror::FourScreen,
+        cassette.read_nametable_mirror()
+    );
+    assert!(cassette.battery_backup().is_none());
+}
+
+/// 対応していないboard名とMAPRがない場合はUnsupportedUnifBoard
+#[test]
+fn test_unif_unsupported_board() {
+    let image = build_unif_image(&[
+        (b"MAPR", b"UNL-FOOROM\0".to_vec()),
+        (b"PRG0", vec![0; 0x4000]),
+    ]);
+    assert_eq!(
+        Some(RomLoadError::UnsupportedUnifBoard),
+        load_unif(&image).err()
+    );
+    let image = build_unif_image(&[(b"PRG0", vec![0; 0x4000])]);
+    assert_eq!(
+        Some(RomLoadError::UnsupportedUnifBoard),
+        load_unif(&image).err()
+    );
+}
+
+/// chunkの長さがファイルの残りより長い場合と、headerが短い場合
+#[test]
+fn test_unif_truncated() {
+    let mut image = build_unif_image(&[
+        (b"MAPR", b"NES-NROM-128\0".to_vec()),
+        (b"PRG0", vec![0; 0x4000]),
+    ]);
+    image.truncate(image.len() - 1);
+    assert_eq!(
+        Some(RomLoadError::TruncatedUnifChunk),
+        load_unif(&image).err()
+    );
+    assert_eq!(
+        Some(RomLoadError::TruncatedHeader),
+        load_unif(&image[..UNIF_HEADER_SIZE - 1]).err()
+    );
+    let mut image = build_unif_image(&[]);
+    image[0] = b'X';
+    assert_eq!(Some(RomLoadError::BadMagic), load_unif(&image).err());
+}
+
+/// hello.nesだけを載せたテスト用のROM database
+static TEST_ROM_DATABASE: &[RomDbEntry] = &[RomDbEntry {
+    crc32: 0x4400_ff8f,
+    sha1: [
+        0xac, 0x04, 0xb0, 0xff, 0x1a, 0x7c, 0x34, 0x6d, 0x96, 0x9c, 0x18, 0xef, 0xc8, 0xe9, 0x3a,
+        0x5a, 0x56, 0x3c, 0x3d, 0x4d,
+    ],
+    title: "Hello World (sample)",
+    mapper_number: 0,
+    submapper_number: 0,
+    nametable_mirror: Some(NameTableMirror::Vertical),
+    is_four_screen: false,
+    is_battery: false,
+    prg_ram_bytes: 0,
+    prg_nvram_bytes: 0,
+    chr_ram_bytes: 0,
+    chr_nvram_bytes: 0,
+    timing: TimingRegion::Ntsc,
+    console_type: ConsoleType::Famicom,
+}];
+
+/// headerが壊れたdumpでも、ROM databaseに載っていればMapper番号とmirroringが補正される
+#[test]
+fn test_rom_db_corrects_bad_header() {
+    let mut image = std::fs::read("../roms/other/hello.nes").unwrap();
+    // Mapper1, horizontal, byte 7 ~ 15にゴミ
+    image[6] = 0x10;
+    image[7..16].copy_from_slice(b"DiskDude!");
+
+    let mut cassette = Cassette {
+        rom_database: Some(TEST_ROM_DATABASE),
+        ..Default::default()
+    };
+    let header = cassette
+        .from_ines_binary(image.len(), |addr: usize| image[addr])
+        .unwrap();
+    assert_eq!(0, header.mapper_number);
+    assert_eq!(NameTableMirror::Vertical, header.nametable_mirror);
+    assert_eq!(NameTableMirror::Vertical, cassette.read_nametable_mirror());
+    assert_eq!(Some("Hello World (sample)"), cassette.rom_title());
+    assert_eq!(Some(TimingRegion::Ntsc), cassette.rom_region());
+
+    // databaseを引かなければheaderのまま
+    let mut cassette = Cassette {
+        rom_database: Some(TEST_ROM_DATABASE),
+        is_ignore_rom_db: true,
+        ..Default::default()
+    };
+    let header = cassette
+        .from_ines_binary(image.len(), |addr: usize| image[addr])
+        .unwrap();
+    assert_eq!(1, header.mapper_number);
+    assert_eq!(NameTableMirror::Horizontal, header.nametable_mirror);
+    assert_eq!(None, cassette.rom_title());
+}
+
+/// 収録しているタイトルはCRC32順に並んでいて、二分探索で引ける
+#[test]
+fn test_rom_db_entries() {
+    assert!(ROM_DATABASE
+        .windows(2)
+        .all(|pair| pair[0].crc32 <= pair[1].crc32));
+    let entry = ROM_DATABASE
+        .iter()
+        .find(|entry| entry.crc32 == 0x3337_ec46)
+        .unwrap();
+    assert_eq!("Super Mario Bros. (World)", entry.title);
+    assert_eq!(0, entry.mapper_number);
+    assert_eq!(Some(NameTableMirror::Vertical), entry.nametable_mirror);
+    // テスト用のイメージは組み込みのdatabaseには載せない
+    assert!(ROM_DATABASE
+        .iter()
+        .all(|entry| entry.crc32 != TEST_ROM_DATABASE[0].crc32));
+}
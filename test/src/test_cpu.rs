@@ -0,0 +1,534 @@
+//! CPUの動作の確認
+//! 手で組み立てた命令列を流して、1命令ごとに進んだcycle数や割り込みに入るタイミングを比べる
+
+use super::{load_cassette, step_cpu_ppu};
+use rust_nes_emulator::prelude::*;
+
+/// PRG-ROMの指定したアドレスに命令列を置いたNROMイメージを読み込み、RESETしたcpuとSystemを返します
+/// RESET vectorは$8000
+fn load_program(code: &[(u16, &[u8])]) -> (Cpu, System) {
+    let mut image = vec![0u8; INES_HEADER_SIZE + 0x8000 + 0x2000];
+    image[0..4].copy_from_slice(b"NES\x1a");
+    image[4] = 2;
+    image[5] = 1;
+    for (addr, data) in code {
+        let offset = INES_HEADER_SIZE + usize::from(addr - 0x8000);
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+    image[INES_HEADER_SIZE + 0x7ffc] = 0x00;
+    image[INES_HEADER_SIZE + 0x7ffd] = 0x80;
+
+    let mut cpu: Cpu = Default::default();
+    let mut system: System = Default::default();
+    system.cassette.is_ignore_rom_db = true;
+    if let Err(e) = system
+        .cassette
+        .from_ines_binary(image.len(), |addr: usize| image[addr])
+    {
+        panic!("ines binary read error: {}", e);
+    }
+    cpu.reset();
+    system.reset();
+    cpu.interrupt(&mut system, Interrupt::RESET);
+    (cpu, system)
+}
+
+/// 1命令進めて、Cpu::cyclesが進んだ分を返します
+/// cycle-accurate featureが有効ならbusアクセスごとに進めるcoreを使う
+fn step(cpu: &mut Cpu, system: &mut System) -> u64 {
+    let before = cpu.cycles;
+    #[cfg(feature = "cycle-accurate")]
+    let cyc = cpu.step_cycle_accurate(system, |_| {});
+    #[cfg(not(feature = "cycle-accurate"))]
+    let cyc = cpu.step(system);
+    assert_eq!(u64::from(cyc), cpu.cycles - before);
+    cpu.cycles - before
+}
+
+/// 指定した命令数だけ進めて、1命令ごとのcycle数を返します
+fn step_n(cpu: &mut Cpu, system: &mut System, count: usize) -> Vec<u64> {
+    (0..count).map(|_| step(cpu, system)).collect()
+}
+
+/// 分岐は成立で+1, 分岐先が別pageならさらに+1
+#[test]
+fn test_cycles_branch() {
+    let (mut cpu, mut system) = load_program(&[
+        (
+            0x8000,
+            &[
+                0xa9, 0x00, // LDA #$00
+                0xd0, 0x02, // BNE +2 (不成立)
+                0xf0, 0x00, // BEQ +0 (成立, 同じpage)
+                0x4c, 0xfb, 0x80, // JMP $80FB
+            ],
+        ),
+        (
+            0x80fb,
+            &[
+                0xf0, 0x10, // BEQ +16 (成立, $810Dへpageをまたぐ)
+            ],
+        ),
+        (0x810d, &[0xea]), // NOP
+    ]);
+    assert_eq!(vec![2, 2, 3, 3, 4, 2], step_n(&mut cpu, &mut system, 6));
+    assert_eq!(0x810e, cpu.pc);
+}
+
+/// indexを足してpageをまたぐ読み出しは+1, 書き込みは常に同じcycle数
+#[test]
+fn test_cycles_page_cross() {
+    let (mut cpu, mut system) = load_program(&[(
+        0x8000,
+        &[
+            0xa2, 0xff, // LDX #$ff
+            0xbd, 0x00, 0x80, // LDA $8000,X ($80ff)
+            0xbd, 0x01, 0x80, // LDA $8001,X ($8100)
+            0x9d, 0x00, 0x02, // STA $0200,X
+            0xa9, 0x01, 0x85, 0x10, // LDA #$01, STA $10
+            0xa9, 0x02, 0x85, 0x11, // LDA #$02, STA $11
+            0xa0, 0xfe, // LDY #$fe
+            0xb1, 0x10, // LDA ($10),Y ($02ff)
+            0xc8, // INY
+            0xb1, 0x10, // LDA ($10),Y ($0300)
+            0x91, 0x10, // STA ($10),Y
+        ],
+    )]);
+    assert_eq!(
+        vec![2, 4, 5, 5, 2, 3, 2, 3, 2, 5, 2, 6, 6],
+        step_n(&mut cpu, &mut system, 13)
+    );
+}
+
+/// OAM DMAは513cycle、奇数cycleから始まると514cycle止まる
+#[test]
+fn test_cycles_oam_dma() {
+    let (mut cpu, mut system) = load_program(&[(
+        0x8000,
+        &[
+            0x8d, 0x14, 0x40, // STA $4014
+            0xea, // NOP
+            0x8d, 0x14, 0x40, // STA $4014
+            0xea, // NOP
+        ],
+    )]);
+    for (i, data) in system.wram[..OAM_SIZE].iter_mut().enumerate() {
+        *data = (i as u8) ^ 0x5a;
+    }
+    let mut stall_cycles = vec![];
+    for _ in 0..2 {
+        let start = cpu.cycles;
+        let cyc = step(&mut cpu, &mut system);
+        // STA abs(4cycle)のあとにDMAが始まる
+        let expect = 4 + OAM_DMA_STALL_CYCLES + ((start + 4) & 0x01) as u16;
+        assert_eq!(u64::from(expect), cyc);
+        stall_cycles.push(cyc - 4);
+        assert_eq!(2, step(&mut cpu, &mut system));
+    }
+    // NOPを挟むと偶奇が入れ替わる
+    stall_cycles.sort();
+    assert_eq!(vec![513, 514], stall_cycles);
+    for (i, data) in system.oam.iter().enumerate() {
+        assert_eq!((i as u8) ^ 0x5a, *data);
+    }
+}
+
+/// DMCはサンプルを1byte読むたびにCPUを4cycle止める
+#[test]
+fn test_cycles_dmc_stall() {
+    let (mut cpu, mut system) = load_program(&[(
+        0x8000,
+        &[
+            0xa9, 0x0f, 0x8d, 0x10, 0x40, // LDA #$0f, STA $4010 (最速のrate)
+            0xa9, 0x01, 0x8d, 0x13, 0x40, // LDA #$01, STA $4013 (17byte)
+            0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10, STA $4015 (再生開始)
+            0x4c, 0x0f, 0x80, // JMP $800F
+        ],
+    )]);
+    assert_eq!(vec![2, 4, 2, 4, 2], step_n(&mut cpu, &mut system, 5));
+    // 1byte目はすぐ読む
+    assert_eq!(
+        4 + u64::from(DMC_FETCH_STALL_CYCLES),
+        step(&mut cpu, &mut system)
+    );
+
+    // 残り16byteは8bit出力するごとに読む
+    let mut jmp_count = 0;
+    let mut total = 0;
+    while system.dmc.bytes_remaining > 0 {
+        let cyc = step(&mut cpu, &mut system);
+        assert!(cyc == 3 || cyc == 3 + u64::from(DMC_FETCH_STALL_CYCLES));
+        jmp_count += 1;
+        total += cyc;
+    }
+    assert_eq!(
+        16 * u64::from(DMC_FETCH_STALL_CYCLES),
+        total - 3 * jmp_count
+    );
+}
+
+/// 描画が有効なら、pre-render lineが奇数frameで1dot短くなり2frameで平均29780.5cycleになる
+#[test]
+fn test_cycles_frame_length() {
+    let mut cpu: Cpu = Default::default();
+    let mut cpu_sys: System = Default::default();
+    let mut ppu: Ppu = Default::default();
+    load_cassette(&mut cpu_sys.cassette, "../roms/other/hello.nes".to_string());
+    cpu.reset();
+    cpu_sys.reset();
+    ppu.reset();
+    cpu.interrupt(&mut cpu_sys, Interrupt::RESET);
+    let mut fb = [[[0; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT];
+
+    // frameの先頭のdot位置を記録する
+    // 命令の途中でframeが切り替わっても、行き過ぎた分をPPUの累積cycleから引けば正確に求まる
+    let mut frame_start_dots = vec![];
+    for _ in 0..5 {
+        let current_frame = ppu.frame_count;
+        while ppu.frame_count == current_frame {
+            step_cpu_ppu(&mut cpu, &mut cpu_sys, &mut ppu, &mut fb);
+        }
+        // line 240を描き終えたところでframeが切り替わる
+        assert_eq!(241, ppu.current_line);
+        let dots = cpu.cycles * PPU_CYCLE_PER_CPU_CYCLE as u64 - ppu.cumulative_ppu_cyc as u64;
+        frame_start_dots.push(dots);
+    }
+    // hello worldは最初のframeで描画を有効にする
+    assert!(cpu_sys.read_ppu_is_write_bg());
+    let frame_dots: Vec<u64> = frame_start_dots.windows(2).map(|w| w[1] - w[0]).collect();
+    for dots in frame_dots[1..].windows(2) {
+        assert_eq!(
+            2 * 262 * PPU_CYCLE_PER_LINE as u64 - 1,
+            dots[0] + dots[1],
+            "{:?}",
+            frame_dots
+        );
+        let average_cycles = (dots[0] + dots[1]) as f64 / PPU_CYCLE_PER_CPU_CYCLE as f64 / 2.0;
+        assert_eq!(29780.5, average_cycles);
+    }
+}
+
+/// NMIは$9000, IRQ/BRKは$A000に飛ぶvectorと、NOPを並べたハンドラ
+/// RESET vectorはload_programが上書きする
+const INTERRUPT_HANDLERS: [(u16, &[u8]); 3] = [
+    (0x9000, &[0xea; 0x10]),
+    (0xa000, &[0xea; 0x10]),
+    (0xfffa, &[0x00, 0x90, 0x00, 0x00, 0x00, 0xa0]),
+];
+
+/// 割り込みハンドラを置いてから命令列を読み込みます
+/// 命令列でハンドラを上書きしてもよい
+fn load_program_with_handlers(code: &[(u16, &[u8])]) -> (Cpu, System) {
+    let mut program = INTERRUPT_HANDLERS.to_vec();
+    program.extend_from_slice(code);
+    load_program(&program)
+}
+
+/// 割り込みでstackに積まれた(P, 戻り先)を返します
+fn read_pushed_frame(cpu: &Cpu, system: &mut System) -> (u8, u16) {
+    let p = system.read_u8(cpu.sp + 1, false);
+    let pcl = system.read_u8(cpu.sp + 2, false);
+    let pch = system.read_u8(cpu.sp + 3, false);
+    (p, u16::from(pcl) | (u16::from(pch) << 8))
+}
+
+/// 指定した命令数を進めるまで割り込みハンドラに入らないことを確認します
+fn step_outside_handler(cpu: &mut Cpu, system: &mut System, count: usize) {
+    for _ in 0..count {
+        step(cpu, system);
+        assert!(cpu.pc < 0x9000, "pc={:04x}", cpu.pc);
+    }
+}
+
+/// CLIでIフラグを下ろしても、IRQに入るのは次の1命令を実行したあと
+#[test]
+fn test_irq_after_cli() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0x58, // CLI
+            0xea, // NOP
+            0xea, // NOP
+        ],
+    )]);
+    system.frame_counter.is_irq = true;
+    step_outside_handler(&mut cpu, &mut system, 2);
+    assert_eq!(7, step(&mut cpu, &mut system));
+    assert_eq!(0xa000, cpu.pc);
+    let (p, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8002, return_addr);
+    assert_eq!(0x00, p & 0x10);
+}
+
+/// CLIの直後のSEIは、pollの時点ではIフラグが下りているのでIRQに入る
+#[test]
+fn test_irq_after_cli_sei() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0x58, // CLI
+            0x78, // SEI
+            0xea, // NOP
+        ],
+    )]);
+    system.frame_counter.is_irq = true;
+    step_outside_handler(&mut cpu, &mut system, 2);
+    step(&mut cpu, &mut system);
+    assert_eq!(0xa000, cpu.pc);
+    let (p, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8002, return_addr);
+    // SEIの結果は積まれている
+    assert_eq!(0x04, p & 0x04);
+}
+
+/// PLPでIフラグを下ろした場合もCLIと同じく1命令遅れる
+#[test]
+fn test_irq_after_plp() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0xa9, 0x00, // LDA #$00
+            0x48, // PHA
+            0x28, // PLP
+            0xea, // NOP
+            0xea, // NOP
+        ],
+    )]);
+    system.frame_counter.is_irq = true;
+    step_outside_handler(&mut cpu, &mut system, 4);
+    step(&mut cpu, &mut system);
+    assert_eq!(0xa000, cpu.pc);
+    let (_, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8005, return_addr);
+}
+
+/// Iフラグが立っていてもBRKは割り込みに入り、PC+2とBフラグを積む
+#[test]
+fn test_brk() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0xea, // NOP
+            0x00, 0xff, // BRK
+        ],
+    )]);
+    assert!(cpu.read_interrupt_flag());
+    step(&mut cpu, &mut system);
+    assert_eq!(7, step(&mut cpu, &mut system));
+    assert_eq!(0xa000, cpu.pc);
+    let (p, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8003, return_addr);
+    assert_eq!(0x10, p & 0x10);
+    // ハンドラの最初の命令はそのまま実行する
+    step(&mut cpu, &mut system);
+    assert_eq!(0xa001, cpu.pc);
+}
+
+/// NMIは立ち上がりでだけ入り、lineが立ったままなら2回目は入らない
+#[test]
+fn test_nmi_edge() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[
+        (
+            0x8000,
+            &[
+                0xa9, 0x80, // LDA #$80
+                0x8d, 0x00, 0x20, // STA $2000 (NMI有効)
+                0xea, // NOP
+                0xea, // NOP
+            ],
+        ),
+        (
+            0x9000,
+            &[
+                0xea, 0xea, 0xea, // NOP x3
+                0xa9, 0x00, // LDA #$00
+                0x8d, 0x00, 0x20, // STA $2000 (NMI無効)
+                0xa9, 0x80, // LDA #$80
+                0x8d, 0x00, 0x20, // STA $2000 (NMI有効)
+                0xea, // NOP
+                0xea, // NOP
+            ],
+        ),
+    ]);
+    system.write_ppu_is_vblank(true);
+    // 命令の最後のcycleで書き込んだ場合、cycle-accurate coreでは次の命令のpollまで遅れる
+    let delay = usize::from(cfg!(feature = "cycle-accurate"));
+    step_outside_handler(&mut cpu, &mut system, 2 + delay);
+    step(&mut cpu, &mut system);
+    assert_eq!(0x9000, cpu.pc);
+    let (_, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8005 + delay as u16, return_addr);
+
+    // lineが立ったままの間は入らない
+    for _ in 0..7 {
+        step(&mut cpu, &mut system);
+        assert!(cpu.pc > 0x9000);
+    }
+    assert_eq!(0x900d, cpu.pc);
+    // 一度下げてから立ち上げると再び入る
+    for _ in 0..delay {
+        step(&mut cpu, &mut system);
+    }
+    step(&mut cpu, &mut system);
+    assert_eq!(0x9000, cpu.pc);
+    let (_, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x900d + delay as u16, return_addr);
+}
+
+/// BRKが積み終わるまでにNMIが来ると、Bフラグを積んだままNMIのvectorに飛ぶ
+#[cfg(feature = "cycle-accurate")]
+#[test]
+fn test_nmi_hijack_brk() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000 (NMI有効)
+            0x00, 0xff, // BRK
+        ],
+    )]);
+    step_n(&mut cpu, &mut system, 2);
+    // Pを積む前のcycleでvblankに入る
+    let mut cyc = 0;
+    cpu.step_cycle_accurate(&mut system, |sys| {
+        cyc += 1;
+        if cyc == 3 {
+            sys.write_ppu_is_vblank(true);
+        }
+    });
+    assert_eq!(0x9000, cpu.pc);
+    let (p, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8007, return_addr);
+    assert_eq!(0x10, p & 0x10);
+    // NMIはここで処理済み
+    step(&mut cpu, &mut system);
+    assert_eq!(0x9001, cpu.pc);
+}
+
+/// IRQが積み終わるまでにNMIが来ると、NMIのvectorに飛ぶ
+#[cfg(feature = "cycle-accurate")]
+#[test]
+fn test_nmi_hijack_irq() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000 (NMI有効)
+            0x58, // CLI
+            0xea, // NOP
+        ],
+    )]);
+    system.frame_counter.is_irq = true;
+    step_outside_handler(&mut cpu, &mut system, 4);
+    let mut cyc = 0;
+    cpu.step_cycle_accurate(&mut system, |sys| {
+        cyc += 1;
+        if cyc == 3 {
+            sys.write_ppu_is_vblank(true);
+        }
+    });
+    assert_eq!(0x9000, cpu.pc);
+    let (p, return_addr) = read_pushed_frame(&cpu, &mut system);
+    assert_eq!(0x8007, return_addr);
+    assert_eq!(0x00, p & 0x10);
+    step(&mut cpu, &mut system);
+    assert_eq!(0x9001, cpu.pc);
+}
+
+/// JAMを実行するとRESETされるまで止まり、NMIも受け付けない
+#[test]
+fn test_jam_halt_and_reset() {
+    let (mut cpu, mut system) = load_program_with_handlers(&[(
+        0x8000,
+        &[
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000 (NMI有効)
+            0x02, // JAM
+        ],
+    )]);
+    step_n(&mut cpu, &mut system, 3);
+    assert_eq!(CpuState::Halted, cpu.state);
+    assert_eq!(0x8005, cpu.pc);
+
+    system.write_ppu_is_vblank(true);
+    let sp = cpu.sp;
+    // 止まっている間は1cycleずつ進むだけ
+    assert_eq!(vec![1; 4], step_n(&mut cpu, &mut system, 4));
+    assert_eq!(CpuState::Halted, cpu.state);
+    assert_eq!(0x8005, cpu.pc);
+    assert_eq!(sp, cpu.sp);
+
+    // RESETで再び動き出す
+    system.write_ppu_is_vblank(false);
+    cpu.interrupt(&mut system, Interrupt::RESET);
+    assert_eq!(CpuState::Running, cpu.state);
+    assert_eq!(0x8000, cpu.pc);
+    step(&mut cpu, &mut system);
+    assert_eq!(0x8002, cpu.pc);
+    assert_eq!(0x80, cpu.a);
+}
+
+/// LXA, XAAはmagic constantとORしてからANDする
+#[test]
+fn test_lxa_xaa() {
+    let (mut cpu, mut system) = load_program(&[(
+        0x8000,
+        &[
+            0xa9, 0x0f, // LDA #$0f
+            0xab, 0x5a, // LXA #$5a
+            0xa9, 0x01, // LDA #$01
+            0xa2, 0xf3, // LDX #$f3
+            0x8b, 0x5f, // XAA #$5f
+        ],
+    )]);
+    step_n(&mut cpu, &mut system, 2);
+    assert_eq!(0x5a, cpu.a);
+    assert_eq!(0x5a, cpu.x);
+    step_n(&mut cpu, &mut system, 3);
+    assert_eq!(0x43, cpu.a);
+    assert_eq!(0xf3, cpu.x);
+}
+
+/// LASはmemoryとSPのANDをA, X, SPに入れる
+#[test]
+fn test_las() {
+    let (mut cpu, mut system) = load_program(&[(
+        0x8000,
+        &[
+            0xa9, 0x7e, // LDA #$7e
+            0x8d, 0x00, 0x03, // STA $0300
+            0xa0, 0x00, // LDY #$00
+            0xbb, 0x00, 0x03, // LAS $0300,Y
+        ],
+    )]);
+    assert_eq!(0x01fd, cpu.sp);
+    step_n(&mut cpu, &mut system, 4);
+    assert_eq!(0x7c, cpu.a);
+    assert_eq!(0x7c, cpu.x);
+    assert_eq!(0x017c, cpu.sp);
+}
+
+/// SHX, SHYは(addressの上位byte + 1)とのANDを書き込み、pageをまたぐと書き込み先の上位byteも置き換わる
+#[test]
+fn test_shx_shy() {
+    let (mut cpu, mut system) = load_program(&[(
+        0x8000,
+        &[
+            0xa2, 0xff, // LDX #$ff
+            0xa0, 0x01, // LDY #$01
+            0x9e, 0x00, 0x02, // SHX $0200,Y
+            0xa2, 0x05, // LDX #$05
+            0x9e, 0xff, 0x02, // SHX $02ff,Y ($0300 -> $0100)
+            0xa0, 0xff, // LDY #$ff
+            0xa2, 0x01, // LDX #$01
+            0x9c, 0x00, 0x04, // SHY $0400,X
+        ],
+    )]);
+    assert_eq!(vec![2, 2, 5], step_n(&mut cpu, &mut system, 3));
+    assert_eq!(0x03, system.read_u8(0x0201, false));
+    assert_eq!(vec![2, 5], step_n(&mut cpu, &mut system, 2));
+    assert_eq!(0x01, system.read_u8(0x0100, false));
+    assert_eq!(0x00, system.read_u8(0x0300, false));
+    assert_eq!(vec![2, 2, 5], step_n(&mut cpu, &mut system, 3));
+    assert_eq!(0x05, system.read_u8(0x0401, false));
+}
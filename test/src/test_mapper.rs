@@ -0,0 +1,1251 @@
+//! Mapperごとのレジスタ動作の確認
+//! テストROMが無くても確認できるように、bank番号で埋めたiNESイメージをその場で組み立てて使う
+
+use rust_nes_emulator::prelude::*;
+
+/// bank番号で埋めたiNESイメージを組み立てます
+/// PRG-ROMは8KBごとに8KB bank番号、CHR-ROMは1KBごとに1KB bank番号を書いておく
+/// `submapper` - SomeならNES 2.0 headerにする(PRG-RAM 8KB, CHR-ROMが無ければCHR-RAM 8KB)
+fn build_ines_image(
+    mapper: u16,
+    submapper: Option<u8>,
+    prg_16k_banks: usize,
+    chr_8k_banks: usize,
+    flags6: u8,
+) -> Vec<u8> {
+    let mut image = vec![0u8; INES_HEADER_SIZE];
+    image[0..4].copy_from_slice(b"NES\x1a");
+    image[4] = prg_16k_banks as u8;
+    image[5] = chr_8k_banks as u8;
+    image[6] = ((mapper as u8 & 0x0f) << 4) | flags6;
+    image[7] = mapper as u8 & 0xf0;
+    if let Some(submapper) = submapper {
+        image[7] |= 0x08;
+        image[8] = (submapper << 4) | ((mapper >> 8) as u8 & 0x0f);
+        image[10] = 0x07;
+        image[11] = if chr_8k_banks == 0 { 0x07 } else { 0x00 };
+    }
+    image.extend((0..prg_16k_banks * 0x4000).map(|offset| (offset / 0x2000) as u8));
+    image.extend((0..chr_8k_banks * 0x2000).map(|offset| (offset / 0x0400) as u8));
+    image
+}
+
+/// 組み立てたイメージをカセットに読み込みます
+/// 適当なイメージがROM databaseに当たらないように、databaseは引かない
+fn load_image(image: &[u8]) -> Cassette {
+    let mut cassette = Cassette {
+        is_ignore_rom_db: true,
+        ..Default::default()
+    };
+    if let Err(e) = cassette.from_ines_binary(image.len(), |addr: usize| image[addr]) {
+        panic!("ines binary read error: {}", e);
+    }
+    cassette
+}
+
+fn load_mapper(
+    mapper: u16,
+    submapper: Option<u8>,
+    prg_16k_banks: usize,
+    chr_8k_banks: usize,
+    flags6: u8,
+) -> Cassette {
+    load_image(&build_ines_image(
+        mapper,
+        submapper,
+        prg_16k_banks,
+        chr_8k_banks,
+        flags6,
+    ))
+}
+
+/// CPU空間のaddrに見えている8KB PRG bank番号
+fn prg_bank_8k(cassette: &mut Cassette, addr: u16) -> u8 {
+    cassette.read_u8(addr, true)
+}
+
+/// PPU空間のaddrに見えている1KB CHR bank番号
+fn chr_bank_1k(cassette: &mut Cassette, addr: u16) -> u8 {
+    cassette.read_video_u8(addr)
+}
+
+mod mmc1 {
+    use super::*;
+
+    /// 5回のserial書き込みでレジスタを設定する
+    fn write_serial(cassette: &mut Cassette, addr: u16, data: u8) {
+        for i in 0..5 {
+            cassette.write_u8(addr, (data >> i) & 0x01, false);
+        }
+    }
+
+    /// 電源投入時は0xc000に最後のbankが固定され、PRG bankの5回目の書き込みで0x8000が切り替わる
+    #[test]
+    fn test_mmc1_serial_prg_bank() {
+        let mut cassette = load_mapper(1, None, 8, 2, 0x00);
+        assert_eq!(0, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+        // LSBから書いて、4回目までは反映されない
+        cassette.write_u8(0xe000, 0x01, false);
+        for _ in 0..3 {
+            cassette.write_u8(0xe000, 0x00, false);
+        }
+        assert_eq!(0, prg_bank_8k(&mut cassette, 0x8000));
+        cassette.write_u8(0xe000, 0x00, false);
+        assert_eq!(2, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+    }
+
+    /// bit7を書くと途中までのshift registerが捨てられ、PRG bank modeが3に戻る
+    #[test]
+    fn test_mmc1_reset_shift_register() {
+        let mut cassette = load_mapper(1, None, 8, 2, 0x00);
+        // 32KB mode, vertical
+        write_serial(&mut cassette, 0x8000, 0x02);
+        write_serial(&mut cassette, 0xe000, 0x02);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(6, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(NameTableMirror::Vertical, cassette.read_nametable_mirror());
+        // 途中まで書いてからreset
+        cassette.write_u8(0xe000, 0x01, false);
+        cassette.write_u8(0xe000, 0x01, false);
+        cassette.write_u8(0xe000, 0x80, false);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+        // resetのあとは1回目から数え直す
+        write_serial(&mut cassette, 0xe000, 0x05);
+        assert_eq!(10, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(NameTableMirror::Vertical, cassette.read_nametable_mirror());
+    }
+
+    /// CHRの4KB * 2 modeとmirroring
+    #[test]
+    fn test_mmc1_chr_bank_and_mirroring() {
+        let mut cassette = load_mapper(1, None, 2, 4, 0x00);
+        // 8KB mode: chr_bank0の下位bitは無視
+        write_serial(&mut cassette, 0x8000, 0x0c);
+        write_serial(&mut cassette, 0xa000, 0x03);
+        assert_eq!(8, chr_bank_1k(&mut cassette, 0x0000));
+        assert_eq!(12, chr_bank_1k(&mut cassette, 0x1000));
+        assert_eq!(
+            NameTableMirror::SingleScreenLower,
+            cassette.read_nametable_mirror()
+        );
+        // 4KB mode, horizontal
+        write_serial(&mut cassette, 0x8000, 0x1f);
+        write_serial(&mut cassette, 0xc000, 0x05);
+        assert_eq!(12, chr_bank_1k(&mut cassette, 0x0000));
+        assert_eq!(20, chr_bank_1k(&mut cassette, 0x1000));
+        assert_eq!(
+            NameTableMirror::Horizontal,
+            cassette.read_nametable_mirror()
+        );
+    }
+
+    /// PRG bankのbit4でPRG-RAMが無効になる
+    #[test]
+    fn test_mmc1_prg_ram_enable() {
+        let mut cassette = load_mapper(1, None, 2, 1, 0x02);
+        cassette.write_u8(0x6000, 0x5a, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+        write_serial(&mut cassette, 0xe000, 0x10);
+        cassette.write_u8(0x6000, 0xa5, false);
+        assert_eq!(0x60, cassette.read_u8(0x6000, false));
+        write_serial(&mut cassette, 0xe000, 0x00);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+    }
+
+    /// Read-Modify-Write命令の2回書きは連続したcycleなので、2回目は無視される
+    #[cfg(feature = "cycle-accurate")]
+    #[test]
+    fn test_mmc1_ignore_consecutive_write() {
+        let mut system = System::default();
+        system.cassette = load_mapper(1, None, 8, 2, 0x00);
+        // PRG-RAMに置いたINC $8000を実行する。0x8000は0を読んで0, 1の順に書く
+        for (offset, data) in [0xee, 0x00, 0x80].iter().enumerate() {
+            system
+                .cassette
+                .write_u8(0x6000 + offset as u16, *data, false);
+        }
+        let mut cpu = Cpu::default();
+        cpu.pc = 0x6000;
+        cpu.step_cycle_accurate(&mut system, |sys| sys.cassette.notify_cpu_cycles(1));
+        // 1回分だけshiftされている
+        match &system.cassette.mapper {
+            MapperBoard::Mmc1(m) => assert_eq!(0x08, m.shift_reg),
+            _ => unreachable!(),
+        }
+        // 間が空いていれば残りの4回は反映され、PRG bankは0b00100になる
+        for data in [0x00, 0x01, 0x00, 0x00].iter() {
+            system.cassette.notify_cpu_cycles(4);
+            system.cassette.write_u8(0xe000, *data, false);
+        }
+        assert_eq!(8, prg_bank_8k(&mut system.cassette, 0x8000));
+    }
+}
+
+mod mmc3 {
+    use super::*;
+
+    /// R6/R7とPRG bank mode
+    #[test]
+    fn test_mmc3_prg_bank() {
+        let mut cassette = load_mapper(4, None, 8, 2, 0x00);
+        cassette.write_u8(0x8000, 0x06, false);
+        cassette.write_u8(0x8001, 0x03, false);
+        cassette.write_u8(0x8000, 0x07, false);
+        cassette.write_u8(0x8001, 0x05, false);
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        // PRG bank modeを切り替えると0x8000と0xc000が入れ替わる
+        cassette.write_u8(0x8000, 0x46, false);
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+    }
+
+    /// R0 ~ R5とCHR A12 inversion, mirroring
+    #[test]
+    fn test_mmc3_chr_bank_and_mirroring() {
+        let mut cassette = load_mapper(4, None, 2, 4, 0x00);
+        for (index, bank) in [0x09u8, 0x0c, 0x10, 0x11, 0x12, 0x13].iter().enumerate() {
+            cassette.write_u8(0x8000, index as u8, false);
+            cassette.write_u8(0x8001, *bank, false);
+        }
+        // R0, R1は2KB bankなので下位bitは無視
+        assert_eq!(8, chr_bank_1k(&mut cassette, 0x0000));
+        assert_eq!(9, chr_bank_1k(&mut cassette, 0x0400));
+        assert_eq!(12, chr_bank_1k(&mut cassette, 0x0800));
+        assert_eq!(16, chr_bank_1k(&mut cassette, 0x1000));
+        assert_eq!(19, chr_bank_1k(&mut cassette, 0x1c00));
+        cassette.write_u8(0x8000, 0x80, false);
+        assert_eq!(16, chr_bank_1k(&mut cassette, 0x0000));
+        assert_eq!(8, chr_bank_1k(&mut cassette, 0x1000));
+        assert_eq!(13, chr_bank_1k(&mut cassette, 0x1c00));
+
+        assert_eq!(
+            NameTableMirror::Horizontal,
+            cassette.read_nametable_mirror()
+        );
+        cassette.write_u8(0xa000, 0x00, false);
+        assert_eq!(NameTableMirror::Vertical, cassette.read_nametable_mirror());
+        cassette.write_u8(0xa000, 0x01, false);
+        assert_eq!(
+            NameTableMirror::Horizontal,
+            cassette.read_nametable_mirror()
+        );
+    }
+
+    /// reloadしたscanlineから数えてlatch回目でIRQ, 0xe000で取り下げる
+    #[test]
+    fn test_mmc3_irq_reload() {
+        let mut cassette = load_mapper(4, None, 2, 1, 0x00);
+        cassette.write_u8(0xc000, 0x03, false);
+        cassette.write_u8(0xc001, 0x00, false);
+        cassette.write_u8(0xe001, 0x00, false);
+        for _ in 0..3 {
+            cassette.notify_scanline();
+            assert!(!cassette.is_irq_asserted());
+        }
+        cassette.notify_scanline();
+        assert!(cassette.is_irq_asserted());
+        cassette.write_u8(0xe000, 0x00, false);
+        assert!(!cassette.is_irq_asserted());
+        // disableの間は0になってもIRQは出ない
+        for _ in 0..4 {
+            cassette.notify_scanline();
+        }
+        assert!(!cassette.is_irq_asserted());
+        // 数えている途中で0xc001を書くと次のscanlineでreloadされる
+        cassette.write_u8(0xe001, 0x00, false);
+        cassette.notify_scanline();
+        cassette.write_u8(0xc001, 0x00, false);
+        for _ in 0..3 {
+            cassette.notify_scanline();
+            assert!(!cassette.is_irq_asserted());
+        }
+        cassette.notify_scanline();
+        assert!(cassette.is_irq_asserted());
+    }
+
+    /// latchが0の場合はscanlineごとにIRQが出る
+    #[test]
+    fn test_mmc3_irq_zero_latch() {
+        let mut cassette = load_mapper(4, None, 2, 1, 0x00);
+        cassette.write_u8(0xc000, 0x00, false);
+        cassette.write_u8(0xc001, 0x00, false);
+        cassette.write_u8(0xe001, 0x00, false);
+        for _ in 0..3 {
+            cassette.notify_scanline();
+            assert!(cassette.is_irq_asserted());
+            cassette.write_u8(0xe000, 0x00, false);
+            cassette.write_u8(0xe001, 0x00, false);
+            assert!(!cassette.is_irq_asserted());
+        }
+    }
+
+    /// 0xa001でPRG-RAMの無効化と書き込み禁止
+    #[test]
+    fn test_mmc3_prg_ram_protect() {
+        let mut cassette = load_mapper(4, None, 2, 1, 0x02);
+        cassette.write_u8(0x6000, 0x5a, false);
+        cassette.write_u8(0xa001, 0xc0, false);
+        cassette.write_u8(0x6000, 0xa5, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+        cassette.write_u8(0xa001, 0x00, false);
+        assert_eq!(0x60, cassette.read_u8(0x6000, false));
+    }
+}
+
+mod discrete {
+    use super::*;
+
+    /// UxROM: submapper 2はbus conflictでROMの値とANDされる, 1はそのまま
+    #[test]
+    fn test_uxrom_bus_conflict() {
+        let mut cassette = load_mapper(2, Some(2), 8, 0, 0x00);
+        // 0x8000のROMは0なので何を書いても0
+        cassette.write_u8(0x8000, 0x03, false);
+        assert_eq!(0, prg_bank_8k(&mut cassette, 0x8000));
+        // 0xc000のROMは14(0b1110)
+        cassette.write_u8(0xc000, 0x03, false);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+
+        let mut cassette = load_mapper(2, Some(1), 8, 0, 0x00);
+        cassette.write_u8(0x8000, 0x03, false);
+        assert_eq!(6, prg_bank_8k(&mut cassette, 0x8000));
+    }
+
+    /// CNROM: submapperの指定がなければbus conflictあり
+    #[test]
+    fn test_cnrom_bus_conflict() {
+        for submapper in [None, Some(0), Some(2)].iter() {
+            let mut cassette = load_mapper(3, *submapper, 2, 4, 0x00);
+            cassette.write_u8(0x8000, 0x02, false);
+            assert_eq!(0, chr_bank_1k(&mut cassette, 0x0000));
+            // 0xe000のROMは3
+            cassette.write_u8(0xe000, 0x06, false);
+            assert_eq!(16, chr_bank_1k(&mut cassette, 0x0000));
+        }
+        let mut cassette = load_mapper(3, Some(1), 2, 4, 0x00);
+        cassette.write_u8(0x8000, 0x02, false);
+        assert_eq!(16, chr_bank_1k(&mut cassette, 0x0000));
+    }
+
+    /// AxROM: submapper 2はbus conflictあり、指定がなければなし
+    #[test]
+    fn test_axrom_bus_conflict() {
+        let mut cassette = load_mapper(7, Some(2), 8, 0, 0x00);
+        cassette.write_u8(0x8000, 0x11, false);
+        assert_eq!(0, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(
+            NameTableMirror::SingleScreenLower,
+            cassette.read_nametable_mirror()
+        );
+        // 0xe000のROMは3
+        cassette.write_u8(0xe000, 0x11, false);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(
+            NameTableMirror::SingleScreenLower,
+            cassette.read_nametable_mirror()
+        );
+
+        let mut cassette = load_mapper(7, None, 8, 0, 0x00);
+        cassette.write_u8(0x8000, 0x11, false);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(
+            NameTableMirror::SingleScreenUpper,
+            cassette.read_nametable_mirror()
+        );
+    }
+
+    /// NINA-001は0x7ffd - 0x7fffのレジスタでPRG 32KB, CHR 4KB * 2を切り替える
+    fn assert_nina001(cassette: &mut Cassette) {
+        cassette.write_u8(0x8000, 0x01, false);
+        assert_eq!(0, prg_bank_8k(cassette, 0x8000));
+        cassette.write_u8(0x7ffd, 0x01, false);
+        cassette.write_u8(0x7ffe, 0x01, false);
+        cassette.write_u8(0x7fff, 0x00, false);
+        assert_eq!(4, prg_bank_8k(cassette, 0x8000));
+        assert_eq!(4, chr_bank_1k(cassette, 0x0000));
+        assert_eq!(0, chr_bank_1k(cassette, 0x1000));
+    }
+
+    /// BNROMは0x8000 - 0xffffでPRG 32KBを切り替える
+    fn assert_bnrom(cassette: &mut Cassette) {
+        cassette.write_u8(0x7ffd, 0x01, false);
+        assert_eq!(0, prg_bank_8k(cassette, 0x8000));
+        // 0xe000のROMは3
+        cassette.write_u8(0xe000, 0x01, false);
+        assert_eq!(4, prg_bank_8k(cassette, 0x8000));
+    }
+
+    /// Mapper34はsubmapperでNINA-001(1)とBNROM(2)を選ぶ
+    #[test]
+    fn test_mapper34_submapper() {
+        // CHR-ROMのサイズからはBNROMに見えるがsubmapperを優先する
+        assert_nina001(&mut load_mapper(34, Some(1), 4, 1, 0x00));
+        assert_bnrom(&mut load_mapper(34, Some(2), 4, 2, 0x00));
+    }
+
+    /// BNROM(submapper 2)はbus conflictあり、指定がなければなし
+    #[test]
+    fn test_bnrom_bus_conflict() {
+        let mut cassette = load_mapper(34, Some(2), 4, 0, 0x00);
+        cassette.write_u8(0x8000, 0x01, false);
+        assert_eq!(0, prg_bank_8k(&mut cassette, 0x8000));
+
+        let mut cassette = load_mapper(34, None, 4, 0, 0x00);
+        cassette.write_u8(0x8000, 0x01, false);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+    }
+
+    /// submapperの指定がなければCHR-ROMのサイズで判断する
+    #[test]
+    fn test_mapper34_chr_size_fallback() {
+        assert_nina001(&mut load_mapper(34, None, 4, 2, 0x00));
+        assert_bnrom(&mut load_mapper(34, None, 4, 0, 0x00));
+        assert_bnrom(&mut load_mapper(34, Some(0), 4, 1, 0x00));
+    }
+}
+
+mod vrc4 {
+    use super::*;
+
+    /// Mapper21(submapperなし)はVRC4a/VRC4cの配線をORして扱う
+    #[test]
+    fn test_vrc4_prg_bank_and_swap() {
+        let mut cassette = load_mapper(21, None, 8, 4, 0x00);
+        cassette.write_u8(0x8000, 0x03, false);
+        cassette.write_u8(0xa000, 0x05, false);
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        // 0x9002(A2 = VRC4aのA1)でswap
+        cassette.write_u8(0x9004, 0x02, false);
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0xc000));
+        // VRC4cの配線(A6, A7)でも同じレジスタに見える
+        cassette.write_u8(0x9080, 0x00, false);
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0x8000));
+    }
+
+    #[test]
+    fn test_vrc4_chr_bank_and_mirroring() {
+        let mut cassette = load_mapper(21, None, 8, 4, 0x00);
+        // 0xb000: CHR0下位4bit, 0xb001: CHR0上位5bit, 0xb002: CHR1下位4bit
+        cassette.write_u8(0xb000, 0x05, false);
+        cassette.write_u8(0xb002, 0x01, false);
+        cassette.write_u8(0xb004, 0x03, false);
+        cassette.write_u8(0xe004, 0x0f, false);
+        cassette.write_u8(0xe006, 0x01, false);
+        assert_eq!(0x15, chr_bank_1k(&mut cassette, 0x0000));
+        assert_eq!(0x03, chr_bank_1k(&mut cassette, 0x0400));
+        assert_eq!(0x1f, chr_bank_1k(&mut cassette, 0x1c00));
+
+        for (data, mirror) in [
+            (0x00, NameTableMirror::Vertical),
+            (0x01, NameTableMirror::Horizontal),
+            (0x02, NameTableMirror::SingleScreenLower),
+            (0x03, NameTableMirror::SingleScreenUpper),
+        ]
+        .iter()
+        {
+            cassette.write_u8(0x9000, *data, false);
+            assert_eq!(*mirror, cassette.read_nametable_mirror());
+        }
+    }
+
+    /// cycle modeではCPU cycleごとにcounterが進み、0xffを超えたらlatchをreloadしてIRQ
+    #[test]
+    fn test_vrc4_cycle_irq() {
+        let mut cassette = load_mapper(21, None, 8, 4, 0x00);
+        cassette.write_u8(0xf000, 0x0d, false);
+        cassette.write_u8(0xf002, 0x0f, false);
+        cassette.write_u8(0xf004, 0x07, false);
+        cassette.notify_cpu_cycles(2);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(1);
+        assert!(cassette.is_irq_asserted());
+        // acknowledgeでAがEにコピーされるので、そのまま数え直す
+        cassette.write_u8(0xf006, 0x00, false);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(3);
+        assert!(cassette.is_irq_asserted());
+        // controlを書くとIRQは取り下げられる
+        cassette.write_u8(0xf004, 0x00, false);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(16);
+        assert!(!cassette.is_irq_asserted());
+    }
+
+    /// scanline modeでは341/3 CPU cycleごとにcounterが進む
+    #[test]
+    fn test_vrc4_scanline_irq() {
+        let mut cassette = load_mapper(21, None, 8, 4, 0x00);
+        cassette.write_u8(0xf000, 0x0f, false);
+        cassette.write_u8(0xf002, 0x0f, false);
+        cassette.write_u8(0xf004, 0x02, false);
+        cassette.notify_cpu_cycles(113);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(1);
+        assert!(cassette.is_irq_asserted());
+    }
+
+    /// VRC2aはCHR bankの最下位bitを無視し、IRQを持たない
+    #[test]
+    fn test_vrc2a() {
+        let mut cassette = load_mapper(22, None, 8, 4, 0x00);
+        cassette.write_u8(0xb000, 0x0b, false);
+        assert_eq!(5, chr_bank_1k(&mut cassette, 0x0000));
+        cassette.write_u8(0xf000, 0x0f, false);
+        cassette.write_u8(0xf002, 0x0f, false);
+        cassette.write_u8(0xf001, 0x07, false);
+        cassette.notify_cpu_cycles(16);
+        assert!(!cassette.is_irq_asserted());
+    }
+}
+
+mod vrc6 {
+    use super::*;
+    use rust_nes_emulator::mapper_vrc6::VRC6_AUDIO_LEVEL_PER_STEP;
+
+    /// 拡張音源の出力がAPUのmixにそのまま加算されることも合わせて確認する
+    fn assert_audio_level(cassette: &Cassette, level: u8) {
+        let expect = f32::from(level) * VRC6_AUDIO_LEVEL_PER_STEP;
+        assert_eq!(expect, cassette.expansion_audio_output());
+        assert_eq!(expect, Apu::default().mix(cassette));
+    }
+
+    #[test]
+    fn test_vrc6_prg_and_chr_bank() {
+        let mut cassette = load_mapper(24, None, 8, 1, 0x00);
+        cassette.write_u8(0x8000, 0x03, false);
+        cassette.write_u8(0xc000, 0x0a, false);
+        assert_eq!(6, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(7, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(10, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        cassette.write_u8(0xd001, 0x05, false);
+        cassette.write_u8(0xe003, 0x07, false);
+        assert_eq!(5, chr_bank_1k(&mut cassette, 0x0400));
+        assert_eq!(7, chr_bank_1k(&mut cassette, 0x1c00));
+
+        cassette.write_u8(0xb003, 0x04, false);
+        assert_eq!(
+            NameTableMirror::Horizontal,
+            cassette.read_nametable_mirror()
+        );
+    }
+
+    /// duty 0の場合は16stepのうち1stepだけvolumeを出力する
+    #[test]
+    fn test_vrc6_pulse() {
+        let mut cassette = load_mapper(24, None, 8, 1, 0x00);
+        assert_audio_level(&cassette, 0);
+        cassette.write_u8(0x9000, 0x0f, false);
+        cassette.write_u8(0x9001, 0x00, false);
+        cassette.write_u8(0x9002, 0x80, false);
+        assert_audio_level(&cassette, 15);
+        cassette.notify_cpu_cycles(1);
+        assert_audio_level(&cassette, 0);
+        cassette.notify_cpu_cycles(15);
+        assert_audio_level(&cassette, 15);
+        // mode bitが立っていればdutyに関係なく出力
+        cassette.write_u8(0xa000, 0x88, false);
+        cassette.write_u8(0xa002, 0x80, false);
+        cassette.notify_cpu_cycles(1);
+        assert_audio_level(&cassette, 8);
+        // disableで止まる
+        cassette.write_u8(0xa002, 0x00, false);
+        assert_audio_level(&cassette, 0);
+    }
+
+    /// 2stepごとにrateを加算し、14stepで0に戻る
+    #[test]
+    fn test_vrc6_saw() {
+        let mut cassette = load_mapper(24, None, 8, 1, 0x00);
+        cassette.write_u8(0xb000, 0x08, false);
+        cassette.write_u8(0xb001, 0x01, false);
+        cassette.write_u8(0xb002, 0x80, false);
+        // period 1なので2 CPU cycleで1step
+        cassette.notify_cpu_cycles(4);
+        assert_audio_level(&cassette, 1);
+        cassette.notify_cpu_cycles(20);
+        assert_audio_level(&cassette, 6);
+        cassette.notify_cpu_cycles(4);
+        assert_audio_level(&cassette, 0);
+    }
+
+    /// 0x9003のhaltで音源が止まる
+    #[test]
+    fn test_vrc6_halt() {
+        let mut cassette = load_mapper(24, None, 8, 1, 0x00);
+        cassette.write_u8(0x9003, 0x01, false);
+        cassette.write_u8(0x9000, 0x0f, false);
+        cassette.write_u8(0x9002, 0x80, false);
+        cassette.notify_cpu_cycles(1);
+        assert_audio_level(&cassette, 15);
+        cassette.write_u8(0x9003, 0x00, false);
+        cassette.notify_cpu_cycles(1);
+        assert_audio_level(&cassette, 0);
+    }
+
+    /// Mapper26はA0とA1が入れ替わっている
+    #[test]
+    fn test_vrc6b_swap_address_line() {
+        let mut cassette = load_mapper(26, None, 8, 1, 0x00);
+        cassette.write_u8(0x9000, 0x8a, false);
+        cassette.write_u8(0x9001, 0x80, false);
+        assert_audio_level(&cassette, 10);
+        cassette.write_u8(0xd002, 0x03, false);
+        assert_eq!(3, chr_bank_1k(&mut cassette, 0x0400));
+    }
+
+    /// VRC6のIRQもVRC4と同じ
+    #[test]
+    fn test_vrc6_cycle_irq() {
+        let mut cassette = load_mapper(24, None, 8, 1, 0x00);
+        cassette.write_u8(0xf000, 0xfe, false);
+        cassette.write_u8(0xf001, 0x06, false);
+        cassette.notify_cpu_cycles(1);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(1);
+        assert!(cassette.is_irq_asserted());
+        cassette.write_u8(0xf002, 0x00, false);
+        assert!(!cassette.is_irq_asserted());
+    }
+}
+
+mod mmc2 {
+    use super::*;
+
+    /// CPU空間のaddrに見えている16KB PRG bank番号
+    fn prg_bank_16k(cassette: &mut Cassette, addr: u16) -> u8 {
+        prg_bank_8k(cassette, addr) / 2
+    }
+
+    /// PPU空間のaddrに見えている4KB CHR bank番号
+    fn chr_bank_4k(cassette: &mut Cassette, addr: u16) -> u8 {
+        chr_bank_1k(cassette, addr & 0x1000) / 4
+    }
+
+    /// MMC2は0x8000だけ切り替わり、後半24KBは最後の3bankに固定
+    #[test]
+    fn test_mmc2_prg_bank() {
+        let mut cassette = load_mapper(9, None, 8, 4, 0x00);
+        cassette.write_u8(0xa000, 0x05, false);
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(13, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(14, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        // MMC2はPRG RAMを持たない
+        cassette.write_u8(0x6000, 0x5a, false);
+        assert_eq!(0x60, cassette.read_u8(0x6000, false));
+    }
+
+    /// $0FD8/$0FE8のfetchでlatchが切り替わり、次のfetchから反映される
+    #[test]
+    fn test_mmc2_chr_latch() {
+        let mut cassette = load_mapper(9, None, 8, 4, 0x00);
+        cassette.write_u8(0xb000, 0x01, false);
+        cassette.write_u8(0xc000, 0x02, false);
+        cassette.write_u8(0xd000, 0x03, false);
+        cassette.write_u8(0xe000, 0x04, false);
+        assert_eq!(2, chr_bank_4k(&mut cassette, 0x0000));
+        assert_eq!(4, chr_bank_4k(&mut cassette, 0x1000));
+
+        // fetchしたbyteはまだ$FE側のbank
+        assert_eq!(2, chr_bank_1k(&mut cassette, 0x0fd8) / 4);
+        assert_eq!(1, chr_bank_4k(&mut cassette, 0x0000));
+        assert_eq!(4, chr_bank_4k(&mut cassette, 0x1000));
+        // 0x0000側は$0FE8ちょうどのみ反応する
+        chr_bank_1k(&mut cassette, 0x0fe9);
+        assert_eq!(1, chr_bank_4k(&mut cassette, 0x0000));
+        chr_bank_1k(&mut cassette, 0x0fe8);
+        assert_eq!(2, chr_bank_4k(&mut cassette, 0x0000));
+        // 0x1000側は$1FD8 - $1FDFの範囲で反応する
+        chr_bank_1k(&mut cassette, 0x1fdf);
+        assert_eq!(3, chr_bank_4k(&mut cassette, 0x1000));
+        chr_bank_1k(&mut cassette, 0x1fea);
+        assert_eq!(4, chr_bank_4k(&mut cassette, 0x1000));
+    }
+
+    #[test]
+    fn test_mmc2_mirroring() {
+        let mut cassette = load_mapper(9, None, 8, 4, 0x01);
+        assert_eq!(NameTableMirror::Vertical, cassette.read_nametable_mirror());
+        cassette.write_u8(0xf000, 0x01, false);
+        assert_eq!(
+            NameTableMirror::Horizontal,
+            cassette.read_nametable_mirror()
+        );
+        cassette.write_u8(0xf000, 0x00, false);
+        assert_eq!(NameTableMirror::Vertical, cassette.read_nametable_mirror());
+    }
+
+    /// MMC4は16KB PRG bankとPRG RAMを持ち、0x0000側も8byteの範囲でlatchが反応する
+    #[test]
+    fn test_mmc4() {
+        let mut cassette = load_mapper(10, None, 8, 4, 0x02);
+        cassette.write_u8(0xa000, 0x03, false);
+        assert_eq!(3, prg_bank_16k(&mut cassette, 0x8000));
+        assert_eq!(7, prg_bank_16k(&mut cassette, 0xc000));
+        cassette.write_u8(0x6000, 0x5a, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+
+        cassette.write_u8(0xb000, 0x01, false);
+        cassette.write_u8(0xc000, 0x02, false);
+        chr_bank_1k(&mut cassette, 0x0fdc);
+        assert_eq!(1, chr_bank_4k(&mut cassette, 0x0000));
+        chr_bank_1k(&mut cassette, 0x0fef);
+        assert_eq!(2, chr_bank_4k(&mut cassette, 0x0000));
+    }
+}
+
+mod mmc5 {
+    use super::*;
+    use rust_nes_emulator::mapper_mmc5::MMC5_PCM_LEVEL_PER_STEP;
+
+    /// PRG RAM 64KBのNES 2.0イメージ
+    fn load_mmc5_64k_ram() -> Cassette {
+        let mut image = build_ines_image(5, Some(0), 8, 4, 0x00);
+        image[10] = 0x0a;
+        load_image(&image)
+    }
+
+    /// 0x5102, 0x5103でPRG RAMの書き込みを許可する
+    fn unlock_prg_ram(cassette: &mut Cassette) {
+        cassette.write_u8(0x5102, 0x02, false);
+        cassette.write_u8(0x5103, 0x01, false);
+    }
+
+    #[test]
+    fn test_mmc5_prg_mode() {
+        let mut cassette = load_mapper(5, None, 8, 4, 0x00);
+        // 起動直後は8KB modeで0x5117が最後のbank
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        cassette.write_u8(0x5114, 0x83, false);
+        cassette.write_u8(0x5115, 0x85, false);
+        cassette.write_u8(0x5116, 0x87, false);
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(7, prg_bank_8k(&mut cassette, 0xc000));
+        // 16KB + 8KB mode: 0x5115の最下位bitは無視
+        cassette.write_u8(0x5100, 0x02, false);
+        assert_eq!(4, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(7, prg_bank_8k(&mut cassette, 0xc000));
+        // 32KB mode: 0x5117だけを使う
+        cassette.write_u8(0x5100, 0x00, false);
+        assert_eq!(12, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+    }
+
+    /// PRG RAMのサイズはheaderから決まり、0x5113 - 0x5116でbankを切り替える
+    #[test]
+    fn test_mmc5_prg_ram_bank() {
+        let mut cassette = load_mmc5_64k_ram();
+        // headerのサイズだけ確保し、ExRAMの1KBはNameTable用VRAMに置く
+        assert_eq!(0x1_0000, cassette.mem.battery_packed_ram.len());
+        assert_eq!(0x0400, cassette.mem.nametable_ram.len());
+        unlock_prg_ram(&mut cassette);
+        for bank in 0..8u8 {
+            cassette.write_u8(0x5113, bank, false);
+            cassette.write_u8(0x6000, 0x10 + bank, false);
+        }
+        for bank in 0..8u8 {
+            cassette.write_u8(0x5113, bank, false);
+            assert_eq!(0x10 + bank, cassette.read_u8(0x6000, false));
+        }
+        // 0x8000 - 0xdfffにもbit7が0ならRAMが見える
+        cassette.write_u8(0x5114, 0x05, false);
+        cassette.write_u8(0x5116, 0x07, false);
+        assert_eq!(0x15, cassette.read_u8(0x8000, false));
+        assert_eq!(0x17, cassette.read_u8(0xc000, false));
+        cassette.write_u8(0xc000, 0x5a, false);
+        cassette.write_u8(0x5113, 0x07, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+        // 書き込み禁止にすると書けない
+        cassette.write_u8(0x5102, 0x00, false);
+        cassette.write_u8(0x6000, 0xa5, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+    }
+
+    /// 8KBのPRG RAMしか持たない場合はbankが全部同じRAMに見える
+    #[test]
+    fn test_mmc5_prg_ram_8k() {
+        let mut cassette = load_mapper(5, Some(0), 8, 4, 0x00);
+        unlock_prg_ram(&mut cassette);
+        cassette.write_u8(0x5113, 0x00, false);
+        cassette.write_u8(0x6000, 0x5a, false);
+        cassette.write_u8(0x5113, 0x03, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+    }
+
+    /// read modeでは0x8000 - 0xbfffの読み出しがPCMの出力になり、0を読むとIRQ
+    #[test]
+    fn test_mmc5_pcm_read_mode() {
+        let mut cassette = load_mapper(5, None, 8, 4, 0x00);
+        cassette.write_u8(0x5114, 0x83, false);
+        cassette.write_u8(0x5010, 0x81, false);
+        cassette.read_u8(0x8000, false);
+        assert_eq!(
+            f32::from(3u8) * MMC5_PCM_LEVEL_PER_STEP,
+            cassette.expansion_audio_output()
+        );
+        // debuggerなどからの読み出しでは変化しない
+        cassette.write_u8(0x5114, 0x80, false);
+        cassette.read_u8(0x8000, true);
+        assert!(!cassette.is_irq_asserted());
+        // 0を読んだらIRQ, 出力はそのまま
+        cassette.read_u8(0x8000, false);
+        assert!(cassette.is_irq_asserted());
+        assert_eq!(
+            f32::from(3u8) * MMC5_PCM_LEVEL_PER_STEP,
+            cassette.expansion_audio_output()
+        );
+        assert_eq!(0x81, cassette.read_u8(0x5010, false));
+        assert!(!cassette.is_irq_asserted());
+        assert_eq!(0x01, cassette.read_u8(0x5010, false));
+    }
+
+    /// write modeでは0x5011に書いた値が出力になり、0は無視される
+    #[test]
+    fn test_mmc5_pcm_write_mode() {
+        let mut cassette = load_mapper(5, None, 8, 4, 0x00);
+        cassette.write_u8(0x5010, 0x80, false);
+        cassette.write_u8(0x5011, 0x40, false);
+        cassette.write_u8(0x5011, 0x00, false);
+        assert_eq!(
+            f32::from(0x40u8) * MMC5_PCM_LEVEL_PER_STEP,
+            cassette.expansion_audio_output()
+        );
+        cassette.write_u8(0x5114, 0x80, false);
+        cassette.read_u8(0x8000, false);
+        assert!(!cassette.is_irq_asserted());
+    }
+
+    /// pre-renderから数えて0x5203のscanlineでIRQ, 0x5204の読み出しで取り下げる
+    #[test]
+    fn test_mmc5_scanline_irq() {
+        let mut cassette = load_mapper(5, None, 8, 4, 0x00);
+        cassette.write_u8(0x5203, 0x02, false);
+        cassette.write_u8(0x5204, 0x80, false);
+        cassette.notify_scanline();
+        assert_eq!(0x40, cassette.read_u8(0x5204, false));
+        cassette.notify_scanline();
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_scanline();
+        assert!(cassette.is_irq_asserted());
+        assert_eq!(0xc0, cassette.read_u8(0x5204, false));
+        assert!(!cassette.is_irq_asserted());
+    }
+
+    #[test]
+    fn test_mmc5_multiplier() {
+        let mut cassette = load_mapper(5, None, 8, 4, 0x00);
+        cassette.write_u8(0x5205, 0x12, false);
+        cassette.write_u8(0x5206, 0x34, false);
+        assert_eq!(0xa8, cassette.read_u8(0x5205, false));
+        assert_eq!(0x03, cassette.read_u8(0x5206, false));
+    }
+}
+
+mod fme7 {
+    use super::*;
+    use rust_nes_emulator::mapper_fme7::{SUNSOFT_5B_CHANNEL_LEVEL, SUNSOFT_5B_CLOCK_DIVIDER};
+
+    /// 0x8000でcommandを選んで0xa000に書く
+    fn write_command(cassette: &mut Cassette, command: u8, data: u8) {
+        cassette.write_u8(0x8000, command, false);
+        cassette.write_u8(0xa000, data, false);
+    }
+
+    /// 0xc000で5Bのregisterを選んで0xe000に書く
+    fn write_audio(cassette: &mut Cassette, register: u8, data: u8) {
+        cassette.write_u8(0xc000, register, false);
+        cassette.write_u8(0xe000, data, false);
+    }
+
+    /// 5Bのclock(CPU 16cycle)をn回進める
+    fn clock_audio(cassette: &mut Cassette, n: usize) {
+        cassette.notify_cpu_cycles(n * usize::from(SUNSOFT_5B_CLOCK_DIVIDER));
+    }
+
+    #[test]
+    fn test_fme7_prg_bank() {
+        let mut cassette = load_mapper(69, None, 8, 4, 0x00);
+        write_command(&mut cassette, 0x09, 0x03);
+        write_command(&mut cassette, 0x0a, 0x05);
+        write_command(&mut cassette, 0x0b, 0x07);
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(7, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        // 0x6000はROMとRAMを切り替える
+        write_command(&mut cassette, 0x08, 0x09);
+        assert_eq!(9, prg_bank_8k(&mut cassette, 0x6000));
+        write_command(&mut cassette, 0x08, 0xc0);
+        cassette.write_u8(0x6000, 0x5a, false);
+        assert_eq!(0x5a, cassette.read_u8(0x6000, false));
+        // RAMが無効ならopen bus
+        write_command(&mut cassette, 0x08, 0x40);
+        assert_eq!(0x60, cassette.read_u8(0x6000, false));
+    }
+
+    #[test]
+    fn test_fme7_chr_bank_and_mirroring() {
+        let mut cassette = load_mapper(69, None, 8, 4, 0x00);
+        for index in 0..8u8 {
+            write_command(&mut cassette, index, 0x10 + index);
+        }
+        for index in 0..8u8 {
+            assert_eq!(
+                0x10 + index,
+                chr_bank_1k(&mut cassette, u16::from(index) * 0x0400)
+            );
+        }
+        for (data, mirror) in [
+            (0x00, NameTableMirror::Vertical),
+            (0x01, NameTableMirror::Horizontal),
+            (0x02, NameTableMirror::SingleScreenLower),
+            (0x03, NameTableMirror::SingleScreenUpper),
+        ]
+        .iter()
+        {
+            write_command(&mut cassette, 0x0c, *data);
+            assert_eq!(*mirror, cassette.read_nametable_mirror());
+        }
+    }
+
+    /// counterはCPU cycleごとに減り、0からwrapしたらIRQ
+    #[test]
+    fn test_fme7_cycle_irq() {
+        let mut cassette = load_mapper(69, None, 8, 4, 0x00);
+        write_command(&mut cassette, 0x0e, 0x03);
+        write_command(&mut cassette, 0x0f, 0x00);
+        // counterだけ有効ならIRQは出ない
+        write_command(&mut cassette, 0x0d, 0x80);
+        cassette.notify_cpu_cycles(8);
+        assert!(!cassette.is_irq_asserted());
+
+        write_command(&mut cassette, 0x0e, 0x03);
+        write_command(&mut cassette, 0x0f, 0x00);
+        write_command(&mut cassette, 0x0d, 0x81);
+        cassette.notify_cpu_cycles(3);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(1);
+        assert!(cassette.is_irq_asserted());
+        // 0xdを書くと取り下げる
+        write_command(&mut cassette, 0x0d, 0x81);
+        assert!(!cassette.is_irq_asserted());
+        // counterを止めると減らない
+        write_command(&mut cassette, 0x0e, 0x00);
+        write_command(&mut cassette, 0x0d, 0x01);
+        cassette.notify_cpu_cycles(8);
+        assert!(!cassette.is_irq_asserted());
+    }
+
+    /// tone/noiseを無効にしたchannelはvolumeをそのまま出力し、toneはperiodごとに反転する
+    #[test]
+    fn test_sunsoft_5b_tone() {
+        let mut cassette = load_mapper(69, None, 8, 4, 0x00);
+        assert_eq!(0.0, cassette.expansion_audio_output());
+        write_audio(&mut cassette, 0x00, 0x01);
+        write_audio(&mut cassette, 0x01, 0x00);
+        write_audio(&mut cassette, 0x07, 0x3e);
+        write_audio(&mut cassette, 0x08, 0x0f);
+        write_audio(&mut cassette, 0x09, 0x0f);
+        assert_eq!(SUNSOFT_5B_CHANNEL_LEVEL, cassette.expansion_audio_output());
+        assert_eq!(SUNSOFT_5B_CHANNEL_LEVEL, Apu::default().mix(&cassette));
+        clock_audio(&mut cassette, 1);
+        assert_eq!(
+            2.0 * SUNSOFT_5B_CHANNEL_LEVEL,
+            cassette.expansion_audio_output()
+        );
+        clock_audio(&mut cassette, 1);
+        assert_eq!(SUNSOFT_5B_CHANNEL_LEVEL, cassette.expansion_audio_output());
+    }
+
+    /// attack + holdのenvelopeは32clock * periodごとに1step上がり、15で止まる
+    #[test]
+    fn test_sunsoft_5b_envelope() {
+        let mut cassette = load_mapper(69, None, 8, 4, 0x00);
+        write_audio(&mut cassette, 0x07, 0x3f);
+        write_audio(&mut cassette, 0x0a, 0x10);
+        write_audio(&mut cassette, 0x0b, 0x01);
+        write_audio(&mut cassette, 0x0c, 0x00);
+        write_audio(&mut cassette, 0x0d, 0x0d);
+        assert_eq!(0.0, cassette.expansion_audio_output());
+        clock_audio(&mut cassette, 32 * 15);
+        assert_eq!(SUNSOFT_5B_CHANNEL_LEVEL, cassette.expansion_audio_output());
+        clock_audio(&mut cassette, 32 * 4);
+        assert_eq!(SUNSOFT_5B_CHANNEL_LEVEL, cassette.expansion_audio_output());
+    }
+}
+
+mod namco163 {
+    use super::*;
+    use rust_nes_emulator::mapper_namco163::{
+        NAMCO163_AUDIO_LEVEL_PER_STEP, NAMCO163_CHANNEL_CPU_CYCLE,
+    };
+
+    /// 0xf800でaddressを選んで(auto increment), 0x4800から内蔵sound RAMに書く
+    fn write_sound_ram(cassette: &mut Cassette, addr: u8, data: &[u8]) {
+        cassette.write_u8(0xf800, 0x80 | addr, false);
+        for d in data {
+            cassette.write_u8(0x4800, *d, false);
+        }
+    }
+
+    #[test]
+    fn test_namco163_prg_and_chr_bank() {
+        let mut cassette = load_mapper(19, None, 8, 4, 0x00);
+        cassette.write_u8(0xe000, 0x03, false);
+        cassette.write_u8(0xe800, 0x05, false);
+        cassette.write_u8(0xf000, 0x07, false);
+        assert_eq!(3, prg_bank_8k(&mut cassette, 0x8000));
+        assert_eq!(5, prg_bank_8k(&mut cassette, 0xa000));
+        assert_eq!(7, prg_bank_8k(&mut cassette, 0xc000));
+        assert_eq!(15, prg_bank_8k(&mut cassette, 0xe000));
+        for index in 0..8u16 {
+            cassette.write_u8(0x8000 + index * 0x0800, 0x10 + index as u8, false);
+        }
+        for index in 0..8u16 {
+            assert_eq!(
+                0x10 + index as u8,
+                chr_bank_1k(&mut cassette, index * 0x0400)
+            );
+        }
+    }
+
+    /// 0xf800の上位4bitが0x4の時だけ、下位4bitで2KBごとに書き込みを禁止する
+    #[test]
+    fn test_namco163_prg_ram_protect() {
+        let mut cassette = load_mapper(19, None, 8, 4, 0x02);
+        cassette.write_u8(0x6000, 0x5a, false);
+        assert_eq!(0x00, cassette.read_u8(0x6000, false));
+        cassette.write_u8(0xf800, 0x41, false);
+        cassette.write_u8(0x6000, 0x5a, false);
+        cassette.write_u8(0x6800, 0xa5, false);
+        assert_eq!(0x00, cassette.read_u8(0x6000, false));
+        assert_eq!(0xa5, cassette.read_u8(0x6800, false));
+    }
+
+    /// counterはCPU cycleごとに増え、0x7fffで止まってIRQ
+    #[test]
+    fn test_namco163_cycle_irq() {
+        let mut cassette = load_mapper(19, None, 8, 4, 0x00);
+        cassette.write_u8(0x5000, 0xfd, false);
+        cassette.write_u8(0x5800, 0xff, false);
+        cassette.notify_cpu_cycles(1);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(1);
+        assert!(cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(16);
+        assert_eq!(0xff, cassette.read_u8(0x5000, false));
+        assert_eq!(0xff, cassette.read_u8(0x5800, false));
+        // counterに書き込むとIRQを取り下げる
+        cassette.write_u8(0x5000, 0x00, false);
+        assert!(!cassette.is_irq_asserted());
+        // bit15が0なら数えない
+        cassette.write_u8(0x5800, 0x7f, false);
+        cassette.write_u8(0x5000, 0xfe, false);
+        cassette.notify_cpu_cycles(16);
+        assert!(!cassette.is_irq_asserted());
+    }
+
+    /// auto incrementで読み書きできる
+    #[test]
+    fn test_namco163_sound_ram() {
+        let mut cassette = load_mapper(19, None, 8, 4, 0x00);
+        write_sound_ram(&mut cassette, 0x7e, &[0x12, 0x34, 0x56]);
+        // 0x7fから0x00にwrapする
+        cassette.write_u8(0xf800, 0xfe, false);
+        assert_eq!(0x12, cassette.read_u8(0x4800, false));
+        assert_eq!(0x34, cassette.read_u8(0x4800, false));
+        assert_eq!(0x56, cassette.read_u8(0x4800, false));
+        // auto incrementしない場合は同じaddress
+        cassette.write_u8(0xf800, 0x7e, false);
+        assert_eq!(0x12, cassette.read_u8(0x4800, false));
+        assert_eq!(0x12, cassette.read_u8(0x4800, false));
+    }
+
+    /// 4sampleの波形を1updateに1sampleずつ進める
+    #[test]
+    fn test_namco163_wavetable() {
+        let mut cassette = load_mapper(19, None, 8, 4, 0x00);
+        let cycle = usize::from(NAMCO163_CHANNEL_CPU_CYCLE);
+        // sample: 0, 15, 8, 8
+        write_sound_ram(&mut cassette, 0x00, &[0xf0, 0x88]);
+        // channel 8: freq 0x10000, length 4, wave address 0, volume 15, 1channel
+        write_sound_ram(
+            &mut cassette,
+            0x78,
+            &[0x00, 0x00, 0x00, 0x00, 0xfd, 0x00, 0x00, 0x0f],
+        );
+        assert_eq!(0.0, cassette.expansion_audio_output());
+        cassette.notify_cpu_cycles(cycle);
+        assert_eq!(
+            105.0 * NAMCO163_AUDIO_LEVEL_PER_STEP,
+            cassette.expansion_audio_output()
+        );
+        assert_eq!(
+            105.0 * NAMCO163_AUDIO_LEVEL_PER_STEP,
+            Apu::default().mix(&cassette)
+        );
+        cassette.notify_cpu_cycles(cycle * 2);
+        assert_eq!(0.0, cassette.expansion_audio_output());
+        cassette.notify_cpu_cycles(cycle);
+        assert_eq!(
+            -120.0 * NAMCO163_AUDIO_LEVEL_PER_STEP,
+            cassette.expansion_audio_output()
+        );
+        // 0xe000 bit6で音源を止める
+        cassette.write_u8(0xe000, 0x40, false);
+        assert_eq!(0.0, cassette.expansion_audio_output());
+    }
+}
+
+mod fds {
+    use super::*;
+    use rust_nes_emulator::fds::*;
+    use rust_nes_emulator::mapper_fds::FDS_AUDIO_LEVEL_PER_STEP;
+
+    /// disk info blockとfile amount blockだけを持つ1面分の.fdsイメージ(headerなし)
+    fn build_fds_image() -> Vec<u8> {
+        let mut image = vec![0u8; FDS_DISK_SIDE_SIZE];
+        image[0] = 0x01;
+        image[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        image[56] = 0x02;
+        image
+    }
+
+    fn load_fds() -> Cassette {
+        let bios = vec![0xeau8; FDS_BIOS_SIZE];
+        let mut cassette = Cassette::default();
+        if let Err(e) = cassette.from_fds_binary(&bios, &build_fds_image()) {
+            panic!("fds binary read error: {}", e);
+        }
+        cassette
+    }
+
+    /// 書き込みはdiffとして持ち、serialize_diff/restore_diffで別のdiskに移せる
+    #[test]
+    fn test_fds_disk_diff_round_trip() {
+        let image = build_fds_image();
+        let mut disk = FdsDisk::parse(&image).unwrap();
+        assert_eq!(1, disk.side_count());
+        assert!(!disk.is_dirty);
+        let pos = 0x1000;
+        let original = disk.read(0, pos);
+        disk.write(0, pos, original ^ 0xff);
+        disk.write(0, pos + 1, 0x5a);
+        assert!(disk.is_dirty);
+        assert_eq!(2, disk.diff_len());
+        assert_eq!(original ^ 0xff, disk.read(0, pos));
+        // 元と同じ値に戻したらdiffから消える
+        disk.write(0, pos + 1, 0x00);
+        assert_eq!(1, disk.diff_len());
+
+        let diff = disk.serialize_diff();
+        assert_eq!(6, diff.len());
+        let mut restored = FdsDisk::parse(&image).unwrap();
+        assert!(restored.restore_diff(&diff));
+        assert!(!restored.is_dirty);
+        assert_eq!(1, restored.diff_len());
+        assert_eq!(original ^ 0xff, restored.read(0, pos));
+
+        restored.clear_diff();
+        assert!(restored.is_dirty);
+        assert_eq!(original, restored.read(0, pos));
+    }
+
+    /// 壊れたdiffはfalse, 範囲外の位置は捨てる
+    #[test]
+    fn test_fds_disk_restore_bad_diff() {
+        let mut disk = FdsDisk::parse(&build_fds_image()).unwrap();
+        assert!(!disk.restore_diff(&[0x00, 0x00, 0x10]));
+        let out_of_range = [0x01, 0x00, 0x10, 0x00, 0x00, 0x5a];
+        assert!(!disk.restore_diff(&out_of_range));
+        assert_eq!(0, disk.diff_len());
+        // 面数が足りないimageは読み込めない
+        assert!(FdsDisk::parse(&[0x01; 100]).is_err());
+    }
+
+    /// 0x6000 - 0xdfffはRAM Adapter上のRAM, 0xe000 - 0xffffはBIOS
+    #[test]
+    fn test_fds_prg_ram() {
+        let mut cassette = load_fds();
+        assert_eq!(1, cassette.fds_side_count());
+        assert_eq!(Some(0), cassette.fds_inserted_side());
+        // RAM Adapter上のRAMはカセット内RAMとして32KBだけ確保する
+        assert_eq!(FDS_PRG_RAM_SIZE, cassette.mem.battery_packed_ram.len());
+        cassette.write_u8(0x6000, 0x12, false);
+        cassette.write_u8(0xdfff, 0x34, false);
+        cassette.write_u8(0xe000, 0x56, false);
+        assert_eq!(0x12, cassette.read_u8(0x6000, false));
+        assert_eq!(0x34, cassette.read_u8(0xdfff, false));
+        assert_eq!(0xea, cassette.read_u8(0xe000, false));
+    }
+
+    /// timer IRQはreload値から数えて0の次のcycleでIRQ, 0x4030の読み出しで取り下げる
+    #[test]
+    fn test_fds_timer_irq() {
+        let mut cassette = load_fds();
+        cassette.write_u8(0x4023, 0x01, false);
+        cassette.write_u8(0x4020, 0x03, false);
+        cassette.write_u8(0x4021, 0x00, false);
+        cassette.write_u8(0x4022, 0x02, false);
+        cassette.notify_cpu_cycles(3);
+        assert!(!cassette.is_irq_asserted());
+        cassette.notify_cpu_cycles(1);
+        assert!(cassette.is_irq_asserted());
+        assert_eq!(0x01, cassette.read_u8(0x4030, false) & 0x01);
+        assert!(!cassette.is_irq_asserted());
+        // repeatしない場合は1回で止まる
+        cassette.notify_cpu_cycles(16);
+        assert!(!cassette.is_irq_asserted());
+    }
+
+    /// 波形メモリを0 - 63のrampにして、accumulatorがあふれるごとに1stepずつ進める
+    /// frequency 0x800なら32cycleで1step
+    #[test]
+    fn test_fds_audio() {
+        let mut cassette = load_fds();
+        cassette.write_u8(0x4023, 0x02, false);
+        cassette.write_u8(0x4089, 0x80, false);
+        for index in 0..0x40u16 {
+            cassette.write_u8(0x4040 + index, index as u8, false);
+        }
+        assert_eq!(0x45, cassette.read_u8(0x4045, false));
+        cassette.write_u8(0x4089, 0x00, false);
+        // envelopeを使わずgain 32
+        cassette.write_u8(0x4080, 0xa0, false);
+        assert_eq!(0x60, cassette.read_u8(0x4090, false));
+        cassette.write_u8(0x4082, 0x00, false);
+        cassette.write_u8(0x4083, 0x08, false);
+        cassette.notify_cpu_cycles(1);
+        assert_eq!(0.0, cassette.expansion_audio_output());
+        cassette.notify_cpu_cycles(32 * 10 - 1);
+        assert_eq!(
+            10.0 * FDS_AUDIO_LEVEL_PER_STEP,
+            cassette.expansion_audio_output()
+        );
+        assert_eq!(
+            10.0 * FDS_AUDIO_LEVEL_PER_STEP,
+            Apu::default().mix(&cassette)
+        );
+        // haltすると先頭に戻る
+        cassette.write_u8(0x4083, 0x80, false);
+        cassette.notify_cpu_cycles(1);
+        assert_eq!(0.0, cassette.expansion_audio_output());
+    }
+}
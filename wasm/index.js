@@ -154,10 +154,13 @@ async function main() {
           // stop emulate
           isEmulateEnable = false;
           // cassette load
-          if (!emu.load(src)) {
+          try {
+            emu.load(src);
+          } catch (err) {
             // error notify
             this.$notify({
-              title: "Load ROM Error"
+              title: "Load ROM Error",
+              message: String(err)
             });
             return;
           }
@@ -110,31 +110,39 @@ impl WasmEmulator {
     }
     /// .nesファイルを読み込みます
     /// `data` - nesファイルのバイナリ
-    pub fn load(&mut self, binary: &[u8]) -> bool {
+    /// 失敗した場合は理由を文字列でthrowする
+    pub fn load(&mut self, binary: &[u8]) -> Result<(), JsValue> {
         console_log!("WasmEmulator::load()");
-        let success = self
-            .cpu_sys
+        self.cpu_sys
             .cassette
-            .from_ines_binary(|addr: usize| binary[addr]);
-        if success {
-            self.reset();
-        }
-        success
+            .from_ines_binary(binary.len(), |addr: usize| binary[addr])
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.reset();
+        Ok(())
     }
     /// 描画領域1面分更新します
     /// TODO: APU対応で1lineごとにする
     pub fn step_line(&mut self) {
         // console_log!("WasmEmulator::step_line()");
-        let mut total_cycle: usize = 0;
-        while total_cycle < CYCLE_PER_DRAW_FRAME {
+        // PPUが1frame描き終えるまで進める
+        let current_frame = self.ppu.frame_count;
+        while self.ppu.frame_count == current_frame {
             // for debug
             // console_log!("a:{:02X} x:{:02X} y:{:02X} pc:{:04X} sp:{:02X} p:{:02X} ", self.cpu.a, self.cpu.x, self.cpu.y, self.cpu.pc, self.cpu.sp, self.cpu.p);
 
-            let cpu_cycle = usize::from(self.cpu.step(&mut self.cpu_sys));
-            if let Some(interrupt) = self.ppu.step(cpu_cycle, &mut self.cpu_sys, &mut self.fb) {
-                self.cpu.interrupt(&mut self.cpu_sys, interrupt);
+            // cycle-accurate featureが有効なら、busアクセスごとにppuを1cycleずつ進める
+            #[cfg(feature = "cycle-accurate")]
+            {
+                let ppu = &mut self.ppu;
+                let fb = &mut self.fb;
+                self.cpu
+                    .step_cycle_accurate(&mut self.cpu_sys, |sys| ppu.step(1, sys, fb));
+            }
+            #[cfg(not(feature = "cycle-accurate"))]
+            {
+                let cpu_cycle = usize::from(self.cpu.step(&mut self.cpu_sys));
+                self.ppu.step(cpu_cycle, &mut self.cpu_sys, &mut self.fb);
             }
-            total_cycle = total_cycle + cpu_cycle;
             // TODO: apu対応(1面分更新だとタイミング的に厳しいかも #8)
         }
     }